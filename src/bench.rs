@@ -0,0 +1,91 @@
+//! Balancing throughput measured against a local mock of BGG.
+//! Built only with `--features bench`. Whether the report comes out
+//! right is checked by the end-to-end test in core.
+use crate::core;
+use crate::error::AppError;
+use crate::mock;
+use crate::progress::NullReporter;
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const USER_AVERAGE: f64 = 6.5;
+
+/// Mock answers every game with `pages` full pages of comments.
+pub struct Settings {
+    pub games: u32,
+    pub pages: u32,
+    pub latency: Duration,
+    pub threads: usize,
+}
+
+pub fn run(settings: &Settings) -> Result<(), AppError> {
+    let (games, pages) = (settings.games, settings.pages);
+    let mock = mock::serve(settings.latency, move |path| route(games, pages, path))?;
+
+    // work in a scratch directory, db and config names are fixed
+    let dir = env::temp_dir().join(format!("bgg_bench_{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    env::set_current_dir(&dir)?;
+    core::create_structure(core::DEFAULT_LIMIT, core::DEFAULT_THREADS)?;
+    let mut config = core::config()?;
+    config.base_url = mock.base_url.clone();
+    config.threads = settings.threads;
+    // the mock has no politeness limits
+    config.max_requests_per_minute = u32::MAX;
+    core::pull_games(&config, false, &AtomicBool::new(true), &mut NullReporter)?;
+
+    let start = Instant::now();
+    let running = Arc::new(AtomicBool::new(true));
+    core::stabilize(config, None, None, running, &mut NullReporter)?;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let requests = mock.requests();
+    println!(
+        "Balanced {} games with {} threads: {} requests in {:.2}s, {:.1} requests/sec.",
+        settings.games,
+        settings.threads,
+        requests,
+        elapsed,
+        f64::from(requests) / elapsed
+    );
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Rating every user gives to the game, differs between games
+/// so balancing has something to sort.
+fn game_rating(id: u32) -> f64 {
+    5.0 + f64::from(id % 5)
+}
+
+/// All games fit on the first search page, every game has `pages`
+/// full pages of ratings by the same users, so the user cache warms
+/// up quickly.
+fn route(games: u32, pages: u32, path: &str) -> mock::Answer {
+    if path.starts_with("/search/boardgame/page/1?") {
+        let rows: Vec<String> = (1..=games)
+            .map(|id| mock::search_row(id, &format!("Game {}", id), 7.0, 7.5, 1000))
+            .collect();
+        mock::ok(mock::search_page(&rows, games))
+    } else if path.starts_with("/search/boardgame/") {
+        mock::ok(mock::search_page(&[], 0))
+    } else if path.starts_with("/xmlapi2/thing") {
+        let (id, page) = (mock::param(path, "id="), mock::param(path, "page="));
+        let ratings: Vec<(String, f64)> = if page <= pages {
+            (0..100)
+                .map(|i| (format!("user{}", (page - 1) * 100 + i), game_rating(id)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        mock::ok(mock::comments_page(&ratings, None))
+    } else if path.starts_with("/user/") {
+        mock::ok(mock::profile_page(USER_AVERAGE))
+    } else {
+        mock::not_found()
+    }
+}
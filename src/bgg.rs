@@ -1,84 +1,439 @@
-use crate::lib::{Game, User};
-use failure::{bail, Error, ResultExt};
-use reqwest::Client;
+use crate::error::{AppError, BggError};
+use crate::profile::{self, Phase};
+use crate::ratelimit::{self, RateLimiter};
+use bgg_swing2::{Game, User};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, USER_AGENT};
 use reqwest::StatusCode;
+use reqwest::{Client, Proxy, Response};
 use select::document::Document;
+use select::node::Node;
 use select::predicate::{Class, Name};
+use serde_derive::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+// most ratings BGG gives on a page, and the least
 pub const USER_PAGE_SIZE: u32 = 100;
+pub const MIN_USER_PAGE_SIZE: u32 = 10;
+const SEARCH_PAGE_SIZE: u32 = 100;
+pub const BASE_URL: &str = "https://boardgamegeek.com";
+const MIN_RATING: f64 = 1.0;
+const MAX_RATING: f64 = 10.0;
+// Title of the html page BGG serves with status 200 when it is down,
+// compared in lower case.
+const MAINTENANCE_TITLE: &str = "down for maintenance";
 
-pub fn get_users_from(client: &Client, game_id: u32, page: u32) -> Result<Vec<(User, f64)>, Error> {
-    let url =  format!(
-        "https://www.boardgamegeek.com/xmlapi2/thing?type=boardgame&id={}&ratingcomments=1&page={}&pagesize={}",
-        game_id,
-        page,
-        USER_PAGE_SIZE
+/// Set once by --offline, no client can be built after that.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn go_offline() {
+    OFFLINE.store(true, Ordering::SeqCst);
+}
+
+/// Commands that need BGG call this before touching anything.
+pub fn ensure_online() -> Result<(), AppError> {
+    if OFFLINE.load(Ordering::SeqCst) {
+        return Err(BggError::Offline.into());
+    }
+    Ok(())
+}
+
+/// Source of every BGG page. Cheap to clone, clones share connections.
+#[derive(Clone)]
+pub struct BggClient {
+    transport: Transport,
+    base: String,
+    strict: bool, // see BggError::FormatChanged
+}
+
+#[derive(Clone)]
+enum Transport {
+    Http(Client),
+    /// Canned pages named `search_{page}.html`, `thing_{id}_{page}.xml`,
+    /// `user_{name}.html`, `user_{name}.json` and `user_{name}.xml`.
+    Fixtures(PathBuf),
+}
+
+impl BggClient {
+    /// Talks to BGG at `base`.
+    pub fn http(
+        base: &str,
+        contact: Option<&str>,
+        proxy: Option<&str>,
+    ) -> Result<BggClient, AppError> {
+        Ok(BggClient {
+            transport: Transport::Http(http_client(contact, proxy)?),
+            base: String::from(base),
+            strict: false,
+        })
+    }
+
+    /// Reads pages from `dir` instead, works in offline mode.
+    pub fn fixtures(dir: &Path) -> BggClient {
+        BggClient {
+            transport: Transport::Fixtures(dir.to_path_buf()),
+            base: String::new(),
+            strict: false,
+        }
+    }
+
+    /// Strict client fails on anything a lenient one would skip.
+    pub fn strict(self, strict: bool) -> BggClient {
+        BggClient { strict, ..self }
+    }
+
+    /// Parsing error, marked as format drift when the client is strict.
+    fn parse_error(&self, e: AppError) -> AppError {
+        if self.strict {
+            BggError::FormatChanged(e.to_string()).into()
+        } else {
+            e
+        }
+    }
+
+    /// Page at `path` below the base url, or `fixture` file.
+    fn document(&self, path: &str, fixture: &str) -> Result<Document, AppError> {
+        let (body, source) = self.body(path, fixture)?;
+        parse_body(&body, &source)
+    }
+
+    /// Text at `path` below the base url, or of `fixture` file, with
+    /// the url or the file it came from.
+    fn body(&self, path: &str, fixture: &str) -> Result<(String, String), AppError> {
+        match &self.transport {
+            Transport::Http(client) => {
+                let url = format!("{}{}", self.base, path);
+                let mut resp = download(client, &url)?;
+                if resp.status() != StatusCode::OK {
+                    let status = resp.status();
+                    return Err(BggError::Status { url, status }.into());
+                }
+                let body = profile::time(Phase::Fetch, || resp.text()).map_err(|source| {
+                    BggError::Download {
+                        url: url.clone(),
+                        source,
+                    }
+                })?;
+                Ok((body, url))
+            }
+            Transport::Fixtures(dir) => {
+                let file = dir.join(fixture);
+                let body = fs::read_to_string(&file).map_err(|source| BggError::Fixture {
+                    path: file.display().to_string(),
+                    source,
+                })?;
+                Ok((body, file.display().to_string()))
+            }
+        }
+    }
+}
+
+/// BGG asks API users to say who they are, so `contact` goes into
+/// the User-Agent. Without `proxy` the one from HTTP_PROXY/HTTPS_PROXY
+/// is used, if any.
+fn http_client(contact: Option<&str>, proxy: Option<&str>) -> Result<Client, AppError> {
+    ensure_online()?;
+    let agent = user_agent(contact);
+    let mut headers = HeaderMap::new();
+    let agent = HeaderValue::from_str(&agent)
+        .map_err(|e| AppError::Config(format!("contact `{}` can't be sent: {}", agent, e)))?;
+    headers.insert(USER_AGENT, agent);
+    // reqwest would add it anyway, sent explicitly to pin it
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+    let builder = Client::builder().default_headers(headers).gzip(true);
+    let builder = match proxy {
+        Some(url) => builder.proxy(Proxy::all(url).map_err(|e| {
+            AppError::Config(format!("http_proxy `{}` is not a valid url: {}", url, e))
+        })?),
+        None => builder.use_sys_proxy(),
+    };
+    builder
+        .build()
+        .map_err(|e| AppError::Config(format!("Can't set up http client: {}", e)))
+}
+
+/// `bgg_swing2/<version>`, with ` (+contact)` if there is one.
+fn user_agent(contact: Option<&str>) -> String {
+    match contact {
+        Some(contact) if !contact.trim().is_empty() => format!(
+            "bgg_swing2/{} (+{})",
+            env!("CARGO_PKG_VERSION"),
+            contact.trim()
+        ),
+        _ => format!("bgg_swing2/{}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/// Single page of user ratings for a game.
+pub struct UserPage {
+    pub users: Vec<(User, f64)>,
+    pub rejected: u32,       // ratings out of the 1-10 scale
+    pub weight: Option<f64>, // asked for on the first page only
+    pub total: Option<u32>,  // ratings of the game on all pages, if BGG tells
+}
+
+/// Path of a page of user ratings, the first one brings stats too.
+pub fn user_ratings_path(game_id: u32, page: u32, page_size: u32) -> String {
+    let mut path = format!(
+        "/xmlapi2/thing?type=boardgame&id={}&ratingcomments=1&page={}&pagesize={}",
+        game_id, page, page_size
     );
-    let resp = client
-        .get(&url)
-        .send()
-        .with_context(|_| format!("could not download page `{}`", url))?;
-    if resp.status() != StatusCode::OK {
-        bail!(
-            "Can't get page {} for {}. Status: {}",
-            page,
-            game_id,
-            resp.status()
-        );
+    if page == 1 {
+        path.push_str("&stats=1");
+    }
+    path
+}
+
+pub fn get_users_from(
+    client: &BggClient,
+    game_id: u32,
+    page: u32,
+    page_size: u32,
+) -> Result<UserPage, AppError> {
+    let path = user_ratings_path(game_id, page, page_size);
+    let fixture = format!("thing_{}_{}.xml", game_id, page);
+    let doc = client.document(&path, &fixture)?;
+    let weight = filter_weight(&doc).map_err(|e| client.parse_error(e))?;
+    let total = doc
+        .find(Name("comments"))
+        .next()
+        .and_then(|tag| tag.attr("totalitems"))
+        .and_then(|t| t.parse().ok());
+    let (users, rejected) = profile::time(Phase::Parse, || filter_users(doc, client.strict))
+        .map_err(|e| client.parse_error(e))?;
+    Ok(UserPage {
+        users,
+        rejected,
+        weight,
+        total,
+    })
+}
+
+/// Complexity is absent without stats=1 and zero if nobody voted.
+fn filter_weight(doc: &Document) -> Result<Option<f64>, AppError> {
+    let weight = match doc
+        .find(Name("averageweight"))
+        .next()
+        .and_then(|tag| tag.attr("value"))
+    {
+        Some(w) => w.parse::<f64>()?,
+        None => return Ok(None),
+    };
+    if weight > 0.0 {
+        Ok(Some(weight))
+    } else {
+        Ok(None)
     }
-    let doc = Document::from_read(resp)?;
-    filter_users(doc)
 }
 
-fn filter_users(doc: Document) -> Result<Vec<(User, f64)>, Error> {
+fn download(client: &Client, url: &str) -> Result<Response, AppError> {
+    let resp = profile::time(Phase::Fetch, || client.get(url).send()).map_err(|source| {
+        BggError::Download {
+            url: String::from(url),
+            source,
+        }
+    })?;
+    Ok(resp)
+}
+
+/// Makes sure the body is not a maintenance page, which would
+/// otherwise parse into an empty list.
+fn parse_body(body: &str, url: &str) -> Result<Document, AppError> {
+    let doc = profile::time(Phase::Parse, || Document::from(body));
+    if is_maintenance(&doc) {
+        return Err(BggError::Maintenance(String::from(url)).into());
+    }
+    Ok(doc)
+}
+
+/// The maintenance page by its title, or the error xmlapi2 answers
+/// with instead of items. Only elements count, the same words in a
+/// game name or a rating comment don't.
+fn is_maintenance(doc: &Document) -> bool {
+    let down = doc
+        .find(Name("title"))
+        .any(|t| t.text().to_lowercase().contains(MAINTENANCE_TITLE));
+    let error = doc.find(Name("items")).next().is_none()
+        && doc
+            .find(Name("error"))
+            .any(|e| e.find(Name("message")).next().is_some());
+    down || error
+}
+
+/// Ratings out of the 1-10 scale are counted and dropped, `strict`
+/// fails on them instead.
+fn filter_users(doc: Document, strict: bool) -> Result<(Vec<(User, f64)>, u32), AppError> {
     let usertags = doc.find(Name("comment"));
 
     let mut users = Vec::new();
+    let mut rejected = 0;
     for tag in usertags {
         let name = match tag.attr("username") {
             Some(n) => String::from(n),
-            _ => bail!("Can't parse username in the user list"),
+            _ => {
+                return Err(AppError::Parse(String::from(
+                    "Can't parse username in the user list",
+                )))
+            }
         };
         let rating = match tag.attr("rating") {
             Some(r) => r.parse::<f64>()?,
-            _ => bail!("Can't parse user rating in the user list"),
+            _ => {
+                return Err(AppError::Parse(String::from(
+                    "Can't parse user rating in the user list",
+                )))
+            }
         };
+        // historical glitches, would wreck the average
+        if !(MIN_RATING..=MAX_RATING).contains(&rating) {
+            if strict {
+                let e = format!("rating {} of {} is out of range", rating, name);
+                return Err(AppError::Parse(e));
+            }
+            rejected += 1;
+            continue;
+        }
         users.push((name, rating));
     }
-    Ok(users)
+    Ok((users, rejected))
 }
 
 pub struct GameIterator<'a> {
-    client: &'a Client,
+    client: &'a BggClient,
+    limiter: &'a RateLimiter,
+    running: &'a AtomicBool, // lowered to stop between requests
     page: u32,
-    user_limit: u32,
-    seen: Option<Game>,
+    query: SearchQuery,
+    seen: HashSet<u32>,            // ids of games from earlier pages
+    total: Option<u32>,            // games BGG counts for the search
+    attempts: u32,                 // extra tries of a page after transient errors
+    delay: Duration,               // wait before a retry grows by this much
+    retried: Vec<(u32, AppError)>, // page and error of every retry
+    retried_total: u32,
+    refetched: u32, // pages asked for again by refetch
 }
 
 impl<'a> GameIterator<'a> {
-    pub fn new(client: &'a Client, user_limit: u32) -> GameIterator {
+    pub fn new(
+        client: &'a BggClient,
+        query: SearchQuery,
+        limiter: &'a RateLimiter,
+        running: &'a AtomicBool,
+    ) -> GameIterator<'a> {
         GameIterator {
             client,
+            limiter,
+            running,
             page: 0,
-            user_limit,
-            seen: None,
+            query,
+            seen: HashSet::new(),
+            total: None,
+            attempts: 0,
+            delay: Duration::from_secs(0),
+            retried: Vec::new(),
+            retried_total: 0,
+            refetched: 0,
+        }
+    }
+
+    /// Asks for a page again up to `attempts` times when it fails with
+    /// a transient error, waiting `delay` longer before each try.
+    pub fn retry(self, attempts: u32, delay: Duration) -> GameIterator<'a> {
+        GameIterator {
+            attempts,
+            delay,
+            ..self
+        }
+    }
+
+    /// Errors that were retried since the last call, with their pages.
+    pub fn take_retried(&mut self) -> Vec<(u32, AppError)> {
+        std::mem::take(&mut self.retried)
+    }
+
+    /// Number of games BGG said the search has, once the first
+    /// page is in.
+    pub fn total(&self) -> Option<u32> {
+        self.total
+    }
+
+    /// Search pages asked for so far, retries included.
+    pub fn requests(&self) -> u32 {
+        self.page + self.retried_total + self.refetched
+    }
+
+    /// Asks for `page` once more, e.g. when results shifted while
+    /// pulling. Games are not checked against the earlier pages.
+    pub fn refetch(&mut self, page: u32) -> Result<SearchPage, AppError> {
+        self.refetched += 1;
+        self.fetch(page)
+    }
+
+    /// A page, retried after transient errors.
+    fn fetch(&mut self, page: u32) -> Result<SearchPage, AppError> {
+        let mut tries = 0;
+        loop {
+            if !self.limiter.acquire(self.running) {
+                return Err(AppError::Interrupted(format!(
+                    "Pull stopped before page {}.",
+                    page
+                )));
+            }
+            match get_games_from(self.client, page, &self.query) {
+                Err(e) if e.is_transient() && tries < self.attempts => {
+                    tries += 1;
+                    self.retried_total += 1;
+                    self.retried.push((page, e));
+                    if !ratelimit::pause(self.delay * tries, self.running) {
+                        return Err(AppError::Interrupted(format!(
+                            "Pull stopped before page {} was asked again.",
+                            page
+                        )));
+                    }
+                }
+                result => return result,
+            }
         }
     }
+
+    fn last_page(&self) -> Option<u32> {
+        self.total.map(|t| t.div_ceil(SEARCH_PAGE_SIZE))
+    }
+}
+
+/// Games of a search page and descriptions of rows that were skipped.
+pub struct SearchPage {
+    pub games: Vec<Game>,
+    pub skipped: Vec<String>,
+    pub total: Option<u32>, // from the results counter, None if it's missing
 }
 
 impl<'a> Iterator for GameIterator<'a> {
-    type Item = Result<Vec<Game>, Error>;
+    type Item = Result<SearchPage, AppError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.last_page().is_some_and(|last| self.page >= last) {
+            return None;
+        }
         self.page += 1;
-        // get games from a page
-        match get_games_from(self.client, self.page, self.user_limit) {
-            Ok(games) => {
-                if games.first() == self.seen.as_ref() || games.is_empty() {
+        // get games from a page, pages before it are kept whatever happens
+        match self.fetch(self.page) {
+            Ok(page) => {
+                if self.page == 1 {
+                    self.total = page.total;
+                }
+                // safety net without the counter: past the last page BGG
+                // serves the last one again, a glitch may repeat a few
+                // games on a real page
+                let before = self.seen.len();
+                self.seen.extend(page.games.iter().map(|g| g.id));
+                if self.seen.len() == before {
                     None
                 } else {
-                    self.seen = Some(games[0].clone());
-                    Some(Ok(games))
+                    Some(Ok(page))
                 }
             }
             Err(e) => Some(Err(e)),
@@ -86,94 +441,324 @@ impl<'a> Iterator for GameIterator<'a> {
     }
 }
 
-fn get_games_from(client: &Client, page: u32, user_limit: u32) -> Result<Vec<Game>, Error> {
-    let url =  format!(
-        "https://boardgamegeek.com/search/boardgame/page/{}?advsearch=1&range%5Bnumvoters%5D%5Bmin%5D={}&nosubtypes%5B0%5D=boardgameexpansion",
-        page,
-        user_limit
-    );
-    let resp = client
-        .get(&url)
-        .send()
-        .with_context(|_| format!("could not download page `{}`", url))?;
-    if resp.status() != StatusCode::OK {
-        bail!("Can't get games from {}", page);
+/// Query of the advanced search: parameters pull sets itself first,
+/// then the ones of search_extra_params as they were written.
+#[derive(Clone)]
+pub struct SearchQuery {
+    params: Vec<(String, String)>, // decoded name, pair as it goes into the url
+}
+
+impl SearchQuery {
+    /// Games with at least `user_limit` votes, no expansions.
+    pub fn new(user_limit: u32) -> SearchQuery {
+        let own = [
+            String::from("advsearch=1"),
+            format!("range%5Bnumvoters%5D%5Bmin%5D={}", user_limit),
+            String::from("nosubtypes%5B0%5D=boardgameexpansion"),
+        ];
+        SearchQuery {
+            params: own
+                .iter()
+                .map(|pair| (param_name(pair), pair.clone()))
+                .collect(),
+        }
+    }
+
+    /// Appends url encoded `fragment`, e.g. `range%5Bminplaytime%5D=60`.
+    /// Parameters pull sets itself, the vote range and the page among
+    /// them, and parameters given twice fail, BGG would silently take
+    /// one of them.
+    pub fn extra(mut self, fragment: &str) -> Result<SearchQuery, AppError> {
+        let own = self.params.len();
+        let pairs = fragment
+            .trim_start_matches('?')
+            .split('&')
+            .filter(|p| !p.is_empty());
+        for pair in pairs {
+            if pair.contains(|c: char| c.is_whitespace() || c == '#') {
+                return Err(AppError::Config(format!(
+                    "search_extra_params must be url encoded, got {}",
+                    pair
+                )));
+            }
+            let name = param_name(pair);
+            let taken = self.params.iter().position(|(n, _)| *n == name);
+            if taken.is_some_and(|i| i < own) || name == "page" || name.contains("numvoters") {
+                return Err(AppError::Config(format!(
+                    "search_extra_params can't set {}, pull sets it itself",
+                    name
+                )));
+            }
+            if taken.is_some() {
+                return Err(AppError::Config(format!(
+                    "search_extra_params sets {} twice",
+                    name
+                )));
+            }
+            self.params.push((name, String::from(pair)));
+        }
+        Ok(self)
+    }
+
+    /// Path of the search results page.
+    pub fn path(&self, page: u32) -> String {
+        let pairs: Vec<&str> = self.params.iter().map(|(_, pair)| pair.as_str()).collect();
+        format!("/search/boardgame/page/{}?{}", page, pairs.join("&"))
     }
-    let doc = Document::from_read(resp)?;
-    filter_games(doc)
 }
 
-fn filter_games(doc: Document) -> Result<Vec<Game>, Error> {
+/// Name of a `name=value` pair with %XX escapes decoded, the way
+/// BGG reads it.
+fn param_name(pair: &str) -> String {
+    let name = pair.split('=').next().unwrap_or("").as_bytes();
+    let mut decoded = Vec::with_capacity(name.len());
+    let mut i = 0;
+    while i < name.len() {
+        let hex = name
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (name[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn get_games_from(
+    client: &BggClient,
+    page: u32,
+    query: &SearchQuery,
+) -> Result<SearchPage, AppError> {
+    let path = query.path(page);
+    let doc = client.document(&path, &format!("search_{}.html", page))?;
+    filter_games(doc, client.strict).map_err(|e| client.parse_error(e))
+}
+
+/// Rows that can't be parsed are skipped, one bad row must not
+/// cost the whole pull. A page with nothing but bad rows fails,
+/// BGG has likely changed the layout. `strict` fails on the first
+/// bad row.
+fn filter_games(doc: Document, strict: bool) -> Result<SearchPage, AppError> {
     let rows = doc
         .find(Class("collection_table"))
         .flat_map(|c| c.find(Name("tr")))
         .skip(1); // skip header
 
     let mut games = Vec::new();
-    for row in rows {
-        let mut r = row.find(Name("td"));
-        let link = r.nth(2);
-        let bgg_geek_rating = r.nth(0);
-        let bgg_avg_rating = r.nth(0);
-        let bgg_num_votes = r.nth(0);
-
-        let link = match link {
-            Some(node) => match node.find(Name("a")).nth(0) {
-                Some(l) => l,
-                None => bail!("Could not find game link."),
-            },
-            None => bail!("Could not find game link."),
-        };
-        let id = match link.attr("href") {
-            Some(href) => href_to_id(href)?,
-            None => bail!("Could not find game id."),
-        };
-        let bgg_geek_rating = match bgg_geek_rating {
-            Some(node) => node.text().trim().parse::<f64>()?,
-            None => bail!("Could not find geek rating."),
-        };
-        let bgg_avg_rating = match bgg_avg_rating {
-            Some(node) => node.text().trim().parse::<f64>()?,
-            None => bail!("Could not find avg rating."),
-        };
-        let bgg_num_votes = match bgg_num_votes {
-            Some(node) => node.text().trim().parse::<u32>()?,
-            None => bail!("Could not find num votes."),
-        };
+    let mut skipped = Vec::new();
+    for (i, row) in rows.enumerate() {
+        match parse_game_row(row) {
+            Ok(game) => games.push(game),
+            Err(e) if strict => return Err(AppError::Parse(format!("row {}: {}", i + 1, e))),
+            Err(e) => skipped.push(format!("skipped row {}: {}", i + 1, e)),
+        }
+    }
+    if games.is_empty() && !skipped.is_empty() {
+        let e = format!("Could not parse any game, {}", skipped.join(", "));
+        return Err(AppError::Parse(e));
+    }
+    let total = doc
+        .find(Name("body"))
+        .next()
+        .and_then(|body| parse_total(&body.text()));
+    Ok(SearchPage {
+        games,
+        skipped,
+        total,
+    })
+}
+
+/// Total of the results counter, e.g. 1432 in "1 – 100 of 1,432".
+fn parse_total(text: &str) -> Option<u32> {
+    text.match_indices(" of ").find_map(|(at, of)| {
+        // the range before "of" tells the counter from other text
+        let range = text[..at].trim_end();
+        let rest = range.trim_end_matches(|c: char| c.is_ascii_digit());
+        if rest.len() == range.len() {
+            return None;
+        }
+        let rest = rest.trim_end();
+        let rest = rest
+            .strip_suffix('–')
+            .or_else(|| rest.strip_suffix('-'))
+            .or_else(|| rest.strip_suffix("to"))?;
+        if !rest.trim_end().ends_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        let digits: String = text[at + of.len()..]
+            .trim_start()
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == ',')
+            .filter(|c| *c != ',')
+            .collect();
+        digits.parse().ok()
+    })
+}
+
+/// Cells of a search result row, in the order BGG lays them out.
+#[derive(Clone, Copy)]
+enum SearchColumn {
+    // rank and thumbnail come first, nothing is read from them
+    Title = 2,
+    GeekRating,
+    AvgRating,
+    NumVotes,
+}
+
+impl SearchColumn {
+    fn name(self) -> &'static str {
+        match self {
+            SearchColumn::Title => "game link",
+            SearchColumn::GeekRating => "geek rating",
+            SearchColumn::AvgRating => "avg rating",
+            SearchColumn::NumVotes => "num votes",
+        }
+    }
+}
+
+/// `td` nodes of a row collected once, read by column.
+struct Cells<'a>(Vec<Node<'a>>);
+
+impl<'a> Cells<'a> {
+    fn of(row: Node<'a>) -> Cells<'a> {
+        Cells(row.find(Name("td")).collect())
+    }
+
+    fn get(&self, column: SearchColumn) -> Result<Node<'a>, AppError> {
+        self.0
+            .get(column as usize)
+            .copied()
+            .ok_or_else(|| AppError::Parse(format!("Could not find {}.", column.name())))
+    }
 
-        games.push(Game {
+    fn parse<T>(&self, column: SearchColumn) -> Result<T, AppError>
+    where
+        T: std::str::FromStr,
+        AppError: From<T::Err>,
+    {
+        Ok(self.get(column)?.text().trim().parse::<T>()?)
+    }
+}
+
+fn parse_game_row(row: Node) -> Result<Game, AppError> {
+    let cells = Cells::of(row);
+    let link = match cells.get(SearchColumn::Title)?.find(Name("a")).next() {
+        Some(l) => l,
+        None => return Err(AppError::Parse(String::from("Could not find game link."))),
+    };
+    let id = match link.attr("href") {
+        Some(href) => href_to_id(href)?,
+        None => return Err(AppError::Parse(String::from("Could not find game id."))),
+    };
+    let bgg_geek_rating = cells.parse::<f64>(SearchColumn::GeekRating)?;
+    let bgg_avg_rating = cells.parse::<f64>(SearchColumn::AvgRating)?;
+    let bgg_num_votes = cells.parse::<u32>(SearchColumn::NumVotes)?;
+
+    let year = link
+        .parent()
+        .and_then(|cell| cell.find(Name("span")).find_map(|s| parse_year(&s.text())));
+
+    Ok(Game {
+        year,
+        ..Game::from_search(
             id,
-            name: link.text(),
-            rating: 0.0,
-            votes: 0,
+            link.text(),
             bgg_num_votes,
             bgg_geek_rating,
             bgg_avg_rating,
-            page: 1,
-        });
-    }
-    Ok(games)
+        )
+    })
+}
+
+/// Year from the "(2017)" BGG puts next to the game name.
+fn parse_year(text: &str) -> Option<u32> {
+    text.trim()
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .parse()
+        .ok()
 }
 
-fn href_to_id(href: &str) -> Result<u32, Error> {
+fn href_to_id(href: &str) -> Result<u32, AppError> {
     let parts: Vec<&str> = href.rsplit('/').take(2).collect();
     let id = match parts.get(1) {
         Some(x) => x.parse::<u32>()?,
-        None => bail!("Can't parse id of the game: {}", href),
+        None => {
+            return Err(AppError::Parse(format!(
+                "Can't parse id of the game: {}",
+                href
+            )))
+        }
     };
     Ok(id)
 }
 
-pub fn get_user_average_rating(client: &Client, user: &User) -> Result<f64, Error> {
-    let url = format!("https://boardgamegeek.com/user/{}", user);
-    let resp = client
-        .get(&url)
-        .send()
-        .with_context(|_| format!("could not download page `{}`", url))?;
-    if resp.status() != StatusCode::OK {
-        bail!("Can't get user average for {}", user);
+/// Path of the user data of the json api, `user` comes through
+/// encode_user.
+pub fn user_json_path(user: &str) -> String {
+    format!("/api/users?username={}", user)
+}
+
+/// User data of the json api, only the part that is read.
+#[derive(Debug, Deserialize)]
+struct UserData {
+    stats: UserStats,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserStats {
+    average_rating: f64,
+}
+
+/// Average rating from the json user data, it doesn't break when
+/// the layout of the profile page changes.
+pub fn get_user_json_average(client: &BggClient, user: &User) -> Result<f64, AppError> {
+    let user = encode_user(user);
+    let (body, source) = client.body(&user_json_path(&user), &format!("user_{}.json", user))?;
+    let data: UserData = serde_json::from_str(&body)
+        .map_err(|e| AppError::Parse(format!("Can't read user data of {}: {}", source, e)))?;
+    Ok(data.stats.average_rating)
+}
+
+/// Path of the profile page with the average rating of the user,
+/// `user` comes through encode_user.
+pub fn user_profile_path(user: &str) -> String {
+    format!("/user/{}", user)
+}
+
+/// Path of the user info with the year of registration, `user`
+/// comes through encode_user.
+pub fn user_info_path(user: &str) -> String {
+    format!("/xmlapi2/user?name={}", user)
+}
+
+/// User name as it goes into a url or a fixture name. Anything but
+/// letters, digits and `-_.` is percent encoded, a name can't reach
+/// another path or parameter.
+fn encode_user(user: &str) -> String {
+    let mut encoded = String::with_capacity(user.len());
+    for b in user.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.".contains(&b) {
+            encoded.push(char::from(b));
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
     }
-    let doc = Document::from_read(resp)?;
+    encoded
+}
+
+pub fn get_user_average_rating(client: &BggClient, user: &User) -> Result<f64, AppError> {
+    let encoded = encode_user(user);
+    let path = user_profile_path(&encoded);
+    let doc = client.document(&path, &format!("user_{}.html", encoded))?;
     let rating = doc
         .find(Class("profile_block"))
         .skip(3)
@@ -186,9 +771,521 @@ pub fn get_user_average_rating(client: &Client, user: &User) -> Result<f64, Erro
         .take(1)
         .flat_map(|tr| tr.find(Name("td")))
         .nth(1);
-    let rating = match rating {
-        None => bail!("Can't find rating element for {}", user),
-        Some(r) => r.text().parse::<f64>()?,
+    match rating {
+        None => Err(AppError::Parse(format!(
+            "Can't find rating element for {}",
+            user
+        ))),
+        Some(r) => r.text().parse::<f64>().map_err(AppError::from),
+    }
+    .map_err(|e| client.parse_error(e))
+}
+
+/// Year the account was registered, None if BGG doesn't tell.
+pub fn get_user_year(client: &BggClient, user: &User) -> Result<Option<u32>, AppError> {
+    let user = encode_user(user);
+    let doc = client.document(&user_info_path(&user), &format!("user_{}.xml", user))?;
+    let year = match doc
+        .find(Name("yearregistered"))
+        .next()
+        .and_then(|tag| tag.attr("value"))
+    {
+        Some(y) if !y.is_empty() => y.parse::<u32>()?,
+        _ => return Ok(None),
     };
-    Ok(rating)
+    Ok(Some(year))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+    use std::thread;
+
+    fn parsed(body: &str) -> Result<Document, AppError> {
+        parse_body(body, "test")
+    }
+
+    /// First row of the collection table in `body`, header skipped.
+    fn first_row(body: &str, test: impl Fn(Node)) {
+        let doc = Document::from(body);
+        let row = doc
+            .find(Name("tr"))
+            .nth(1)
+            .expect("no row under the header");
+        test(row);
+    }
+
+    #[test]
+    fn search_row_is_read_by_column() {
+        let page = "<table class=\"collection_table\"><tr><th></th></tr>\
+                    <tr><td class=\"rank\">12</td><td><img src=\"thumb.jpg\"/></td>\
+                    <td><a href=\"/boardgame/174430/gloomhaven\">Gloomhaven</a> <span>(2017)</span></td>\
+                    <td> 8.367 </td><td>8.61</td><td>61234</td></tr></table>";
+        first_row(page, |row| {
+            let game = parse_game_row(row).unwrap();
+            assert_eq!(game.id, 174430);
+            assert_eq!(game.name, "Gloomhaven");
+            assert_eq!(game.year, Some(2017));
+            assert_eq!(game.bgg_geek_rating, 8.367);
+            assert_eq!(game.bgg_avg_rating, 8.61);
+            assert_eq!(game.bgg_num_votes, 61234);
+        });
+        // a row cut before the votes names the missing column
+        let cut = "<table><tr><th></th></tr><tr><td></td><td></td>\
+                   <td><a href=\"/boardgame/1/x\">X</a></td><td>7</td><td>7</td></tr></table>";
+        first_row(cut, |row| {
+            let e = parse_game_row(row).unwrap_err();
+            assert_eq!(e.to_string(), "Could not find num votes.");
+        });
+    }
+
+    fn extra_error(fragment: &str) -> String {
+        match SearchQuery::new(100).extra(fragment) {
+            Err(AppError::Config(e)) => e,
+            Err(e) => panic!("{}: {:?}", fragment, e),
+            Ok(_) => panic!("{} is taken", fragment),
+        }
+    }
+
+    #[test]
+    fn extra_params_follow_the_own_ones() {
+        let query = SearchQuery::new(100)
+            .extra("?range%5Bminplaytime%5D=60&&playerrangetype=normal")
+            .unwrap();
+        assert_eq!(
+            query.path(3),
+            "/search/boardgame/page/3?advsearch=1&range%5Bnumvoters%5D%5Bmin%5D=100\
+             &nosubtypes%5B0%5D=boardgameexpansion&range%5Bminplaytime%5D=60&playerrangetype=normal"
+        );
+        assert_eq!(
+            SearchQuery::new(100).extra("").unwrap().path(1),
+            SearchQuery::new(100).path(1)
+        );
+    }
+
+    #[test]
+    fn extra_params_cant_take_what_pull_sets() {
+        let pull_sets = |name: &str| {
+            format!(
+                "search_extra_params can't set {}, pull sets it itself",
+                name
+            )
+        };
+        assert_eq!(extra_error("page=2"), pull_sets("page"));
+        assert_eq!(
+            extra_error("range%5Bnumvoters%5D%5Bmax%5D=500"),
+            pull_sets("range[numvoters][max]")
+        );
+        assert_eq!(extra_error("advsearch=0"), pull_sets("advsearch"));
+        assert_eq!(
+            extra_error("nosubtypes%5B0%5D=boardgameaccessory"),
+            pull_sets("nosubtypes[0]")
+        );
+        // the same name, encoded another way
+        assert_eq!(extra_error("%61dvsearch=0"), pull_sets("advsearch"));
+        assert_eq!(
+            extra_error("nosubtypes[0]=boardgameaccessory"),
+            pull_sets("nosubtypes[0]")
+        );
+    }
+
+    #[test]
+    fn extra_params_are_given_once_and_encoded() {
+        assert_eq!(
+            extra_error("playerrangetype=normal&playerrangetype=exclusive"),
+            "search_extra_params sets playerrangetype twice"
+        );
+        assert_eq!(
+            extra_error("range%5Bminplaytime%5D=60&range[minplaytime]=90"),
+            "search_extra_params sets range[minplaytime] twice"
+        );
+        for pair in ["q=ticket to ride", "q=catan\t", "designer=1#top"] {
+            assert_eq!(
+                extra_error(pair),
+                format!("search_extra_params must be url encoded, got {}", pair)
+            );
+        }
+    }
+
+    #[test]
+    fn maintenance_page_is_transient() {
+        let page = "<html><head><title>BoardGameGeek is Down for Maintenance</title></head>\
+                    <body><p>Back soon.</p></body></html>";
+        let e = parsed(page).err().unwrap();
+        assert!(matches!(e, AppError::Http(BggError::Maintenance(_))));
+        assert!(e.is_transient());
+    }
+
+    #[test]
+    fn xml_error_is_maintenance() {
+        let page = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                    <errors><error><message>Rate limit exceeded.</message></error></errors>";
+        assert!(parsed(page).is_err());
+    }
+
+    #[test]
+    fn maintenance_words_in_content_are_not() {
+        let search = "<html><head><title>Board Game Search</title></head><body>\
+                      <table class=\"collection_table\"><tr><th></th></tr>\
+                      <tr><td></td><td></td><td><a href=\"/boardgame/1/x\">Down for Maintenance</a></td>\
+                      <td>7</td><td>7</td><td>100</td></tr></table>\
+                      <div class=\"maintenance-note\">class=\"maintenance</div></body></html>";
+        assert!(parsed(search).is_ok());
+        let comments = "<items><item><comments totalitems=\"1\">\
+                        <comment username=\"a\" rating=\"7\" value=\"&lt;errors&gt;down for maintenance\"/>\
+                        </comments></item></items>";
+        assert!(parsed(comments).is_ok());
+    }
+
+    #[test]
+    fn empty_comments_page_is_the_last_one() {
+        let page = "<items><item><comments totalitems=\"100\"></comments></item></items>";
+        let (users, rejected) = filter_users(parsed(page).unwrap(), false).unwrap();
+        assert!(users.is_empty());
+        assert_eq!(rejected, 0);
+    }
+
+    #[test]
+    fn ratings_out_of_scale_are_dropped() {
+        let page = "<items><item><comments>\
+                    <comment username=\"a\" rating=\"6\"/><comment username=\"b\" rating=\"0\"/>\
+                    <comment username=\"c\" rating=\"10\"/><comment username=\"d\" rating=\"1e9\"/>\
+                    <comment username=\"e\" rating=\"10.5\"/><comment username=\"f\" rating=\"1\"/>\
+                    </comments></item></items>";
+        let (users, rejected) = filter_users(parsed(page).unwrap(), false).unwrap();
+        let names: Vec<&str> = users.iter().map(|(u, _)| u.as_str()).collect();
+        assert_eq!(names, ["a", "c", "f"]);
+        assert_eq!(rejected, 3);
+        let e = filter_users(parsed(page).unwrap(), true).unwrap_err();
+        assert!(
+            e.to_string().contains("rating 0 of b is out of range"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn user_year_from_fixtures() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = |year: &str| {
+            format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                 <user id=\"1\" name=\"x\"><yearregistered value=\"{}\"/></user>",
+                year
+            )
+        };
+        fs::write(dir.path().join("user_old.xml"), info("2004")).unwrap();
+        fs::write(dir.path().join("user_hidden.xml"), info("")).unwrap();
+        fs::write(dir.path().join("user_odd.xml"), info("soon")).unwrap();
+        fs::write(dir.path().join("user_bare.xml"), "<user id=\"1\"></user>").unwrap();
+        let client = BggClient::fixtures(dir.path());
+        let year = |user: &str| get_user_year(&client, &User::from(user));
+
+        assert_eq!(year("old").unwrap(), Some(2004));
+        assert_eq!(year("hidden").unwrap(), None);
+        assert_eq!(year("bare").unwrap(), None);
+        assert!(year("odd").is_err());
+        assert!(matches!(
+            year("nobody").unwrap_err(),
+            AppError::Http(BggError::Fixture { .. })
+        ));
+    }
+
+    #[test]
+    fn user_names_are_encoded() {
+        assert_eq!(encode_user("Alice_2-b.c"), "Alice_2-b.c");
+        assert_eq!(encode_user("a b&name=c"), "a%20b%26name%3Dc");
+        assert_eq!(encode_user("../x"), "..%2Fx");
+        assert_eq!(encode_user("ü"), "%C3%BC");
+        assert_eq!(
+            user_info_path(&encode_user("a&b")),
+            "/xmlapi2/user?name=a%26b"
+        );
+    }
+
+    /// Search without the results counter, past the last of `pages`
+    /// the last one is served again, the way BGG does.
+    fn uncounted_search(pages: Vec<Vec<u32>>) -> mock::Mock {
+        mock::serve(Duration::from_millis(0), move |path| {
+            let page = path
+                .strip_prefix("/search/boardgame/page/")
+                .and_then(|p| p.split('?').next())
+                .and_then(|p| p.parse::<usize>().ok())
+                .unwrap_or(1);
+            let ids = &pages[page.min(pages.len()) - 1];
+            let mut body =
+                String::from("<html><body><table class=\"collection_table\"><tr><th></th></tr>");
+            for &id in ids {
+                body.push_str(&mock::search_row(
+                    id,
+                    &format!("Game {}", id),
+                    7.0,
+                    7.5,
+                    1000,
+                ));
+            }
+            body.push_str("</table></body></html>");
+            mock::ok(body)
+        })
+        .unwrap()
+    }
+
+    /// Ids of every page the iterator hands out.
+    fn pulled_pages(bgg: &mock::Mock) -> Vec<Vec<u32>> {
+        let client = BggClient::http(&bgg.base_url, None, None).unwrap();
+        let limiter = RateLimiter::per_minute(u32::MAX);
+        let running = AtomicBool::new(true);
+        GameIterator::new(&client, SearchQuery::new(100), &limiter, &running)
+            .map(|page| page.unwrap().games.iter().map(|g| g.id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn gzipped_pages_are_read() {
+        let bgg = mock::serve_gzipped(Duration::from_millis(0), |_| {
+            let rows = [
+                mock::search_row(13, "CATAN", 7.0, 7.1, 1200),
+                mock::search_row(822, "Carcassonne", 7.3, 7.4, 1100),
+            ];
+            mock::ok(mock::search_page(&rows, 2))
+        })
+        .unwrap();
+        assert_eq!(pulled_pages(&bgg), [vec![13, 822]]);
+        assert_eq!(bgg.requests(), 1);
+    }
+
+    #[test]
+    fn page_starting_with_a_seen_game_is_kept() {
+        // a glitch puts game 1 on top of the second page again
+        let bgg = uncounted_search(vec![vec![1, 2, 3], vec![1, 4, 5], vec![6]]);
+        assert_eq!(pulled_pages(&bgg), [vec![1, 2, 3], vec![1, 4, 5], vec![6]]);
+    }
+
+    #[test]
+    fn last_page_served_again_ends_the_search() {
+        let bgg = uncounted_search(vec![vec![1, 2, 3], vec![4, 5]]);
+        assert_eq!(pulled_pages(&bgg), [vec![1, 2, 3], vec![4, 5]]);
+        // the repeat was asked for, nothing after it
+        assert_eq!(bgg.asked("/search/").len(), 3);
+    }
+
+    #[test]
+    fn results_counter_is_read() {
+        assert_eq!(parse_total("Results 1 – 100 of 1,432 Next »"), Some(1432));
+        assert_eq!(parse_total("1-100 of 1432"), Some(1432));
+        assert_eq!(parse_total("101 to 200 of 12,345"), Some(12345));
+        // "of" without a range before it is some other text
+        assert_eq!(parse_total("Best of 2020, 1 game"), None);
+        assert_eq!(parse_total("Page 1 of 15"), None);
+        assert_eq!(parse_total("No results"), None);
+    }
+
+    /// Fixture pages of a search of `total` games, page by page.
+    fn search_fixtures(total: u32) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let ids: Vec<u32> = (1..=total).collect();
+        for (i, chunk) in ids.chunks(SEARCH_PAGE_SIZE as usize).enumerate() {
+            let from = i as u32 * SEARCH_PAGE_SIZE + 1;
+            let mut body = format!(
+                "<html><body><div class=\"infobox\">{} &ndash; {} of {}</div>\
+                 <table class=\"collection_table\"><tr><th></th></tr>",
+                from,
+                from + chunk.len() as u32 - 1,
+                total
+            );
+            for &id in chunk {
+                body.push_str(&mock::search_row(
+                    id,
+                    &format!("Game {}", id),
+                    7.0,
+                    7.5,
+                    1000,
+                ));
+            }
+            body.push_str("</table></body></html>");
+            fs::write(dir.path().join(format!("search_{}.html", i + 1)), body).unwrap();
+        }
+        dir
+    }
+
+    fn fixture_pages(dir: &Path) -> Vec<Result<Vec<u32>, AppError>> {
+        let client = BggClient::fixtures(dir);
+        let limiter = RateLimiter::per_minute(u32::MAX);
+        let running = AtomicBool::new(true);
+        GameIterator::new(&client, SearchQuery::new(100), &limiter, &running)
+            .map(|page| page.map(|p| p.games.iter().map(|g| g.id).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn maintenance_fixture_is_not_the_end_of_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        fs::copy(
+            fixtures.join("maintenance.html"),
+            dir.path().join("search_1.html"),
+        )
+        .unwrap();
+        let client = BggClient::fixtures(dir.path());
+        let limiter = RateLimiter::per_minute(u32::MAX);
+        let running = AtomicBool::new(true);
+        let mut pages = GameIterator::new(&client, SearchQuery::new(100), &limiter, &running);
+        // an empty page would have ended the pull here
+        match pages.next() {
+            Some(Err(e)) => {
+                assert!(matches!(e, AppError::Http(BggError::Maintenance(_))));
+                assert!(e.is_transient());
+            }
+            _ => panic!("maintenance page was taken for results"),
+        }
+    }
+
+    #[test]
+    fn stop_cuts_the_wait_before_a_retry() {
+        let bgg = mock::serve(Duration::from_millis(0), |_| {
+            ("503 Service Unavailable", String::new())
+        })
+        .unwrap();
+        let client = BggClient::http(&bgg.base_url, None, None).unwrap();
+        let limiter = RateLimiter::per_minute(u32::MAX);
+        let running = AtomicBool::new(true);
+        let started = std::time::Instant::now();
+        let page = thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(300));
+                running.store(false, Ordering::SeqCst);
+            });
+            GameIterator::new(&client, SearchQuery::new(100), &limiter, &running)
+                .retry(5, Duration::from_secs(60))
+                .next()
+        });
+        assert!(started.elapsed() < Duration::from_secs(10));
+        match page {
+            Some(Err(AppError::Interrupted(message))) => {
+                assert_eq!(message, "Pull stopped before page 1 was asked again.")
+            }
+            _ => panic!("the retry wait was not cut"),
+        }
+        // the failed try, no retry after the stop
+        assert_eq!(bgg.asked("/search/").len(), 1);
+    }
+
+    #[test]
+    fn counter_tells_how_many_pages_to_ask_for() {
+        // a full last page, there is no page 3 to ask for
+        let exact = search_fixtures(2 * SEARCH_PAGE_SIZE);
+        let pages = fixture_pages(exact.path());
+        let sizes: Vec<usize> = pages.iter().map(|p| p.as_ref().unwrap().len()).collect();
+        assert_eq!(sizes, [100, 100]);
+
+        let over = search_fixtures(2 * SEARCH_PAGE_SIZE + 1);
+        let pages = fixture_pages(over.path());
+        let sizes: Vec<usize> = pages.iter().map(|p| p.as_ref().unwrap().len()).collect();
+        assert_eq!(sizes, [100, 100, 1]);
+    }
+
+    #[test]
+    fn user_average_from_encoded_fixture() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = dir.path().join("inner");
+        fs::create_dir(&inner).unwrap();
+        fs::write(dir.path().join("user_x.html"), mock::profile_page(9.0)).unwrap();
+        fs::write(inner.join("user_a%2F..%2Fx.html"), mock::profile_page(5.5)).unwrap();
+        let client = BggClient::fixtures(&inner);
+        let average = |user: &str| get_user_average_rating(&client, &User::from(user));
+
+        assert_eq!(average("a/../x").unwrap(), 5.5);
+        // no way out of the fixture dir
+        assert!(matches!(
+            average("../user_x").unwrap_err(),
+            AppError::Http(BggError::Fixture { .. })
+        ));
+    }
+
+    #[test]
+    fn user_json_is_read_or_refused() {
+        let bgg = mock::serve(Duration::from_millis(0), |path| {
+            if path.starts_with("/api/users?username=a") {
+                mock::ok(mock::user_data(8.0))
+            } else if path.starts_with("/api/users?username=b") {
+                mock::ok(String::from("{\"stats\": {}}"))
+            } else {
+                mock::not_found()
+            }
+        })
+        .unwrap();
+        let client = BggClient::http(&bgg.base_url, None, None).unwrap();
+        let average = |user: &str| get_user_json_average(&client, &User::from(user));
+
+        assert_eq!(average("a").unwrap(), 8.0);
+        assert!(matches!(average("b").unwrap_err(), AppError::Parse(_)));
+        assert!(matches!(
+            average("c").unwrap_err(),
+            AppError::Http(BggError::Status { .. })
+        ));
+    }
+
+    #[test]
+    fn json_fixtures_match_the_profile_pages() {
+        let client = BggClient::fixtures(&Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures"));
+        for user in ["alice", "bob", "carol"] {
+            let user = User::from(user);
+            assert_eq!(
+                get_user_json_average(&client, &user).unwrap(),
+                get_user_average_rating(&client, &user).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn user_pages_are_asked_with_encoded_names() {
+        let bgg = mock::serve(Duration::from_millis(0), |path| {
+            if path.starts_with("/user/") {
+                mock::ok(mock::profile_page(7.0))
+            } else {
+                mock::not_found()
+            }
+        })
+        .unwrap();
+        let client = BggClient::http(&bgg.base_url, None, None).unwrap();
+        get_user_average_rating(&client, &User::from("a b/../c?d")).unwrap();
+        assert_eq!(bgg.asked("/user/"), vec!["/user/a%20b%2F..%2Fc%3Fd"]);
+    }
+
+    #[test]
+    fn contact_goes_into_the_user_agent() {
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(user_agent(None), format!("bgg_swing2/{}", version));
+        assert_eq!(user_agent(Some("  ")), format!("bgg_swing2/{}", version));
+        assert_eq!(
+            user_agent(Some(" me@example.com ")),
+            format!("bgg_swing2/{} (+me@example.com)", version)
+        );
+        let e = BggClient::http("http://localhost", Some("me\nX-Evil: 1"), None)
+            .err()
+            .unwrap();
+        assert!(matches!(e, AppError::Config(_)), "{}", e);
+    }
+
+    #[test]
+    fn every_request_names_the_app() {
+        let bgg = mock::serve(Duration::from_millis(0), |_| {
+            mock::ok(mock::profile_page(6.5))
+        })
+        .unwrap();
+        let client = BggClient::http(&bgg.base_url, Some("me@example.com"), None).unwrap();
+        let anonymous = BggClient::http(&bgg.base_url, None, None).unwrap();
+        let user = User::from("alice");
+        assert_eq!(get_user_average_rating(&client, &user).unwrap(), 6.5);
+        assert_eq!(get_user_average_rating(&anonymous, &user).unwrap(), 6.5);
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            bgg.agents(),
+            [
+                format!("bgg_swing2/{} (+me@example.com)", version),
+                format!("bgg_swing2/{}", version)
+            ]
+        );
+    }
 }
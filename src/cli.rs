@@ -1,25 +1,552 @@
+use crate::core::{Listed, RatingBounds, TimeLimit};
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 /// Utility to reevaluate bgg top
 /// ignoring overhyped users.
-pub enum Cli {
+pub struct Cli {
+    #[structopt(long = "db", parse(from_os_str))]
+    /// Database file to use instead of top.db.
+    pub db: Option<PathBuf>,
+    #[structopt(long = "output-db", parse(from_os_str))]
+    /// Copies the database here and writes only to the copy.
+    pub output_db: Option<PathBuf>,
+    #[structopt(long = "force")]
+    /// Overwrites existing --output-db file.
+    pub force: bool,
+    #[structopt(long = "offline")]
+    /// Guarantees no network calls, commands that need BGG fail.
+    pub offline: bool,
+    #[structopt(long = "yes")]
+    /// Lets pull and balance create missing app.config and db
+    /// with defaults instead of asking.
+    pub yes: bool,
+    #[structopt(long = "use-defaults")]
+    /// Runs with default settings when app.config is missing,
+    /// nothing is written in its place. A broken app.config still
+    /// fails.
+    pub use_defaults: bool,
+    #[structopt(long = "append-log", parse(from_os_str))]
+    /// Also appends what pull, balance and review print to this
+    /// file, without colors and with timestamps.
+    pub append_log: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
     #[structopt(name = "new")]
     /// Creates new .db and .config files.
     New {},
     #[structopt(name = "report")]
     /// Prints arranged list of games if it
     /// has been stabilized.
-    Report {},
+    Report {
+        #[structopt(flatten)]
+        args: ReportArgs,
+    },
+    #[structopt(name = "top-movers")]
+    /// Lists games that moved up and down the most
+    /// against BGG ranking.
+    TopMovers {
+        #[structopt(short = "n", default_value = "10")]
+        /// Number of games in each direction.
+        n: usize,
+        #[structopt(long = "precision", default_value = "2")]
+        /// Number of decimal places for rating swing.
+        precision: usize,
+    },
+    #[structopt(name = "compare")]
+    /// Lists games that entered, left or moved between two
+    /// snapshots or json reports, or one of them and the current
+    /// list.
+    Compare {
+        #[structopt(parse(from_str))]
+        /// Id of the older snapshot or path of a .json or .ndjson
+        /// report.
+        old: Listed,
+        #[structopt(parse(from_str))]
+        /// Id of the newer snapshot or path of a json report, the
+        /// current list if not given.
+        new: Option<Listed>,
+        #[structopt(
+            long = "format",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"csv\", \"json\"]")
+        )]
+        /// Output format: text, csv or json.
+        format: String,
+        #[structopt(long = "precision", default_value = "2")]
+        /// Number of decimal places for ratings in text.
+        precision: usize,
+    },
+    #[structopt(name = "compare-db")]
+    /// Lists games rated differently in another db balanced from
+    /// the same pull, e.g. with other trust bounds.
+    CompareDb {
+        #[structopt(parse(from_os_str))]
+        /// Path of the other db, it is only read.
+        other: PathBuf,
+        #[structopt(
+            long = "format",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"csv\", \"json\"]")
+        )]
+        /// Output format: text, csv or json.
+        format: String,
+        #[structopt(long = "threshold", default_value = "0.1")]
+        /// Smallest rating difference to list a game.
+        threshold: f64,
+        #[structopt(long = "precision", default_value = "2")]
+        /// Number of decimal places for ratings in text.
+        precision: usize,
+    },
+    #[structopt(name = "histogram")]
+    /// Charts how many games have each rating, ours
+    /// next to BGG average.
+    Histogram {
+        #[structopt(
+            long = "bucket",
+            default_value = "0.5",
+            parse(try_from_str = "parse_bucket")
+        )]
+        /// Width of a rating bucket, from 0.05 up.
+        bucket: f64,
+        #[structopt(long = "include-unstable")]
+        /// Charts games even if balancing is not finished.
+        include_unstable: bool,
+    },
     #[structopt(name = "pull")]
     /// Pulls games from bgg with n user ratings.
     /// Ignores extensions. Takes n from config file.
-    Pull {},
+    /// search_extra_params in config adds url encoded
+    /// filters of BGG advanced search, copied from the url
+    /// BGG shows for them, e.g. games from 2010 on:
+    /// range%5Byearpublished%5D%5Bmin%5D=2010, or games
+    /// from the 1990s: range%5Byearpublished%5D%5Bmin%5D=1990&range%5Byearpublished%5D%5Bmax%5D=1999
+    Pull {
+        #[structopt(
+            long = "progress",
+            default_value = "console",
+            raw(possible_values = "&[\"console\", \"json\", \"none\"]")
+        )]
+        /// How to report progress: console, json or none.
+        progress: String,
+        #[structopt(long = "limit")]
+        /// Overrides number of user ratings from config.
+        limit: Option<u32>,
+        #[structopt(long = "add")]
+        /// Keeps games from earlier pulls, pulled games get the
+        /// number of user ratings as their tier.
+        add: bool,
+        #[structopt(long = "strict-parse")]
+        /// Fails on any row that would be skipped, to catch
+        /// changes of BGG format.
+        strict_parse: bool,
+        #[structopt(long = "i-know-what-im-doing")]
+        /// Runs despite warnings about the config.
+        force: bool,
+        #[structopt(long = "explain")]
+        /// Prints the search urls pull would ask for and exits,
+        /// without any network calls.
+        explain: bool,
+    },
     #[structopt(name = "balance")]
     /// Runs balancing processes until game list is
-    /// stabilized.
-    Balance {},
+    /// stabilized. SIGINT (ctrl+c), SIGTERM and SIGHUP
+    /// stop it gracefully, saving progress.
+    Balance {
+        #[structopt(
+            long = "progress",
+            default_value = "console",
+            raw(possible_values = "&[\"console\", \"json\", \"none\"]")
+        )]
+        /// How to report progress: console, json or none.
+        progress: String,
+        #[structopt(long = "sample")]
+        /// Balances only n random unstable games. Meant for
+        /// experiments, the list won't get stable enough to report.
+        sample: Option<u32>,
+        #[structopt(long = "bounds")]
+        /// Overrides trusted range of user average ratings, e.g. 2.0..8.0.
+        bounds: Option<RatingBounds>,
+        #[structopt(long = "tier")]
+        /// Balances only games pulled with this number of user ratings.
+        /// Without it higher tiers go first.
+        tier: Option<u32>,
+        #[structopt(long = "seed")]
+        /// Overrides seed from config. Games are taken in the same order
+        /// every time, with threads set to 1 requests are too.
+        seed: Option<u32>,
+        #[structopt(long = "full")]
+        /// Ignores sample_pages, sample_fraction and max_pages_per_game
+        /// from config, games rated from a sample are balanced again
+        /// from every page.
+        full: bool,
+        #[structopt(long = "max-pages-per-game")]
+        /// Overrides max_pages_per_game from config, even with --full.
+        max_pages_per_game: Option<u32>,
+        #[structopt(long = "explain")]
+        /// Prints the urls of ratings and user stats balance would ask
+        /// for and exits, without any network calls.
+        explain: bool,
+        #[structopt(long = "strict-parse")]
+        /// Stops on any rating or user stats that would be skipped,
+        /// to catch changes of BGG format.
+        strict_parse: bool,
+        #[structopt(long = "i-know-what-im-doing")]
+        /// Runs despite warnings about the config.
+        force: bool,
+        #[structopt(long = "metrics-file", parse(from_os_str))]
+        /// Writes gauges of every run to this file in Prometheus
+        /// text format, e.g. for node_exporter's textfile collector.
+        metrics_file: Option<PathBuf>,
+        #[structopt(long = "profile")]
+        /// Prints time spent waiting, fetching, parsing and in db
+        /// at the end, summed over all threads.
+        profile: bool,
+        #[structopt(long = "stuck-after", default_value = "120")]
+        /// Seconds without news after which a worker is shown red
+        /// in the live worker lines of console progress.
+        stuck_after: u64,
+        #[structopt(long = "max-duration")]
+        /// Stops gracefully after this long, e.g. 2h, 90m or 1h30m.
+        /// Ends --watch rounds too.
+        max_duration: Option<TimeLimit>,
+        #[structopt(flatten)]
+        watch: WatchArgs,
+    },
     #[structopt(name = "review")]
-    /// Marks users as unstable again after a period.
-    Review {},
+    /// Checks trust of known users against the current config,
+    /// e.g. after bounds or min_account_age_years change.
+    Review {
+        #[structopt(long = "fetch")]
+        /// Asks BGG again about users checked before the review, or
+        /// before user_ttl_days. Uses threads and request limits of
+        /// balance, SIGINT (ctrl+c) stops it and the next review goes
+        /// on where it stopped.
+        fetch: bool,
+        #[structopt(
+            long = "progress",
+            default_value = "console",
+            raw(possible_values = "&[\"console\", \"json\", \"none\"]")
+        )]
+        /// How to report progress of --fetch: console, json or none.
+        progress: String,
+        #[structopt(long = "i-know-what-im-doing")]
+        /// Runs --fetch despite warnings about the config.
+        force: bool,
+    },
+    #[structopt(name = "rerate")]
+    /// Sets trust of a user by hand, games the user rated
+    /// are recounted.
+    SetUserTrust {
+        /// BGG user name.
+        name: String,
+        #[structopt(raw(possible_values = "&[\"trusted\", \"untrusted\"]"))]
+        /// trusted or untrusted.
+        trust: String,
+    },
+    #[structopt(name = "watch")]
+    /// Balances the game again on every balance run, even
+    /// after it stabilizes. Until it stabilizes again report
+    /// prints only with --include-unstable. Watched games
+    /// stay watched over pulls.
+    Watch {
+        /// BGG game id.
+        id: u32,
+    },
+    #[structopt(name = "unwatch")]
+    /// Lets a watched game stay stable.
+    Unwatch {
+        /// BGG game id.
+        id: u32,
+    },
+    #[structopt(name = "game")]
+    /// Shows where the game came from and its notes.
+    Game {
+        /// BGG game id.
+        id: u32,
+        #[structopt(long = "note")]
+        /// Replaces notes of the game, empty text clears them.
+        /// Notes survive pulls.
+        note: Option<String>,
+    },
+    #[structopt(name = "snapshot")]
+    /// Saves the current game list, `report --snapshot` renders it later.
+    Snapshot {
+        #[structopt(long = "name")]
+        /// Label to recognize the snapshot by.
+        name: Option<String>,
+        #[structopt(long = "list")]
+        /// Lists saved snapshots instead.
+        list: bool,
+    },
+    #[structopt(name = "history")]
+    /// Lists previous balance runs.
+    History {
+        #[structopt(long = "limit", default_value = "20")]
+        /// Number of latest runs to show.
+        limit: u32,
+        #[structopt(long = "users")]
+        /// Charts users seen for the first time in every run.
+        users: bool,
+        #[structopt(long = "last-run")]
+        /// Shows only the outcome of the latest run.
+        last_run: bool,
+    },
+    #[structopt(name = "status")]
+    /// Shows size of the db and the config it was pulled and
+    /// balanced with, warns if app.config has changed since.
+    Status {},
+    #[structopt(name = "info")]
+    /// Shows versions of the app and the db, its size and
+    /// contents, and where they came from. Useful in bug reports.
+    Info {
+        #[structopt(long = "json")]
+        /// Prints the same as json.
+        json: bool,
+    },
+    #[structopt(name = "check")]
+    /// Looks for inconsistencies in the db.
+    Check {
+        #[structopt(long = "repair")]
+        /// Applies fixes that don't need BGG.
+        repair: bool,
+    },
+    #[structopt(name = "users")]
+    /// Lists cached users with their trust, average rating, number
+    /// of ratings in the db and when BGG was last asked about them.
+    Users {
+        #[structopt(long = "trusted", conflicts_with = "untrusted")]
+        /// Lists only trusted users.
+        trusted: bool,
+        #[structopt(long = "untrusted")]
+        /// Lists only untrusted users.
+        untrusted: bool,
+        #[structopt(long = "pinned")]
+        /// Lists only users whose trust was set by hand with rerate.
+        pinned: bool,
+        #[structopt(long = "stale")]
+        /// Lists only users last checked longer ago than this,
+        /// e.g. 180d or 12h.
+        stale: Option<TimeLimit>,
+        #[structopt(
+            long = "sort",
+            default_value = "updated",
+            raw(possible_values = "&[\"updated\", \"rating\", \"name\"]")
+        )]
+        /// updated lists users checked longest ago first, rating the
+        /// highest averages first, name goes alphabetically.
+        sort: String,
+        #[structopt(long = "limit")]
+        /// Number of users to list.
+        limit: Option<u32>,
+        #[structopt(long = "offset", default_value = "0")]
+        /// Number of users to skip first.
+        offset: u32,
+        #[structopt(
+            long = "format",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"tsv\", \"csv\", \"ndjson\"]")
+        )]
+        /// Output format: text, tsv, csv or ndjson, one json object
+        /// per user. tsv is text with tabs and csv text with commas
+        /// whatever --delimiter is.
+        format: String,
+        #[structopt(long = "precision", default_value = "2")]
+        /// Number of decimal places for the average in text.
+        precision: usize,
+        #[structopt(long = "delimiter", default_value = "\t")]
+        /// Column delimiter.
+        delimiter: char,
+    },
+    #[structopt(name = "prune-users")]
+    /// Deletes users that no game refers to, users whose trust was
+    /// set by hand are kept.
+    PruneUsers {},
+    #[cfg(feature = "bench")]
+    #[structopt(name = "bench")]
+    /// Measures balancing throughput against a local mock of BGG.
+    Bench {
+        #[structopt(long = "games", default_value = "20")]
+        /// Number of games to balance.
+        games: u32,
+        #[structopt(long = "pages", default_value = "5")]
+        /// Pages of comments every game has.
+        pages: u32,
+        #[structopt(long = "latency", default_value = "50")]
+        /// Mock server response time, ms.
+        latency: u64,
+        #[structopt(long = "threads", default_value = "4")]
+        /// Number of balancing threads.
+        threads: usize,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ReportArgs {
+    #[structopt(long = "precision", default_value = "2")]
+    /// Number of decimal places for rating columns.
+    pub precision: usize,
+    #[structopt(long = "max-weight")]
+    /// Shows only games with known weight (complexity) up to this value.
+    pub max_weight: Option<f64>,
+    #[structopt(long = "decimal-comma")]
+    /// Uses comma as decimal separator.
+    pub decimal_comma: bool,
+    #[structopt(long = "delimiter", default_value = "\t")]
+    /// Column delimiter, e.g. ';' for european spreadsheets.
+    pub delimiter: char,
+    #[structopt(
+        long = "format",
+        raw(
+            possible_values = "&[\"text\", \"tsv\", \"csv\", \"json\", \"markdown\", \"geeklist-csv\", \"compact\", \"ndjson\"]"
+        )
+    )]
+    /// Output format: text, tsv, csv, json, a single array, markdown,
+    /// a table, geeklist-csv for BGG geeklist import, compact, one short
+    /// line per game sized to $COLUMNS, or ndjson, one json object per
+    /// game. tsv is text with tabs and csv text with commas, semicolons
+    /// with --decimal-comma, whatever --delimiter is. Defaults to the one
+    /// --output extension stands for, text otherwise.
+    pub format: Option<String>,
+    #[structopt(long = "output", parse(from_os_str))]
+    /// Writes the report to this file instead of stdout. Extensions
+    /// .txt, .tsv, .csv, .json, .md and .ndjson pick text, tsv, csv,
+    /// json, markdown and ndjson format unless --format is given. Other
+    /// extensions need --format, a file without one gets text.
+    pub output: Option<PathBuf>,
+    #[structopt(long = "top")]
+    /// Prints only the first n games, after filtering and sorting.
+    pub top: Option<usize>,
+    #[structopt(long = "group-by", raw(possible_values = "&[\"decade\"]"))]
+    /// Groups games by decade they were published in, groups
+    /// come in the order of their best placed game. Works on
+    /// the games left by --top.
+    pub group_by: Option<String>,
+    #[structopt(long = "tier")]
+    /// Reports only games pulled with this number of user ratings.
+    pub tier: Option<u32>,
+    #[structopt(
+        long = "sort",
+        raw(
+            possible_values = "&[\"rating\", \"votes\", \"geek-rating\", \"bgg-rating\", \"bgg-votes\", \"weight\", \"name\", \"id\", \"ci-low\", \"bayes\", \"polarizing\"]"
+        )
+    )]
+    /// Sorts by the field, repeat for ties, e.g. --sort rating --sort votes.
+    /// ci-low, the lower bound of rating confidence interval, and bayes,
+    /// rating pulled towards the mean like BGG geek rating, go first
+    /// wherever they are given. polarizing is the share of the smaller
+    /// camp of ratings 4 and below or 8 and above, set when a game
+    /// stabilizes with enough trusted ratings.
+    pub sort: Vec<String>,
+    #[structopt(
+        long = "order",
+        default_value = "desc",
+        raw(possible_values = "&[\"asc\", \"desc\"]")
+    )]
+    /// Sort direction: asc or desc.
+    pub order: String,
+    #[structopt(long = "timings")]
+    /// Lists games that took longest to balance and how many users
+    /// they asked bgg about or found in the db instead,
+    /// works before the list is stable.
+    pub timings: bool,
+    #[structopt(long = "extended")]
+    /// Adds 95% confidence interval of the rating, bayesian rating,
+    /// polarizing score, hype, how much untrusted users raise the
+    /// rating when keep_all_ratings is set, and the vote tier.
+    pub extended: bool,
+    #[structopt(long = "snapshot")]
+    /// Reports games saved by `snapshot` instead of the current ones.
+    pub snapshot: Option<u32>,
+    #[structopt(long = "include-unstable")]
+    /// Prints current rankings even if balancing is not finished,
+    /// the Stable column tells unstable games apart, compact output
+    /// marks their names with *.
+    /// Games far from BGG average are always told by the Flagged
+    /// column, compact output marks them with !.
+    pub include_unstable: bool,
+}
+
+/// Narrower buckets chart more lines than ratings tell apart.
+const MIN_BUCKET: f64 = 0.05;
+
+fn parse_bucket(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(width) if width >= MIN_BUCKET && width.is_finite() => Ok(width),
+        Ok(_) => Err(format!("bucket must be from {} up, got {}", MIN_BUCKET, s)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct WatchArgs {
+    #[structopt(long = "watch")]
+    /// Keeps running balance rounds until every game is stable.
+    pub watch: bool,
+    #[structopt(long = "pause", default_value = "60")]
+    /// Seconds to wait between rounds.
+    pub pause: u64,
+    #[structopt(long = "max-rounds", default_value = "10")]
+    /// Gives up after this many rounds.
+    pub max_rounds: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(width: &str) -> Result<f64, structopt::clap::Error> {
+        let args = ["bgg_swing2", "histogram", "--bucket", width];
+        match Cli::from_iter_safe(args.iter())?.command {
+            Command::Histogram { bucket, .. } => Ok(bucket),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn bucket_has_a_lower_bound() {
+        assert_eq!(bucket("0.5").unwrap(), 0.5);
+        assert_eq!(bucket("0.05").unwrap(), 0.05);
+        for width in ["0.01", "0", "-1", "NaN", "inf", "wide"] {
+            assert!(bucket(width).is_err(), "{}", width);
+        }
+        let e = bucket("0.001").unwrap_err();
+        assert!(
+            e.message.contains("bucket must be from 0.05 up, got 0.001"),
+            "{}",
+            e.message
+        );
+    }
+
+    #[test]
+    fn db_is_compared_by_its_own_command() {
+        let args = ["bgg_swing2", "compare-db", "other.db", "--threshold", "0.2"];
+        match Cli::from_iter_safe(args.iter()).unwrap().command {
+            Command::CompareDb {
+                other, threshold, ..
+            } => assert_eq!((other, threshold), (PathBuf::from("other.db"), 0.2)),
+            other => panic!("{:?}", other),
+        }
+        // neither takes what only the other one needs
+        let args = ["bgg_swing2", "compare", "1", "--threshold", "0.2"];
+        assert!(Cli::from_iter_safe(args.iter()).is_err());
+        let args = ["bgg_swing2", "compare-db", "other.db", "2"];
+        assert!(Cli::from_iter_safe(args.iter()).is_err());
+        let args = ["bgg_swing2", "compare", "1", "top.json"];
+        match Cli::from_iter_safe(args.iter()).unwrap().command {
+            Command::Compare { old, new, .. } => assert_eq!(
+                (old, new),
+                (
+                    Listed::Snapshot(1),
+                    Some(Listed::Export(PathBuf::from("top.json")))
+                )
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
 }
@@ -1,92 +1,1018 @@
 use crate::bgg;
 use crate::db;
-use crate::lib::{Game, User};
-use failure::{ensure, Error, ResultExt};
-use reqwest::Client;
+use crate::error::AppError;
+use crate::profile::{self, Phase};
+use crate::progress::Reporter;
+use crate::ratelimit::RateLimiter;
+use crate::runlog;
+use bgg_swing2::{Game, User};
+use chrono::{DateTime, Datelike, Local};
+use serde::{de, Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
-use serde_json::{from_str, to_string_pretty};
-use std::collections::HashMap;
+use serde_json::{from_str, to_string_pretty, to_value, Value};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use threadpool::ThreadPool;
+use std::time::{Duration, Instant};
+use threadpool::Builder;
 
-const CONFIG_FILE_NAME: &str = "app.config";
+pub const CONFIG_FILE_NAME: &str = "app.config";
 const LOWER_BOUND: f64 = 2.0;
 const UPPER_BOUND: f64 = 8.0;
+const HEARTBEAT: Duration = Duration::from_secs(10);
+// requests per second that BGG tolerates without bans
+const POLITE_RATE: f64 = 10.0;
+// budget of a config that doesn't set one, well below POLITE_RATE
+const DEFAULT_REQUESTS_PER_MINUTE: u32 = 120;
+const MIN_LIMIT: u32 = 100;
+// config `new` writes
+pub const DEFAULT_LIMIT: u32 = 1000;
+pub const DEFAULT_THREADS: usize = 4;
+const DEFAULT_ATTEMPTS: u32 = 20;
+const DEFAULT_DELAY: u32 = 500;
+// number of error-prone games and users in the summary
+const TOP_ERROR_GAMES: usize = 5;
+// previous run's backoff is forgotten after an hour
+const BACKOFF_MEMORY: Duration = Duration::from_secs(3600);
+const BACKOFF_DECAY: Duration = Duration::from_secs(300);
+const BACKOFF_LEVEL: &str = "backoff_level";
+const BACKOFF_SAVED: &str = "backoff_saved";
+// start of a review that asks BGG again, kept until every user is done
+const REVIEW_STARTED: &str = "review_started";
+// failed tries of a user before a review leaves it for the next one
+const USER_TRIES: u32 = 3;
+// longest pause a worker can take before the next request
+const MAX_DELAY: Duration = Duration::from_secs(600);
 
-pub fn create_structure() -> Result<(), Error> {
+// Tests move the clock cached users grow stale by, see testing::Clock.
+#[cfg(test)]
+pub static TEST_NOW: Mutex<Option<DateTime<Local>>> = Mutex::new(None);
+
+/// Time stats of cached users are judged stale by, and the age of
+/// accounts.
+fn now() -> DateTime<Local> {
+    #[cfg(test)]
+    {
+        if let Some(now) = *TEST_NOW.lock().unwrap_or_else(|e| e.into_inner()) {
+            return now;
+        }
+    }
+    Local::now()
+}
+
+/// Creates an empty db unless there is one, true if it did.
+pub fn ensure_db() -> Result<bool, AppError> {
+    if db::db_path().exists() {
+        return Ok(false);
+    }
+    db::initialize()?;
+    Ok(true)
+}
+
+pub fn create_structure(limit: u32, threads: usize) -> Result<(), AppError> {
     // create config file
-    let new_conf = to_string_pretty(&Config::new(1000, 20, 500, 4))?;
+    let new_conf = to_string_pretty(&Config::new(
+        limit,
+        DEFAULT_ATTEMPTS,
+        DEFAULT_DELAY,
+        threads,
+    ))
+    .map_err(|e| AppError::Config(e.to_string()))?;
     fs::write(CONFIG_FILE_NAME, new_conf)?;
     // create db file
     db::initialize()?;
     Ok(())
 }
 
-pub fn pull_games(limit: u32, progress: impl Fn(usize) -> ()) -> Result<(), Error> {
-    ensure!(limit > 0, "Can't get top.");
+/// Totals of a pull.
+#[derive(Debug, Default, Serialize)]
+pub struct PullStats {
+    pub games: u32,       // games collected, each counted once
+    pub pages: u32,       // search pages that brought games
+    pub requests: u32,    // search pages asked for, the final check included
+    pub duplicates: u32,  // games listed on several pages
+    pub gaps: u32,        // ranks whose games may have been missed
+    pub implausible: u32, // games left out by strict_pull
+}
+
+/// Source of the games that come from BGG search, see db::add_games.
+const PULL_SOURCE: &str = "search";
+
+// meta keys of the config the list was pulled with and the
+// trust settings of the last balance run
+const PULL_CONFIG: &str = "pull_config";
+const BALANCE_CONFIG: &str = "balance_config";
+// Config fields that decide which games are in the list
+// and which users are trusted, results made under other
+// values don't match the current config.
+pub const PULL_FIELDS: [&str; 3] = ["limit", "strict_pull", "search_extra_params"];
+pub const BALANCE_FIELDS: [&str; 3] = ["bounds", "inclusive_bounds", "min_account_age_years"];
 
-    // clear db
-    db::drop_all_games()?;
+/// Search results may shift while pulling, every duplicate means that
+/// some other game has fallen into the gap between pages. The last
+/// page is asked for once more then, games that slid down to it are
+/// found again, the rest of the gap is counted in the stats.
+/// With `keep` games from earlier pulls stay, the vote limit
+/// becomes the tier of the pulled games. Lowering `running` stops
+/// the pull before its next request, pages pulled by then are kept.
+/// Games pulled before are renewed page by page and the ones no
+/// longer listed retired only when the pull is done, a pull that
+/// fails on the way leaves the rest of the list as it was.
+pub fn pull_games(
+    config: &Config,
+    keep: bool,
+    running: &AtomicBool,
+    reporter: &mut dyn Reporter,
+) -> Result<PullStats, AppError> {
+    if config.limit == 0 {
+        return Err(AppError::Config(String::from("Can't get top.")));
+    }
+    let client = bgg_client(config)?;
+
+    // bring older db files up to date
+    db::upgrade()?;
+    // keep old ratings to show how much games change
+    let previous = db::get_stable_ratings()?;
     // Collect games
-    for (i, games) in bgg::GameIterator::new(&Client::new(), limit).enumerate() {
+    let mut pulled = Pulled {
+        config,
+        keep,
+        previous,
+        seen: HashSet::new(),
+        saved: HashSet::new(),
+        stats: PullStats::default(),
+    };
+    let limiter = RateLimiter::per_minute(config.max_requests_per_minute);
+    let mut pages = bgg::GameIterator::new(&client, config.search_query()?, &limiter, running)
+        .retry(
+            config.attempts,
+            Duration::from_millis(u64::from(config.delay)),
+        );
+    let mut last_page = 0;
+    let mut listed_games = 0; // rows of games as BGG lists them, repeats too
+    while let Some(games) = pages.next() {
+        let i = last_page;
+        for (page, e) in pages.take_retried() {
+            let warning = format!("retried page {} after: {}", page, e.describe());
+            reporter.on_pull_warning(page as usize, &warning);
+        }
         // Error will be elevated and next() will be never called again
-        let games_on_page = games?;
-        db::add_games(games_on_page)?;
-        progress(i + 1);
+        let page = games?;
+        for row in &page.skipped {
+            reporter.on_pull_warning(i + 1, row);
+        }
+        let listed = page.games.len();
+        listed_games += listed;
+        let new_games = pulled.take(page.games, i + 1, reporter)?;
+        pulled.stats.duplicates += (listed - new_games) as u32;
+        reporter.on_pull_page(i + 1);
+        last_page = i + 1;
     }
-    Ok(())
+    let mut stats = if pulled.stats.duplicates > 0 {
+        let gaps = pulled.stats.duplicates;
+        // a failed second look changes nothing, the gap is told anyway
+        let found = match pages.refetch(last_page as u32) {
+            Ok(page) => pulled.take(page.games, last_page, reporter)?,
+            Err(e) => {
+                let warning = format!("could not look at the last page again: {}", e.describe());
+                reporter.on_pull_warning(last_page, &warning);
+                0
+            }
+        };
+        PullStats {
+            gaps: gaps.saturating_sub(found as u32),
+            ..pulled.stats
+        }
+    } else {
+        pulled.stats
+    };
+    let seen = pulled.seen.len();
+    // games added by other means stay
+    if !keep {
+        db::retire_games_from(PULL_SOURCE, &pulled.saved)?;
+    }
+    let pulled = without_private(to_value(config).map_err(config_error)?);
+    db::DbConn::new()?.meta_set(PULL_CONFIG, &pulled.to_string())?;
+    stats.games = seen as u32 - stats.implausible;
+    stats.pages = last_page as u32;
+    stats.requests = pages.requests();
+    // BGG counts what it lists, before repeats and implausible games
+    // are dropped here, rows without ratings are not games to it
+    if let Some(total) = pages.total().filter(|&t| t as usize != listed_games) {
+        let warning = format!("BGG counts {} games, pulled {}", total, listed_games);
+        reporter.on_pull_warning(last_page, &warning);
+    }
+    Ok(stats)
 }
 
-pub fn make_report() -> Result<Vec<Game>, Error> {
-    let conn = db::DbConn::new()?;
-    if conn.get_number_of_unstable_games()? == 0 {
-        conn.get_all_games()
+/// Games collected by a pull so far.
+struct Pulled<'a> {
+    config: &'a Config,
+    keep: bool,                  // games pulled before keep their progress
+    previous: HashMap<u32, f64>, // stable ratings before the pull
+    seen: HashSet<u32>,
+    saved: HashSet<u32>, // seen games that were plausible
+    stats: PullStats,
+}
+
+impl Pulled<'_> {
+    /// Saves games not seen before, returns how many there were.
+    fn take(
+        &mut self,
+        games: Vec<Game>,
+        page: usize,
+        reporter: &mut dyn Reporter,
+    ) -> Result<usize, AppError> {
+        let seen = &mut self.seen;
+        let previous = &self.previous;
+        let new_games: Vec<Game> = games
+            .into_iter()
+            .filter(|g| seen.insert(g.id))
+            .map(|g| Game {
+                previous_rating: previous.get(&g.id).cloned(),
+                ..g
+            })
+            .collect();
+        let found = new_games.len();
+        let new_games = if self.config.strict_pull {
+            let (plausible, implausible): (Vec<Game>, Vec<Game>) =
+                new_games.into_iter().partition(plausible);
+            for game in &implausible {
+                let warning = format!("skipped {}, no BGG votes or rating", game.name);
+                reporter.on_pull_warning(page, &warning);
+            }
+            self.stats.implausible += implausible.len() as u32;
+            plausible
+        } else {
+            new_games
+        };
+        self.saved.extend(new_games.iter().map(|g| g.id));
+        if self.keep {
+            db::add_games(new_games, self.config.limit, PULL_SOURCE)?;
+        } else {
+            db::renew_games(new_games, self.config.limit, PULL_SOURCE)?;
+        }
+        Ok(found)
+    }
+}
+
+/// Games heavier than `max_weight` or with unknown weight
+/// are left out when the limit is set. With `tier` only that
+/// tier has to be stable. Games come from the `snapshot`, if any.
+/// Empty while any game is unstable, unless `include_unstable`.
+pub fn make_report(
+    max_weight: Option<f64>,
+    tier: Option<u32>,
+    order: &db::Order,
+    include_unstable: bool,
+    snapshot: Option<u32>,
+) -> Result<Vec<Game>, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    let mut games = match snapshot {
+        Some(id) => {
+            let snapshots = conn.list_snapshots()?;
+            if !snapshots.iter().any(|s| s.id == id) {
+                let ids: Vec<String> = snapshots.iter().map(|s| s.id.to_string()).collect();
+                let e = if ids.is_empty() {
+                    format!("Snapshot {} not found, there are no snapshots yet.", id)
+                } else {
+                    format!("Snapshot {} not found, available: {}.", id, ids.join(", "))
+                };
+                return Err(AppError::Config(e));
+            }
+            conn.get_snapshot(id, tier, order)?
+        }
+        None => conn.get_all_games(tier, order)?,
+    };
+    if !include_unstable && games.iter().any(|g| !g.stable) {
+        return Ok(Vec::new());
+    }
+    if let Some(max) = max_weight {
+        games.retain(|g| g.weight.is_some_and(|w| w <= max));
+    }
+    Ok(games)
+}
+
+/// Old and new list of a comparison.
+pub type Lists = (Vec<Game>, Vec<Game>);
+
+/// One of the lists `compare` reads, a snapshot id or the path of
+/// a json report written earlier.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listed {
+    Snapshot(u32),
+    Export(PathBuf),
+}
+
+impl From<&str> for Listed {
+    fn from(arg: &str) -> Listed {
+        match arg.parse() {
+            Ok(id) => Listed::Snapshot(id),
+            Err(_) => Listed::Export(PathBuf::from(arg)),
+        }
+    }
+}
+
+/// Games of list `old` and of list `new` or of the current list.
+/// Snapshots are read as they were taken, unstable games and all.
+/// None while the current list is not stable.
+pub fn compared_lists(old: &Listed, new: Option<&Listed>) -> Result<Option<Lists>, AppError> {
+    let old_games = listed_games(old)?;
+    let new_games = match new {
+        Some(new) => listed_games(new)?,
+        None => make_report(None, None, &db::Order::default(), false, None)?,
+    };
+    if new.is_none() && new_games.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((old_games, new_games)))
+}
+
+fn listed_games(listed: &Listed) -> Result<Vec<Game>, AppError> {
+    match listed {
+        Listed::Snapshot(id) => make_report(None, None, &db::Order::default(), true, Some(*id)),
+        Listed::Export(path) => exported_games(path),
+    }
+}
+
+/// Game as `report --format json` or ndjson wrote it. Columns of the
+/// extended report and the group are not needed to compare.
+#[derive(Deserialize)]
+struct ExportedGame {
+    id: u32,
+    name: String,
+    rating: f64,
+    votes: u32,
+    bgg_geek_rating: f64,
+    bgg_avg_rating: f64,
+    bgg_num_votes: u32,
+    weight: Option<f64>,
+    year: Option<u32>,
+    stable: bool,
+    sampled: bool,
+    flagged: bool,
+}
+
+/// Games of a json report, an array or one object per line, best
+/// first whatever order or grouping it was written in.
+pub fn exported_games(path: &Path) -> Result<Vec<Game>, AppError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| AppError::Config(format!("Can't open: {}: {}", path.display(), e)))?;
+    let bad = |e: serde_json::Error| {
+        AppError::Parse(format!("{} is not a json report: {}", path.display(), e))
+    };
+    let exported: Vec<ExportedGame> = if text.trim_start().starts_with('[') {
+        from_str(&text).map_err(bad)?
     } else {
-        Ok(Vec::new())
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(from_str)
+            .collect::<Result<_, _>>()
+            .map_err(bad)?
+    };
+    let mut games: Vec<Game> = exported
+        .into_iter()
+        .map(|e| Game {
+            rating: e.rating,
+            votes: e.votes,
+            weight: e.weight,
+            year: e.year,
+            stable: e.stable,
+            sampled: e.sampled,
+            flagged: e.flagged,
+            ..Game::from_search(
+                e.id,
+                e.name,
+                e.bgg_num_votes,
+                e.bgg_geek_rating,
+                e.bgg_avg_rating,
+            )
+        })
+        .collect();
+    games.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+    Ok(games)
+}
+
+/// Same games as make_report without a snapshot, handed to `f` as
+/// they are read. None of them are if some are not stable and
+/// `include_unstable` is not set.
+pub fn for_each_game(
+    filter: &db::GameFilter,
+    order: &db::Order,
+    include_unstable: bool,
+    f: impl FnMut(Game) -> Result<(), AppError>,
+) -> Result<(), AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    if !include_unstable && conn.get_number_of_unstable_games(filter.tier, None)? > 0 {
+        return Ok(());
     }
+    conn.for_each_game(filter, order, f)
 }
 
-fn trust(rating: f64) -> bool {
-    LOWER_BOUND < rating && rating < UPPER_BOUND
+/// Games of another db for `compare`. A db with unstable games or
+/// with tables older than its version says is refused.
+pub fn other_games(path: &Path) -> Result<Vec<Game>, AppError> {
+    let conn = db::DbConn::open_read_only(path)?;
+    let games = match conn.get_all_games(None, &db::Order::default()) {
+        Err(AppError::Db(e))
+            if ["no such column", "no such table"]
+                .iter()
+                .any(|m| e.to_string().contains(m)) =>
+        {
+            return Err(AppError::Config(format!(
+                "{} has tables of an older version ({}). Run `bgg_swing2 --db {} check` to upgrade it.",
+                path.display(),
+                e,
+                path.display()
+            )))
+        }
+        games => games?,
+    };
+    let unstable = games.iter().filter(|g| !g.stable).count();
+    if unstable > 0 {
+        return Err(AppError::Config(format!(
+            "{} has {} unstable games, balance it before comparing.",
+            path.display(),
+            unstable
+        )));
+    }
+    Ok(games)
+}
+
+/// Saves the current game list, returns the snapshot id.
+pub fn take_snapshot(name: Option<&str>) -> Result<u32, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.take_snapshot(name)
+}
+
+pub fn list_snapshots() -> Result<Vec<db::Snapshot>, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    conn.list_snapshots()
+}
+
+/// Games that took longest to balance, slowest first.
+pub fn timings() -> Result<Vec<db::Timing>, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    conn.get_timings()
+}
+
+pub fn count_unstable_games(tier: Option<u32>) -> Result<u32, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    conn.get_number_of_unstable_games(tier, None)
+}
+
+/// Unstable games balance can still get on with. Games left at
+/// max_pages_per_game by on_page_cap leave wait for a higher cap.
+pub fn count_balanceable_games(config: &Config, tier: Option<u32>) -> Result<u32, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    let max_page = match config.on_page_cap {
+        PageCap::Leave => config.max_pages_per_game,
+        PageCap::Finish => None,
+    };
+    conn.get_number_of_unstable_games(tier, max_page)
+}
+
+/// Games balance would take first, in the order it takes them.
+pub fn unstable_games(
+    limit: Option<u32>,
+    tier: Option<u32>,
+    seed: Option<u32>,
+) -> Result<Vec<Game>, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    conn.get_unstable_games(limit, tier, seed)
+}
+
+pub fn history(limit: u32) -> Result<Vec<db::Run>, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    conn.get_runs(limit)
+}
+
+/// Sizes of the db and the config its data was made with.
+pub struct Status {
+    pub totals: db::Totals,
+    pub pulled: Option<Value>, // config of the last pull, if there was one
+    pub balanced: Option<Value>, // trust settings of the last balance run
+    pub drift: Vec<String>,    // fields the current config has changed since
+}
+
+pub fn status(config: &Config) -> Result<Status, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    let stored = |key| -> Result<Option<Value>, AppError> {
+        match conn.meta_get(key)? {
+            Some(json) => {
+                Ok(Some(from_str(&json).map_err(|e| {
+                    AppError::Parse(format!("{} is not valid: {}", key, e))
+                })?))
+            }
+            None => Ok(None),
+        }
+    };
+    let pulled = stored(PULL_CONFIG)?;
+    let balanced = stored(BALANCE_CONFIG)?;
+    let mut drift = Vec::new();
+    if let Some(pulled) = &pulled {
+        drift.extend(config_drift(pulled, config, &PULL_FIELDS, "pulled")?);
+    }
+    if let Some(balanced) = &balanced {
+        drift.extend(config_drift(balanced, config, &BALANCE_FIELDS, "balanced")?);
+    }
+    Ok(Status {
+        totals: conn.get_totals()?,
+        pulled,
+        balanced,
+        drift,
+    })
+}
+
+/// Versions, size and origin of the db for bug reports.
+#[derive(Serialize)]
+pub struct Info {
+    pub version: &'static str,
+    pub schema_version: u32, // of the tables in the file
+    pub db_path: PathBuf,
+    pub db_size: u64, // bytes, without the wal file
+    pub totals: db::Totals,
+    pub pulled: Option<Value>,        // config of the last pull, see info
+    pub last_balance: Option<String>, // finish of the last uninterrupted balance
+}
+
+pub fn info() -> Result<Info, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    let schema_version = conn.schema_version()?;
+    let pulled = match conn.meta_get(PULL_CONFIG)? {
+        Some(json) => {
+            let pulled: Value = from_str(&json)
+                .map_err(|e| AppError::Parse(format!("{} is not valid: {}", PULL_CONFIG, e)))?;
+            // older pulls stored them
+            Some(without_private(pulled))
+        }
+        None => None,
+    };
+    Ok(Info {
+        version: env!("CARGO_PKG_VERSION"),
+        schema_version,
+        db_path: db::db_path().to_path_buf(),
+        db_size: fs::metadata(db::db_path())?.len(),
+        totals: conn.get_totals()?,
+        pulled,
+        last_balance: conn.last_balance()?,
+    })
+}
+
+/// Config fields without those that may name a person or hold
+/// a password, they are never stored in the db.
+fn without_private(mut config: Value) -> Value {
+    if let Some(fields) = config.as_object_mut() {
+        fields.remove("contact");
+        fields.remove("http_proxy");
+    }
+    config
+}
+
+/// Given `fields` of the config as a json object.
+fn fields(config: &Config, fields: &[&str]) -> Result<Value, AppError> {
+    let all = to_value(config).map_err(config_error)?;
+    Ok(Value::Object(
+        fields
+            .iter()
+            .map(|&f| (String::from(f), all.get(f).cloned().unwrap_or(Value::Null)))
+            .collect(),
+    ))
+}
+
+/// Describes every field of `fields` that differs between the stored
+/// config and the current one. Fields the stored config lacks, saved
+/// by an older version, are not compared.
+fn config_drift(
+    stored: &Value,
+    config: &Config,
+    fields: &[&str],
+    done: &str,
+) -> Result<Vec<String>, AppError> {
+    let current = to_value(config).map_err(config_error)?;
+    Ok(fields
+        .iter()
+        .filter_map(|&f| {
+            let was = stored.get(f)?;
+            let now = current.get(f).unwrap_or(&Value::Null);
+            if was == now {
+                return None;
+            }
+            Some(format!(
+                "{} is {} in {}, the db was {} with {}",
+                f,
+                show_value(now),
+                CONFIG_FILE_NAME,
+                done,
+                show_value(was)
+            ))
+        })
+        .collect())
+}
+
+/// Json value as the user wrote it in the config, strings unquoted.
+pub fn show_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::from("none"),
+        v => v.to_string(),
+    }
+}
+
+fn config_error(e: serde_json::Error) -> AppError {
+    AppError::Config(format!("Can't serialize config: {}", e))
+}
+
+/// Returns findings that are left after repair, and the number of repaired ones.
+/// Upgrades the tables first, read only commands won't.
+pub fn check(repair: bool) -> Result<(Vec<db::consistency::Finding>, usize), AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    let mut left = Vec::new();
+    let mut repaired = 0;
+    for finding in db::consistency::check(&conn)? {
+        if repair && db::consistency::repair(&conn, &finding)? {
+            repaired += 1;
+        } else {
+            left.push(finding);
+        }
+    }
+    Ok((left, repaired))
+}
+
+/// Checks stored users against the current config, returns the number
+/// of users whose trust changed and of games recounted.
+pub fn review_users(config: &Config) -> Result<(usize, usize), AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    let mut users = 0;
+    let mut games = 0;
+    for user in conn.get_checked_users()? {
+        let trusted = config.trusts(user.average, user.year);
+        if trusted != user.trusted {
+            users += 1;
+            games += conn.update_user_trust(&user.name, trusted, &|g| config.flags(g))?;
+        }
+    }
+    Ok((users, games))
+}
+
+/// Asks BGG again about users checked before the review started, or
+/// before user_ttl_days if that is earlier. Every user is saved as soon
+/// as it is done, an interrupted review goes on with the rest next time.
+/// A user that fails USER_TRIES times is skipped, the review is over
+/// once no user is left.
+pub fn fetch_users(
+    config: Config,
+    running: Arc<AtomicBool>,
+    reporter: &mut dyn Reporter,
+) -> Result<ReviewSummary, AppError> {
+    let client = bgg_client(&config)?;
+    let started = now();
+    let clock = Instant::now();
+    let limiter = Arc::new(RateLimiter::per_minute(config.max_requests_per_minute));
+
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    let since = match conn.meta_get(REVIEW_STARTED)? {
+        Some(since) => DateTime::parse_from_rfc3339(&since)
+            .map_err(|e| AppError::Parse(format!("{} is not a date: {}", REVIEW_STARTED, e)))?
+            .with_timezone(&Local),
+        None => {
+            conn.meta_set(REVIEW_STARTED, &started.to_rfc3339())?;
+            started
+        }
+    };
+    let before = match config.user_ttl_days {
+        Some(days) => since.min(started - chrono::Duration::days(i64::from(days))),
+        None => since,
+    };
+    let users = conn.get_users_checked_before(&before)?;
+    let queue = Arc::new(Mutex::new(
+        users.into_iter().map(|u| (u, 0)).collect::<VecDeque<_>>(),
+    ));
+    let backoff = Arc::new(AtomicU32::new(restore_backoff(&conn)?));
+
+    let pool = Builder::new()
+        .num_threads(config.threads)
+        .thread_name(String::from("reviewer"))
+        .build();
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..config.threads {
+        let tx = tx.clone();
+        let shared = Shared {
+            running: running.clone(),
+            backoff: backoff.clone(),
+            limiter: limiter.clone(),
+        };
+        let queue = queue.clone();
+        let config = config.clone();
+        let client = client.clone();
+        pool.execute(move || reviewer(config, client, shared, tx, queue));
+    }
+    drop(tx);
+
+    let mut result = Ok(());
+    let mut summary = ReviewSummary::default();
+    loop {
+        let received = match rx.recv_timeout(HEARTBEAT) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => {
+                reporter.on_heartbeat();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match received {
+            Message::DieErr(e) => {
+                running.store(false, Ordering::SeqCst);
+                result = Err(e);
+            }
+            Message::NoteErr(e, _) => {
+                summary.errors += 1;
+                reporter.on_error(&e);
+            }
+            Message::NoteUserReviewed {
+                user,
+                flipped,
+                games,
+            } => {
+                summary.reviewed += 1;
+                summary.flipped += u32::from(flipped);
+                summary.games += games as u32;
+                reporter.on_user_reviewed(&user, flipped, games);
+            }
+            Message::NoteUserSkipped(user) => {
+                summary.skipped += 1;
+                reporter.on_user_skipped(&user);
+            }
+            Message::NoteRateLimited(delay) => reporter.on_rate_limited(delay),
+            // the rest is about games, reviewers don't send it
+            _ => {}
+        }
+    }
+    pool.join();
+    summary.remaining = queue.lock().unwrap().len() as u32;
+    summary.requests = limiter.granted();
+    summary.duration = clock.elapsed().as_secs_f64();
+    if summary.remaining == 0 {
+        conn.meta_delete(REVIEW_STARTED)?;
+    }
+    conn.add_review_run(&started, &summary)?;
+    conn.meta_set(BACKOFF_LEVEL, &backoff.load(Ordering::SeqCst).to_string())?;
+    conn.meta_set(BACKOFF_SAVED, &Local::now().to_rfc3339())?;
+    reporter.on_review_finished(&summary);
+    result.map(|_| summary)
+}
+
+/// Takes users from the shared queue until it is empty, the process
+/// is stopped or BGG fails too often. Users that failed go back to
+/// the end of the queue with their failed tries, until USER_TRIES.
+fn reviewer(
+    config: Config,
+    client: bgg::BggClient,
+    shared: Shared,
+    tx: Sender<Message>,
+    queue: Arc<Mutex<VecDeque<(db::CheckedUser, u32)>>>,
+) {
+    let Shared {
+        running,
+        backoff,
+        limiter,
+    } = shared;
+    let conn = match db::DbConn::new() {
+        Err(e) => {
+            tx.send(Message::DieErr(e)).unwrap();
+            return;
+        }
+        Ok(cn) => cn,
+    };
+    let delay_step = Duration::from_millis(config.delay as u64);
+    let mut tkn = RegulationToken::new(
+        config.attempts,
+        delay_step,
+        backoff,
+        limiter,
+        running.clone(),
+    );
+    while running.load(Ordering::SeqCst) {
+        if tkn.is_stopped() {
+            let e = String::from("Regulation token stopped the review, run it again later.");
+            tx.send(Message::DieErr(AppError::Interrupted(e))).unwrap();
+            return;
+        }
+        let (user, failed) = match queue.lock().unwrap().pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        let delay = tkn.delay();
+        if delay > Duration::from_secs(0) {
+            tx.send(Message::NoteRateLimited(delay)).unwrap();
+        }
+        profile::time(Phase::Wait, || thread::sleep(delay));
+        match review_user(&conn, &client, &tkn, &config, &user) {
+            Ok(Some((flipped, games))) => {
+                tkn.ease();
+                tx.send(Message::NoteUserReviewed {
+                    user: user.name,
+                    flipped,
+                    games,
+                })
+                .unwrap();
+            }
+            // stopped while waiting, the user is left for the next review
+            Ok(None) => queue.lock().unwrap().push_front((user, failed)),
+            Err(e) if e.is_format_change() => {
+                queue.lock().unwrap().push_front((user, failed));
+                tx.send(Message::DieErr(e)).unwrap();
+                return;
+            }
+            Err(e) => {
+                tx.send(Message::NoteErr(e, None)).unwrap();
+                tkn.harden();
+                if failed + 1 < USER_TRIES {
+                    queue.lock().unwrap().push_back((user, failed + 1));
+                } else {
+                    tx.send(Message::NoteUserSkipped(user.name)).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Fetches stats of the user, saves them and tells whether trust has
+/// changed and how many games were recounted. None if the
+/// review was stopped while waiting for the request budget.
+fn review_user(
+    conn: &db::DbConn,
+    client: &bgg::BggClient,
+    tkn: &RegulationToken,
+    config: &Config,
+    user: &db::CheckedUser,
+) -> Result<Option<(bool, usize)>, AppError> {
+    let average = match user_average(client, tkn, config, &user.name)? {
+        Some(average) => average,
+        None => return Ok(None),
+    };
+    let year = match config.min_account_age_years {
+        Some(_) => {
+            if !tkn.acquire() {
+                return Ok(None);
+            }
+            bgg::get_user_year(client, &user.name)?
+        }
+        None => user.year, // not asked for, the stored one stays
+    };
+    let trusted = config.trusts(average, year);
+    let games = profile::time(Phase::Db, || {
+        conn.refresh_user(&user.name, trusted, average, year, &|g| config.flags(g))
+    })?;
+    Ok(Some((trusted != user.trusted, games)))
+}
+
+/// Returns the number of games recounted.
+pub fn set_user_trust(config: &Config, user: &User, trusted: bool) -> Result<usize, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.set_user_trust(user, trusted, &|g| config.flags(g))
+}
+
+/// false => the game is not in the list.
+pub fn set_watched(game_id: u32, watched: bool) -> Result<bool, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.set_watched(game_id, watched)
+}
+
+pub fn game_notes(game_id: u32) -> Result<Option<(String, Option<String>)>, AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    conn.get_game_notes(game_id)
+}
+
+pub fn set_game_notes(game_id: u32, notes: &str) -> Result<bool, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.set_game_notes(game_id, notes)
+}
+
+/// Sends sampled games back to the first page for a full pass.
+pub fn reset_sampled_games() -> Result<usize, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.reset_sampled_games()
+}
+
+/// Fits page counters of unstable games to `page_size`.
+pub fn convert_pages(page_size: u32) -> Result<usize, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.convert_pages(page_size)
+}
+
+pub fn reset_watched_games() -> Result<usize, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.reset_watched_games()
+}
+
+pub fn for_each_user(
+    filter: &db::UserFilter,
+    f: impl FnMut(db::UserRecord) -> Result<(), AppError>,
+) -> Result<(), AppError> {
+    let conn = db::DbConn::new_read_only()?;
+    conn.for_each_user(filter, f)
+}
+
+pub fn prune_users() -> Result<usize, AppError> {
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    conn.prune_users()
 }
 
 /// Err => Unrecoverable error, no signal sent
 /// None => bgg is busy, must ask again later
 /// Hashmap => got info on every user
-fn check_users<'a>(
+#[allow(clippy::too_many_arguments)]
+fn classify_users<'a>(
     tx: &Sender<Message>,
     conn: &db::DbConn,
-    client: &Client,
+    client: &bgg::BggClient,
     tkn: &mut RegulationToken,
+    config: &Config,
+    game_id: u32,
     users: &'a [(User, f64)],
-) -> Result<Option<HashMap<&'a User, bool>>, Error> {
+    counts: &mut db::UserCounts,
+) -> Result<Option<HashMap<&'a User, bool>>, AppError> {
     let mut user_map: HashMap<&User, bool> = HashMap::new();
+    let mut cached = 0;
+    let stale_before = config
+        .user_ttl_days
+        .map(|days| now() - chrono::Duration::days(i64::from(days)));
     for (user, _) in users {
         // check if we have seen user already
-        match conn.check_user(&user) {
-            // see him first time
-            Ok(None) => {
+        match profile::time(Phase::Db, || conn.check_user(user, stale_before)) {
+            // see him first time or must look again
+            Ok(known @ (db::Known::Unseen | db::Known::Stale(_))) => {
                 // ask bgg for user stats
-                let rating = match bgg::get_user_average_rating(client, &user) {
-                    Err(e) => {
-                        tx.send(Message::NoteErr(e)).unwrap();
+                let fetched = match user_average(client, tkn, config, user).transpose() {
+                    Some(fetched) => fetched,
+                    None => return Ok(None), // stopped while waiting for the budget
+                };
+                let fetched = match (fetched, config.min_account_age_years) {
+                    (Ok(average), Some(_)) => {
+                        if !tkn.acquire() {
+                            return Ok(None);
+                        }
+                        bgg::get_user_year(client, user).map(|y| (average, y))
+                    }
+                    (fetched, _) => fetched.map(|average| (average, None)),
+                };
+                let (trusted, average, year) = match (fetched, known) {
+                    // strict_parse, the run must stop
+                    (Err(e), _) if e.is_format_change() => return Err(e),
+                    (Err(e), db::Known::Stale(trusted)) => {
+                        // old stats are better than none
+                        tx.send(Message::NoteUserErr(e, game_id, user.clone()))
+                            .unwrap();
+                        tkn.harden();
+                        user_map.insert(user, trusted);
+                        continue;
+                    }
+                    (Err(e), _) => {
+                        tx.send(Message::NoteUserErr(e, game_id, user.clone()))
+                            .unwrap();
                         tkn.harden(); // wait a bit longer before next request
-                        return Ok(None);
+                        match config.on_user_fetch_failure {
+                            FetchFailure::Retry => return Ok(None),
+                            // not saved, so ratings of the user don't count
+                            FetchFailure::SkipUser => continue,
+                            FetchFailure::AssumeUntrusted => (false, None, None),
+                        }
                     }
-                    Ok(rate) => rate,
+                    (Ok((average, year)), _) => (config.trusts(average, year), Some(average), year),
                 };
                 // save user to db
-                let trusted = trust(rating);
-                match conn.add_user(&user, trusted) {
+                let saved = profile::time(Phase::Db, || match (known, average) {
+                    (db::Known::Stale(_), Some(average)) => conn
+                        .refresh_user(user, trusted, average, year, &|g| config.flags(g))
+                        .map(|_| ()),
+                    _ => conn.add_user(user, trusted, average, year),
+                });
+                match saved {
                     Err(e) => return Err(e), // no signal sent
                     Ok(_) => {
                         tkn.ease();
+                        counts.new += 1;
                         tx.send(Message::NoteUserProgress(user.clone())).unwrap();
                         // memorize
                         user_map.insert(user, trusted);
@@ -94,7 +1020,8 @@ fn check_users<'a>(
                 }
             }
             // seen already, memorize
-            Ok(Some(v)) => {
+            Ok(db::Known::Trust(v)) => {
+                cached += 1;
                 user_map.insert(user, v);
             }
             // Error, no signal sent
@@ -102,9 +1029,67 @@ fn check_users<'a>(
         };
     }
     // we have info on every user
+    if cached > 0 {
+        counts.cached += cached;
+        tx.send(Message::NoteUsersCached(cached)).unwrap();
+    }
     Ok(Some(user_map))
 }
 
+/// Average rating of the user from the json user data when the config
+/// prefers it, from the profile page otherwise or when the json fails.
+/// Every request waits for its own share of the budget, a failed json
+/// costs two. None if stopped while waiting.
+fn user_average(
+    client: &bgg::BggClient,
+    tkn: &RegulationToken,
+    config: &Config,
+    user: &User,
+) -> Result<Option<f64>, AppError> {
+    if config.prefer_json {
+        if !tkn.acquire() {
+            return Ok(None);
+        }
+        match bgg::get_user_json_average(client, user) {
+            Ok(average) => return Ok(Some(average)),
+            Err(e) => runlog::complain(&format!(
+                "Warning: no json user data of {}, reading the profile page: {}",
+                user,
+                e.describe()
+            )),
+        }
+    }
+    if !tkn.acquire() {
+        return Ok(None);
+    }
+    bgg::get_user_average_rating(client, user).map(Some)
+}
+
+/// Err => BGG format has changed, no signal sent
+/// None => bgg is busy, must ask again later
+fn fetch_page(
+    tx: &Sender<Message>,
+    client: &bgg::BggClient,
+    tkn: &mut RegulationToken,
+    game: &Game,
+    page_size: u32,
+) -> Result<Option<bgg::UserPage>, AppError> {
+    if !tkn.acquire() {
+        return Ok(None); // stopped while waiting for the budget
+    }
+    match bgg::get_users_from(client, game.id, game.page, page_size) {
+        Err(e) if e.is_format_change() => Err(e),
+        Err(e) => {
+            tkn.harden(); // wait a bit longer before next request
+            tx.send(Message::NoteErr(e, Some(game.id))).unwrap();
+            Ok(None)
+        }
+        // eased once the page is saved, a page whose users fail
+        // again and again must not keep the token going
+        Ok(page) => Ok(Some(page)),
+    }
+}
+
 /// Err => Unrecoverable error, no signal sent
 /// None => bgg is busy, must ask again later
 /// true => last page has been reached
@@ -112,50 +1097,131 @@ fn check_users<'a>(
 fn check_game(
     tx: &Sender<Message>,
     conn: &db::DbConn,
-    client: &Client,
+    client: &bgg::BggClient,
     tkn: &mut RegulationToken,
+    config: &Config,
     game: &mut Game,
-) -> Result<Option<bool>, Error> {
+    counts: &mut db::UserCounts,
+) -> Result<Option<bool>, AppError> {
     // ask for user ratings
-    tx.send(Message::NoteGameProgress(game.clone())).unwrap();
-    let user_page = bgg::get_users_from(&client, game.id, game.page);
-    let users = match user_page {
-        Err(e) => {
-            tkn.harden(); // wait a bit longer before next request
-            tx.send(Message::NoteErr(e)).unwrap();
-            // get to the next loop iter
-            return Ok(None); // need to reiterate
-        }
-        Ok(vec) => {
-            tkn.ease();
-            vec
-        }
+    let bgg::UserPage {
+        users,
+        rejected,
+        weight,
+        total,
+    } = match fetch_page(tx, client, tkn, game, config.page_size)? {
+        None => return Ok(None), // need to reiterate
+        Some(page) => page,
     };
-    if users.is_empty() {
+    if game.page == 1 {
+        profile::time(Phase::Db, || conn.set_weight(game.id, weight))?;
+        game.weight = weight;
+    }
+    if rejected > 0 {
+        let e = AppError::Parse(format!(
+            "Ignored {} out of range ratings on page {} of {}",
+            rejected, game.page, game.name
+        ));
+        tx.send(Message::NoteErr(e, Some(game.id))).unwrap();
+    }
+    if users.is_empty() && rejected == 0 {
+        tkn.ease();
         game.page += 1;
         return Ok(Some(true)); // no users, the last page has been reached
     }
 
-    let mut avg = Avg::new(game.votes, game.rating);
+    let returned = users.len() as u32 + rejected;
     // check user trust
-    let user_map = check_users(tx, conn, client, tkn, &users)?;
-    let user_map = match user_map {
+    let user_map = match classify_users(tx, conn, client, tkn, config, game.id, &users, counts)? {
         None => return Ok(None), // need to reiterate, http failed
-        Some(m) => m,
+        Some(user_map) => user_map,
     };
-    for (user, rating) in users.iter() {
-        if user_map.get(user) == Some(&true) {
-            avg.add(*rating);
-        }
+    // users left out of the map don't count, they aren't stored either
+    let known: HashSet<User> = user_map.into_keys().cloned().collect();
+    // with sample_pages or sample_fraction only pages of the plan
+    // are asked for, the game is done after the last of them
+    let paging = config.paging();
+    let last = total.map(|t| paging.total_pages(t));
+    let plan = match (config.sample_pages, last) {
+        (Some(n), Some(last)) => Some(sample_plan(game.id, config.seed.unwrap_or(0), n, last))
+            .filter(|plan| (plan.len() as u32) < last),
+        (Some(_), None) => None,
+        (None, last) => fraction_plan(paging, config.sample_fraction, game.bgg_num_votes, last),
+    };
+    game.sampled = plan.is_some();
+    let mut update = apply_page(game, users, &known, plan.as_deref());
+    // no need to ask for an empty page after the last one
+    if paging.is_last(game.page, returned, total) {
+        update.next_page = None;
+    }
+    // ratings and the next page are saved together, a crash in
+    // between can't make the page count twice or get skipped
+    let next_page = update.next_page.unwrap_or(game.page + 1);
+    let (votes, rating) = profile::time(Phase::Db, || {
+        conn.commit_page(game, &update.ratings, next_page, config.keep_all_ratings)
+    })?;
+    tkn.ease();
+    game.votes = votes;
+    game.rating = rating;
+    game.page = next_page;
+    Ok(Some(update.next_page.is_none()))
+}
+
+/// What a page of ratings does to its game.
+#[derive(Debug, PartialEq)]
+struct PageUpdate {
+    /// ratings to store, one per user whose trust is known, trust
+    /// is applied when the game is counted
+    ratings: Vec<(User, f64)>,
+    /// None when no page is left to ask for
+    next_page: Option<u32>,
+}
+
+/// Works out what `users` of the page the game is on do to it,
+/// touches neither network nor db. Ratings of users not `known`,
+/// e.g. skipped after a failed fetch, are dropped. With a `plan`
+/// only its pages are asked for.
+fn apply_page(
+    game: &Game,
+    users: Vec<(User, f64)>,
+    known: &HashSet<User>,
+    plan: Option<&[u32]>,
+) -> PageUpdate {
+    let next_page = match plan {
+        Some(plan) => plan.iter().cloned().find(|&p| p > game.page),
+        None => Some(game.page + 1),
+    };
+    let mut ratings: Vec<(User, f64)> = Vec::with_capacity(users.len());
+    for (user, rating) in users.into_iter().filter(|(u, _)| known.contains(u)) {
+        // edited ratings may list a user twice, the last one wins
+        ratings.retain(|(u, _)| *u != user);
+        ratings.push((user, rating));
     }
-    // update game stats
-    game.rating = avg.result();
-    game.votes = avg.n();
-    game.page += 1;
-    Ok(Some(false))
+    PageUpdate { ratings, next_page }
 }
 
-fn runner(config: Config, running: Arc<AtomicBool>, tx: Sender<Message>, mut game: Game) -> () {
+/// State every worker of a run shares.
+#[derive(Clone)]
+struct Shared {
+    running: Arc<AtomicBool>,
+    backoff: Arc<AtomicU32>,
+    limiter: Arc<RateLimiter>,
+}
+
+fn runner(
+    worker: usize,
+    config: &Config,
+    client: &bgg::BggClient,
+    shared: Shared,
+    tx: Sender<Message>,
+    game: &mut Game,
+    counts: &mut db::UserCounts,
+) {
+    let Shared {
+        running,
+        backoff,
+        limiter,
+    } = shared;
     // Configure thread
     let conn = match db::DbConn::new() {
         Err(e) => {
@@ -164,14 +1230,19 @@ fn runner(config: Config, running: Arc<AtomicBool>, tx: Sender<Message>, mut gam
         }
         Ok(cn) => cn,
     };
-    let client = Client::new();
     let delay_step = Duration::from_millis(config.delay as u64);
-    let mut tkn = RegulationToken::new(config.attempts, delay_step);
+    let mut tkn = RegulationToken::new(
+        config.attempts,
+        delay_step,
+        backoff,
+        limiter,
+        running.clone(),
+    );
     loop {
         // check if token stop flag is raised
         if tkn.is_stopped() {
-            let e = failure::err_msg("Regulation token stopped the process.");
-            tx.send(Message::DieErr(e)).unwrap();
+            // coordinator gives the game another chance later
+            tx.send(Message::DieRegulated(game.clone())).unwrap();
             return;
         }
         // check if we got stop command
@@ -180,174 +1251,1163 @@ fn runner(config: Config, running: Arc<AtomicBool>, tx: Sender<Message>, mut gam
             return;
         }
 
+        // guards against games whose pages never run out
+        if config.page_cap_reached(game) {
+            tx.send(Message::NoteCapped(game.clone())).unwrap();
+            if config.on_page_cap == PageCap::Finish {
+                game.sampled = true;
+                finish_game(config, &conn, &tx, game);
+            }
+            return;
+        }
+
         // Wait a bit
-        thread::sleep(tkn.delay());
+        let delay = tkn.delay();
+        if delay > Duration::from_secs(0) {
+            tx.send(Message::NoteRateLimited(delay)).unwrap();
+        }
+        profile::time(Phase::Wait, || thread::sleep(delay));
         // Start doing main job
-        match check_game(&tx, &conn, &client, &mut tkn, &mut game) {
+        tx.send(Message::NoteGameProgress(worker, game.clone()))
+            .unwrap();
+        match check_game(&tx, &conn, client, &mut tkn, config, game, counts) {
             Err(e) => {
                 // propagate error
                 tx.send(Message::DieErr(e)).unwrap();
                 return;
             }
             Ok(None) => continue, // recoverable err occured, skip to the next iteration
-            Ok(Some(false)) => {
-                // update game data
-                match conn.update_game(&game, false) {
-                    Err(e) => {
-                        tx.send(Message::DieErr(e)).unwrap();
-                        return;
-                    }
-                    Ok(()) => continue, // gathered some data, skip to the next iteration
-                };
-            }
+            Ok(Some(false)) => continue, // page is saved, skip to the next iteration
             Ok(Some(true)) => {
                 // gathered all data
-                // update game data
-                match conn.update_game(&game, true) {
-                    Err(e) => {
-                        tx.send(Message::DieErr(e)).unwrap();
-                        return;
-                    }
-                    Ok(()) => tx.send(Message::DieResult(game)).unwrap(),
-                };
+                finish_game(config, &conn, &tx, game);
                 return;
             }
         };
     }
 }
 
+/// Saves the game as stable and tells the coordinator about it.
+fn finish_game(config: &Config, conn: &db::DbConn, tx: &Sender<Message>, game: &mut Game) {
+    let mut divergence = None;
+    // the rating of the last page may count users whose trust has
+    // changed since, it is taken again
+    let finished = profile::time(Phase::Db, || {
+        conn.commit_finish(game, |game, ratings| {
+            divergence = config.divergence(game);
+            game.flagged = divergence.is_some();
+            game.polarizing = config.polarizing(ratings);
+        })
+    });
+    match finished {
+        Err(e) => tx.send(Message::DieErr(e)).unwrap(),
+        Ok(false) => tx
+            .send(Message::DieErr(db::page_went_back(game, game.page)))
+            .unwrap(),
+        Ok(true) => {
+            if let Some(delta) = divergence {
+                tx.send(Message::NoteDivergence {
+                    game: game.clone(),
+                    delta,
+                })
+                .unwrap();
+            }
+            tx.send(Message::DieResult(game.clone())).unwrap()
+        }
+    };
+}
+
+/// Balances one game in a worker, `runner` but for tests that
+/// need a worker to fail.
+type Runner = Arc<
+    dyn Fn(usize, &Config, &bgg::BggClient, Shared, Sender<Message>, &mut Game, &mut db::UserCounts)
+        + Send
+        + Sync,
+>;
+
+/// Takes games from the shared queue one by one until
+/// the queue is empty or the process is stopped.
+fn worker(
+    id: usize,
+    config: Config,
+    client: bgg::BggClient,
+    shared: Shared,
+    tx: Sender<Message>,
+    queue: Arc<Mutex<VecDeque<Game>>>,
+    run: Runner,
+) {
+    while shared.running.load(Ordering::SeqCst) {
+        let mut game = match queue.lock().unwrap().pop_front() {
+            Some(game) => game,
+            None => break,
+        };
+        let started = Local::now();
+        let clock = Instant::now();
+        let first_page = game.page;
+        let mut counts = db::UserCounts::default();
+        // a panic in runner must not leave the coordinator waiting
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            run(
+                id,
+                &config,
+                &client,
+                shared.clone(),
+                tx.clone(),
+                &mut game,
+                &mut counts,
+            )
+        }));
+        if outcome.is_err() {
+            let e = AppError::Interrupted(format!("Worker panicked while balancing {}", game.name));
+            tx.send(Message::DieErr(e)).unwrap();
+        }
+        // games may take several runs, every run adds its own span
+        let pages = game.page.saturating_sub(first_page);
+        if let Err(e) = db::add_game_span(game.id, &started, clock.elapsed(), pages, &counts) {
+            tx.send(Message::NoteErr(e, Some(game.id))).unwrap();
+        }
+    }
+    tx.send(Message::NoteWorkerDone(id)).unwrap();
+}
+
 pub fn stabilize(
     config: Config,
+    sample: Option<u32>,
+    tier: Option<u32>,
+    running: Arc<AtomicBool>,
+    reporter: &mut dyn Reporter,
+) -> Result<(), AppError> {
+    stabilize_by(config, sample, tier, running, reporter, Arc::new(runner))
+}
+
+/// stabilize with every game balanced by `run`.
+fn stabilize_by(
+    config: Config,
+    sample: Option<u32>,
+    tier: Option<u32>,
     running: Arc<AtomicBool>,
-    mut progress: impl FnMut(Message) -> (),
-) -> Result<(), Error> {
+    reporter: &mut dyn Reporter,
+    run: Runner,
+) -> Result<(), AppError> {
     // NB. Errors from mpsc channels use unwrap(). If channels fail,
     // the core of the programm is severely damaged, panic is the only option.
+    let client = bgg_client(&config)?;
+    let started = Local::now();
+    let clock = Instant::now();
+    let limiter = Arc::new(RateLimiter::per_minute(config.max_requests_per_minute));
 
-    // Channel for communication
-    let (tx, rx) = mpsc::channel();
-    let pool = ThreadPool::new(config.threads);
+    let pool = Builder::new()
+        .num_threads(config.threads)
+        .thread_name(String::from("balancer"))
+        .build();
 
-    let games = db::get_unstable_games()?;
-    let job_size = games.len();
-    for game in games {
-        let tx = tx.clone();
-        let running = running.clone();
-        pool.execute(move || runner(config, running, tx, game));
-    }
+    // bring older db files up to date
+    db::upgrade()?;
+    let conn = db::DbConn::new()?;
+    // start where the previous run left BGG
+    let backoff = Arc::new(AtomicU32::new(restore_backoff(&conn)?));
+    let mut games = conn.get_unstable_games(sample, tier, config.seed)?;
 
-    // This will block main until iterator yields None
-    // which will never happen in case of threadpool
     let mut result = Ok(());
-    let mut finished = 0;
-    for received in rx {
-        // handle messages
-        match received {
-            Message::DieErr(e) => {
-                // stop every thread
-                running.store(false, Ordering::SeqCst);
-                result = Err(e);
-                finished += 1;
-            }
-            Message::DieResult(game) => {
-                finished += 1;
-                progress(Message::DieResult(game));
+    let mut summary = StabilizeSummary::default();
+    let mut names: HashMap<u32, String> = HashMap::new();
+    let mut game_errors: HashMap<u32, u32> = HashMap::new();
+    let mut user_errors: HashMap<User, u32> = HashMap::new();
+    let mut retries_left = config.retry_passes;
+    loop {
+        let queue: Arc<Mutex<VecDeque<Game>>> = Arc::new(Mutex::new(games.into_iter().collect()));
+        // Channel for communication
+        let (tx, rx) = mpsc::channel();
+        for id in 0..config.threads {
+            let tx = tx.clone();
+            let shared = Shared {
+                running: running.clone(),
+                backoff: backoff.clone(),
+                limiter: limiter.clone(),
+            };
+            let queue = queue.clone();
+            let config = config.clone();
+            let client = client.clone();
+            let run = run.clone();
+            pool.execute(move || worker(id, config, client, shared, tx, queue, run));
+        }
+        // workers hold the only senders now
+        drop(tx);
+
+        // This will block main until every worker is gone
+        // and the channel is closed
+        let mut regulated = Vec::new();
+        // games of this pass given up since the last one balanced
+        let mut regulated_in_row = 0;
+        // game id => worker on it, errors are told by game
+        let mut workers: HashMap<u32, usize> = HashMap::new();
+        loop {
+            let received = match rx.recv_timeout(HEARTBEAT) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => {
+                    reporter.on_heartbeat();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            // errors of a user count for the user and for the game
+            let received = match received {
+                Message::NoteUserErr(e, game_id, user) => {
+                    *user_errors.entry(user).or_insert(0) += 1;
+                    Message::NoteErr(e, Some(game_id))
+                }
+                received => received,
+            };
+            // handle messages
+            match received {
+                Message::DieErr(e) => {
+                    // stop every thread
+                    running.store(false, Ordering::SeqCst);
+                    // the game of the worker that failed is left unstable
+                    summary.interrupted += 1;
+                    summary.errors += 1;
+                    *summary.error_kinds.entry(e.category()).or_insert(0) += 1;
+                    result = Err(e);
+                }
+                Message::DieResult(game) => {
+                    regulated_in_row = 0;
+                    summary.balanced += 1;
+                    match config.moved(&game) {
+                        Some(previous) => reporter.on_game_changed(&game, previous),
+                        None => reporter.on_game_balanced(&game),
+                    }
+                }
+                Message::DieInterrupt => summary.interrupted += 1,
+                Message::DieRegulated(game) => {
+                    reporter.on_game_regulated(&game);
+                    regulated.push(game);
+                    regulated_in_row += 1;
+                    if regulated_in_row == config.max_regulated_in_row {
+                        // BGG refuses whatever is asked, the rest would wait in vain
+                        running.store(false, Ordering::SeqCst);
+                        if result.is_ok() {
+                            result = Err(AppError::Interrupted(format!(
+                                "{} games in a row had too many errors, run balance again later.",
+                                regulated_in_row
+                            )));
+                        }
+                    }
+                }
+                Message::NoteErr(e, game_id) => {
+                    summary.errors += 1;
+                    *summary.error_kinds.entry(e.category()).or_insert(0) += 1;
+                    if let Some(id) = game_id {
+                        *game_errors.entry(id).or_insert(0) += 1;
+                    }
+                    reporter.on_error(&e);
+                    if let Some(worker) = game_id.and_then(|id| workers.get(&id)) {
+                        reporter.on_worker_error(*worker);
+                    }
+                }
+                Message::NoteUserProgress(user) => {
+                    summary.users += 1;
+                    reporter.on_user_seen(&user);
+                }
+                Message::NoteGameProgress(worker, game) => {
+                    summary.requests += 1;
+                    names.entry(game.id).or_insert_with(|| game.name.clone());
+                    workers.insert(game.id, worker);
+                    reporter.on_game_page(worker, &game);
+                }
+                Message::NoteUsersCached(n) => summary.cached_users += n,
+                Message::NoteWorkerDone(worker) => {
+                    workers.retain(|_, w| *w != worker);
+                    reporter.on_worker_done(worker);
+                }
+                Message::NoteCapped(game) => {
+                    summary.capped += 1;
+                    reporter.on_game_capped(&game, config.on_page_cap);
+                }
+                Message::NoteRateLimited(delay) => reporter.on_rate_limited(delay),
+                Message::NoteDivergence { game, delta } => reporter.on_divergence(&game, delta),
+                // only reviewers send them, user errors were made game errors
+                Message::NoteUserReviewed { .. }
+                | Message::NoteUserSkipped(_)
+                | Message::NoteUserErr(..) => {}
             }
-            Message::DieInterrupt => finished += 1,
-            msg => progress(msg),
         }
-        if finished == job_size {
-            // every thread died somehow
+        pool.join();
+        // games left behind by ctrl+c or a fatal error
+        summary.remaining += queue.lock().unwrap().len() as u32;
+        if regulated.is_empty() {
             break;
         }
+        if !running.load(Ordering::SeqCst) {
+            summary.remaining += regulated.len() as u32;
+            break;
+        }
+        if retries_left == 0 {
+            summary.failed = regulated.len() as u32;
+            break;
+        }
+        // BGG had time to calm down while the queue drained
+        retries_left -= 1;
+        summary.retried += regulated.len() as u32;
+        backoff.store(backoff.load(Ordering::SeqCst) / 2, Ordering::SeqCst);
+        games = regulated;
+    }
+    summary.duration = clock.elapsed().as_secs_f64();
+    summary.rate = f64::from(limiter.granted()) * 60.0 / summary.duration;
+    let game_errors = game_errors
+        .into_iter()
+        .map(|(id, n)| (names.remove(&id).unwrap_or_else(|| id.to_string()), n));
+    summary.error_games = most_errors(game_errors);
+    summary.error_users = most_errors(user_errors);
+    conn.add_run(&started, &summary)?;
+    conn.meta_set(BACKOFF_LEVEL, &backoff.load(Ordering::SeqCst).to_string())?;
+    conn.meta_set(BACKOFF_SAVED, &Local::now().to_rfc3339())?;
+    conn.meta_set(
+        BALANCE_CONFIG,
+        &fields(&config, &BALANCE_FIELDS)?.to_string(),
+    )?;
+    reporter.on_finished(&summary);
+    if summary.failed > 0 {
+        result?;
+        return Err(AppError::Interrupted(format!(
+            "Regulation token stopped {} games.",
+            summary.failed
+        )));
     }
-    pool.join();
     result
 }
 
-pub fn config() -> Result<Config, Error> {
-    let conf = fs::read_to_string(CONFIG_FILE_NAME)
-        .with_context(|_| format!("Can't open: {}", CONFIG_FILE_NAME))?;
-    let conf = from_str(&conf)?;
-    Ok(conf)
+/// Backoff level saved by the previous run, lowered by one step
+/// for every BACKOFF_DECAY that has passed since then.
+fn restore_backoff(conn: &db::DbConn) -> Result<u32, AppError> {
+    let (level, saved) = match (conn.meta_get(BACKOFF_LEVEL)?, conn.meta_get(BACKOFF_SAVED)?) {
+        (Some(level), Some(saved)) => (level, saved),
+        _ => return Ok(0),
+    };
+    let level = level.parse::<u32>()?;
+    let saved = DateTime::parse_from_rfc3339(&saved)
+        .map_err(|e| AppError::Parse(format!("{} is not a date: {}", BACKOFF_SAVED, e)))?;
+    let elapsed = Local::now()
+        .signed_duration_since(saved)
+        .to_std()
+        .unwrap_or_else(|_| Duration::from_secs(0)); // clock went backwards
+    if elapsed > BACKOFF_MEMORY {
+        return Ok(0);
+    }
+    let steps = (elapsed.as_secs() / BACKOFF_DECAY.as_secs()) as u32;
+    Ok(level.saturating_sub(steps))
+}
+
+/// Share of the smaller camp of ratings 4 and below or 8 and above,
+/// from 0 to 0.5. Above 0.25 both camps hold over a quarter of the
+/// ratings, the game is polarizing rather than average.
+pub fn polarization(ratings: &[f64]) -> f64 {
+    if ratings.is_empty() {
+        return 0.0;
+    }
+    let total = ratings.len() as f64;
+    let low = ratings.iter().filter(|&&r| r <= 4.0).count() as f64;
+    let high = ratings.iter().filter(|&&r| r >= 8.0).count() as f64;
+    low.min(high) / total
+}
+
+/// Pages to ask for when `n` out of `last` pages are sampled: the
+/// first one, it tells how many there are, and a random page from
+/// each of n - 1 equal stretches of the rest. The same game and seed
+/// give the same pages, an interrupted sample goes on where it stopped.
+pub fn sample_plan(game_id: u32, seed: u32, n: u32, last: u32) -> Vec<u32> {
+    if n >= last {
+        return (1..=last).collect();
+    }
+    let mut rng = SplitMix((u64::from(game_id) << 32) | u64::from(seed));
+    let rest = u64::from(last - 1); // pages 2..=last
+    let stretches = u64::from(n.max(1) - 1);
+    let mut pages = vec![1];
+    for i in 0..stretches {
+        let from = 2 + rest * i / stretches;
+        let to = 2 + rest * (i + 1) / stretches;
+        pages.push((from + rng.next() % (to - from)) as u32);
+    }
+    pages
+}
+
+/// First pages that hold `fraction` of the votes BGG counts, at least
+/// one. None when that is every page of the game anyway or BGG votes
+/// are unknown. Without the total of comments the last page is guessed
+/// from the votes.
+pub fn fraction_plan(
+    paging: Paging,
+    fraction: f64,
+    votes: u32,
+    last: Option<u32>,
+) -> Option<Vec<u32>> {
+    if fraction >= 1.0 || votes == 0 {
+        return None;
+    }
+    let comments = (fraction * f64::from(votes)).ceil() as u32;
+    let pages = paging.total_pages(comments).max(1);
+    let last = last.unwrap_or_else(|| paging.total_pages(votes));
+    Some((1..=pages).collect()).filter(|_| pages < last)
+}
+
+/// Page arithmetic for the ratings of a game, BGG numbers pages from 1.
+#[derive(Debug, Clone, Copy)]
+pub struct Paging {
+    pub page_size: u32,
+}
+
+impl Paging {
+    /// Pages it takes to list `items` ratings.
+    pub fn total_pages(&self, items: u32) -> u32 {
+        items.div_ceil(self.page_size)
+    }
+
+    /// Ratings `page` holds when `items` ratings are listed, a full
+    /// page but for the last one, none past it.
+    pub fn expected_on(&self, page: u32, items: u32) -> u32 {
+        let before = u64::from(page.max(1) - 1) * u64::from(self.page_size);
+        (u64::from(items).saturating_sub(before)).min(u64::from(self.page_size)) as u32
+    }
+
+    /// Whether nothing is listed after `page`, which `returned` that
+    /// many ratings. Told by `items` when BGG counts them, else a page
+    /// short of a full one is the last.
+    pub fn is_last(&self, page: u32, returned: u32, items: Option<u32>) -> bool {
+        match items {
+            Some(items) => self.expected_on(page.saturating_add(1), items) == 0,
+            None => returned < self.page_size,
+        }
+    }
+}
+
+/// splitmix64, small and good enough to pick pages.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// A game BGG lists without votes or rating is most likely misparsed.
+fn plausible(game: &Game) -> bool {
+    game.bgg_num_votes > 0 && game.bgg_geek_rating > 0.0 && game.bgg_avg_rating > 0.0
+}
+
+/// BGG itself, or canned pages when offline_dir is set.
+fn bgg_client(config: &Config) -> Result<bgg::BggClient, AppError> {
+    let client = match &config.offline_dir {
+        Some(dir) => bgg::BggClient::fixtures(dir),
+        None => bgg::BggClient::http(
+            &config.base_url,
+            config.contact.as_deref(),
+            config.http_proxy.as_deref(),
+        )?,
+    };
+    Ok(client.strict(config.strict_parse))
+}
+
+/// Error of a command that needs app.config where there is none.
+pub fn missing_config() -> AppError {
+    let dir = std::env::current_dir()
+        .map(|d| d.display().to_string())
+        .unwrap_or_else(|_| String::from("current directory"));
+    AppError::Config(format!(
+        "{} not found in {}. Run `bgg_swing2 new` there first, pass --yes to create it with defaults or --use-defaults to run without it.",
+        CONFIG_FILE_NAME, dir
+    ))
+}
+
+/// Set once by --use-defaults.
+static USE_DEFAULTS: AtomicBool = AtomicBool::new(false);
+// the fallback is told about once, config() is called many times
+static DEFAULTS_TOLD: AtomicBool = AtomicBool::new(false);
+
+/// Lets config() run on defaults when app.config is missing.
+pub fn use_defaults() {
+    USE_DEFAULTS.store(true, Ordering::SeqCst);
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub fn uses_defaults() -> bool {
+    USE_DEFAULTS.load(Ordering::SeqCst)
+}
+
+/// Config of `new` with the default limit and threads.
+fn default_config() -> Config {
+    if !DEFAULTS_TOLD.swap(true, Ordering::SeqCst) {
+        runlog::complain(&format!(
+            "Warning: {} not found, running with defaults.",
+            CONFIG_FILE_NAME
+        ));
+    }
+    Config::new(
+        DEFAULT_LIMIT,
+        DEFAULT_ATTEMPTS,
+        DEFAULT_DELAY,
+        DEFAULT_THREADS,
+    )
+}
+
+/// A missing file is an error unless --use-defaults is given,
+/// a file that can't be read or parsed always is.
+pub fn config() -> Result<Config, AppError> {
+    let conf = match fs::read_to_string(CONFIG_FILE_NAME) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && uses_defaults() => {
+            return Ok(default_config())
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(missing_config()),
+        Err(e) => {
+            let e = format!("Can't open: {}: {}", CONFIG_FILE_NAME, e);
+            return Err(AppError::Config(e));
+        }
+        Ok(conf) => conf,
+    };
+    from_str(&conf)
+        .map_err(|e| AppError::Config(format!("Can't parse: {}: {}", CONFIG_FILE_NAME, e)))
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub limit: u32,     // number or user ratings for a game
     pub attempts: u32,  // number or errors that thread can handle before stop
     pub delay: u32,     // ms, delay increase after every failure
     pub threads: usize, // number of threads
+    #[serde(default)]
+    pub bounds: RatingBounds, // users with average out of bounds are not trusted
+    #[serde(default)]
+    pub inclusive_bounds: bool, // users exactly on the bounds are trusted
+    #[serde(default)]
+    pub prefer_json: bool, // user averages from the json api, the profile page if it fails
+    #[serde(default = "default_base_url")]
+    pub base_url: String, // where BGG lives, changed for mock servers
+    #[serde(default = "default_geeklist_template")]
+    pub geeklist_template: String, // comment of a geeklist item, see report::write_geeklist
+    #[serde(default = "default_change_threshold")]
+    pub change_threshold: f64, // smaller rating changes are not highlighted
+    #[serde(default)]
+    pub contact: Option<String>, // e-mail or url sent to BGG in the User-Agent
+    #[serde(default)]
+    pub offline_dir: Option<PathBuf>, // canned BGG pages to use instead of the site
+    #[serde(default)]
+    pub http_proxy: Option<String>, // e.g. http://proxy:3128, for both http and https
+    #[serde(default)]
+    pub seed: Option<u32>, // fixed order of games, reproducible runs with one thread
+    #[serde(default)]
+    pub strict_pull: bool, // leaves out pulled games without BGG votes or rating
+    #[serde(default)]
+    pub strict_parse: bool, // rows that would be skipped are errors, catches BGG format changes
+    #[serde(default = "default_retry_passes")]
+    pub retry_passes: u32, // extra passes over games that had too many errors
+    #[serde(default = "default_max_regulated_in_row")]
+    pub max_regulated_in_row: u32, // games in a row with too many errors stop the run, 0 never
+    #[serde(default = "default_divergence_warning")]
+    pub divergence_warning: f64, // larger gap to BGG average flags the game, 0 disables
+    #[serde(default)]
+    pub sample_pages: Option<u32>, // provisional rating from this many pages of a game
+    #[serde(default = "default_sample_fraction")]
+    pub sample_fraction: f64, // provisional rating from first pages with this share of BGG votes
+    #[serde(default)]
+    pub keep_all_ratings: bool, // also saves the average of untrusted ratings, see report::hype
+    #[serde(default = "default_polarizing_min_votes")]
+    pub polarizing_min_votes: u32, // fewer trusted ratings give no polarizing score
+    #[serde(default = "default_max_requests_per_minute")]
+    pub max_requests_per_minute: u32, // shared by all threads
+    #[serde(default)]
+    pub user_ttl_days: Option<u32>, // older user stats are fetched again, never if None
+    #[serde(default)]
+    pub min_account_age_years: Option<u32>, // younger accounts are not trusted
+    #[serde(default)]
+    pub on_user_fetch_failure: FetchFailure, // what to do when user page can't be downloaded
+    #[serde(default = "default_page_size")]
+    pub page_size: u32, // ratings asked for per page, 10 to 100
+    #[serde(default)]
+    pub max_pages_per_game: Option<u32>, // pages a game is read from at most, no cap if None
+    #[serde(default)]
+    pub on_page_cap: PageCap, // what happens to a game that reached max_pages_per_game
+    #[serde(default)]
+    pub search_extra_params: Option<String>, // url encoded, appended to BGG search, see bgg::SearchQuery
+    #[serde(default = "default_bayes_prior_votes")]
+    pub bayes_prior_votes: f64, // dummy votes of bayesian rating
+    #[serde(default = "default_bayes_prior_mean")]
+    pub bayes_prior_mean: f64, // rating of the dummy votes
+}
+
+fn default_base_url() -> String {
+    String::from(bgg::BASE_URL)
+}
+
+fn default_bayes_prior_votes() -> f64 {
+    100.0
+}
+
+fn default_bayes_prior_mean() -> f64 {
+    5.5
+}
+
+fn default_max_requests_per_minute() -> u32 {
+    DEFAULT_REQUESTS_PER_MINUTE
+}
+
+fn default_change_threshold() -> f64 {
+    0.05
+}
+
+fn default_retry_passes() -> u32 {
+    1
+}
+
+fn default_max_regulated_in_row() -> u32 {
+    10
+}
+
+fn default_divergence_warning() -> f64 {
+    1.5
+}
+
+fn default_page_size() -> u32 {
+    bgg::USER_PAGE_SIZE
+}
+
+fn default_sample_fraction() -> f64 {
+    1.0
+}
+
+fn default_polarizing_min_votes() -> u32 {
+    30
+}
+
+fn default_geeklist_template() -> String {
+    String::from(
+        "Rated {rating} by {votes} trusted users, {swing} against BGG average {bgg_rating}.",
+    )
 }
 
 impl Config {
-    fn new(limit: u32, attempts: u32, delay: u32, threads: usize) -> Config {
+    pub fn new(limit: u32, attempts: u32, delay: u32, threads: usize) -> Config {
         Config {
             limit,
             attempts,
             delay,
             threads,
+            bounds: RatingBounds::default(),
+            inclusive_bounds: false,
+            prefer_json: false,
+            base_url: default_base_url(),
+            geeklist_template: default_geeklist_template(),
+            change_threshold: default_change_threshold(),
+            contact: None,
+            offline_dir: None,
+            http_proxy: None,
+            seed: None,
+            strict_pull: false,
+            strict_parse: false,
+            retry_passes: default_retry_passes(),
+            max_regulated_in_row: default_max_regulated_in_row(),
+            divergence_warning: default_divergence_warning(),
+            sample_pages: None,
+            sample_fraction: default_sample_fraction(),
+            keep_all_ratings: false,
+            polarizing_min_votes: default_polarizing_min_votes(),
+            max_requests_per_minute: default_max_requests_per_minute(),
+            user_ttl_days: None,
+            min_account_age_years: None,
+            on_user_fetch_failure: FetchFailure::default(),
+            page_size: default_page_size(),
+            max_pages_per_game: None,
+            on_page_cap: PageCap::default(),
+            search_extra_params: None,
+            bayes_prior_votes: default_bayes_prior_votes(),
+            bayes_prior_mean: default_bayes_prior_mean(),
+        }
+    }
+
+    /// Whether balance may rate games from some of their pages.
+    pub fn samples(&self) -> bool {
+        self.sample_pages.is_some()
+            || self.sample_fraction < 1.0
+            || (self.max_pages_per_game.is_some() && self.on_page_cap == PageCap::Finish)
+    }
+
+    pub fn paging(&self) -> Paging {
+        Paging {
+            page_size: self.page_size,
+        }
+    }
+
+    /// Search pull asks BGG for, with search_extra_params.
+    pub fn search_query(&self) -> Result<bgg::SearchQuery, AppError> {
+        let query = bgg::SearchQuery::new(self.limit);
+        match &self.search_extra_params {
+            Some(extra) => query.extra(extra),
+            None => Ok(query),
+        }
+    }
+
+    /// Whether the game has been read from max_pages_per_game pages.
+    fn page_cap_reached(&self, game: &Game) -> bool {
+        self.max_pages_per_game.is_some_and(|max| game.page > max)
+    }
+
+    /// Rating the game had before the last pull when it has moved by
+    /// change_threshold or more since. Games new to the list have none.
+    fn moved(&self, game: &Game) -> Option<f64> {
+        game.previous_rating
+            .filter(|previous| (game.rating - previous).abs() >= self.change_threshold)
+    }
+
+    /// Difference between the balanced rating and BGG average when it is
+    /// suspiciously large. Games without votes on either side are skipped.
+    fn divergence(&self, game: &Game) -> Option<f64> {
+        if self.divergence_warning <= 0.0 || game.votes == 0 || game.bgg_avg_rating == 0.0 {
+            return None;
+        }
+        let delta = game.rating - game.bgg_avg_rating;
+        Some(delta).filter(|d| d.abs() > self.divergence_warning)
+    }
+
+    /// Whether a stable game with this rating is flagged for a look.
+    fn flags(&self, game: &Game) -> bool {
+        self.divergence(game).is_some()
+    }
+
+    /// Polarization of the ratings, None when there are too few
+    /// of them for the score to mean anything.
+    fn polarizing(&self, ratings: &[f64]) -> Option<f64> {
+        if ratings.is_empty() || ratings.len() < self.polarizing_min_votes as usize {
+            return None;
+        }
+        Some(polarization(ratings))
+    }
+
+    fn trusts(&self, rating: f64, year: Option<u32>) -> bool {
+        let in_bounds = if self.inclusive_bounds {
+            self.bounds.contains_inclusive(rating)
+        } else {
+            self.bounds.contains(rating)
+        };
+        in_bounds && !self.too_young(year)
+    }
+
+    /// Accounts of unknown age pass.
+    fn too_young(&self, year: Option<u32>) -> bool {
+        match (self.min_account_age_years, year) {
+            (Some(min), Some(year)) => i64::from(now().year()) - i64::from(year) < i64::from(min),
+            _ => false,
         }
     }
 }
 
+impl Config {
+    /// Cross-field sanity checks, run before any network traffic.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if self.attempts == 0 {
+            issues.push(ConfigIssue::error("attempts must be above 0"));
+        }
+        if self.threads == 0 {
+            issues.push(ConfigIssue::error("threads must be above 0"));
+        }
+        if self.limit < MIN_LIMIT {
+            issues.push(ConfigIssue::error(&format!(
+                "limit must be at least {}, got {}",
+                MIN_LIMIT, self.limit
+            )));
+        }
+        if !(bgg::MIN_USER_PAGE_SIZE..=bgg::USER_PAGE_SIZE).contains(&self.page_size) {
+            issues.push(ConfigIssue::error(&format!(
+                "page_size must be from {} to {}, got {}",
+                bgg::MIN_USER_PAGE_SIZE,
+                bgg::USER_PAGE_SIZE,
+                self.page_size
+            )));
+        }
+        if let Err(e) = self.search_query() {
+            issues.push(ConfigIssue::error(&e.to_string()));
+        }
+        if self.max_pages_per_game == Some(0) {
+            issues.push(ConfigIssue::error("max_pages_per_game must be above 0"));
+        }
+        if self.sample_pages == Some(0) {
+            issues.push(ConfigIssue::error("sample_pages must be above 0"));
+        }
+        if !(self.sample_fraction > 0.0 && self.sample_fraction <= 1.0) {
+            issues.push(ConfigIssue::error(&format!(
+                "sample_fraction must be above 0 and at most 1, got {}",
+                self.sample_fraction
+            )));
+        }
+        if self.sample_pages.is_some() && self.sample_fraction < 1.0 {
+            issues.push(ConfigIssue::notice(
+                "sample_pages is set, sample_fraction is ignored",
+            ));
+        }
+        if self.max_requests_per_minute == 0 {
+            issues.push(ConfigIssue::error(
+                "max_requests_per_minute must be above 0",
+            ));
+        }
+        if self.contact.as_deref().is_none_or(|c| c.trim().is_empty()) {
+            issues.push(ConfigIssue::notice(
+                "contact is empty, BGG asks API users to identify themselves",
+            ));
+        }
+        if self.seed.is_some() && self.threads > 1 {
+            issues.push(ConfigIssue::notice(
+                "seed fixes the order of games, but with several threads \
+                 the order of requests still varies",
+            ));
+        }
+        let rate = f64::from(self.max_requests_per_minute) / 60.0;
+        if rate > POLITE_RATE {
+            issues.push(ConfigIssue::warning(&format!(
+                "max_requests_per_minute allows {:.1} requests per second, \
+                 BGG is likely to ban you above {}",
+                rate, POLITE_RATE
+            )));
+        }
+        issues
+    }
+}
+
+/// Policy for games that reached max_pages_per_game.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PageCap {
+    /// Makes the game stable with the rating of the pages read,
+    /// marked as sampled.
+    #[default]
+    Finish,
+    /// Leaves the game unstable, a higher cap lets it go on.
+    Leave,
+}
+
+/// Policy for users whose average rating BGG didn't give.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchFailure {
+    /// Asks for the whole page again later.
+    #[default]
+    Retry,
+    /// Leaves the user out of this page.
+    SkipUser,
+    /// Saves the user as untrusted.
+    AssumeUntrusted,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Severity {
+    Notice,  // printed only
+    Warning, // needs confirmation to proceed
+    Error,   // never proceed
+}
+
+#[derive(Debug)]
+pub struct ConfigIssue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn error(message: &str) -> ConfigIssue {
+        ConfigIssue {
+            severity: Severity::Error,
+            message: String::from(message),
+        }
+    }
+    fn notice(message: &str) -> ConfigIssue {
+        ConfigIssue {
+            severity: Severity::Notice,
+            message: String::from(message),
+        }
+    }
+    fn warning(message: &str) -> ConfigIssue {
+        ConfigIssue {
+            severity: Severity::Warning,
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Notice => write!(f, "Notice: {}", self.message),
+            Severity::Warning => write!(f, "Warning: {}", self.message),
+            Severity::Error => write!(f, "Error: {}", self.message),
+        }
+    }
+}
+
+/// Time limit written like `2h`, `90m`, `45s`, `1h30m` or `180d`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeLimit(pub Duration);
+
+impl FromStr for TimeLimit {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<TimeLimit, AppError> {
+        let mut secs: u64 = 0;
+        let mut number = String::new();
+        for c in s.trim().chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let unit = match c {
+                'd' => 86400,
+                'h' => 3600,
+                'm' => 60,
+                's' => 1,
+                _ => {
+                    let e = format!("Unknown unit {} in {}, use d, h, m or s", c, s);
+                    return Err(AppError::Config(e));
+                }
+            };
+            if number.is_empty() {
+                let e = format!("Duration must look like 2h, 90m or 1h30m, got {}", s);
+                return Err(AppError::Config(e));
+            }
+            // only digits are left, a number that doesn't parse is too big
+            secs = number
+                .parse::<u64>()
+                .ok()
+                .and_then(|n| n.checked_mul(unit))
+                .and_then(|part| part.checked_add(secs))
+                .ok_or_else(|| AppError::Config(format!("Duration {} is too long", s)))?;
+            number.clear();
+        }
+        if !number.is_empty() {
+            let e = format!("Duration must look like 2h, 90m or 1h30m, got {}", s);
+            return Err(AppError::Config(e));
+        }
+        if secs == 0 {
+            let e = format!("Duration must be longer than zero, got {}", s);
+            return Err(AppError::Config(e));
+        }
+        Ok(TimeLimit(Duration::from_secs(secs)))
+    }
+}
+
+impl fmt::Display for TimeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        if secs >= 3600 {
+            write!(f, "{}h{:02}m", secs / 3600, secs % 3600 / 60)
+        } else if secs >= 60 {
+            write!(f, "{}m{:02}s", secs / 60, secs % 60)
+        } else {
+            write!(f, "{}s", secs)
+        }
+    }
+}
+
+/// Range of average ratings of a trusted user, written as `2.0..8.0`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RatingBounds {
+    lower: f64,
+    upper: f64,
+}
+
+impl RatingBounds {
+    pub fn new(lower: f64, upper: f64) -> Result<RatingBounds, AppError> {
+        if lower.partial_cmp(&upper) != Some(std::cmp::Ordering::Less) {
+            return Err(AppError::Config(format!(
+                "Lower bound {} must be less than upper bound {}",
+                lower, upper
+            )));
+        }
+        Ok(RatingBounds { lower, upper })
+    }
+    /// Both ends are excluded.
+    pub fn contains(&self, rating: f64) -> bool {
+        self.lower < rating && rating < self.upper
+    }
+    /// Both ends are included.
+    pub fn contains_inclusive(&self, rating: f64) -> bool {
+        self.lower <= rating && rating <= self.upper
+    }
+}
+
+impl Default for RatingBounds {
+    fn default() -> RatingBounds {
+        RatingBounds {
+            lower: LOWER_BOUND,
+            upper: UPPER_BOUND,
+        }
+    }
+}
+
+impl FromStr for RatingBounds {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<RatingBounds, AppError> {
+        let (lower, upper) = match s.find("..") {
+            Some(i) => (&s[..i], &s[i + 2..]),
+            None => {
+                let e = format!("Bounds must look like 2.0..8.0, got {}", s);
+                return Err(AppError::Config(e));
+            }
+        };
+        let lower = lower
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| AppError::Config(format!("Can't parse lower bound: {}", lower)))?;
+        let upper = upper
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| AppError::Config(format!("Can't parse upper bound: {}", upper)))?;
+        RatingBounds::new(lower, upper)
+    }
+}
+
+impl fmt::Display for RatingBounds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}..{:?}", self.lower, self.upper)
+    }
+}
+
+impl serde::Serialize for RatingBounds {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for RatingBounds {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RatingBounds, D::Error> {
+        let s: String = serde::Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
 #[derive(Debug)]
 pub enum Message {
-    DieErr(Error),   // thread must stop after that message
-    DieResult(Game), // thread must stop after that message
-    DieInterrupt,    // thread must stop after that message
-    NoteErr(Error),
+    DieErr(AppError),                 // thread must stop after that message
+    DieResult(Game),                  // thread must stop after that message
+    DieInterrupt,                     // thread must stop after that message
+    DieRegulated(Game),               // too many errors on the game, thread must stop
+    NoteErr(AppError, Option<u32>),   // game id, if error belongs to a game
+    NoteUserErr(AppError, u32, User), // stats of the user failed on the game
     NoteUserProgress(User),
-    NoteGameProgress(Game),
+    NoteGameProgress(usize, Game), // worker id, game before its next page
+    NoteRateLimited(Duration),
+    NoteUsersCached(u32),  // users on a page that were seen before
+    NoteWorkerDone(usize), // worker id, it takes no more games
+    NoteCapped(Game),      // game reached max_pages_per_game
+    NoteDivergence {
+        game: Game,
+        delta: f64,
+    }, // rating minus BGG average
+    NoteUserReviewed {
+        user: User,
+        flipped: bool,
+        games: usize,
+    }, // games recounted
+    NoteUserSkipped(User), // failed USER_TRIES times
+}
+
+/// The TOP_ERROR_GAMES names with most errors, most first,
+/// names break ties.
+fn most_errors(errors: impl IntoIterator<Item = (String, u32)>) -> Vec<(String, u32)> {
+    let mut errors: Vec<(String, u32)> = errors.into_iter().collect();
+    errors.sort_by(|(a, n), (b, m)| m.cmp(n).then_with(|| a.cmp(b)));
+    errors.truncate(TOP_ERROR_GAMES);
+    errors
+}
+
+/// Totals of a single balancing run.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StabilizeSummary {
+    pub users: u32,                               // users seen for the first time
+    pub cached_users: u32,                        // users already known from db
+    pub balanced: u32,                            // games that became stable
+    pub errors: u32,                              // recoverable and fatal errors
+    pub requests: u32,                            // game pages requested
+    pub interrupted: u32,                         // games stopped by ctrl+c or fatal error
+    pub retried: u32,                             // games given another pass after too many errors
+    pub failed: u32,                              // games that had too many errors on every pass
+    pub remaining: u32,                           // games never picked up
+    pub capped: u32,                              // games that reached max_pages_per_game
+    pub error_kinds: BTreeMap<&'static str, u32>, // errors by AppError::category
+    pub error_games: Vec<(String, u32)>,          // games with most errors
+    pub error_users: Vec<(User, u32)>,            // users whose stats failed most
+    pub rate: f64,                                // average requests per minute
+    pub duration: f64,                            // seconds
+}
+
+/// Totals of a review that asked BGG about users again.
+#[derive(Debug, Default, Serialize)]
+pub struct ReviewSummary {
+    pub reviewed: u32,  // users whose stats were fetched again
+    pub flipped: u32,   // users whose trust changed
+    pub games: u32,     // games recounted
+    pub errors: u32,    // failed requests, their users are retried
+    pub skipped: u32,   // users that failed USER_TRIES times
+    pub requests: u32,  // user pages asked for
+    pub remaining: u32, // users left for the next review
+    pub duration: f64,  // seconds
 }
 
 struct RegulationToken {
     limit: u32,
     delay_step: Duration,
     i: u32,
+    shared: Arc<AtomicU32>, // level of the run, see ease and harden
+    limiter: Arc<RateLimiter>,
+    running: Arc<AtomicBool>,
 }
 
 impl RegulationToken {
-    fn new(limit: u32, delay_step: Duration) -> RegulationToken {
+    /// Starts from the shared level, but never stopped.
+    fn new(
+        limit: u32,
+        delay_step: Duration,
+        shared: Arc<AtomicU32>,
+        limiter: Arc<RateLimiter>,
+        running: Arc<AtomicBool>,
+    ) -> RegulationToken {
+        let i = shared.load(Ordering::SeqCst).min(limit.saturating_sub(1));
         RegulationToken {
             limit,
             delay_step,
-            i: 0,
+            i,
+            shared,
+            limiter,
+            running,
         }
     }
+    /// Waits for the shared request budget, false if stopped meanwhile.
+    fn acquire(&self) -> bool {
+        profile::time(Phase::Wait, || self.limiter.acquire(&self.running))
+    }
     fn delay(&self) -> Duration {
-        self.delay_step * self.i
+        self.delay_step
+            .checked_mul(self.i)
+            .map_or(MAX_DELAY, |d| d.min(MAX_DELAY))
     }
     fn is_stopped(&self) -> bool {
         self.i >= self.limit
     }
-    fn ease(&mut self) -> () {
+    /// Success lowers the shared level by one step, whichever
+    /// thread raised it.
+    fn ease(&mut self) {
         if !self.is_stopped() && self.i != 0 {
             self.i -= 1;
+            let _ = self
+                .shared
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |s| s.checked_sub(1));
         }
     }
-    fn harden(&mut self) -> () {
-        self.i += 1;
+    /// Failure raises the shared level to this token's, a thread
+    /// that knows less of BGG's mood can't lower it.
+    fn harden(&mut self) {
+        // stopped token stays at the limit
+        self.i = self.i.saturating_add(1).min(self.limit);
+        self.shared.fetch_max(self.i, Ordering::SeqCst);
     }
 }
 
-struct Avg {
-    n: u32,
-    val: f64,
-}
-
-impl Avg {
-    fn new(n: u32, val: f64) -> Avg {
-        Avg { n, val }
-    }
-    fn add(&mut self, nmbr: f64) -> () {
-        self.n += 1;
-        self.val = (nmbr + (self.n - 1) as f64 * self.val) / self.n as f64;
-    }
-    fn result(&self) -> f64 {
-        self.val
-    }
-    fn n(&self) -> u32 {
-        self.n
-    }
-}
+#[cfg(test)]
+mod tests;
@@ -1,13 +1,115 @@
-use crate::lib::{Game, User};
-use chrono::Local;
-use failure::{bail, Error};
-use rusqlite::types::ToSql;
-use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use crate::bgg;
+use crate::core::{ReviewSummary, StabilizeSummary};
+use crate::error::AppError;
+use crate::runlog;
+use bgg_swing2::{Game, User};
+use chrono::{DateTime, Local};
+use rusqlite::types::{FromSql, ToSql, Value};
+use rusqlite::{Connection, DatabaseName, OpenFlags, Row, NO_PARAMS};
+use serde_derive::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub mod consistency;
 
 const DB_FILE_NAME: &str = "top.db";
 
-pub fn initialize() -> Result<(), Error> {
-    let conn = Connection::open(DB_FILE_NAME)?;
+// Set once at startup from the command line, top.db otherwise.
+static DB_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// Tests give every case a db of its own, see testing::TestDb.
+#[cfg(test)]
+pub static TEST_PATH: Mutex<Option<&'static Path>> = Mutex::new(None);
+
+pub fn db_path() -> &'static Path {
+    #[cfg(test)]
+    {
+        if let Some(path) = *TEST_PATH.lock().unwrap_or_else(|e| e.into_inner()) {
+            return path;
+        }
+    }
+    DB_PATH
+        .get()
+        .map(PathBuf::as_path)
+        .unwrap_or_else(|| Path::new(DB_FILE_NAME))
+}
+
+/// Points all connections to `input` instead of top.db. With `output`
+/// the input is copied there first and only the copy gets written to.
+pub fn select(
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    overwrite: bool,
+) -> Result<(), AppError> {
+    let input = input.unwrap_or_else(|| PathBuf::from(DB_FILE_NAME));
+    let path = match output {
+        Some(output) => {
+            if output.exists() && !overwrite {
+                return Err(AppError::Config(format!(
+                    "{} already exists. Pass --force to overwrite it.",
+                    output.display()
+                )));
+            }
+            if !input.exists() {
+                let e = format!("{} not found, nothing to copy.", input.display());
+                return Err(AppError::Config(e));
+            }
+            copy_db(&input, &output)?;
+            output
+        }
+        None => input,
+    };
+    if DB_PATH.set(path).is_err() {
+        let e = String::from("Database file is already selected.");
+        return Err(AppError::Config(e));
+    }
+    Ok(())
+}
+
+/// Copies the db at `input` to `output` page by page through sqlite,
+/// so the copy is consistent while balance writes and has what is
+/// still in the wal file.
+fn copy_db(input: &Path, output: &Path) -> Result<(), AppError> {
+    let source = Connection::open_with_flags(input, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    source.backup(DatabaseName::Main, output, None)?;
+    Ok(())
+}
+
+/// Opening a missing file would silently create an empty db,
+/// tell the user how to get a proper one instead.
+pub fn ensure_exists() -> Result<(), AppError> {
+    if !db_path().exists() {
+        return Err(AppError::Config(format!(
+            "{} not found. Run `new` first to create initial structure.",
+            db_path().display()
+        )));
+    }
+    Ok(())
+}
+
+/// Version of the tables initialize makes, kept in `pragma user_version`.
+/// Bump it with every change below, files last opened by older builds
+/// show a lower number and 0 is anything before versioning. Read only
+/// connections refuse a lower number, they can't upgrade.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Brings tables of an existing db up to date.
+pub fn upgrade() -> Result<(), AppError> {
+    ensure_exists()?;
+    initialize()
+}
+
+pub fn initialize() -> Result<(), AppError> {
+    let conn = Connection::open(db_path())?;
+    // readers don't wait for a running balance, the mode sticks to the file
+    conn.query_row("pragma journal_mode = wal", NO_PARAMS, |r| -> String {
+        r.get(0)
+    })?;
     // create db file
     conn.execute(
         "create table if not exists games (
@@ -20,7 +122,8 @@ pub fn initialize() -> Result<(), Error> {
             bgg_num_votes integer,
             bgg_geek_rating real,
             bgg_avg_rating real,
-            page integer
+            page integer,
+            weight real
          )",
         NO_PARAMS,
     )?;
@@ -32,109 +135,1538 @@ pub fn initialize() -> Result<(), Error> {
          )",
         NO_PARAMS,
     )?;
+    conn.execute(
+        "create table if not exists game_users (
+            game_id integer,
+            name text,
+            rating real,
+            primary key (game_id, name)
+         )",
+        NO_PARAMS,
+    )?;
+    // ratings of a user, for recounts and the users command
+    conn.execute(
+        "create index if not exists game_users_name on game_users (name)",
+        NO_PARAMS,
+    )?;
+    conn.execute(
+        "create table if not exists meta (
+            key text primary key,
+            value text
+         )",
+        NO_PARAMS,
+    )?;
+    conn.execute(
+        "create table if not exists runs (
+            id integer primary key,
+            started datetime,
+            finished datetime,
+            balanced integer,
+            errors integer,
+            requests integer,
+            interrupted integer,
+            remaining integer,
+            new_users integer,
+            cached_users integer
+         )",
+        NO_PARAMS,
+    )?;
+    // columns added after the first release
+    add_column(&conn, "games", "weight", "real")?;
+    add_column(&conn, "games", "previous_rating", "real")?;
+    add_column(&conn, "games", "tier", "integer")?;
+    add_column(&conn, "games", "balancing_started_at", "datetime")?;
+    add_column(&conn, "users", "average", "real")?;
+    add_column(&conn, "users", "year_registered", "integer")?;
+    // trust set by hand with set-user-trust
+    add_column(&conn, "users", "manual", "integer")?;
+    add_column(&conn, "games", "stabilized_at", "datetime")?;
+    // review or balance, null in older files means balance
+    add_column(&conn, "runs", "kind", "text")?;
+    add_column(&conn, "runs", "reviewed", "integer")?;
+    add_column(&conn, "runs", "flipped", "integer")?;
+    add_column(&conn, "games", "flagged", "integer")?;
+    add_column(&conn, "games", "watched", "integer")?;
+    add_column(&conn, "games", "polarizing", "real")?;
+    // who put the game into the list: search, manual, collection or
+    // geeklist; null in older files means search
+    add_column(&conn, "games", "source", "text")?;
+    add_column(&conn, "games", "notes", "text")?;
+    add_column(&conn, "games", "sampled", "integer")?;
+    add_column(&conn, "games", "year", "integer")?;
+    // average of untrusted ratings too, see Config::keep_all_ratings
+    add_column(&conn, "games", "all_rating", "real")?;
+    add_column(&conn, "games", "all_votes", "integer")?;
+    // ratings per page the page counter counts, null is bgg::USER_PAGE_SIZE
+    add_column(&conn, "games", "page_size", "integer")?;
+    // games as they were when the snapshot was taken, enough
+    // to render any report from them
+    conn.execute(
+        "create table if not exists snapshots (
+            id integer primary key,
+            taken datetime,
+            name text
+         )",
+        NO_PARAMS,
+    )?;
+    conn.execute(
+        "create table if not exists snapshot_games (
+            snapshot_id integer,
+            id integer,
+            name text not null,
+            rating real,
+            num_votes integer,
+            bgg_num_votes integer,
+            bgg_geek_rating real,
+            bgg_avg_rating real,
+            weight real,
+            previous_rating real,
+            stable integer,
+            flagged integer,
+            polarizing real,
+            tier integer,
+            mean_sq real,
+            primary key (snapshot_id, id)
+         )",
+        NO_PARAMS,
+    )?;
+    add_column(&conn, "snapshot_games", "sampled", "integer")?;
+    add_column(&conn, "snapshot_games", "year", "integer")?;
+    add_column(&conn, "snapshot_games", "all_rating", "real")?;
+    add_column(&conn, "snapshot_games", "all_votes", "integer")?;
+    // pages a sampled game was rated from, see core::sample_plan
+    conn.execute(
+        "create table if not exists sampled_pages (
+            game_id integer,
+            page integer,
+            primary key (game_id, page)
+         )",
+        NO_PARAMS,
+    )?;
+    conn.execute(
+        "create table if not exists game_spans (
+            game_id integer,
+            started datetime,
+            seconds real,
+            pages integer
+         )",
+        NO_PARAMS,
+    )?;
+    // users a span asked bgg about and users it found in the db
+    add_column(&conn, "game_spans", "new_users", "integer")?;
+    add_column(&conn, "game_spans", "cached_users", "integer")?;
+    conn.execute_batch(&format!("pragma user_version = {}", SCHEMA_VERSION))?;
+    Ok(())
+}
+
+/// Adds a column that older db files lack.
+fn add_column(conn: &Connection, table: &str, column: &str, kind: &str) -> Result<(), AppError> {
+    let mut stmt = conn.prepare(&format!("pragma table_info({})", table))?;
+    let names = stmt.query_map(NO_PARAMS, |r| -> String { r.get(1) })?;
+    for name in names {
+        if name? == column {
+            return Ok(());
+        }
+    }
+    conn.execute(
+        &format!("alter table {} add column {} {}", table, column, kind),
+        NO_PARAMS,
+    )?;
+    Ok(())
+}
+
+/// Removes games from `source` that are not in `kept`, with their
+/// ratings and timings. Games from other sources stay.
+pub fn retire_games_from(source: &str, kept: &HashSet<u32>) -> Result<(), AppError> {
+    let mut conn = Connection::open(db_path())?;
+    let tx = conn.transaction()?;
+    let retired: Vec<u32> = game_ids_from(&tx, source)?
+        .into_iter()
+        .filter(|id| !kept.contains(id))
+        .collect();
+    delete_games(&tx, &retired)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Ids of the games that came from `source`.
+fn game_ids_from(conn: &Connection, source: &str) -> Result<HashSet<u32>, AppError> {
+    const COLUMNS: &str = "id";
+    let mut stmt = conn.prepare(&format!(
+        "select {} from games where coalesce(source, 'search') = ?1",
+        COLUMNS
+    ))?;
+    let iter = stmt.query_and_then(&[source], |r| -> Result<u32, AppError> {
+        Fields::new(r, "games", COLUMNS).get(0)
+    })?;
+    iter.collect()
+}
+
+/// Deletes games with their ratings and timings.
+fn delete_games(conn: &Connection, ids: &[u32]) -> Result<(), AppError> {
+    for table in ["game_users", "game_spans", "sampled_pages"] {
+        let mut stmt = conn.prepare_cached(&format!("delete from {} where game_id = ?1", table))?;
+        for id in ids {
+            stmt.execute(&[id])?;
+        }
+    }
+    let mut stmt = conn.prepare_cached("delete from games where id = ?1")?;
+    for id in ids {
+        stmt.execute(&[id])?;
+    }
     Ok(())
 }
 
-pub fn drop_all_games() -> Result<(), Error> {
-    let conn = Connection::open(DB_FILE_NAME)?;
-    conn.execute("delete from games", NO_PARAMS)?;
+/// Ratings of stable games, remembered before they get pulled again.
+pub fn get_stable_ratings() -> Result<HashMap<u32, f64>, AppError> {
+    let conn = Connection::open(db_path())?;
+    const COLUMNS: &str = "id, rating";
+    let mut stmt = conn.prepare(&format!("select {} from games where stable", COLUMNS))?;
+    let iter = stmt.query_and_then(NO_PARAMS, |r| -> Result<(u32, f64), AppError> {
+        let f = Fields::new(r, "games", COLUMNS);
+        Ok((f.get(0)?, f.get(1)?))
+    })?;
+    let mut ratings = HashMap::new();
+    for pair in iter {
+        let (id, rating) = pair?;
+        ratings.insert(id, rating);
+    }
+    Ok(ratings)
+}
+
+/// What the user set on a game by hand, kept over a pull.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalState {
+    pub notes: Option<String>,
+    pub watched: bool,
+    pub flagged: bool,
+}
+
+/// Local state of games from `source`. Games with nothing set are
+/// left out.
+#[cfg(test)]
+pub fn get_local_state_from(source: &str) -> Result<HashMap<u32, LocalState>, AppError> {
+    let conn = Connection::open(db_path())?;
+    local_state_from(&conn, source)
+}
+
+fn local_state_from(conn: &Connection, source: &str) -> Result<HashMap<u32, LocalState>, AppError> {
+    const COLUMNS: &str = "id, notes, coalesce(watched, 0), coalesce(flagged, 0)";
+    let mut stmt = conn.prepare(&format!(
+        "select {} from games
+        where (notes is not null or watched or flagged) and coalesce(source, 'search') = ?1",
+        COLUMNS
+    ))?;
+    let iter = stmt.query_and_then(&[source], |r| -> Result<(u32, LocalState), AppError> {
+        let f = Fields::new(r, "games", COLUMNS);
+        let state = LocalState {
+            notes: f.get(1)?,
+            watched: f.get(2)?,
+            flagged: f.get(3)?,
+        };
+        Ok((f.get(0)?, state))
+    })?;
+    let mut states = HashMap::new();
+    for pair in iter {
+        let (id, state) = pair?;
+        states.insert(id, state);
+    }
+    Ok(states)
+}
+
+/// Games `source` pulls again start over, their ratings, timings and
+/// progress go, what the user set on them stays. Games new to the db
+/// are added as by add_games, all in one transaction.
+pub fn renew_games(games: Vec<Game>, tier: u32, source: &str) -> Result<(), AppError> {
+    let mut conn = Connection::open(db_path())?;
+    let tx = conn.transaction()?;
+    let states = local_state_from(&tx, source)?;
+    let known = game_ids_from(&tx, source)?;
+    let renewed: Vec<u32> = games
+        .iter()
+        .map(|g| g.id)
+        .filter(|id| known.contains(id))
+        .collect();
+    delete_games(&tx, &renewed)?;
+    insert_games(&tx, games, tier, source)?;
+    for id in &renewed {
+        if let Some(state) = states.get(id) {
+            tx.execute(
+                "update games set notes = ?1, watched = ?2, flagged = ?3 where id = ?4",
+                &[
+                    &state.notes as &dyn ToSql,
+                    &state.watched,
+                    &state.flagged,
+                    id,
+                ],
+            )?;
+        }
+    }
+    tx.commit()?;
     Ok(())
 }
 
-pub fn add_games(games: Vec<Game>) -> Result<(), Error> {
-    let mut conn = Connection::open(DB_FILE_NAME)?;
+/// Games already in db keep their progress and local state, their
+/// tier is raised if `source` pulls them again with a higher vote
+/// limit. Games from other sources are left as they are.
+pub fn add_games(games: Vec<Game>, tier: u32, source: &str) -> Result<(), AppError> {
+    let mut conn = Connection::open(db_path())?;
     let tx = conn.transaction()?;
+    insert_games(&tx, games, tier, source)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn insert_games(
+    conn: &Connection,
+    games: Vec<Game>,
+    tier: u32,
+    source: &str,
+) -> Result<(), AppError> {
     let now = Local::now();
     for game in games {
-        tx.execute("insert into games (id, name, updated, stable, bgg_num_votes, bgg_geek_rating, bgg_avg_rating, page, num_votes, rating) 
-        values (?1, ?2, ?3, 0, ?4, ?5, ?6, 1, 0, 0)",
-            &[&game.id as &ToSql, &game.name, &now.to_string(), &game.bgg_num_votes, &game.bgg_geek_rating, &game.bgg_avg_rating])?;
+        conn.execute("insert or ignore into games (id, name, updated, stable, bgg_num_votes, bgg_geek_rating, bgg_avg_rating, page, num_votes, rating, previous_rating, tier, source, year) 
+        values (?1, ?2, ?3, 0, ?4, ?5, ?6, 1, 0, 0, ?7, ?8, ?9, ?10)",
+            &[&game.id as &dyn ToSql, &game.name, &now.to_string(), &game.bgg_num_votes, &game.bgg_geek_rating, &game.bgg_avg_rating, &game.previous_rating, &tier, &source, &game.year])?;
+        // games pulled before years were read get theirs
+        conn.execute(
+            "update games set year = ?1 where id = ?2 and year is null",
+            &[&game.year as &dyn ToSql, &game.id],
+        )?;
+        conn.execute(
+            "update games set tier = ?1
+            where id = ?2 and (tier is null or tier < ?1) and coalesce(source, 'search') = ?3",
+            &[&tier as &dyn ToSql, &game.id, &source],
+        )?;
     }
-    tx.commit()?;
     Ok(())
 }
 
-pub fn get_unstable_games() -> Result<Vec<Game>, Error> {
-    let conn = Connection::open(DB_FILE_NAME)?;
-    let mut stmt = conn.prepare(
-        "select id, name, page, num_votes, rating from games where not stable order by random()",
+/// Sample standard deviation from the mean of squares, None when
+/// ratings were not stored or there are less than two of them.
+fn sample_sd(n: u32, mean: f64, mean_sq: Option<f64>) -> Option<f64> {
+    let mean_sq = mean_sq?;
+    if n < 2 {
+        return None;
+    }
+    let n = f64::from(n);
+    Some(((mean_sq - mean * mean).max(0.0) * n / (n - 1.0)).sqrt())
+}
+
+/// Fields games can be sorted by and their columns.
+pub const SORT_FIELDS: [(&str, &str); 9] = [
+    ("rating", "rating"),
+    ("votes", "num_votes"),
+    ("geek-rating", "bgg_geek_rating"),
+    ("bgg-rating", "bgg_avg_rating"),
+    ("bgg-votes", "bgg_num_votes"),
+    ("weight", "weight"),
+    ("name", "name"),
+    ("id", "id"),
+    ("polarizing", "polarizing"),
+];
+
+/// Fields users can be sorted by and their order. Users checked
+/// longest ago and users with the highest average come first.
+pub const USER_SORT_FIELDS: [(&str, &str); 3] = [
+    ("updated", "updated asc"),
+    ("rating", "average desc"),
+    ("name", "name asc"),
+];
+
+/// Cached user as the users command lists it.
+pub struct UserRecord {
+    pub name: String,
+    pub trusted: bool,
+    pub average: Option<f64>, // average rating BGG gave
+    pub ratings: u32,         // ratings of the user in game_users
+    pub updated: Option<String>,
+    pub pinned: bool, // trust was set by hand with rerate
+}
+
+/// Which users to list and in what order, None doesn't filter.
+pub struct UserFilter {
+    pub trusted: Option<bool>,
+    pub pinned: bool, // only users whose trust was set by hand
+    pub updated_before: Option<DateTime<Local>>,
+    pub sort: String, // key of USER_SORT_FIELDS
+    pub limit: Option<u32>,
+    pub offset: u32,
+}
+
+/// Which games to walk in for_each_game, None doesn't filter.
+pub struct GameFilter {
+    pub tier: Option<u32>,
+    pub max_weight: Option<f64>, // games without weight are left out
+    pub limit: Option<u32>,
+}
+
+/// Fields computed from the loaded games, sorted after the query.
+pub const DERIVED_FIELDS: [&str; 2] = ["ci-low", "bayes"];
+
+/// Sort keys in order of priority, all in the same direction.
+pub struct Order {
+    pub keys: Vec<String>,
+    pub descending: bool,
+}
+
+impl Default for Order {
+    fn default() -> Order {
+        Order {
+            keys: vec![String::from("rating")],
+            descending: true,
+        }
+    }
+}
+
+impl Order {
+    /// Only columns from SORT_FIELDS ever get into the query.
+    fn to_sql(&self) -> Result<String, AppError> {
+        let direction = if self.descending { "desc" } else { "asc" };
+        let mut columns = Vec::new();
+        for key in self
+            .keys
+            .iter()
+            .filter(|k| !DERIVED_FIELDS.contains(&k.as_str()))
+        {
+            match SORT_FIELDS.iter().find(|(field, _)| field == key) {
+                Some((_, column)) => columns.push(format!("{} {}", column, direction)),
+                None => return Err(AppError::Config(format!("Can't sort by {}", key))),
+            }
+        }
+        if columns.is_empty() {
+            columns.push(format!("rating {}", direction));
+        }
+        Ok(columns.join(", "))
+    }
+}
+
+/// Time a worker spent on a game within one run.
+pub fn add_game_span(
+    game_id: u32,
+    started: &DateTime<Local>,
+    elapsed: Duration,
+    pages: u32,
+    users: &UserCounts,
+) -> Result<(), AppError> {
+    let conn = Connection::open(db_path())?;
+    conn.execute(
+        "insert into game_spans (game_id, started, seconds, pages, new_users, cached_users)
+        values (?1, ?2, ?3, ?4, ?5, ?6)",
+        &[
+            &game_id as &dyn ToSql,
+            &started.to_string(),
+            &elapsed.as_secs_f64(),
+            &pages,
+            &users.new,
+            &users.cached,
+        ],
     )?;
-    let iter = stmt.query_map(NO_PARAMS, |r| Game {
-        id: r.get(0),
-        name: r.get(1),
-        page: r.get(2),
-        votes: r.get(3),
-        rating: r.get(4),
-        bgg_avg_rating: 0.0,
-        bgg_geek_rating: 0.0,
-        bgg_num_votes: 0,
-    })?;
-    let mut gameboxes = Vec::new();
-    for gamebox in iter {
-        gameboxes.push(gamebox?);
+    conn.execute(
+        "update games set balancing_started_at = ?1 where id = ?2 and balancing_started_at is null",
+        &[&started.to_string() as &dyn ToSql, &game_id],
+    )?;
+    Ok(())
+}
+
+/// Balancing time of a game summed over every run.
+pub struct Timing {
+    pub id: u32,
+    pub name: String,
+    pub pages: u32,
+    pub seconds: f64,
+    pub started: Option<String>,
+    pub stabilized: Option<String>,
+    pub users: UserCounts,
+}
+
+/// Users whose ratings a game needed, split by where their trust came from.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct UserCounts {
+    /// asked bgg about
+    pub new: u32,
+    /// found in the db
+    pub cached: u32,
+}
+
+/// Single balance run as recorded in runs table.
+pub struct Run {
+    pub id: u32,
+    pub kind: String, // balance or review
+    pub started: String,
+    pub finished: String,
+    pub balanced: u32,
+    pub errors: u32,
+    pub requests: u32,
+    pub interrupted: u32,
+    pub remaining: u32,
+    pub new_users: u32,
+    pub cached_users: u32,
+    pub reviewed: u32, // users asked about again by review
+    pub flipped: u32,  // of them, users whose trust changed
+}
+
+/// Saved copy of the game list.
+pub struct Snapshot {
+    pub id: u32,
+    pub taken: String,
+    pub name: Option<String>,
+    pub games: u32,
+}
+
+// Columns of a user checked on BGG, in the order checked_user_from_row reads them.
+const CHECKED_USER_COLUMNS: &str = "name, average, year_registered, trusted";
+
+fn checked_user_from_row(row: &Row) -> Result<CheckedUser, AppError> {
+    let f = Fields::new(row, "users", CHECKED_USER_COLUMNS);
+    Ok(CheckedUser {
+        name: f.get(0)?,
+        average: f.get(1)?,
+        year: f.get(2)?,
+        trusted: f.get(3)?,
+    })
+}
+
+// Columns both games and snapshot_games have, in the order game_from_row reads them.
+const GAME_COLUMNS: &str = "id, name, rating, num_votes, bgg_num_votes, bgg_geek_rating, bgg_avg_rating, weight, previous_rating, stable, coalesce(flagged, 0), polarizing, coalesce(sampled, 0), year, all_rating, all_votes, tier";
+// Mean of squared trusted ratings of the game in games table.
+const MEAN_SQ: &str = "(select avg(gu.rating * gu.rating) from game_users gu join users u on u.name = gu.name where gu.game_id = games.id and u.trusted = 1)";
+
+/// Game from GAME_COLUMNS followed by the mean of squared ratings.
+fn game_from_row(row: &Row) -> Result<Game, AppError> {
+    let f = Fields::new(row, "games", GAME_COLUMNS);
+    let votes = f.count(3)?;
+    let rating = f.get(2)?;
+    Ok(Game {
+        rating,
+        votes,
+        weight: f.get(7)?,
+        previous_rating: f.get(8)?,
+        rating_sd: sample_sd(votes, rating, row.get_checked(17)?),
+        stable: f.get(9)?,
+        flagged: f.get(10)?,
+        polarizing: f.get(11)?,
+        sampled: f.get(12)?,
+        year: f.get(13)?,
+        all_rating: f.get(14)?,
+        all_votes: f.get(15)?,
+        tier: f.get(16)?,
+        ..Game::from_search(f.get(0)?, f.get(1)?, f.count(4)?, f.get(5)?, f.get(6)?)
+    })
+}
+
+// Columns of a game still being balanced, in the order unstable_game_from_row reads them.
+const UNSTABLE_COLUMNS: &str =
+    "id, name, page, num_votes, rating, weight, previous_rating, bgg_avg_rating, bgg_num_votes";
+
+/// Game from UNSTABLE_COLUMNS, just what balancing needs to resume it.
+fn unstable_game_from_row(row: &Row) -> Result<Game, AppError> {
+    let f = Fields::new(row, "games", UNSTABLE_COLUMNS);
+    Ok(Game {
+        page: f.get(2)?,
+        votes: f.count(3)?,
+        rating: f.get(4)?,
+        weight: f.get(5)?,
+        previous_rating: f.get(6)?,
+        bgg_avg_rating: f.get(7)?,
+        bgg_num_votes: f.count(8)?,
+        ..Game::new(f.get(0)?, f.get(1)?)
+    })
+}
+
+/// Reads a row by the column list of its query, the key comes first.
+/// A NULL or a value of the wrong type, e.g. after a half done
+/// migration, is an error naming the table, the column and the row
+/// instead of a rusqlite panic.
+struct Fields<'r, 'a, 'b> {
+    row: &'r Row<'a, 'b>,
+    table: &'static str,
+    columns: &'static str,
+}
+
+impl<'r, 'a, 'b> Fields<'r, 'a, 'b> {
+    fn new(row: &'r Row<'a, 'b>, table: &'static str, columns: &'static str) -> Self {
+        Fields {
+            row,
+            table,
+            columns,
+        }
+    }
+
+    fn get<T: FromSql>(&self, index: usize) -> Result<T, AppError> {
+        self.row.get_checked(index).map_err(|e| {
+            AppError::Parse(format!(
+                "Can't read {}.{} of {}: {}",
+                self.table,
+                column_name(self.columns, index),
+                self.key(),
+                e
+            ))
+        })
+    }
+
+    /// Count that can't be negative, a negative one reads as 0 with a
+    /// warning. check reports games whose votes don't add up.
+    fn count(&self, index: usize) -> Result<u32, AppError> {
+        let n: i64 = self.get(index)?;
+        if n < 0 {
+            runlog::complain(&format!(
+                "Warning: {}.{} of {} is {}, read as 0.",
+                self.table,
+                column_name(self.columns, index),
+                self.key(),
+                n
+            ));
+        }
+        Ok(n.clamp(0, i64::from(u32::MAX)) as u32)
+    }
+
+    /// Key of the row as text, ? if it can't be read either.
+    fn key(&self) -> String {
+        match self.row.get_checked(0) {
+            Ok(Value::Integer(key)) => key.to_string(),
+            Ok(Value::Text(key)) => key,
+            _ => String::from("?"),
+        }
+    }
+}
+
+/// Name of column `index` in a select list, commas inside calls
+/// like coalesce(flagged, 0) don't count.
+fn column_name(columns: &str, index: usize) -> &str {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut n = 0;
+    for (i, c) in columns.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                if n == index {
+                    return columns[start..i].trim();
+                }
+                n += 1;
+                start = i + 1;
+            }
+            _ => {}
+        }
     }
-    Ok(gameboxes)
+    if n == index {
+        columns[start..].trim()
+    } else {
+        "?"
+    }
+}
+
+/// Pages of size `to` that hold the ratings of `page` of size `from`.
+fn converted_pages(page: u32, from: u32, to: u32) -> RangeInclusive<u32> {
+    let first = u64::from(page.max(1) - 1) * u64::from(from);
+    let last = first + u64::from(from) - 1;
+    let to = u64::from(to);
+    (first / to + 1) as u32..=(last / to + 1) as u32
+}
+
+/// Whether a timestamp written as Local::now().to_string() is older
+/// than `before`, false if it can't be read.
+fn written_before(updated: &str, before: &DateTime<Local>) -> bool {
+    DateTime::parse_from_str(updated, "%Y-%m-%d %H:%M:%S%.f %:z")
+        .is_ok_and(|updated| updated < *before)
+}
+
+/// Sizes of games and users tables.
+#[derive(Serialize)]
+pub struct Totals {
+    pub games_stable: u32,
+    pub games_unstable: u32,
+    pub users_trusted: u32,
+    pub users_untrusted: u32,
+    pub ratings: u32, // stored ratings of trusted and untrusted users
+}
+
+pub struct CheckedUser {
+    pub name: User,
+    pub average: f64,
+    pub year: Option<u32>,
+    pub trusted: bool,
+}
+
+/// What db knows about a user.
+#[derive(Clone, Copy)]
+pub enum Known {
+    Unseen,
+    /// checked too long ago, holds the trust found back then
+    Stale(bool),
+    Trust(bool),
 }
 
 pub struct DbConn {
     conn: Connection,
 }
 
+/// Transaction on the connection a DbConn only lends out. Rolled back
+/// when dropped before commit, so an early return with `?` leaves
+/// nothing half written. A failed rollback is told, the error that
+/// stopped the work is still the one returned.
+struct Tx<'a> {
+    conn: &'a Connection,
+    done: bool,
+}
+
+impl<'a> Tx<'a> {
+    /// `begin` is the statement that opens it, e.g. begin immediate.
+    fn begin(conn: &'a Connection, begin: &str) -> Result<Tx<'a>, AppError> {
+        conn.execute_batch(begin)?;
+        Ok(Tx { conn, done: false })
+    }
+
+    fn commit(mut self) -> Result<(), AppError> {
+        self.conn.execute_batch("commit")?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for Tx<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Err(e) = self.conn.execute_batch("rollback") {
+            runlog::complain(&format!("Rollback failed: {}", e));
+        }
+    }
+}
+
 impl DbConn {
-    pub fn new() -> Result<DbConn, Error> {
+    pub fn new() -> Result<DbConn, AppError> {
+        ensure_exists()?;
         let conn = Connection::open_with_flags(
-            DB_FILE_NAME,
+            db_path(),
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX, // for multi thread
         )?;
         Ok(DbConn { conn })
     }
 
-    pub fn add_user(&self, user: &User, trusted: bool) -> Result<(), Error> {
+    /// Connection for commands that only look, works while balance
+    /// writes. The db is not upgraded, see open_read_only.
+    pub fn new_read_only() -> Result<DbConn, AppError> {
+        ensure_exists()?;
+        DbConn::open_read_only(db_path())
+    }
+
+    /// Read only connection to the selected db or another one, it is
+    /// neither created nor upgraded. A db with tables of an older
+    /// build is an error, its columns may be missing.
+    pub fn open_read_only(path: &Path) -> Result<DbConn, AppError> {
+        if !path.exists() {
+            let e = format!("{} not found.", path.display());
+            return Err(AppError::Config(e));
+        }
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let conn = DbConn { conn };
+        let version = conn.schema_version()?;
+        if version < SCHEMA_VERSION {
+            return Err(AppError::Config(format!(
+                "{} has tables of version {}, this build reads {}. Run `bgg_swing2 --db {} check` to upgrade it.",
+                path.display(),
+                version,
+                SCHEMA_VERSION,
+                path.display()
+            )));
+        }
+        Ok(conn)
+    }
+
+    /// `average` and `year` are kept to reevaluate trust offline.
+    pub fn add_user(
+        &self,
+        user: &User,
+        trusted: bool,
+        average: Option<f64>,
+        year: Option<u32>,
+    ) -> Result<(), AppError> {
         let now = Local::now();
         match self.conn.execute(
-            "insert or ignore into users (name, updated, trusted) values (?1, ?2, ?3)",
-            &[&user as &ToSql, &now.to_string(), &trusted],
+            "insert or ignore into users (name, updated, trusted, average, year_registered) values (?1, ?2, ?3, ?4, ?5)",
+            &[&user as &dyn ToSql, &now.to_string(), &trusted, &average, &year],
         ) {
             Ok(_) => Ok(()),
-            Err(err) => bail!(err),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Saves ratings of the current page of `game` and moves it to the
+    /// next page in one transaction. Ratings seen on earlier pages get
+    /// replaced, so the average is taken from the table instead of being
+    /// added up page by page. Returns the new number of votes and rating.
+    /// With `keep_all` the average of every rating is saved as well.
+    pub fn commit_page(
+        &self,
+        game: &Game,
+        users: &[(User, f64)],
+        next_page: u32,
+        keep_all: bool,
+    ) -> Result<(u32, f64), AppError> {
+        let tx = Tx::begin(&self.conn, "begin")?;
+        let average = self.save_page(game, users, next_page, keep_all)?;
+        tx.commit()?;
+        Ok(average)
+    }
+
+    /// Recounts `game` from its stored ratings with the trust users have
+    /// now, lets `judge` look at the trusted ratings and saves the game
+    /// as stable, all in one transaction. Trust that changes while the
+    /// game is balanced is counted for every page, a change after this
+    /// recounts the game as for any stable game. Returns the same as
+    /// update_game.
+    pub fn commit_finish(
+        &self,
+        game: &mut Game,
+        judge: impl FnOnce(&mut Game, &[f64]),
+    ) -> Result<bool, AppError> {
+        // the write lock up front, trust can't change between the
+        // recount and the update
+        let tx = Tx::begin(&self.conn, "begin immediate")?;
+        let (votes, rating) = self.get_game_average(game.id)?;
+        game.votes = votes;
+        game.rating = rating;
+        judge(game, &self.get_game_ratings(game.id)?);
+        if !self.update_game(game, true)? {
+            return Ok(false);
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn save_page(
+        &self,
+        game: &Game,
+        users: &[(User, f64)],
+        next_page: u32,
+        keep_all: bool,
+    ) -> Result<(u32, f64), AppError> {
+        let mut stmt = self.conn.prepare_cached(
+            "insert or replace into game_users (game_id, name, rating) values (?1, ?2, ?3)",
+        )?;
+        for (user, rating) in users {
+            stmt.execute(&[&game.id as &dyn ToSql, user, rating])?;
+        }
+        // every pass starts on the first page, pages of an earlier
+        // sample don't describe it
+        if game.page == 1 {
+            self.conn
+                .execute("delete from sampled_pages where game_id = ?1", &[&game.id])?;
+        }
+        if game.sampled {
+            self.conn.execute(
+                "insert or ignore into sampled_pages (game_id, page) values (?1, ?2)",
+                &[&game.id, &game.page],
+            )?;
+        }
+        let (votes, rating) = self.get_game_average(game.id)?;
+        // cleared when the option is off, an old value would be stale
+        let all = if keep_all {
+            Some(self.get_game_all_average(game.id)?)
+        } else {
+            None
+        };
+        // the page only moves forward, commit_page rolls back otherwise
+        let updated = self.conn.execute(
+            "update games set page = ?1, rating = ?2, num_votes = ?3, updated = ?4, all_votes = ?5, all_rating = ?6
+            where id = ?7 and page <= ?1",
+            &[
+                &next_page as &dyn ToSql,
+                &rating,
+                &votes,
+                &Local::now().to_string(),
+                &all.map(|(votes, _)| votes),
+                &all.map(|(_, rating)| rating),
+                &game.id,
+            ],
+        )?;
+        if updated == 0 {
+            return Err(page_went_back(game, next_page));
         }
+        Ok((votes, rating))
     }
 
-    pub fn get_number_of_unstable_games(&self) -> Result<u32, Error> {
+    pub fn meta_get(&self, key: &str) -> Result<Option<String>, AppError> {
+        let mut stmt = self.conn.prepare("select value from meta where key = ?")?;
+        match stmt.query_row(&[key], |r| -> String { r.get(0) }) {
+            Ok(value) => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn meta_set(&self, key: &str, value: &str) -> Result<(), AppError> {
+        self.conn.execute(
+            "insert or replace into meta (key, value) values (?1, ?2)",
+            &[key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn meta_delete(&self, key: &str) -> Result<(), AppError> {
+        self.conn
+            .execute("delete from meta where key = ?", &[key])?;
+        Ok(())
+    }
+
+    pub fn add_run(
+        &self,
+        started: &DateTime<Local>,
+        summary: &StabilizeSummary,
+    ) -> Result<(), AppError> {
+        self.conn.execute(
+            "insert into runs (started, finished, balanced, errors, requests, interrupted, remaining, new_users, cached_users)
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            &[
+                &started.to_string() as &dyn ToSql,
+                &Local::now().to_string(),
+                &summary.balanced,
+                &summary.errors,
+                &summary.requests,
+                &summary.interrupted,
+                &summary.remaining,
+                &summary.users,
+                &summary.cached_users,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Review run, counts that only balance has are left at 0.
+    pub fn add_review_run(
+        &self,
+        started: &DateTime<Local>,
+        summary: &ReviewSummary,
+    ) -> Result<(), AppError> {
+        self.conn.execute(
+            "insert into runs (kind, started, finished, balanced, errors, requests, interrupted, remaining, new_users, cached_users, reviewed, flipped)
+            values ('review', ?1, ?2, 0, ?3, ?4, 0, ?5, 0, 0, ?6, ?7)",
+            &[
+                &started.to_string() as &dyn ToSql,
+                &Local::now().to_string(),
+                &summary.errors,
+                &summary.requests,
+                &summary.remaining,
+                &summary.reviewed,
+                &summary.flipped,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_timings(&self) -> Result<Vec<Timing>, AppError> {
+        const TIMING_COLUMNS: &str =
+            "g.id, g.name, sum(s.pages), sum(s.seconds), g.balancing_started_at, g.stabilized_at, \
+            coalesce(sum(s.new_users), 0), coalesce(sum(s.cached_users), 0)";
+        let mut stmt = self.conn.prepare(&format!(
+            "select {} from games g join game_spans s on s.game_id = g.id
+            group by g.id order by sum(s.seconds) desc",
+            TIMING_COLUMNS
+        ))?;
+        let iter = stmt.query_and_then(NO_PARAMS, |r| -> Result<Timing, AppError> {
+            let f = Fields::new(r, "game_spans", TIMING_COLUMNS);
+            Ok(Timing {
+                id: f.get(0)?,
+                name: f.get(1)?,
+                pages: f.count(2)?,
+                seconds: f.get(3)?,
+                started: f.get(4)?,
+                stabilized: f.get(5)?,
+                users: UserCounts {
+                    new: f.count(6)?,
+                    cached: f.count(7)?,
+                },
+            })
+        })?;
+        let mut timings = Vec::new();
+        for timing in iter {
+            timings.push(timing?);
+        }
+        Ok(timings)
+    }
+
+    /// Latest runs, oldest first.
+    pub fn get_runs(&self, limit: u32) -> Result<Vec<Run>, AppError> {
+        const RUN_COLUMNS: &str = "id, started, finished, balanced, errors, requests, interrupted, remaining, new_users, cached_users, coalesce(kind, 'balance'), coalesce(reviewed, 0), coalesce(flipped, 0)";
+        let mut stmt = self.conn.prepare(&format!(
+            "select * from (
+                select {} from runs order by id desc limit ?
+            ) order by id",
+            RUN_COLUMNS
+        ))?;
+        let iter = stmt.query_and_then(&[&limit], |r| -> Result<Run, AppError> {
+            let f = Fields::new(r, "runs", RUN_COLUMNS);
+            Ok(Run {
+                id: f.get(0)?,
+                started: f.get(1)?,
+                finished: f.get(2)?,
+                balanced: f.count(3)?,
+                errors: f.count(4)?,
+                requests: f.count(5)?,
+                interrupted: f.count(6)?,
+                remaining: f.count(7)?,
+                new_users: f.count(8)?,
+                cached_users: f.count(9)?,
+                kind: f.get(10)?,
+                reviewed: f.count(11)?,
+                flipped: f.count(12)?,
+            })
+        })?;
+        let mut runs = Vec::new();
+        for run in iter {
+            runs.push(run?);
+        }
+        Ok(runs)
+    }
+
+    /// Overrides trust of the user, stats fetched earlier are kept.
+    /// Games rated by the user are recounted, `flag` tells which stable
+    /// ones are flagged then. Returns the number of such games.
+    pub fn set_user_trust(
+        &self,
+        user: &User,
+        trusted: bool,
+        flag: &dyn Fn(&Game) -> bool,
+    ) -> Result<usize, AppError> {
+        let now = Local::now();
+        let tx = Tx::begin(&self.conn, "begin immediate")?;
+        self.conn.execute(
+            "insert into users (name, updated, trusted, manual) values (?1, ?2, ?3, 1)
+            on conflict(name) do update set trusted = excluded.trusted, manual = 1",
+            &[user as &dyn ToSql, &now.to_string(), &trusted],
+        )?;
+        let games = self.recount_user_games(user, flag)?;
+        tx.commit()?;
+        Ok(games)
+    }
+
+    /// Stores fresh stats of a stale user, games rated by the user are
+    /// recounted if trust changed. Trust set by hand is kept. Returns
+    /// the number of games recounted.
+    pub fn refresh_user(
+        &self,
+        user: &User,
+        trusted: bool,
+        average: f64,
+        year: Option<u32>,
+        flag: &dyn Fn(&Game) -> bool,
+    ) -> Result<usize, AppError> {
+        let now = Local::now();
+        let tx = Tx::begin(&self.conn, "begin immediate")?;
+        let changed = self.conn.execute(
+            "update users set updated = ?1, average = ?2, year_registered = ?3, trusted = ?4
+            where name = ?5 and trusted != ?4 and not coalesce(manual, 0)",
+            &[
+                &now.to_string() as &dyn ToSql,
+                &average,
+                &year,
+                &trusted,
+                user,
+            ],
+        )?;
+        if changed == 0 {
+            self.conn.execute(
+                "update users set updated = ?1, average = ?2, year_registered = ?3 where name = ?4",
+                &[&now.to_string() as &dyn ToSql, &average, &year, user],
+            )?;
+            tx.commit()?;
+            return Ok(0);
+        }
+        let games = self.recount_user_games(user, flag)?;
+        tx.commit()?;
+        Ok(games)
+    }
+
+    /// Changes trust of a user that was checked automatically,
+    /// returns the number of games recounted.
+    pub fn update_user_trust(
+        &self,
+        user: &User,
+        trusted: bool,
+        flag: &dyn Fn(&Game) -> bool,
+    ) -> Result<usize, AppError> {
+        let tx = Tx::begin(&self.conn, "begin immediate")?;
+        self.conn.execute(
+            "update users set trusted = ?1 where name = ?2",
+            &[&trusted as &dyn ToSql, user],
+        )?;
+        let games = self.recount_user_games(user, flag)?;
+        tx.commit()?;
+        Ok(games)
+    }
+
+    /// Counts games rated by the user again from their stored ratings,
+    /// with the trust users have now. Stable games stay stable and get
+    /// the flag `flag` gives their new rating, balancing games go on
+    /// from their page and are flagged once they are done.
+    fn recount_user_games(
+        &self,
+        user: &User,
+        flag: &dyn Fn(&Game) -> bool,
+    ) -> Result<usize, AppError> {
+        let sql = format!(
+            "select {}, {} from games where id in (select game_id from game_users where name = ?)",
+            GAME_COLUMNS, MEAN_SQ
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let games = stmt
+            .query_and_then(&[user], game_from_row)?
+            .collect::<Result<Vec<Game>, _>>()?;
+        let recounted = games.len();
+        for mut game in games {
+            let (votes, rating) = self.get_game_average(game.id)?;
+            game.votes = votes;
+            game.rating = rating;
+            if game.stable {
+                game.flagged = flag(&game);
+            }
+            self.conn.execute(
+                "update games set num_votes = ?1, rating = ?2, flagged = ?3 where id = ?4",
+                &[&votes as &dyn ToSql, &rating, &game.flagged, &game.id],
+            )?;
+        }
+        Ok(recounted)
+    }
+
+    /// false => no such game in the list.
+    pub fn set_watched(&self, game_id: u32, watched: bool) -> Result<bool, AppError> {
+        let games = self.conn.execute(
+            "update games set watched = ?1 where id = ?2",
+            &[&watched as &dyn ToSql, &game_id],
+        )?;
+        Ok(games > 0)
+    }
+
+    /// Source and notes of the game, None if it is not in the list.
+    pub fn get_game_notes(
+        &self,
+        game_id: u32,
+    ) -> Result<Option<(String, Option<String>)>, AppError> {
+        const COLUMNS: &str = "id, coalesce(source, 'search'), notes";
         let mut stmt = self
             .conn
-            .prepare("select count(*) from games where not stable")?;
-        let count: u32 = stmt.query_row(NO_PARAMS, |r| r.get(0))?;
+            .prepare(&format!("select {} from games where id = ?1", COLUMNS))?;
+        let mut rows = stmt.query_and_then(&[&game_id], |r| -> Result<_, AppError> {
+            let f = Fields::new(r, "games", COLUMNS);
+            Ok((f.get(1)?, f.get(2)?))
+        })?;
+        rows.next().transpose()
+    }
+
+    /// Empty `notes` clear them. false => no such game in the list.
+    pub fn set_game_notes(&self, game_id: u32, notes: &str) -> Result<bool, AppError> {
+        let notes = Some(notes).filter(|n| !n.is_empty());
+        let games = self.conn.execute(
+            "update games set notes = ?1 where id = ?2",
+            &[&notes as &dyn ToSql, &game_id],
+        )?;
+        Ok(games > 0)
+    }
+
+    /// Makes sampled games unstable, the next pass rates them from
+    /// every page. Stored ratings go, a user who took one back since
+    /// must not stay in the average.
+    pub fn reset_sampled_games(&self) -> Result<usize, AppError> {
+        self.reset_games("sampled", "stable = 0, page = 1, sampled = 0")
+    }
+
+    /// Hands users matching `filter` to `f` one by one, as they are read.
+    pub fn for_each_user(
+        &self,
+        filter: &UserFilter,
+        mut f: impl FnMut(UserRecord) -> Result<(), AppError>,
+    ) -> Result<(), AppError> {
+        // only orders from USER_SORT_FIELDS ever get into the query
+        let order = match USER_SORT_FIELDS.iter().find(|(key, _)| *key == filter.sort) {
+            Some((_, order)) => order,
+            None => {
+                return Err(AppError::Config(format!(
+                    "Can't sort users by {}",
+                    filter.sort
+                )))
+            }
+        };
+        // updated is written as Local::now().to_string(), it compares
+        // as text within one time zone
+        const COLUMNS: &str = "name, coalesce(trusted, 0), average,
+            (select count(*) from game_users gu where gu.name = users.name), updated,
+            coalesce(manual, 0)";
+        let mut stmt = self.conn.prepare(&format!(
+            "select {} from users
+            where (?1 is null or trusted = ?1) and (?2 is null or updated < ?2)
+            and (not ?5 or coalesce(manual, 0))
+            order by {}, name
+            limit ?3 offset ?4",
+            COLUMNS, order
+        ))?;
+        let before = filter.updated_before.map(|before| before.to_string());
+        // negative limit means no limit for sqlite
+        let limit = filter.limit.map_or(-1, i64::from);
+        let mut rows = stmt.query(&[
+            &filter.trusted as &dyn ToSql,
+            &before,
+            &limit,
+            &filter.offset,
+            &filter.pinned,
+        ])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let fields = Fields::new(&row, "users", COLUMNS);
+            f(UserRecord {
+                name: fields.get(0)?,
+                trusted: fields.get(1)?,
+                average: fields.get(2)?,
+                ratings: fields.count(3)?,
+                updated: fields.get(4)?,
+                pinned: fields.get(5)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Moves page counters of unstable games read with another page size
+    /// to the page of `page_size` that holds the first rating not read
+    /// yet. The part of a page read twice is replaced, not added twice.
+    /// Pages of a sample become the pages that hold the same ratings.
+    pub fn convert_pages(&self, page_size: u32) -> Result<usize, AppError> {
+        const CONVERTED: &str = "not stable and coalesce(page_size, ?2) != ?1";
+        let params = [&page_size as &dyn ToSql, &bgg::USER_PAGE_SIZE];
+        let tx = Tx::begin(&self.conn, "begin immediate")?;
+        let mut stmt = self.conn.prepare(&format!(
+            "select s.game_id, s.page, coalesce(g.page_size, ?2) from sampled_pages s
+            join games g on g.id = s.game_id where {}",
+            CONVERTED
+        ))?;
+        let sampled = stmt
+            .query_and_then(&params, |r| -> Result<(u32, u32, u32), AppError> {
+                Ok((r.get_checked(0)?, r.get_checked(1)?, r.get_checked(2)?))
+            })?
+            .collect::<Result<Vec<_>, AppError>>()?;
+        self.conn.execute(
+            &format!(
+                "delete from sampled_pages where game_id in (select id from games where {})",
+                CONVERTED
+            ),
+            &params,
+        )?;
+        let mut insert = self
+            .conn
+            .prepare("insert or ignore into sampled_pages (game_id, page) values (?1, ?2)")?;
+        for (game_id, page, from) in sampled {
+            for page in converted_pages(page, from, page_size) {
+                insert.execute(&[&game_id, &page])?;
+            }
+        }
+        let games = self.conn.execute(
+            &format!(
+                "update games set page = ((page - 1) * coalesce(page_size, ?2)) / ?1 + 1, page_size = ?1
+                where {}",
+                CONVERTED
+            ),
+            &params,
+        )?;
+        tx.commit()?;
+        Ok(games)
+    }
+
+    /// Makes stable watched games unstable, so they are balanced again
+    /// from their current ratings only.
+    pub fn reset_watched_games(&self) -> Result<usize, AppError> {
+        self.reset_games("watched and stable", "stable = 0, page = 1")
+    }
+
+    /// Applies `set` to games matching `filter` and deletes their
+    /// ratings, the next pass reads them anew.
+    fn reset_games(&self, filter: &str, set: &str) -> Result<usize, AppError> {
+        let tx = Tx::begin(&self.conn, "begin immediate")?;
+        self.conn.execute(
+            &format!(
+                "delete from game_users where game_id in (select id from games where {})",
+                filter
+            ),
+            NO_PARAMS,
+        )?;
+        let games = self.conn.execute(
+            &format!("update games set {} where {}", set, filter),
+            NO_PARAMS,
+        )?;
+        tx.commit()?;
+        Ok(games)
+    }
+
+    /// Users with stats from BGG that were checked before `before`,
+    /// the longest unchecked first. Users whose trust was set by hand
+    /// are not asked about again.
+    pub fn get_users_checked_before(
+        &self,
+        before: &DateTime<Local>,
+    ) -> Result<Vec<CheckedUser>, AppError> {
+        let mut stmt = self.conn.prepare(&format!(
+            "select {}, coalesce(updated, '') from users
+            where average is not null and not coalesce(manual, 0) order by updated",
+            CHECKED_USER_COLUMNS
+        ))?;
+        let iter =
+            stmt.query_and_then(NO_PARAMS, |r| -> Result<(CheckedUser, String), AppError> {
+                Ok((checked_user_from_row(r)?, r.get_checked(4)?))
+            })?;
+        let mut users = Vec::new();
+        for row in iter {
+            let (user, updated) = row?;
+            if written_before(&updated, before) {
+                users.push(user);
+            }
+        }
+        Ok(users)
+    }
+
+    /// Users whose average was stored when they were checked, users
+    /// whose trust was set by hand are left as they are.
+    pub fn get_checked_users(&self) -> Result<Vec<CheckedUser>, AppError> {
+        let mut stmt = self.conn.prepare(&format!(
+            "select {} from users where average is not null and not coalesce(manual, 0)",
+            CHECKED_USER_COLUMNS
+        ))?;
+        let iter = stmt.query_and_then(NO_PARAMS, checked_user_from_row)?;
+        let mut users = Vec::new();
+        for user in iter {
+            users.push(user?);
+        }
+        Ok(users)
+    }
+
+    /// Deletes users that are not referenced by any game, users whose
+    /// trust was set by hand stay.
+    pub fn prune_users(&self) -> Result<usize, AppError> {
+        let removed = self.conn.execute(
+            "delete from users where not coalesce(manual, 0)
+            and not exists (select 1 from game_users where game_users.name = users.name)",
+            NO_PARAMS,
+        )?;
+        Ok(removed)
+    }
+
+    /// Returns unstable games of `tier` or of every tier, higher tiers
+    /// first, in random order within a tier, at most `limit` of them.
+    /// The same `seed` gives the same order every time.
+    pub fn get_unstable_games(
+        &self,
+        limit: Option<u32>,
+        tier: Option<u32>,
+        seed: Option<u32>,
+    ) -> Result<Vec<Game>, AppError> {
+        // multiplicative hash of the id shuffles games without random(),
+        // the seed goes in before it so every seed gives its own order.
+        // The sum is cut to 32 bits and multiplied by 2654435761 in two
+        // halves, 40503 * 65536 + 31153, neither product leaves i64, a
+        // product that did would turn REAL and lose the low bits.
+        let mut stmt = self.conn.prepare(&format!(
+            "select {} from games
+            where not stable and (?1 is null or tier = ?1)
+            order by tier desc,
+                case when ?3 is null then random()
+                    else ((((id + ?3) % 4294967296) * 40503 % 65536) * 65536
+                        + ((id + ?3) % 4294967296) * 31153) % 4294967296 end,
+                id
+            limit ?2",
+            UNSTABLE_COLUMNS
+        ))?;
+        // negative limit means no limit for sqlite
+        let limit = limit.map_or(-1, i64::from);
+        let iter = stmt.query_and_then(
+            &[&tier as &dyn ToSql, &limit, &seed],
+            unstable_game_from_row,
+        )?;
+        let mut gameboxes = Vec::new();
+        for gamebox in iter {
+            gameboxes.push(gamebox?);
+        }
+        Ok(gameboxes)
+    }
+
+    /// Counts unstable games of `tier` or of every tier, only those read
+    /// from `max_page` pages or less when it is given.
+    pub fn get_number_of_unstable_games(
+        &self,
+        tier: Option<u32>,
+        max_page: Option<u32>,
+    ) -> Result<u32, AppError> {
+        let mut stmt = self.conn.prepare(
+            "select count(*) from games where not stable and (?1 is null or tier = ?1)
+            and (?2 is null or page <= ?2)",
+        )?;
+        let count: u32 = stmt.query_row(&[&tier, &max_page], |r| r.get(0))?;
         Ok(count)
     }
 
-    pub fn check_user(&self, user: &User) -> Result<Option<bool>, Error> {
+    pub fn get_totals(&self) -> Result<Totals, AppError> {
+        let (games_stable, games_unstable) = self.conn.query_row(
+            "select coalesce(sum(stable), 0), coalesce(sum(not stable), 0) from games",
+            NO_PARAMS,
+            |r| (r.get(0), r.get(1)),
+        )?;
+        let (users_trusted, users_untrusted) = self.conn.query_row(
+            "select coalesce(sum(trusted), 0), coalesce(sum(not trusted), 0) from users",
+            NO_PARAMS,
+            |r| (r.get(0), r.get(1)),
+        )?;
+        let ratings = self
+            .conn
+            .query_row("select count(*) from game_users", NO_PARAMS, |r| r.get(0))?;
+        Ok(Totals {
+            games_stable,
+            games_unstable,
+            users_trusted,
+            users_untrusted,
+            ratings,
+        })
+    }
+
+    /// Value of `pragma user_version`, see SCHEMA_VERSION.
+    pub fn schema_version(&self) -> Result<u32, AppError> {
+        Ok(self
+            .conn
+            .query_row("pragma user_version", NO_PARAMS, |r| r.get(0))?)
+    }
+
+    /// Finish time of the latest balance run that was not interrupted.
+    pub fn last_balance(&self) -> Result<Option<String>, AppError> {
+        let mut stmt = self.conn.prepare(
+            "select finished from runs
+            where coalesce(kind, 'balance') = 'balance' and interrupted = 0
+            order by id desc limit 1",
+        )?;
+        match stmt.query_row(NO_PARAMS, |r| -> String { r.get(0) }) {
+            Ok(finished) => Ok(Some(finished)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Users checked before `stale_before` are stale. Users set by hand
+    /// or without stored average, assumed untrusted, never are.
+    pub fn check_user(
+        &self,
+        user: &User,
+        stale_before: Option<DateTime<Local>>,
+    ) -> Result<Known, AppError> {
+        const COLUMNS: &str =
+            "name, trusted, updated, average is not null and not coalesce(manual, 0)";
         let mut stmt = self
             .conn
-            .prepare("select trusted from users where name = ?")?;
-        let result: Option<bool> = match stmt.query_row(&[user as &ToSql], |r| -> bool { r.get(0) })
-        {
-            Ok(true) => Some(true),                            // trusted
-            Ok(false) => Some(false),                          // not trusted
-            Err(rusqlite::Error::QueryReturnedNoRows) => None, // not seen
-            Err(e) => bail!(e),
+            .prepare(&format!("select {} from users where name = ?", COLUMNS))?;
+        let mut rows = stmt.query_and_then(
+            &[user as &dyn ToSql],
+            |r| -> Result<(bool, String, bool), AppError> {
+                let f = Fields::new(r, "users", COLUMNS);
+                Ok((f.get(1)?, f.get(2)?, f.get(3)?))
+            },
+        )?;
+        let (trusted, updated, refreshable) = match rows.next() {
+            Some(row) => row?,
+            None => return Ok(Known::Unseen),
         };
-        Ok(result)
+        let stale =
+            refreshable && stale_before.is_some_and(|before| written_before(&updated, &before));
+        if stale {
+            Ok(Known::Stale(trusted))
+        } else {
+            Ok(Known::Trust(trusted))
+        }
     }
 
-    pub fn get_all_games(&self) -> Result<Vec<Game>, Error> {
-        let conn = Connection::open(DB_FILE_NAME)?;
-        let mut stmt = conn.prepare("SELECT id, name, rating, num_votes, bgg_num_votes, bgg_geek_rating, bgg_avg_rating FROM games order by rating desc")?;
-        let games_iter = stmt.query_map(NO_PARAMS, |row| Game {
-            id: row.get(0),
-            name: row.get(1),
-            rating: row.get(2),
-            votes: row.get(3),
-            bgg_num_votes: row.get(4),
-            bgg_geek_rating: row.get(5),
-            bgg_avg_rating: row.get(6),
-            page: 0,
+    pub fn get_all_games(&self, tier: Option<u32>, order: &Order) -> Result<Vec<Game>, AppError> {
+        let filter = GameFilter {
+            tier,
+            max_weight: None,
+            limit: None,
+        };
+        let mut games = Vec::new();
+        self.for_each_game(&filter, order, |game| {
+            games.push(game);
+            Ok(())
         })?;
+        Ok(games)
+    }
+
+    /// Hands games to `f` one by one as they are read, lists of any
+    /// length take the memory of a single game.
+    pub fn for_each_game(
+        &self,
+        filter: &GameFilter,
+        order: &Order,
+        mut f: impl FnMut(Game) -> Result<(), AppError>,
+    ) -> Result<(), AppError> {
+        let query = format!(
+            "SELECT {}, {} FROM games
+            where (?1 is null or tier = ?1) and (?2 is null or weight <= ?2)
+            order by {} limit ?3",
+            GAME_COLUMNS,
+            MEAN_SQ,
+            order.to_sql()?
+        );
+        let mut stmt = self.conn.prepare(&query)?;
+        // negative limit means no limit for sqlite
+        let limit = filter.limit.map_or(-1, i64::from);
+        let games = stmt.query_and_then(
+            &[&filter.tier as &dyn ToSql, &filter.max_weight, &limit],
+            game_from_row,
+        )?;
+        for game in games {
+            f(game?)?;
+        }
+        Ok(())
+    }
+
+    /// Copies every game into a new snapshot, returns its id.
+    pub fn take_snapshot(&self, name: Option<&str>) -> Result<u32, AppError> {
+        let tx = Tx::begin(&self.conn, "begin")?;
+        let id = self.save_snapshot(name)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    fn save_snapshot(&self, name: Option<&str>) -> Result<u32, AppError> {
+        self.conn.execute(
+            "insert into snapshots (taken, name) values (?1, ?2)",
+            &[&Local::now().to_string() as &dyn ToSql, &name],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        let columns = GAME_COLUMNS
+            .replace("coalesce(flagged, 0)", "flagged")
+            .replace("coalesce(sampled, 0)", "sampled");
+        self.conn.execute(
+            &format!(
+                "insert into snapshot_games (snapshot_id, {}, mean_sq) select ?1, {}, {} from games",
+                columns, GAME_COLUMNS, MEAN_SQ
+            ),
+            &[&id],
+        )?;
+        Ok(id as u32)
+    }
+
+    /// Games of the snapshot, in the same shape and order as get_all_games.
+    pub fn get_snapshot(
+        &self,
+        snapshot_id: u32,
+        tier: Option<u32>,
+        order: &Order,
+    ) -> Result<Vec<Game>, AppError> {
+        let query = format!(
+            "SELECT {}, mean_sq FROM snapshot_games where snapshot_id = ?2 and (?1 is null or tier = ?1) order by {}",
+            GAME_COLUMNS,
+            order.to_sql()?
+        );
+        let mut stmt = self.conn.prepare(&query)?;
+        let games_iter =
+            stmt.query_and_then(&[&tier as &dyn ToSql, &snapshot_id], game_from_row)?;
         let mut games = Vec::new();
         for game in games_iter {
             games.push(game?);
@@ -142,12 +1674,115 @@ impl DbConn {
         Ok(games)
     }
 
-    pub fn update_game(&self, game: &Game, stable: bool) -> Result<(), Error> {
+    /// Snapshots, the oldest first.
+    pub fn list_snapshots(&self) -> Result<Vec<Snapshot>, AppError> {
+        const COLUMNS: &str = "s.id, s.taken, s.name, count(g.id)";
+        let mut stmt = self.conn.prepare(&format!(
+            "select {} from snapshots s
+            left join snapshot_games g on g.snapshot_id = s.id
+            group by s.id order by s.id",
+            COLUMNS
+        ))?;
+        let iter = stmt.query_and_then(NO_PARAMS, |r| -> Result<Snapshot, AppError> {
+            let f = Fields::new(r, "snapshots", COLUMNS);
+            Ok(Snapshot {
+                id: f.get(0)?,
+                taken: f.get(1)?,
+                name: f.get(2)?,
+                games: f.count(3)?,
+            })
+        })?;
+        let mut snapshots = Vec::new();
+        for snapshot in iter {
+            snapshots.push(snapshot?);
+        }
+        Ok(snapshots)
+    }
+
+    pub fn set_weight(&self, game_id: u32, weight: Option<f64>) -> Result<(), AppError> {
+        self.conn.execute(
+            "update games set weight = ?1 where id = ?2",
+            &[&weight as &dyn ToSql, &game_id],
+        )?;
+        Ok(())
+    }
+
+    /// Ratings of trusted users.
+    pub fn get_game_ratings(&self, game_id: u32) -> Result<Vec<f64>, AppError> {
+        // the name only tells whose rating can't be read
+        const COLUMNS: &str = "gu.name, gu.rating";
+        let mut stmt = self.conn.prepare(&format!(
+            "select {} from game_users gu
+            join users u on u.name = gu.name
+            where gu.game_id = ? and u.trusted = 1",
+            COLUMNS
+        ))?;
+        let iter = stmt.query_and_then(&[&game_id], |r| -> Result<f64, AppError> {
+            Fields::new(r, "game_users", COLUMNS).get(1)
+        })?;
+        let mut ratings = Vec::new();
+        for rating in iter {
+            ratings.push(rating?);
+        }
+        Ok(ratings)
+    }
+
+    /// Number of trusted ratings and their average.
+    pub fn get_game_average(&self, game_id: u32) -> Result<(u32, f64), AppError> {
+        let result = self.conn.query_row(
+            "select count(*), coalesce(avg(gu.rating), 0.0) from game_users gu
+            join users u on u.name = gu.name
+            where gu.game_id = ? and u.trusted = 1",
+            &[&game_id],
+            |r| (r.get(0), r.get(1)),
+        )?;
+        Ok(result)
+    }
+
+    /// Number and average of every rating of the game, trusted or not.
+    pub fn get_game_all_average(&self, game_id: u32) -> Result<(u32, f64), AppError> {
+        let result = self.conn.query_row(
+            "select count(*), coalesce(avg(rating), 0.0) from game_users where game_id = ?",
+            &[&game_id],
+            |r| (r.get(0), r.get(1)),
+        )?;
+        Ok(result)
+    }
+
+    /// false => the game is on a later page in the db than `game`
+    /// says, nothing is written.
+    pub fn update_game(&self, game: &Game, stable: bool) -> Result<bool, AppError> {
         let now = Local::now();
-        match self.conn.execute("UPDATE games SET page = ?1, stable = ?2, rating = ?3, num_votes = ?4, updated = ?5 WHERE id = ?6",
-                &[&game.page as &ToSql, &stable, &game.rating, &game.votes, &now.to_string(), &game.id]) {
-            Ok(_) => Ok(()),
-            Err(err) => bail!(err)
+        let updated = self.conn.execute("UPDATE games SET page = ?1, stable = ?2, rating = ?3, num_votes = ?4, updated = ?5 WHERE id = ?6 AND page <= ?1",
+                &[&game.page as &dyn ToSql, &stable, &game.rating, &game.votes, &now.to_string(), &game.id])?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        if stable {
+            // the flag of the previous balance is replaced, not kept
+            self.conn.execute(
+                "update games set stabilized_at = ?1, flagged = ?2, polarizing = ?3, sampled = ?4 where id = ?5",
+                &[
+                    &now.to_string() as &dyn ToSql,
+                    &game.flagged,
+                    &game.polarizing,
+                    &game.sampled,
+                    &game.id,
+                ],
+            )?;
         }
+        Ok(true)
     }
 }
+
+/// Someone else moved the game further, e.g. another balance on the
+/// same db. Writing `page` would read counted ratings again.
+pub fn page_went_back(game: &Game, page: u32) -> AppError {
+    AppError::Interrupted(format!(
+        "{} is past page {} in the db, is another balance running?",
+        game.name, page
+    ))
+}
+
+#[cfg(test)]
+mod tests;
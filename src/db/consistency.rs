@@ -0,0 +1,409 @@
+use super::{DbConn, Fields};
+use crate::error::AppError;
+use bgg_swing2::User;
+use rusqlite::NO_PARAMS;
+use std::fmt;
+
+/// Something that should not be in a healthy db.
+pub enum Finding {
+    /// sqlite integrity_check message
+    Corrupted(String),
+    /// unstable game on page < 1
+    BadPage { game_id: u32, page: i64 },
+    /// num_votes or rating differ from a recount of the stored
+    /// ratings with the trust users have now
+    VoteMismatch {
+        game_id: u32,
+        votes: i64, // may be negative in a broken db
+        rating: f64,
+        counted: u32,
+        recounted: f64,
+    },
+    /// rating > 0 but num_votes = 0
+    RatingWithoutVotes { game_id: u32 },
+    /// rating of a user missing from users
+    MissingUser(User),
+    /// ratings of a game missing from games
+    OrphanRatings { game_id: u32, ratings: u32 },
+    /// balanced rating far from BGG average
+    Diverged { game_id: u32, delta: f64 },
+}
+
+impl Finding {
+    fn suggestion(&self) -> &'static str {
+        match self {
+            Finding::Corrupted(_) => "restore top.db from a backup or pull again",
+            Finding::BadPage { .. } => "--repair restarts the game from the first page",
+            Finding::VoteMismatch { .. } | Finding::RatingWithoutVotes { .. } => {
+                "--repair recounts the game from its ratings"
+            }
+            Finding::MissingUser(_) => "pull and balance again to check the user",
+            Finding::OrphanRatings { .. } => "--repair deletes them",
+            Finding::Diverged { .. } => "inspect its ratings by hand",
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::Corrupted(msg) => write!(f, "Integrity check failed: {}", msg)?,
+            Finding::BadPage { game_id, page } => {
+                write!(f, "Game {} is unstable on page {}", game_id, page)?
+            }
+            Finding::VoteMismatch {
+                game_id,
+                votes,
+                rating,
+                counted,
+                recounted,
+            } => write!(
+                f,
+                "Game {} has {} votes rated {:.3}, but {} trusted ratings rated {:.3}",
+                game_id, votes, rating, counted, recounted
+            )?,
+            Finding::RatingWithoutVotes { game_id } => {
+                write!(f, "Game {} has rating without votes", game_id)?
+            }
+            Finding::MissingUser(user) => write!(f, "User {} has ratings but is unknown", user)?,
+            Finding::OrphanRatings { game_id, ratings } => write!(
+                f,
+                "{} ratings belong to game {} that is not in the list",
+                ratings, game_id
+            )?,
+            Finding::Diverged { game_id, delta } => {
+                write!(f, "Game {} is {:+.2} away from BGG average", game_id, delta)?
+            }
+        }
+        write!(f, ", {}.", self.suggestion())
+    }
+}
+
+pub fn check(conn: &DbConn) -> Result<Vec<Finding>, AppError> {
+    let mut findings = Vec::new();
+    let mut stmt = conn.conn.prepare("pragma integrity_check")?;
+    for msg in stmt.query_and_then(NO_PARAMS, |r| -> Result<String, AppError> {
+        Fields::new(r, "pragma", "integrity_check").get(0)
+    })? {
+        let msg = msg?;
+        if msg != "ok" {
+            findings.push(Finding::Corrupted(msg));
+        }
+    }
+    // no point in looking further if sqlite itself is unhappy
+    if !findings.is_empty() {
+        return Ok(findings);
+    }
+
+    const PAGE: &str = "id, page";
+    let mut stmt = conn.conn.prepare(&format!(
+        "select {} from games where not stable and page < 1",
+        PAGE
+    ))?;
+    for pair in stmt.query_and_then(NO_PARAMS, |r| -> Result<(u32, i64), AppError> {
+        let f = Fields::new(r, "games", PAGE);
+        Ok((f.get(0)?, f.get(1)?))
+    })? {
+        let (game_id, page) = pair?;
+        findings.push(Finding::BadPage { game_id, page });
+    }
+
+    // the same recount as DbConn::get_game_average, games balanced
+    // before ratings were stored have no rows to compare with, but
+    // negative votes are wrong anyway
+    const VOTES: &str = "id, num_votes, rating, counted, recounted";
+    let mut stmt = conn.conn.prepare(&format!(
+        "select {} from (
+            select g.id, g.num_votes, g.rating, count(u.name) as counted,
+                coalesce(avg(case when u.name is null then null else gu.rating end), 0.0) as recounted
+            from games g
+            join game_users gu on gu.game_id = g.id
+            left join users u on u.name = gu.name and u.trusted = 1
+            group by g.id
+        ) where num_votes != counted or abs(rating - recounted) > 1e-9
+        union all
+        select id, num_votes, rating, 0, 0.0 from games
+        where num_votes < 0 and id not in (select game_id from game_users)",
+        VOTES
+    ))?;
+    for row in stmt.query_and_then(NO_PARAMS, |r| -> Result<_, AppError> {
+        let f = Fields::new(r, "games", VOTES);
+        Ok((f.get(0)?, f.get(1)?, f.get(2)?, f.get(3)?, f.get(4)?))
+    })? {
+        let (game_id, votes, rating, counted, recounted) = row?;
+        findings.push(Finding::VoteMismatch {
+            game_id,
+            votes,
+            rating,
+            counted,
+            recounted,
+        });
+    }
+
+    let mut stmt = conn
+        .conn
+        .prepare("select id from games where rating > 0 and num_votes = 0")?;
+    for game_id in stmt.query_and_then(NO_PARAMS, |r| -> Result<u32, AppError> {
+        Fields::new(r, "games", "id").get(0)
+    })? {
+        let game_id = game_id?;
+        let counted = findings.iter().any(|f| match f {
+            Finding::VoteMismatch { game_id: id, .. } => *id == game_id,
+            _ => false,
+        });
+        if !counted {
+            findings.push(Finding::RatingWithoutVotes { game_id });
+        }
+    }
+
+    let mut stmt = conn.conn.prepare(
+        "select distinct name from game_users where name not in (select name from users)",
+    )?;
+    for user in stmt.query_and_then(NO_PARAMS, |r| -> Result<User, AppError> {
+        Fields::new(r, "game_users", "name").get(0)
+    })? {
+        findings.push(Finding::MissingUser(user?));
+    }
+
+    const ORPHANS: &str = "game_id, count(*)";
+    let mut stmt = conn.conn.prepare(&format!(
+        "select {} from game_users
+        where game_id not in (select id from games) group by game_id",
+        ORPHANS
+    ))?;
+    for pair in stmt.query_and_then(NO_PARAMS, |r| -> Result<(u32, u32), AppError> {
+        let f = Fields::new(r, "game_users", ORPHANS);
+        Ok((f.get(0)?, f.count(1)?))
+    })? {
+        let (game_id, ratings) = pair?;
+        findings.push(Finding::OrphanRatings { game_id, ratings });
+    }
+
+    const DIVERGED: &str = "id, rating - bgg_avg_rating";
+    let mut stmt = conn
+        .conn
+        .prepare(&format!("select {} from games where flagged", DIVERGED))?;
+    for pair in stmt.query_and_then(NO_PARAMS, |r| -> Result<(u32, f64), AppError> {
+        let f = Fields::new(r, "games", DIVERGED);
+        Ok((f.get(0)?, f.get(1)?))
+    })? {
+        let (game_id, delta) = pair?;
+        findings.push(Finding::Diverged { game_id, delta });
+    }
+    Ok(findings)
+}
+
+/// Applies the fix for a repairable finding, returns false for others.
+pub fn repair(conn: &DbConn, finding: &Finding) -> Result<bool, AppError> {
+    match finding {
+        Finding::BadPage { game_id, .. } => {
+            conn.conn
+                .execute("delete from game_users where game_id = ?", &[game_id])?;
+            conn.conn.execute(
+                "update games set page = 1, num_votes = 0, rating = 0 where id = ?",
+                &[game_id],
+            )?;
+        }
+        Finding::VoteMismatch { game_id, .. } | Finding::RatingWithoutVotes { game_id } => {
+            let (votes, rating) = conn.get_game_average(*game_id)?;
+            conn.conn.execute(
+                "update games set num_votes = ?1, rating = ?2 where id = ?3",
+                &[&votes as &dyn rusqlite::types::ToSql, &rating, game_id],
+            )?;
+        }
+        Finding::OrphanRatings { game_id, .. } => {
+            conn.conn
+                .execute("delete from game_users where game_id = ?", &[game_id])?;
+        }
+        Finding::Corrupted(_) | Finding::MissingUser(_) | Finding::Diverged { .. } => {
+            return Ok(false)
+        }
+    }
+    Ok(true)
+}
+
+/// Findings left after a check fail it with an error of their own,
+/// scripts tell them from a check that could not run.
+pub fn verdict(left: &[Finding]) -> Result<(), AppError> {
+    if left.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::Inconsistent(format!(
+        "Found {} problems.",
+        left.len()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{add_games, DbConn};
+    use crate::testing::TestDb;
+    use bgg_swing2::Game;
+
+    /// Game 1 rated 8 by a trusted user and 2 by an untrusted one.
+    fn rated() -> DbConn {
+        let game = Game::from_search(1, String::from("Game 1"), 1000, 7.0, 7.5);
+        add_games(vec![game.clone()], 100, "search").unwrap();
+        let conn = DbConn::new().unwrap();
+        conn.add_user(&User::from("fan"), true, Some(6.0), None)
+            .unwrap();
+        conn.add_user(&User::from("bot"), false, Some(9.9), None)
+            .unwrap();
+        let page = vec![(User::from("fan"), 8.0), (User::from("bot"), 2.0)];
+        conn.commit_page(&game, &page, 2, false).unwrap();
+        conn
+    }
+
+    fn run(conn: &DbConn, sql: &str) {
+        conn.conn.execute_batch(sql).unwrap();
+    }
+
+    /// Checks, repairs what can be repaired and checks again.
+    fn repaired(conn: &DbConn) -> Vec<Finding> {
+        for finding in check(conn).unwrap() {
+            repair(conn, &finding).unwrap();
+        }
+        check(conn).unwrap()
+    }
+
+    #[test]
+    fn healthy_db_has_no_findings() {
+        let _db = TestDb::new();
+        assert!(check(&rated()).unwrap().is_empty());
+        assert!(verdict(&[]).is_ok());
+    }
+
+    #[test]
+    fn findings_left_have_their_own_error() {
+        let _db = TestDb::new();
+        let conn = rated();
+        run(
+            &conn,
+            "update games set num_votes = 0, rating = 8.0 where id = 1",
+        );
+        run(&conn, "delete from users where name = 'fan'");
+        let left = check(&conn).unwrap();
+        assert_eq!(left.len(), 2);
+        match verdict(&left) {
+            Err(e @ AppError::Inconsistent(_)) => {
+                assert_eq!(e.to_string(), "Found 2 problems.");
+                assert_eq!(e.exit_code(), 8);
+                assert_eq!(e.category(), "inconsistent");
+            }
+            other => panic!("{:?}", other),
+        }
+        // a missing user is never repaired
+        match &repaired(&conn)[..] {
+            [finding @ Finding::MissingUser(_)] => {
+                assert!(matches!(
+                    verdict(std::slice::from_ref(finding)),
+                    Err(AppError::Inconsistent(_))
+                ))
+            }
+            _ => panic!("expected a missing user"),
+        }
+    }
+
+    #[test]
+    fn trust_flip_is_recounted() {
+        let _db = TestDb::new();
+        let conn = rated();
+        run(&conn, "update users set trusted = 1 where name = 'bot'");
+        match &check(&conn).unwrap()[..] {
+            [Finding::VoteMismatch {
+                game_id: 1,
+                votes: 1,
+                counted: 2,
+                recounted,
+                ..
+            }] => assert!((recounted - 5.0).abs() < 1e-9),
+            _ => panic!("expected a vote mismatch"),
+        }
+        assert!(repaired(&conn).is_empty());
+        assert_eq!(conn.get_game_average(1).unwrap(), (2, 5.0));
+    }
+
+    #[test]
+    fn rating_off_with_right_votes_is_a_mismatch() {
+        let _db = TestDb::new();
+        let conn = rated();
+        run(&conn, "update games set rating = 9.0 where id = 1");
+        match &check(&conn).unwrap()[..] {
+            [Finding::VoteMismatch {
+                counted: 1, rating, ..
+            }] => assert_eq!(*rating, 9.0),
+            _ => panic!("expected a vote mismatch"),
+        }
+        assert!(repaired(&conn).is_empty());
+    }
+
+    #[test]
+    fn orphan_ratings_are_found_and_deleted() {
+        let _db = TestDb::new();
+        let conn = rated();
+        run(
+            &conn,
+            "insert into game_users (game_id, name, rating) values (7, 'fan', 6.0), (7, 'bot', 3.0)",
+        );
+        match &check(&conn).unwrap()[..] {
+            [Finding::OrphanRatings {
+                game_id: 7,
+                ratings: 2,
+            }] => (),
+            _ => panic!("expected orphan ratings"),
+        }
+        assert!(repaired(&conn).is_empty());
+        let left: u32 = conn
+            .conn
+            .query_row("select count(*) from game_users", NO_PARAMS, |r| r.get(0))
+            .unwrap();
+        assert_eq!(left, 2);
+    }
+
+    #[test]
+    fn bad_page_restarts_the_game() {
+        let _db = TestDb::new();
+        let conn = rated();
+        run(&conn, "update games set page = 0 where id = 1");
+        match &check(&conn).unwrap()[..] {
+            [Finding::BadPage {
+                game_id: 1,
+                page: 0,
+            }] => (),
+            _ => panic!("expected a bad page"),
+        }
+        assert!(repaired(&conn).is_empty());
+        assert_eq!(conn.get_game_average(1).unwrap(), (0, 0.0));
+    }
+
+    #[test]
+    fn null_in_a_checked_column_is_named() {
+        let _db = TestDb::new();
+        let conn = rated();
+        run(
+            &conn,
+            "update games set flagged = 1, bgg_avg_rating = null where id = 1",
+        );
+        match check(&conn) {
+            Err(e) => assert!(
+                e.to_string()
+                    .contains("Can't read games.rating - bgg_avg_rating of 1"),
+                "{}",
+                e
+            ),
+            Ok(_) => panic!("null read as a finding"),
+        }
+    }
+
+    #[test]
+    fn missing_user_is_left_to_balance() {
+        let _db = TestDb::new();
+        let conn = rated();
+        run(&conn, "delete from users where name = 'bot'");
+        match &repaired(&conn)[..] {
+            [Finding::MissingUser(user)] => assert_eq!(user, "bot"),
+            _ => panic!("expected the missing user"),
+        }
+    }
+}
@@ -0,0 +1,682 @@
+use super::*;
+use crate::testing::TestDb;
+
+fn game(id: u32) -> Game {
+    Game::from_search(id, format!("Game {}", id), 1000, 7.0, 7.5)
+}
+
+fn ratings(users: &[&str], rating: f64) -> Vec<(User, f64)> {
+    users.iter().map(|u| (User::from(*u), rating)).collect()
+}
+
+fn count(conn: &DbConn, sql: &str) -> u32 {
+    conn.conn.query_row(sql, NO_PARAMS, |r| r.get(0)).unwrap()
+}
+
+#[test]
+fn prune_keeps_rating_and_manual_users() {
+    let _db = TestDb::new();
+    add_games(vec![game(1)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    for user in &["rater", "gone", "by_hand"] {
+        conn.add_user(&User::from(*user), true, Some(6.0), None)
+            .unwrap();
+    }
+    conn.set_user_trust(&User::from("by_hand"), false, &|_| false)
+        .unwrap();
+    conn.commit_page(&game(1), &ratings(&["rater"], 7.0), 2, false)
+        .unwrap();
+
+    assert_eq!(conn.prune_users().unwrap(), 1);
+    let left: Vec<String> = conn
+        .conn
+        .prepare("select name from users order by name")
+        .unwrap()
+        .query_map(NO_PARAMS, |r| r.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(left, ["by_hand", "rater"]);
+}
+
+#[test]
+fn failed_page_leaves_nothing_behind() {
+    let _db = TestDb::new();
+    add_games(vec![game(1)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    conn.add_user(&User::from("a"), true, Some(6.0), None)
+        .unwrap();
+    conn.add_user(&User::from("late"), true, Some(6.0), None)
+        .unwrap();
+    conn.commit_page(&game(1), &ratings(&["a"], 7.0), 3, false)
+        .unwrap();
+
+    // another balance is past this page already
+    let stale = Game { page: 1, ..game(1) };
+    let e = conn
+        .commit_page(&stale, &ratings(&["late"], 2.0), 2, false)
+        .unwrap_err();
+    assert!(e.to_string().contains("is past page 2"), "{}", e);
+    assert_eq!(count(&conn, "select count(*) from game_users"), 1);
+    assert_eq!(count(&conn, "select page from games where id = 1"), 3);
+    // the connection is usable, no transaction was left open
+    conn.commit_page(&game(1), &ratings(&["late"], 2.0), 4, false)
+        .unwrap();
+}
+
+#[test]
+fn page_counts_trusted_ratings_only() {
+    let _db = TestDb::new();
+    add_games(vec![game(1), game(2)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    conn.add_user(&User::from("fan"), true, Some(6.0), None)
+        .unwrap();
+    conn.add_user(&User::from("bot"), false, Some(9.9), None)
+        .unwrap();
+    let mut page = ratings(&["fan"], 8.0);
+    page.extend(ratings(&["bot"], 1.0));
+    assert_eq!(
+        conn.commit_page(&game(1), &page, 2, false).unwrap(),
+        (1, 8.0)
+    );
+    // a page of untrusted users only counts nothing, yet is stored
+    assert_eq!(
+        conn.commit_page(&game(2), &ratings(&["bot"], 1.0), 2, false)
+            .unwrap(),
+        (0, 0.0)
+    );
+    assert_eq!(count(&conn, "select count(*) from game_users"), 3);
+}
+
+#[test]
+fn games_and_snapshots_keep_the_highest_tier() {
+    let _db = TestDb::new();
+    add_games(vec![game(1), game(2)], 1000, "search").unwrap();
+    add_games(vec![game(1)], 5000, "search").unwrap();
+    add_games(vec![game(2)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    let tiers = |games: Vec<Game>| -> Vec<_> { games.iter().map(|g| (g.id, g.tier)).collect() };
+    let order = Order::default();
+    let expected = vec![(1, Some(5000)), (2, Some(1000))];
+    let mut current = tiers(conn.get_all_games(None, &order).unwrap());
+    current.sort();
+    assert_eq!(current, expected);
+    let id = conn.take_snapshot(None).unwrap();
+    let mut saved = tiers(conn.get_snapshot(id, None, &order).unwrap());
+    saved.sort();
+    assert_eq!(saved, expected);
+}
+
+#[test]
+fn games_read_back_with_the_defaults_of_their_context() {
+    let _db = TestDb::new();
+    let pulled = Game {
+        year: Some(1995),
+        ..game(1)
+    };
+    add_games(vec![pulled], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+
+    // balancing gets what it resumes from, BGG geek rating isn't needed
+    let unstable = conn.get_unstable_games(None, None, None).unwrap();
+    let expected = Game {
+        bgg_geek_rating: 0.0,
+        ..game(1)
+    };
+    assert_eq!(unstable, [expected]);
+
+    // reports get everything the db has
+    let all = conn.get_all_games(None, &Order::default()).unwrap();
+    let expected = Game {
+        year: Some(1995),
+        tier: Some(100),
+        ..game(1)
+    };
+    assert_eq!(all, [expected]);
+}
+
+#[test]
+fn game_page_never_goes_back() {
+    let _db = TestDb::new();
+    add_games(vec![game(1)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    // another run got the game to page 5
+    let ahead = Game { page: 5, ..game(1) };
+    assert!(conn.update_game(&ahead, false).unwrap());
+
+    let behind = Game { page: 2, ..game(1) };
+    assert!(!conn.update_game(&behind, true).unwrap());
+    let err = conn
+        .commit_page(&behind, &ratings(&["late"], 7.0), 3, false)
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        page_went_back(&behind, 3).to_string(),
+        "{:?}",
+        err
+    );
+    assert_eq!(count(&conn, "select count(*) from game_users"), 0);
+    let mut finished = behind.clone();
+    assert!(!conn.commit_finish(&mut finished, |_, _| ()).unwrap());
+    assert_eq!(count(&conn, "select page from games where id = 1"), 5);
+    assert_eq!(count(&conn, "select stable from games where id = 1"), 0);
+}
+
+#[test]
+fn trust_set_by_hand_keeps_stats_and_is_never_stale() {
+    let _db = TestDb::new();
+    let conn = DbConn::new().unwrap();
+    let user = User::from("veteran");
+    conn.add_user(&user, true, Some(6.5), Some(2004)).unwrap();
+    conn.set_user_trust(&user, false, &|_| false).unwrap();
+
+    let stored: (bool, f64, u32, bool) = conn
+        .conn
+        .query_row(
+            "select trusted, average, year_registered, manual from users where name = 'veteran'",
+            NO_PARAMS,
+            |r| (r.get(0), r.get(1), r.get(2), r.get(3)),
+        )
+        .unwrap();
+    assert_eq!(stored, (false, 6.5, 2004, true));
+    // balance never asks BGG about it again
+    let later = Local::now() + chrono::Duration::days(1);
+    let known = conn.check_user(&user, Some(later)).unwrap();
+    assert!(matches!(known, Known::Trust(false)));
+}
+
+#[test]
+fn refresh_keeps_trust_set_by_hand() {
+    let _db = TestDb::new();
+    let conn = DbConn::new().unwrap();
+    let user = User::from("veteran");
+    conn.add_user(&user, true, Some(6.5), None).unwrap();
+    conn.set_user_trust(&user, false, &|_| false).unwrap();
+    assert_eq!(
+        conn.refresh_user(&user, true, 7.0, Some(2010), &|_| false)
+            .unwrap(),
+        0
+    );
+    let stored: (bool, f64, u32) = conn
+        .conn
+        .query_row(
+            "select trusted, average, year_registered from users where name = 'veteran'",
+            NO_PARAMS,
+            |r| (r.get(0), r.get(1), r.get(2)),
+        )
+        .unwrap();
+    assert_eq!(stored, (false, 7.0, 2010));
+}
+
+#[test]
+fn trust_change_recounts_games_in_place() {
+    let _db = TestDb::new();
+    add_games(vec![game(1), game(2)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    for user in &["fan", "shill"] {
+        conn.add_user(&User::from(*user), true, Some(6.0), None)
+            .unwrap();
+    }
+    let mut page = ratings(&["fan"], 6.0);
+    page.extend(ratings(&["shill"], 10.0));
+    conn.commit_page(&game(1), &page, 3, false).unwrap();
+    let mut stable = Game { page: 3, ..game(1) };
+    assert!(conn.commit_finish(&mut stable, |_, _| {}).unwrap());
+    conn.commit_page(&game(2), &ratings(&["shill"], 10.0), 2, false)
+        .unwrap();
+
+    assert_eq!(
+        conn.set_user_trust(&User::from("shill"), false, &|_| true)
+            .unwrap(),
+        2
+    );
+    let games: Vec<(u32, bool, u32, u32, f64)> = conn
+        .conn
+        .prepare("select id, stable, page, num_votes, rating from games order by id")
+        .unwrap()
+        .query_map(NO_PARAMS, |r| {
+            (r.get(0), r.get(1), r.get(2), r.get(3), r.get(4))
+        })
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    // nothing is fetched again, the stable game stays stable
+    assert_eq!(games, [(1, true, 3, 1, 6.0), (2, false, 2, 0, 0.0)]);
+    // only a game that is done gets a flag, others when they are
+    assert_eq!(count(&conn, "select count(*) from games where flagged"), 1);
+    assert_eq!(count(&conn, "select flagged from games where id = 1"), 1);
+
+    assert_eq!(
+        conn.update_user_trust(&User::from("shill"), true, &|_| false)
+            .unwrap(),
+        2
+    );
+    assert_eq!(conn.get_game_average(1).unwrap(), (2, 8.0));
+    assert_eq!(count(&conn, "select num_votes from games where id = 2"), 1);
+}
+
+#[test]
+fn copy_has_what_the_wal_still_holds() {
+    let _db = TestDb::new();
+    // an open writer keeps its pages in the wal file
+    let writer = Connection::open(db_path()).unwrap();
+    writer
+        .execute_batch("pragma wal_autocheckpoint = 0")
+        .unwrap();
+    writer
+        .execute(
+            "insert into games (id, name, stable, page) values (7, 'Fresh', 0, 1)",
+            NO_PARAMS,
+        )
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("copy.db");
+    copy_db(db_path(), &output).unwrap();
+
+    let copy = DbConn::open_read_only(&output).unwrap();
+    assert_eq!(count(&copy, "select count(*) from games where id = 7"), 1);
+    assert_eq!(copy.schema_version().unwrap(), SCHEMA_VERSION);
+}
+
+#[test]
+fn reset_games_read_their_ratings_anew() {
+    let _db = TestDb::new();
+    add_games(vec![game(1), game(2), game(3)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    conn.add_user(&User::from("a"), true, Some(6.0), None)
+        .unwrap();
+    for id in 1..=3 {
+        conn.commit_page(&game(id), &ratings(&["a"], 7.0), 2, false)
+            .unwrap();
+    }
+    conn.conn
+        .execute_batch(
+            "update games set stable = 1;
+            update games set watched = 1 where id = 1;
+            update games set sampled = 1 where id = 2;",
+        )
+        .unwrap();
+    let rated = |id| {
+        count(
+            &conn,
+            &format!("select count(*) from game_users where game_id = {}", id),
+        )
+    };
+
+    assert_eq!(conn.reset_watched_games().unwrap(), 1);
+    assert_eq!((rated(1), rated(2), rated(3)), (0, 1, 1));
+    assert_eq!(conn.reset_sampled_games().unwrap(), 1);
+    assert_eq!((rated(1), rated(2), rated(3)), (0, 0, 1));
+    assert_eq!(
+        count(
+            &conn,
+            "select count(*) from games where not stable and page = 1"
+        ),
+        2
+    );
+}
+
+#[test]
+fn seed_gives_its_own_order_every_time() {
+    let _db = TestDb::new();
+    add_games((1..=20).map(game).collect(), 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    let order = |seed| -> Vec<u32> {
+        conn.get_unstable_games(None, None, seed)
+            .unwrap()
+            .iter()
+            .map(|g| g.id)
+            .collect()
+    };
+
+    let first = order(Some(1));
+    assert_eq!(order(Some(1)), first);
+    let mut sorted = first.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, (1..=20).collect::<Vec<u32>>());
+    assert_ne!(first, sorted);
+    // the next seed starts elsewhere, not just a step further
+    let second = order(Some(2));
+    assert_ne!(second, first);
+    assert_ne!(second[0], first[1]);
+    // large seeds shuffle as well, the hash doesn't overflow into
+    // the same key for every game
+    for seed in [4_000_000_000, u32::MAX] {
+        let large = order(Some(seed));
+        assert_eq!(order(Some(seed)), large);
+        let mut sorted_large = large.clone();
+        sorted_large.sort_unstable();
+        assert_eq!(sorted_large, sorted);
+        assert_ne!(large, sorted, "seed {}", seed);
+    }
+}
+
+#[test]
+fn failed_snapshot_leaves_nothing_behind() {
+    let _db = TestDb::new();
+    add_games(vec![game(1)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    assert_eq!(conn.take_snapshot(Some("first")).unwrap(), 1);
+    conn.conn
+        .execute_batch("alter table snapshot_games rename to kept")
+        .unwrap();
+
+    assert!(conn.take_snapshot(Some("broken")).is_err());
+    assert_eq!(count(&conn, "select count(*) from snapshots"), 1);
+    // no transaction was left open
+    conn.conn
+        .execute_batch("alter table kept rename to snapshot_games")
+        .unwrap();
+    assert_eq!(conn.take_snapshot(None).unwrap(), 2);
+    assert_eq!(count(&conn, "select count(*) from snapshot_games"), 2);
+}
+
+/// `select` of literal values mapped by `from_row`, no tables needed.
+fn mock_row<T>(select: &str, from_row: fn(&Row) -> Result<T, AppError>) -> Result<T, AppError> {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.query_row(select, NO_PARAMS, from_row).unwrap()
+}
+
+#[test]
+fn unstable_row_maps_every_column() {
+    let game = mock_row(
+        "select 7, 'Brass', 3, 250, 8.1, 3.9, 8.0, 8.05, 40000",
+        unstable_game_from_row,
+    )
+    .unwrap();
+    let expected = Game {
+        page: 3,
+        votes: 250,
+        rating: 8.1,
+        weight: Some(3.9),
+        previous_rating: Some(8.0),
+        bgg_avg_rating: 8.05,
+        bgg_num_votes: 40000,
+        ..Game::new(7, String::from("Brass"))
+    };
+    assert_eq!(game, expected);
+
+    // nullable columns and counts gone below zero
+    let game = mock_row(
+        "select 7, 'Brass', 1, -5, 0.0, null, null, 8.05, -1",
+        unstable_game_from_row,
+    )
+    .unwrap();
+    assert_eq!((game.weight, game.previous_rating), (None, None));
+    assert_eq!((game.votes, game.bgg_num_votes), (0, 0));
+}
+
+#[test]
+fn negative_count_is_complained_about() {
+    let (game, warnings) = runlog::catch(|| {
+        mock_row(
+            "select 7, 'Brass', 1, -5, 0.0, null, null, 8.05, 100",
+            unstable_game_from_row,
+        )
+        .unwrap()
+    });
+    assert_eq!(game.votes, 0);
+    assert_eq!(
+        warnings,
+        ["Warning: games.num_votes of 7 is -5, read as 0."]
+    );
+}
+
+#[test]
+fn unreadable_row_names_its_column() {
+    let e = mock_row(
+        "select 7, null, 1, 0, 0.0, null, null, 8.05, 100",
+        unstable_game_from_row,
+    )
+    .unwrap_err();
+    assert!(
+        e.to_string().contains("Can't read games.name of 7"),
+        "{}",
+        e
+    );
+    let e = mock_row(
+        "select 7, 'Brass', 'first', 0, 0.0, null, null, 8.05, 100",
+        unstable_game_from_row,
+    )
+    .unwrap_err();
+    assert!(
+        e.to_string().contains("Can't read games.page of 7"),
+        "{}",
+        e
+    );
+}
+
+#[test]
+fn null_in_the_db_names_its_column() {
+    let _db = TestDb::new();
+    add_games(vec![game(1)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    conn.add_user(&User::from("fan"), true, Some(6.0), None)
+        .unwrap();
+    conn.commit_page(&game(1), &ratings(&["fan"], 7.0), 2, false)
+        .unwrap();
+    conn.take_snapshot(None).unwrap();
+    conn.conn
+        .execute_batch(
+            "update games set stable = 1, rating = null, notes = 'mine', watched = 1;
+            update users set updated = null;
+            update game_users set rating = null;
+            update snapshots set taken = null;",
+        )
+        .unwrap();
+
+    fn unreadable<T>(read: Result<T, AppError>, what: &str) {
+        match read {
+            Err(e) => assert!(
+                e.to_string().contains(&format!("Can't read {}", what)),
+                "{}",
+                e
+            ),
+            Ok(_) => panic!("{} read as a value", what),
+        }
+    }
+    unreadable(get_stable_ratings(), "games.rating of 1");
+    let stale = Some(Local::now());
+    unreadable(
+        conn.check_user(&User::from("fan"), stale),
+        "users.updated of fan",
+    );
+    unreadable(conn.get_game_ratings(1), "game_users.gu.rating of fan");
+    unreadable(conn.list_snapshots(), "snapshots.s.taken of 1");
+
+    conn.conn
+        .execute_batch("update games set notes = x'ff'")
+        .unwrap();
+    unreadable(conn.get_game_notes(1), "games.notes of 1");
+    unreadable(get_local_state_from("search"), "games.notes of 1");
+}
+
+#[test]
+fn full_row_maps_every_column() {
+    let game = mock_row(
+        "select 7, 'Brass', 8.0, 2, 40000, 7.9, 8.05, 3.9, 7.5, 1, 1, 0.4, 0, 2018, 7.8, 5, 100, 64.5",
+        game_from_row,
+    )
+    .unwrap();
+    let expected = Game {
+        rating: 8.0,
+        votes: 2,
+        weight: Some(3.9),
+        previous_rating: Some(7.5),
+        // sample sd of two votes averaging 8.0 with mean square 64.5
+        rating_sd: Some(1.0),
+        stable: true,
+        flagged: true,
+        polarizing: Some(0.4),
+        year: Some(2018),
+        all_rating: Some(7.8),
+        all_votes: Some(5),
+        tier: Some(100),
+        ..Game::from_search(7, String::from("Brass"), 40000, 7.9, 8.05)
+    };
+    assert_eq!(game, expected);
+}
+
+fn sampled_pages(conn: &DbConn, game_id: u32) -> Vec<u32> {
+    conn.conn
+        .prepare("select page from sampled_pages where game_id = ?1 order by page")
+        .unwrap()
+        .query_map(&[&game_id], |r| r.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+}
+
+#[test]
+fn converted_pages_hold_the_same_ratings() {
+    assert_eq!(converted_pages(1, 100, 50), 1..=2);
+    assert_eq!(converted_pages(4, 100, 50), 7..=8);
+    assert_eq!(converted_pages(3, 100, 30), 7..=10);
+    assert_eq!(converted_pages(7, 25, 100), 2..=2);
+    assert_eq!(converted_pages(2, 50, 50), 2..=2);
+}
+
+#[test]
+fn sampled_pages_are_converted_with_the_page_counter() {
+    let _db = TestDb::new();
+    add_games(vec![game(1), game(2), game(3)], 100, "search").unwrap();
+    let conn = DbConn::new().unwrap();
+    conn.conn
+        .execute_batch(
+            "update games set page = 4, sampled = 1 where id in (1, 2);
+            update games set stable = 1 where id = 2;
+            update games set page = 2, page_size = 25 where id = 3;
+            insert into sampled_pages (game_id, page) values (1, 1), (1, 3), (2, 1), (2, 3), (3, 1);",
+        )
+        .unwrap();
+    // game 3 already counts in pages of 25
+    assert_eq!(conn.convert_pages(50).unwrap(), 2);
+    assert_eq!(sampled_pages(&conn, 1), [1, 2, 5, 6]);
+    assert_eq!(count(&conn, "select page from games where id = 1"), 7);
+    // stable games keep the pages they were read with
+    assert_eq!(sampled_pages(&conn, 2), [1, 3]);
+    assert_eq!(count(&conn, "select page from games where id = 2"), 4);
+    assert_eq!(sampled_pages(&conn, 3), [1]);
+    assert_eq!(count(&conn, "select page from games where id = 3"), 1);
+    // nothing left to convert
+    assert_eq!(conn.convert_pages(50).unwrap(), 0);
+    assert_eq!(sampled_pages(&conn, 1), [1, 2, 5, 6]);
+}
+
+fn users_of(conn: &DbConn, filter: &UserFilter) -> Vec<(String, u32)> {
+    let mut users = Vec::new();
+    conn.for_each_user(filter, |u| {
+        users.push((u.name, u.ratings));
+        Ok(())
+    })
+    .unwrap();
+    users
+}
+
+#[test]
+fn users_are_filtered_sorted_and_paged() {
+    use chrono::TimeZone;
+    let _db = TestDb::new();
+    let conn = DbConn::new().unwrap();
+    let day = |year| Local.with_ymd_and_hms(year, 6, 1, 0, 0, 0).unwrap();
+    // name, trusted, average, updated, games rated, trust set by hand
+    let users = [
+        ("a", true, 6.0, 2020, 1, false),
+        ("b", false, 8.0, 2021, 0, true),
+        ("c", true, 7.0, 2022, 2, true),
+        ("d", false, 5.0, 2023, 3, false),
+    ];
+    for (name, trusted, average, year, rated, manual) in users {
+        conn.add_user(&User::from(name), trusted, Some(average), None)
+            .unwrap();
+        conn.conn
+            .execute(
+                "update users set updated = ?2, manual = ?3 where name = ?1",
+                &[&name as &dyn ToSql, &day(year).to_string(), &manual],
+            )
+            .unwrap();
+        for game_id in 0..rated {
+            conn.conn
+                .execute(
+                    "insert into game_users (game_id, name, rating) values (?1, ?2, 7)",
+                    &[&game_id as &dyn ToSql, &name],
+                )
+                .unwrap();
+        }
+    }
+
+    for (trusted, pinned) in [None, Some(true), Some(false)]
+        .iter()
+        .flat_map(|&t| [(t, false), (t, true)])
+    {
+        for before in [None, Some(2022)] {
+            for (sort, order) in [("updated", "abcd"), ("rating", "bcad"), ("name", "abcd")] {
+                for (limit, offset) in [(None, 0), (Some(1), 0), (Some(2), 1), (None, 3)] {
+                    let filter = UserFilter {
+                        trusted,
+                        pinned,
+                        updated_before: before.map(day),
+                        sort: String::from(sort),
+                        limit,
+                        offset,
+                    };
+                    let expected: Vec<(String, u32)> = order
+                        .chars()
+                        .map(|c| users.iter().find(|u| u.0 == c.to_string()).unwrap())
+                        .filter(|u| trusted.is_none_or(|t| u.1 == t))
+                        .filter(|u| before.is_none_or(|b| u.3 < b))
+                        .filter(|u| u.5 || !pinned)
+                        .skip(offset as usize)
+                        .take(limit.map_or(usize::MAX, |l| l as usize))
+                        .map(|u| (String::from(u.0), u.4))
+                        .collect();
+                    assert_eq!(
+                        users_of(&conn, &filter),
+                        expected,
+                        "trusted {:?}, pinned {}, before {:?}, by {}, limit {:?} offset {}",
+                        trusted,
+                        pinned,
+                        before,
+                        sort,
+                        limit,
+                        offset
+                    );
+                }
+            }
+        }
+    }
+    let filter = UserFilter {
+        trusted: None,
+        pinned: false,
+        updated_before: None,
+        sort: String::from("name"),
+        limit: None,
+        offset: 0,
+    };
+    let mut pinned = Vec::new();
+    conn.for_each_user(&filter, |u| {
+        pinned.push(u.pinned);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(pinned, [false, true, true, false]);
+}
+
+#[test]
+fn ratings_of_a_user_are_counted_by_index() {
+    let _db = TestDb::new();
+    let conn = DbConn::new().unwrap();
+    let plan: Vec<String> = conn
+        .conn
+        .prepare("explain query plan select count(*) from game_users gu where gu.name = 'a'")
+        .unwrap()
+        .query_map(NO_PARAMS, |r| r.get(3))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert!(
+        plan.iter().any(|step| step.contains("game_users_name")),
+        "{:?}",
+        plan
+    );
+}
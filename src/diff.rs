@@ -0,0 +1,164 @@
+use bgg_swing2::Game;
+use std::collections::{HashMap, HashSet};
+
+/// Game found in both dbs with the rating each gave it.
+pub struct Shared {
+    pub id: u32,
+    pub name: String,
+    pub rating: f64,
+    pub other_rating: f64,
+}
+
+impl Shared {
+    pub fn difference(&self) -> f64 {
+        self.other_rating - self.rating
+    }
+}
+
+/// Outcome of two balance runs over the same pulled list.
+pub struct DbDiff {
+    /// shared games further apart than the threshold, largest gap first
+    pub differing: Vec<Shared>,
+    /// games missing from the other db, in list order
+    pub only_this: Vec<Game>,
+    /// games missing from this db, in list order
+    pub only_other: Vec<Game>,
+    pub shared: usize,
+    /// None when no game is shared
+    pub mean_abs_difference: Option<f64>,
+    /// Spearman correlation of shared ratings, None for fewer than
+    /// two games or when one side rates them all the same
+    pub rank_correlation: Option<f64>,
+}
+
+/// Joins both lists on game id.
+pub fn diff(this: &[Game], other: &[Game], threshold: f64) -> DbDiff {
+    let others: HashMap<u32, &Game> = other.iter().map(|g| (g.id, g)).collect();
+    let shared: Vec<Shared> = this
+        .iter()
+        .filter_map(|game| {
+            others.get(&game.id).map(|o| Shared {
+                id: game.id,
+                name: game.name.clone(),
+                rating: game.rating,
+                other_rating: o.rating,
+            })
+        })
+        .collect();
+    let mean_abs_difference = if shared.is_empty() {
+        None
+    } else {
+        let total: f64 = shared.iter().map(|s| s.difference().abs()).sum();
+        Some(total / shared.len() as f64)
+    };
+    let ratings: Vec<f64> = shared.iter().map(|s| s.rating).collect();
+    let other_ratings: Vec<f64> = shared.iter().map(|s| s.other_rating).collect();
+    let rank_correlation = spearman(&ratings, &other_ratings);
+    let count = shared.len();
+    let mut differing: Vec<Shared> = shared
+        .into_iter()
+        .filter(|s| s.difference().abs() > threshold)
+        .collect();
+    differing.sort_by(|a, b| b.difference().abs().total_cmp(&a.difference().abs()));
+    let ids: HashSet<u32> = this.iter().map(|g| g.id).collect();
+    DbDiff {
+        differing,
+        only_this: this
+            .iter()
+            .filter(|g| !others.contains_key(&g.id))
+            .cloned()
+            .collect(),
+        only_other: other
+            .iter()
+            .filter(|g| !ids.contains(&g.id))
+            .cloned()
+            .collect(),
+        shared: count,
+        mean_abs_difference,
+        rank_correlation,
+    }
+}
+
+/// Spearman's rho: Pearson correlation of the ranks, tied values
+/// share the average of the ranks they take.
+pub fn spearman(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() != b.len() || a.len() < 2 {
+        return None;
+    }
+    pearson(&ranks(a), &ranks(b))
+}
+
+/// 1-based ranks of `values`, ascending.
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&x, &y| values[x].total_cmp(&values[y]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start;
+        while end + 1 < order.len() && values[order[end + 1]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end) as f64 / 2.0 + 1.0;
+        for &i in &order[start..=end] {
+            ranks[i] = rank;
+        }
+        start = end + 1;
+    }
+    ranks
+}
+
+fn pearson(x: &[f64], y: &[f64]) -> Option<f64> {
+    let n = x.len() as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (a, b) in x.iter().zip(y) {
+        cov += (a - mean_x) * (b - mean_y);
+        var_x += (a - mean_x).powi(2);
+        var_y += (b - mean_y).powi(2);
+    }
+    if var_x == 0.0 || var_y == 0.0 {
+        return None;
+    }
+    Some(cov / (var_x * var_y).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(rho: Option<f64>, expected: f64) {
+        let rho = rho.expect("no correlation");
+        assert!((rho - expected).abs() < 1e-12, "{} != {}", rho, expected);
+    }
+
+    #[test]
+    fn spearman_without_ties() {
+        close(spearman(&[1.0, 2.0, 3.0, 4.0], &[5.0, 6.0, 7.0, 8.0]), 1.0);
+        // one swap of four: 1 - 6 * 2 / (4 * 15)
+        close(
+            spearman(&[1.0, 2.0, 3.0, 4.0], &[10.0, 20.0, 40.0, 30.0]),
+            0.8,
+        );
+    }
+
+    #[test]
+    fn spearman_with_ties() {
+        assert_eq!(ranks(&[7.0, 8.0, 7.0, 6.0]), [2.5, 4.0, 2.5, 1.0]);
+        // ranks 1, 2.5, 2.5, 4 against 1, 2, 3, 4
+        close(
+            spearman(&[1.0, 2.0, 2.0, 3.0], &[1.0, 2.0, 3.0, 4.0]),
+            0.9f64.sqrt(),
+        );
+        // one side rates them all the same
+        assert_eq!(spearman(&[7.0, 7.0, 7.0], &[1.0, 2.0, 3.0]), None);
+    }
+
+    #[test]
+    fn spearman_reversed() {
+        close(spearman(&[1.0, 2.0, 3.0, 4.0], &[9.0, 8.5, 8.0, 7.0]), -1.0);
+        assert_eq!(spearman(&[1.0], &[9.0]), None);
+        assert_eq!(spearman(&[1.0, 2.0], &[9.0]), None);
+    }
+}
@@ -0,0 +1,156 @@
+use reqwest::StatusCode;
+use std::error::Error as StdError;
+use std::io;
+use std::num::{ParseFloatError, ParseIntError};
+use thiserror::Error;
+
+/// Every error of the application, by where it comes from.
+#[derive(Debug, Error)]
+pub enum AppError {
+    /// Config, command line or files the user can fix.
+    #[error("{0}")]
+    Config(String),
+    #[error(transparent)]
+    Db(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Http(#[from] BggError),
+    /// Data that doesn't look the way it should.
+    #[error("{0}")]
+    Parse(String),
+    /// Run stopped before its games were done.
+    #[error("{0}")]
+    Interrupted(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// `check` found problems in the db that were left there.
+    #[error("{0}")]
+    Inconsistent(String),
+}
+
+/// Failures to get a page from BGG.
+#[derive(Debug, Error)]
+pub enum BggError {
+    #[error("BGG is not reachable in offline mode, run without --offline.")]
+    Offline,
+    #[error("could not download page `{url}`")]
+    Download {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Can't get `{url}`. Status: {status}")]
+    Status { url: String, status: StatusCode },
+    #[error("BGG is under maintenance, got error page from `{0}`")]
+    Maintenance(String),
+    #[error("fixture {path} is missing")]
+    Fixture {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Page parsed, but not the way BGG used to lay it out.
+    /// Only a strict client raises it, a lenient one skips the bad part.
+    #[error("BGG format has changed, {0}")]
+    FormatChanged(String),
+}
+
+impl AppError {
+    /// Process exit code, one per kind so scripts can tell them apart.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Config(_) => 2,
+            AppError::Db(_) => 3,
+            AppError::Http(_) => 4,
+            AppError::Parse(_) => 5,
+            AppError::Interrupted(_) => 6,
+            AppError::Io(_) => 7,
+            AppError::Inconsistent(_) => 8,
+        }
+    }
+
+    /// Strict client found something a lenient one would skip.
+    pub fn is_format_change(&self) -> bool {
+        matches!(self, AppError::Http(BggError::FormatChanged(_)))
+    }
+
+    /// Failure that may go away if the request is sent again later:
+    /// network trouble, server errors, rate limiting and maintenance.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            AppError::Http(BggError::Download { .. })
+            | AppError::Http(BggError::Maintenance(_)) => true,
+            AppError::Http(BggError::Status { status, .. }) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+
+    /// Short name of the kind of failure, errors of a run are counted by it.
+    pub fn category(&self) -> &'static str {
+        match self {
+            AppError::Http(BggError::Status { status, .. })
+                if *status == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                "rate-limited"
+            }
+            AppError::Http(BggError::Status { status, .. }) if *status == StatusCode::NOT_FOUND => {
+                "not-found"
+            }
+            AppError::Http(BggError::Fixture { .. }) => "not-found",
+            AppError::Http(BggError::Download { source, .. }) if source.is_timeout() => "timeout",
+            AppError::Http(BggError::Maintenance(_)) => "maintenance",
+            AppError::Http(BggError::FormatChanged(_)) | AppError::Parse(_) => "parse",
+            AppError::Http(_) => "network",
+            AppError::Db(_) => "db",
+            AppError::Config(_) => "config",
+            AppError::Interrupted(_) => "interrupted",
+            AppError::Io(_) => "io",
+            AppError::Inconsistent(_) => "inconsistent",
+        }
+    }
+
+    /// Message followed by its causes, e.g. why a download failed.
+    pub fn describe(&self) -> String {
+        let mut text = self.to_string();
+        let mut cause = self.source();
+        while let Some(e) = cause {
+            text.push_str(": ");
+            text.push_str(&e.to_string());
+            cause = e.source();
+        }
+        text
+    }
+}
+
+impl From<ParseFloatError> for AppError {
+    fn from(e: ParseFloatError) -> AppError {
+        AppError::Parse(e.to_string())
+    }
+}
+
+impl From<ParseIntError> for AppError {
+    fn from(e: ParseIntError) -> AppError {
+        AppError::Parse(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kind_exits_with_its_own_code() {
+        let errors = [
+            AppError::Config(String::new()),
+            AppError::Db(rusqlite::Error::QueryReturnedNoRows),
+            AppError::Http(BggError::Offline),
+            AppError::Parse(String::new()),
+            AppError::Interrupted(String::new()),
+            AppError::Io(io::Error::other("")),
+            AppError::Inconsistent(String::new()),
+        ];
+        let codes: Vec<i32> = errors.iter().map(AppError::exit_code).collect();
+        assert_eq!(codes, [2, 3, 4, 5, 6, 7, 8]);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::core;
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+/// Questions asked on the terminal. Streams are given from outside,
+/// so answers can come from anywhere.
+pub struct Prompt<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+    pub fn new(input: R, output: W) -> Prompt<R, W> {
+        Prompt { input, output }
+    }
+
+    /// Answer of the user, trimmed. None when input has ended.
+    fn ask(&mut self, question: &str) -> io::Result<Option<String>> {
+        write!(self.output, "{} ", question)?;
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(String::from(line.trim())))
+    }
+
+    /// Yes or no, empty answer or end of input is `default`.
+    pub fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "[Y/n]" } else { "[y/N]" };
+        loop {
+            let answer = match self.ask(&format!("{} {}", question, hint))? {
+                None => return Ok(default),
+                Some(answer) => answer.to_lowercase(),
+            };
+            match answer.as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => writeln!(self.output, "Please answer y or n.")?,
+            }
+        }
+    }
+
+    /// Value of type T, asked again until it parses. Empty answer
+    /// or end of input is `default`.
+    pub fn value<T>(&mut self, question: &str, default: T) -> io::Result<T>
+    where
+        T: FromStr + Display,
+    {
+        loop {
+            let answer = match self.ask(&format!("{} [{}]", question, default))? {
+                None => return Ok(default),
+                Some(answer) => answer,
+            };
+            if answer.is_empty() {
+                return Ok(default);
+            }
+            match answer.parse() {
+                Ok(value) => return Ok(value),
+                Err(_) => writeln!(self.output, "Can't read `{}`, try again.", answer)?,
+            }
+        }
+    }
+}
+
+/// Limit and threads of the config to create, asked through `prompt`.
+/// None if the user doesn't want one, or if there is no `terminal` to
+/// ask at and nothing is asked.
+pub fn ask_structure<R: BufRead, W: Write>(
+    prompt: &mut Prompt<R, W>,
+    terminal: bool,
+) -> io::Result<Option<(u32, usize)>> {
+    if !terminal {
+        return Ok(None);
+    }
+    let question = format!(
+        "{} not found, create it and the db here?",
+        core::CONFIG_FILE_NAME
+    );
+    if !prompt.confirm(&question, true)? {
+        return Ok(None);
+    }
+    let limit = prompt.value("Number of user ratings a game needs", core::DEFAULT_LIMIT)?;
+    let threads = prompt.value("Number of threads", core::DEFAULT_THREADS)?;
+    Ok(Some((limit, threads)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Result of `ask` given `answers` and what was written meanwhile.
+    fn asked<T>(
+        answers: &str,
+        ask: impl FnOnce(&mut Prompt<&[u8], &mut Vec<u8>>) -> io::Result<T>,
+    ) -> (T, String) {
+        let mut output = Vec::new();
+        let result = ask(&mut Prompt::new(answers.as_bytes(), &mut output)).unwrap();
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn confirm_reads_yes_and_no() {
+        for (answers, default, expected) in [
+            ("y\n", false, true),
+            (" YES \n", false, true),
+            ("n\n", true, false),
+            ("No\n", true, false),
+            ("\n", true, true),
+            ("\n", false, false),
+            ("", true, true),
+            ("", false, false),
+        ] {
+            let (answer, _) = asked(answers, |p| p.confirm("Go?", default));
+            assert_eq!(answer, expected, "{:?}", answers);
+        }
+    }
+
+    #[test]
+    fn confirm_asks_again_until_it_understands() {
+        let (answer, output) = asked("maybe\ny\n", |p| p.confirm("Go?", false));
+        assert!(answer);
+        assert_eq!(output, "Go? [y/N] Please answer y or n.\nGo? [y/N] ");
+        // input that ends on a bad answer is the default
+        let (answer, _) = asked("maybe\n", |p| p.confirm("Go?", true));
+        assert!(answer);
+    }
+
+    #[test]
+    fn value_is_asked_again_until_it_parses() {
+        let (value, output) = asked("ten\n-1\n 7 \n", |p| p.value("Limit", 100u32));
+        assert_eq!(value, 7);
+        assert_eq!(
+            output,
+            "Limit [100] Can't read `ten`, try again.\n\
+             Limit [100] Can't read `-1`, try again.\n\
+             Limit [100] "
+        );
+        assert_eq!(asked("\n", |p| p.value("Limit", 100u32)).0, 100);
+        assert_eq!(asked("", |p| p.value("Limit", 100u32)).0, 100);
+    }
+
+    #[test]
+    fn structure_comes_from_answers() {
+        let (answers, _) = asked("\n50\n4\n", |p| ask_structure(p, true));
+        assert_eq!(answers, Some((50, 4)));
+        let (answers, _) = asked("y\n\n\n", |p| ask_structure(p, true));
+        assert_eq!(answers, Some((core::DEFAULT_LIMIT, core::DEFAULT_THREADS)));
+        let (answers, output) = asked("n\n50\n", |p| ask_structure(p, true));
+        assert_eq!(answers, None);
+        assert!(!output.contains("threads"), "{}", output);
+    }
+
+    #[test]
+    fn piped_run_is_not_asked() {
+        let (answers, output) = asked("y\n50\n4\n", |p| ask_structure(p, false));
+        assert_eq!(answers, None);
+        assert_eq!(output, "");
+        // and fails telling what to run instead
+        let message = core::missing_config().to_string();
+        assert!(
+            message.contains("Run `bgg_swing2 new` there first"),
+            "{}",
+            message
+        );
+        assert!(message.contains("--yes"), "{}", message);
+    }
+}
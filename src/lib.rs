@@ -8,6 +8,111 @@ pub struct Game {
     pub bgg_num_votes: u32,
     pub bgg_geek_rating: f64,
     pub bgg_avg_rating: f64,
+    pub weight: Option<f64>,          // None when nobody rated complexity
+    pub previous_rating: Option<f64>, // rating before the last pull, if it was stable
+    pub rating_sd: Option<f64>,       // spread of trusted ratings, None if they weren't stored
+    pub stable: bool,
+    pub flagged: bool, // rating is too far from BGG average, see Config::divergence
+    pub polarizing: Option<f64>, // see core::polarization, None if there are too few ratings
+    pub sampled: bool, // rating comes from some pages only, see core::sample_plan
+    pub year: Option<u32>, // year published, None if BGG search didn't show it
+    pub all_rating: Option<f64>, // average of every rating, untrusted too, see Config::keep_all_ratings
+    pub all_votes: Option<u32>,
+    pub tier: Option<u32>, // vote tier the game was pulled into, None if the db doesn't tell
+}
+
+impl Game {
+    /// Game that is not balanced yet: first page, no votes,
+    /// nothing known from BGG.
+    pub fn new(id: u32, name: String) -> Game {
+        Game {
+            id,
+            name,
+            rating: 0.0,
+            votes: 0,
+            page: 1,
+            bgg_num_votes: 0,
+            bgg_geek_rating: 0.0,
+            bgg_avg_rating: 0.0,
+            weight: None,
+            previous_rating: None,
+            rating_sd: None,
+            stable: false,
+            flagged: false,
+            polarizing: None,
+            sampled: false,
+            year: None,
+            all_rating: None,
+            all_votes: None,
+            tier: None,
+        }
+    }
+
+    /// Game as listed in BGG search.
+    pub fn from_search(
+        id: u32,
+        name: String,
+        bgg_num_votes: u32,
+        bgg_geek_rating: f64,
+        bgg_avg_rating: f64,
+    ) -> Game {
+        Game {
+            bgg_num_votes,
+            bgg_geek_rating,
+            bgg_avg_rating,
+            ..Game::new(id, name)
+        }
+    }
 }
 
 pub type User = String; // user name
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_game_starts_unbalanced_on_the_first_page() {
+        let game = Game::new(13, String::from("Catan"));
+        assert_eq!((game.id, game.name.as_str()), (13, "Catan"));
+        assert_eq!((game.page, game.votes, game.rating), (1, 0, 0.0));
+        assert_eq!(
+            (
+                game.bgg_num_votes,
+                game.bgg_geek_rating,
+                game.bgg_avg_rating
+            ),
+            (0, 0.0, 0.0)
+        );
+        assert!(!game.stable && !game.flagged && !game.sampled);
+        assert_eq!(
+            (game.weight, game.previous_rating, game.rating_sd),
+            (None, None, None)
+        );
+        assert_eq!((game.polarizing, game.year, game.tier), (None, None, None));
+        assert_eq!((game.all_rating, game.all_votes), (None, None));
+    }
+
+    #[test]
+    fn search_game_keeps_bgg_numbers_only() {
+        let game = Game::from_search(13, String::from("Catan"), 120_000, 7.0, 7.1);
+        assert_eq!(
+            (
+                game.bgg_num_votes,
+                game.bgg_geek_rating,
+                game.bgg_avg_rating
+            ),
+            (120_000, 7.0, 7.1)
+        );
+        let bare = Game::new(13, String::from("Catan"));
+        assert_eq!(
+            Game {
+                bgg_num_votes: bare.bgg_num_votes,
+                bgg_geek_rating: bare.bgg_geek_rating,
+                bgg_avg_rating: bare.bgg_avg_rating,
+                ..game
+            },
+            bare
+        );
+    }
+}
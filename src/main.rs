@@ -1,131 +1,1308 @@
+#[cfg(feature = "bench")]
+mod bench;
 mod bgg;
 mod cli;
 mod core;
 mod db;
-mod lib;
-
-use crate::core::Message;
-use cli::Cli;
-use ctrlc;
-use exitfailure::ExitFailure;
-use failure::Error;
-use std::io::Write;
+mod diff;
+mod error;
+mod interactive;
+mod metrics;
+#[cfg(any(test, feature = "bench"))]
+mod mock;
+mod profile;
+mod progress;
+mod ratelimit;
+mod report;
+mod runlog;
+mod signals;
+#[cfg(test)]
+mod testing;
+
+use crate::core::{Config, Listed, RatingBounds, Severity, TimeLimit};
+use chrono::{DateTime, Local};
+use cli::{Cli, Command, ReportArgs, WatchArgs};
+use error::AppError;
+use interactive::Prompt;
+use metrics::MetricsReporter;
+use progress::{ConsoleReporter, JsonReporter, NullReporter, Reporter};
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use terminal_size::{terminal_size, Width};
+
+// Report formats --output picks by file extension.
+const REPORT_EXTENSIONS: [(&str, &str); 6] = [
+    ("txt", "text"),
+    ("tsv", "tsv"),
+    ("csv", "csv"),
+    ("json", "json"),
+    ("md", "markdown"),
+    ("ndjson", "ndjson"),
+];
+
+fn main() {
+    if let Err(e) = run(Cli::from_args()) {
+        runlog::complain(&format!("Error: {}", e.describe()));
+        process::exit(e.exit_code());
+    }
+}
 
-fn main() -> Result<(), ExitFailure> {
-    let cli = Cli::from_args();
-    match cli {
-        Cli::New {} => create_structure()?,
-        Cli::Report {} => make_report()?,
-        Cli::Pull {} => pull_games()?,
-        Cli::Balance {} => stabilize()?,
-        Cli::Review {} => review_users()?,
+fn run(cli: Cli) -> Result<(), AppError> {
+    if let Some(path) = &cli.append_log {
+        runlog::open(path)?;
+        let args: Vec<String> = std::env::args().collect();
+        runlog::record(&format!("$ {}", args.join(" ")));
+    }
+    db::select(cli.db, cli.output_db, cli.force)?;
+    if cli.offline {
+        bgg::go_offline();
+    }
+    if cli.use_defaults {
+        core::use_defaults();
+    }
+    match cli.command {
+        Command::New {} => create_structure(core::DEFAULT_LIMIT, core::DEFAULT_THREADS)?,
+        Command::Report { args } => make_report(args)?,
+        Command::TopMovers { n, precision } => top_movers(n, precision)?,
+        Command::Compare {
+            old,
+            new,
+            format,
+            precision,
+        } => compare(&old, new.as_ref(), &format, precision)?,
+        Command::CompareDb {
+            other,
+            format,
+            threshold,
+            precision,
+        } => compare_db(&other, &format, threshold, precision)?,
+        Command::Histogram {
+            bucket,
+            include_unstable,
+        } => histogram(bucket, include_unstable)?,
+        Command::Pull {
+            progress,
+            limit,
+            add,
+            strict_parse,
+            force,
+            explain,
+        } => {
+            if explain {
+                return explain_pull(limit);
+            }
+            ensure_online_before_structure()?;
+            ensure_structure(cli.yes)?;
+            pull_games(limit, add, strict_parse, force, reporter(&progress))?
+        }
+        Command::Balance {
+            progress,
+            sample,
+            bounds,
+            tier,
+            seed,
+            full,
+            max_pages_per_game,
+            explain,
+            strict_parse,
+            force,
+            metrics_file,
+            profile,
+            stuck_after,
+            max_duration,
+            watch,
+        } => {
+            if explain {
+                let config = balance_config(bounds, seed, full, max_pages_per_game, strict_parse)?;
+                return explain_balance(&config, sample, tier);
+            }
+            ensure_online_before_structure()?;
+            ensure_structure(cli.yes)?;
+            if profile {
+                profile::enable();
+            }
+            let mut reporter = if progress == "console" {
+                let stuck_after = Duration::from_secs(stuck_after);
+                Box::new(ConsoleReporter::with_workers(stuck_after))
+            } else {
+                reporter(&progress)
+            };
+            if let Some(path) = metrics_file {
+                reporter = Box::new(MetricsReporter::new(reporter, path));
+            }
+            let result = balance_config(bounds, seed, full, max_pages_per_game, strict_parse)
+                .and_then(|config| {
+                    stabilize(config, sample, tier, force, max_duration, watch, reporter)
+                });
+            if let Some(line) = signals::stopped(&result) {
+                runlog::say(&line);
+            }
+            if profile {
+                print_profile();
+            }
+            result?
+        }
+        Command::Review {
+            fetch,
+            progress,
+            force,
+        } => {
+            if fetch {
+                fetch_users(force, reporter(&progress))?
+            } else {
+                review_users()?
+            }
+        }
+        Command::SetUserTrust { name, trust } => set_user_trust(name, trust == "trusted")?,
+        Command::Watch { id } => set_watched(id, true)?,
+        Command::Unwatch { id } => set_watched(id, false)?,
+        Command::Game { id, note } => game_notes(id, note)?,
+        Command::Snapshot { name, list } => snapshot(name, list)?,
+        Command::History {
+            limit,
+            users,
+            last_run,
+        } => {
+            if last_run {
+                last_run_summary()?
+            } else {
+                history(limit, users)?
+            }
+        }
+        Command::Status {} => status()?,
+        Command::Info { json } => info(json)?,
+        Command::Check { repair } => check(repair)?,
+        Command::Users {
+            trusted,
+            untrusted,
+            pinned,
+            stale,
+            sort,
+            limit,
+            offset,
+            format,
+            precision,
+            delimiter,
+        } => {
+            let delimiter = match format.as_str() {
+                "tsv" => '\t',
+                "csv" => ',',
+                _ => delimiter,
+            };
+            let style = report::Style::new(precision, false, delimiter)?;
+            let filter = db::UserFilter {
+                trusted: match (trusted, untrusted) {
+                    (true, _) => Some(true),
+                    (_, true) => Some(false),
+                    _ => None,
+                },
+                pinned,
+                updated_before: stale.map(|TimeLimit(age)| stale_cutoff(age)).transpose()?,
+                sort,
+                limit,
+                offset,
+            };
+            list_users(&filter, &format, &style)?
+        }
+        Command::PruneUsers {} => prune_users()?,
+        #[cfg(feature = "bench")]
+        Command::Bench {
+            games,
+            pages,
+            latency,
+            threads,
+        } => bench::run(&bench::Settings {
+            games,
+            pages,
+            latency: std::time::Duration::from_millis(latency),
+            threads,
+        })?,
     }
     Ok(())
 }
 
-fn create_structure() -> Result<(), Error> {
-    core::create_structure()?;
+fn reporter(kind: &str) -> Box<dyn Reporter> {
+    match kind {
+        "json" => Box::new(JsonReporter),
+        "none" => Box::new(NullReporter),
+        _ => Box::new(ConsoleReporter::new()),
+    }
+}
+
+/// Prints config issues, errors always stop the command,
+/// warnings only without `force`.
+fn validate(config: &Config, force: bool) -> Result<(), AppError> {
+    let issues = config.validate();
+    for issue in &issues {
+        runlog::complain(&issue.to_string());
+    }
+    if issues.iter().any(|i| i.severity == Severity::Error) {
+        let e = format!("Fix {} before running.", core::CONFIG_FILE_NAME);
+        return Err(AppError::Config(e));
+    }
+    if !force && issues.iter().any(|i| i.severity != Severity::Notice) {
+        let e = String::from("Pass --i-know-what-im-doing to run anyway.");
+        return Err(AppError::Config(e));
+    }
+    Ok(())
+}
+
+/// --offline stops commands that need BGG before app.config or the db
+/// are created. Without a config there is no offline_dir to read from.
+fn ensure_online_before_structure() -> Result<(), AppError> {
+    let offline_dir = if Path::new(core::CONFIG_FILE_NAME).exists() || core::uses_defaults() {
+        core::config()?.offline_dir
+    } else {
+        None
+    };
+    if offline_dir.is_none() {
+        bgg::ensure_online()?;
+    }
+    Ok(())
+}
+
+fn create_structure(limit: u32, threads: usize) -> Result<(), AppError> {
+    core::create_structure(limit, threads)?;
     println!("Created initial structure files.");
     Ok(())
 }
 
-fn make_report() -> Result<(), Error> {
-    let games = core::make_report()?;
+/// Creates app.config and db if there is no config yet: with `yes`
+/// from defaults, on a terminal from answers of the user. Piped runs
+/// fail and tell what to run instead. With --use-defaults only the
+/// db is created.
+fn ensure_structure(yes: bool) -> Result<(), AppError> {
+    if Path::new(core::CONFIG_FILE_NAME).exists() {
+        return Ok(());
+    }
+    // nothing is written but the db the command can't do without
+    if core::uses_defaults() {
+        if core::ensure_db()? {
+            println!("Created {}.", db::db_path().display());
+        }
+        return Ok(());
+    }
+    if yes {
+        return create_structure(core::DEFAULT_LIMIT, core::DEFAULT_THREADS);
+    }
+    let stdin = io::stdin();
+    let terminal = stdin.is_terminal();
+    let mut prompt = Prompt::new(stdin.lock(), io::stdout());
+    match interactive::ask_structure(&mut prompt, terminal)? {
+        Some((limit, threads)) => create_structure(limit, threads),
+        None => Err(core::missing_config()),
+    }
+}
+
+fn make_report(args: ReportArgs) -> Result<(), AppError> {
+    if args.timings {
+        return timings(args.top);
+    }
+    let style = report::Style::new(args.precision, args.decimal_comma, args.delimiter)?;
+    let descending = args.order == "desc";
+    let extended = args.extended;
+    let derived = args
+        .sort
+        .iter()
+        .find(|k| db::DERIVED_FIELDS.contains(&k.as_str()))
+        .cloned();
+    let prior = if args.extended || derived.is_some() {
+        let config = core::config()?;
+        Some(report::Prior {
+            votes: config.bayes_prior_votes,
+            mean: config.bayes_prior_mean,
+        })
+    } else {
+        None
+    };
+    let format = match (&args.format, &args.output) {
+        (Some(format), _) => format.as_str(),
+        (None, Some(path)) => format_of(path)?,
+        (None, None) => "text",
+    };
+    let order = db::Order {
+        keys: args.sort.clone(),
+        descending,
+    };
+    // formats that go row by row don't need the list in memory, unless
+    // it is reordered or grouped after the query
+    let streamed = args.snapshot.is_none() && derived.is_none() && args.group_by.is_none();
+    if streamed && ["tsv", "geeklist-csv", "ndjson"].contains(&format) {
+        let config;
+        let stream = match format {
+            "tsv" => report::Stream::Tsv(prior.as_ref().filter(|_| extended)),
+            "geeklist-csv" => {
+                config = core::config()?;
+                report::Stream::Geeklist(&config.geeklist_template)
+            }
+            _ => report::Stream::Ndjson(prior.as_ref().filter(|_| extended)),
+        };
+        let filter = db::GameFilter {
+            tier: args.tier,
+            max_weight: args.max_weight,
+            limit: args.top.map(|top| top as u32),
+        };
+        return stream_report(
+            &filter,
+            &order,
+            args.include_unstable,
+            &stream,
+            &style,
+            args.output.as_deref(),
+        );
+    }
+    let mut games = core::make_report(
+        args.max_weight,
+        args.tier,
+        &order,
+        args.include_unstable,
+        args.snapshot,
+    )?;
+    if let (Some(_), Some(prior)) = (&derived, &prior) {
+        // the query can't sort by derived keys, every key is taken
+        // again in the order given
+        report::sort(&mut games, &order, prior);
+    }
     if games.is_empty() {
-        println!("Game list is not stable enough.");
+        say_no_games(args.include_unstable);
+        // a file asked for is written anyway, its header tells what
+        // the report would have held
+        if args.output.is_none() {
+            return Ok(());
+        }
+    }
+    let group = args.group_by.as_deref().and_then(report::group_by);
+    // compact groups the sorted list itself, its ranks are those of the sort
+    let games = report::arrange(games, args.top, group.filter(|_| format != "compact"));
+    let mut out = report_output(args.output.as_deref())?;
+    match format {
+        "geeklist-csv" => {
+            let config = core::config()?;
+            report::write_geeklist(&mut out, &games, &config.geeklist_template, &style, group)?
+        }
+        "tsv" => report::write_tsv(
+            &mut out,
+            &games,
+            &style,
+            prior.as_ref().filter(|_| extended),
+            group,
+        )?,
+        "csv" => report::write_csv(
+            &mut out,
+            &games,
+            &style,
+            prior.as_ref().filter(|_| extended),
+            group,
+        )?,
+        "markdown" => report::write_markdown(
+            &mut out,
+            &games,
+            &style,
+            prior.as_ref().filter(|_| extended),
+            group,
+        )?,
+        "json" => report::write_json(&mut out, &games, prior.as_ref().filter(|_| extended), group)?,
+        "compact" => report::write_compact(&mut out, &games, &style, terminal_width(), group)?,
+        "ndjson" => {
+            report::write_ndjson(&mut out, &games, prior.as_ref().filter(|_| extended), group)?
+        }
+        _ => report::write_text(
+            &mut out,
+            &games,
+            &style,
+            prior.as_ref().filter(|_| extended),
+            group,
+        )?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn report_output(path: Option<&Path>) -> Result<Box<dyn Write>, AppError> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(std::io::stdout()),
+    })
+}
+
+/// Writes every game as soon as it is read from the db.
+fn stream_report(
+    filter: &db::GameFilter,
+    order: &db::Order,
+    include_unstable: bool,
+    stream: &report::Stream,
+    style: &report::Style,
+    output: Option<&Path>,
+) -> Result<(), AppError> {
+    let start = || -> Result<Box<dyn Write>, AppError> {
+        let mut out = report_output(output)?;
+        report::write_stream_header(&mut out, stream)?;
+        Ok(out)
+    };
+    // stdout gets the header with the first game, a file gets it anyway
+    let mut out = match output {
+        Some(_) => Some(start()?),
+        None => None,
+    };
+    let mut written = false;
+    core::for_each_game(filter, order, include_unstable, |game| {
+        let out = match &mut out {
+            Some(out) => out,
+            None => out.insert(start()?),
+        };
+        written = true;
+        Ok(report::write_streamed(out, stream, &game, style)?)
+    })?;
+    if let Some(mut out) = out {
+        out.flush()?;
+    }
+    if !written {
+        say_no_games(include_unstable);
+    }
+    Ok(())
+}
+
+fn say_no_games(include_unstable: bool) {
+    if include_unstable {
+        println!("No games to report.");
     } else {
-        println!("Id\tName\tRating\tVotes\tGeek Rating\tAvg BGG Rating\tBGG Votes");
-        for game in games {
+        println!("Game list is not stable enough.");
+    }
+}
+
+/// Whether `compare` can read the file as a json report.
+fn is_json_report(path: &Path) -> bool {
+    matches!(format_of(path), Ok("json") | Ok("ndjson"))
+}
+
+/// Report format the file extension stands for, text for a file
+/// without one.
+fn format_of(path: &Path) -> Result<&'static str, AppError> {
+    let extension = match path.extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => return Ok("text"),
+    };
+    match REPORT_EXTENSIONS.iter().find(|(e, _)| *e == extension) {
+        Some((_, format)) => Ok(format),
+        None => {
+            let known: Vec<String> = REPORT_EXTENSIONS
+                .iter()
+                .map(|(e, _)| format!(".{}", e))
+                .collect();
+            Err(AppError::Config(format!(
+                "No report format for .{} files, use {} or give --format.",
+                extension,
+                known.join(", ")
+            )))
+        }
+    }
+}
+
+/// Width of the terminal stdout goes to. $COLUMNS when stdout is not
+/// a terminal, 80 if the shell doesn't export it either.
+fn terminal_width() -> usize {
+    if let Some((Width(w), _)) = terminal_size() {
+        return usize::from(w);
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .filter(|&c| c > 0)
+        .unwrap_or(80)
+}
+
+fn timings(top: Option<usize>) -> Result<(), AppError> {
+    let mut timings = core::timings()?;
+    if let Some(top) = top {
+        timings.truncate(top);
+    }
+    println!("Id\tName\tPages\tSeconds\tNew users\tCached users\tStarted\tStabilized");
+    for t in timings {
+        println!(
+            "{}\t{}\t{}\t{:.1}\t{}\t{}\t{}\t{}",
+            t.id,
+            t.name,
+            t.pages,
+            t.seconds,
+            t.users.new,
+            t.users.cached,
+            t.started.unwrap_or_default(),
+            t.stabilized.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn top_movers(n: usize, precision: usize) -> Result<(), AppError> {
+    let style = report::Style::new(precision, false, '\t')?;
+    let games = core::make_report(None, None, &db::Order::default(), false, None)?;
+    if games.is_empty() {
+        println!("Game list is not stable enough.");
+        return Ok(());
+    }
+    let stdout = std::io::stdout();
+    report::write_movers(&mut stdout.lock(), &games, n, &style)?;
+    Ok(())
+}
+
+fn compare(
+    old: &Listed,
+    new: Option<&Listed>,
+    format: &str,
+    precision: usize,
+) -> Result<(), AppError> {
+    for listed in std::iter::once(old).chain(new) {
+        match listed {
+            Listed::Export(path) if !is_json_report(path) => {
+                return Err(AppError::Config(format!(
+                    "{} is neither a snapshot id nor a .json or .ndjson report, a db is compared with compare-db.",
+                    path.display()
+                )))
+            }
+            _ => (),
+        }
+    }
+    let style = report::Style::new(precision, false, '\t')?;
+    let (old_games, new_games) = match core::compared_lists(old, new)? {
+        Some(lists) => lists,
+        None => {
+            println!("Game list is not stable enough.");
+            return Ok(());
+        }
+    };
+    let changes = report::compare(&old_games, &new_games);
+    let stdout = std::io::stdout();
+    match format {
+        "json" => println!("{}", report::comparison_json(&changes)),
+        "csv" => {
+            let style = report::Style::new(precision, false, ',')?;
+            report::write_comparison(&mut stdout.lock(), &changes, &style)?
+        }
+        _ => report::write_comparison(&mut stdout.lock(), &changes, &style)?,
+    }
+    Ok(())
+}
+
+/// Ratings of the selected db against another one balanced from the
+/// same pull, e.g. with other trust bounds.
+fn compare_db(
+    other: &Path,
+    format: &str,
+    threshold: f64,
+    precision: usize,
+) -> Result<(), AppError> {
+    if threshold.is_nan() || threshold < 0.0 {
+        let e = String::from("Threshold can't be below 0.");
+        return Err(AppError::Config(e));
+    }
+    let games = core::make_report(None, None, &db::Order::default(), false, None)?;
+    let other_games = core::other_games(other)?;
+    if games.is_empty() {
+        println!("Game list is not stable enough.");
+        return Ok(());
+    }
+    if other_games.is_empty() {
+        println!("{} has no games.", other.display());
+        return Ok(());
+    }
+    let diff = diff::diff(&games, &other_games, threshold);
+    let stdout = std::io::stdout();
+    match format {
+        "json" => println!("{}", report::db_diff_json(&diff, threshold)),
+        "csv" => report::write_db_diff_csv(&mut stdout.lock(), &diff)?,
+        _ => {
+            let style = report::Style::new(precision, false, '\t')?;
+            let names = (
+                db::db_path().display().to_string(),
+                other.display().to_string(),
+            );
+            report::write_db_diff(
+                &mut stdout.lock(),
+                &diff,
+                &style,
+                (&names.0, &names.1),
+                threshold,
+            )?
+        }
+    }
+    Ok(())
+}
+
+fn histogram(bucket: f64, include_unstable: bool) -> Result<(), AppError> {
+    let games = core::make_report(None, None, &db::Order::default(), include_unstable, None)?;
+    if games.is_empty() {
+        println!("Game list is not stable enough.");
+        return Ok(());
+    }
+    let stdout = std::io::stdout();
+    report::write_histogram(&mut stdout.lock(), &games, bucket)?;
+    Ok(())
+}
+
+/// Search urls of pull, nothing is downloaded.
+fn explain_pull(limit: Option<u32>) -> Result<(), AppError> {
+    let mut config = core::config()?;
+    if let Some(limit) = limit {
+        config.limit = limit;
+    }
+    let query = config.search_query()?;
+    println!("Pull asks for games with at least {} votes:", config.limit);
+    for page in 1..=2 {
+        println!("{}{}", config.base_url, query.path(page));
+    }
+    println!("and so on, up to the page with the last of the results counted on page 1.");
+    Ok(())
+}
+
+/// Rating and user urls of balance, nothing is downloaded.
+fn explain_balance(
+    config: &Config,
+    sample: Option<u32>,
+    tier: Option<u32>,
+) -> Result<(), AppError> {
+    let games = core::unstable_games(sample, tier, config.seed)?;
+    match games.first() {
+        None => println!("No unstable games, balance has nothing to ask for."),
+        Some(game) => {
+            println!(
+                "Balance asks for ratings of {} games, {} pages at a time, {} first:",
+                games.len(),
+                config.page_size,
+                game.name
+            );
             println!(
-                "{}\t{}\t{:.2}\t{}\t{}\t{}\t{}",
-                game.id,
-                game.name,
-                game.rating,
-                game.votes,
-                game.bgg_geek_rating,
-                game.bgg_avg_rating,
-                game.bgg_num_votes
+                "{}{}",
+                config.base_url,
+                bgg::user_ratings_path(game.id, game.page, config.page_size)
             );
+            println!("and the next pages until one comes back empty.");
         }
     }
+    println!("Users not known yet, or older than user_ttl_days, are looked up at:");
+    if config.prefer_json {
+        println!("{}{}", config.base_url, bgg::user_json_path("{user}"));
+    }
+    println!("{}{}", config.base_url, bgg::user_profile_path("{user}"));
+    if config.min_account_age_years.is_some() {
+        println!("{}{}", config.base_url, bgg::user_info_path("{user}"));
+    }
     Ok(())
 }
 
-fn pull_games() -> Result<(), Error> {
-    let config = core::config()?;
-    println!("Starting download.");
-    core::pull_games(config.limit, |i| {
-        println!("Downloaded page: {}", i);
-    })?;
-    println!("Finished download.");
+fn pull_games(
+    limit: Option<u32>,
+    add: bool,
+    strict_parse: bool,
+    force: bool,
+    mut reporter: Box<dyn Reporter>,
+) -> Result<(), AppError> {
+    let mut config = core::config()?;
+    if let Some(limit) = limit {
+        config.limit = limit;
+    }
+    config.strict_parse |= strict_parse;
+    validate(&config, force)?;
+    let running = Arc::new(AtomicBool::new(true));
+    let _signals = signals::set_handler(running.clone())?;
+    runlog::say("Starting download.");
+    let stats = core::pull_games(&config, add, &running, reporter.as_mut())?;
+    if stats.gaps > 0 {
+        runlog::say(&format!(
+            "Warning: {} games appeared on several pages, {} ranks may hold games that were missed. Consider pulling again.",
+            stats.duplicates, stats.gaps
+        ));
+    } else if stats.duplicates > 0 {
+        runlog::say(&format!(
+            "{} games appeared on several pages, the last page was asked for again and filled the gaps.",
+            stats.duplicates
+        ));
+    }
+    if stats.implausible > 0 {
+        runlog::say(&format!(
+            "Left out {} games without BGG votes or rating.",
+            stats.implausible
+        ));
+    }
+    runlog::say(&format!(
+        "Finished download: {} games from {} pages, {} requests.",
+        stats.games, stats.pages, stats.requests
+    ));
     Ok(())
 }
 
-fn stabilize() -> Result<(), Error> {
+/// Config with overrides from the command line.
+fn balance_config(
+    bounds: Option<RatingBounds>,
+    seed: Option<u32>,
+    full: bool,
+    max_pages_per_game: Option<u32>,
+    strict_parse: bool,
+) -> Result<Config, AppError> {
+    let mut config = core::config()?;
+    if let Some(bounds) = bounds {
+        config.bounds = bounds;
+    }
+    if seed.is_some() {
+        config.seed = seed;
+    }
+    if full {
+        config.sample_pages = None;
+        config.sample_fraction = 1.0;
+        config.max_pages_per_game = None;
+    }
+    if max_pages_per_game.is_some() {
+        config.max_pages_per_game = max_pages_per_game;
+    }
+    config.strict_parse |= strict_parse;
+    Ok(config)
+}
+
+fn stabilize(
+    config: Config,
+    sample: Option<u32>,
+    tier: Option<u32>,
+    force: bool,
+    max_duration: Option<TimeLimit>,
+    watch: WatchArgs,
+    mut reporter: Box<dyn Reporter>,
+) -> Result<(), AppError> {
     // // Cancellation token
     let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    // Bind cancellation token with ctrl+c command
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })?;
-    // Load config
+    // Bind cancellation token with ctrl+c, SIGTERM and SIGHUP,
+    // console close events on Windows
+    let _signals = signals::set_handler(running.clone())?;
+    validate(&config, force)?;
+    // before the db is touched, rounds of --watch would keep failing
+    // one after another anyway
+    if config.offline_dir.is_none() {
+        bgg::ensure_online()?;
+    }
+    // once per command, rounds of --watch must be able to finish
+    let watched = core::reset_watched_games()?;
+    if watched > 0 {
+        runlog::say(&format!(
+            "{} watched games will be balanced again.",
+            watched
+        ));
+    }
+    if !config.samples() {
+        let sampled = core::reset_sampled_games()?;
+        if sampled > 0 {
+            runlog::say(&format!(
+                "{} sampled games will be balanced from every page.",
+                sampled
+            ));
+        }
+    }
+    let converted = core::convert_pages(config.page_size)?;
+    if converted > 0 {
+        runlog::say(&format!(
+            "{} games were read with another page_size, their page counters are converted.",
+            converted
+        ));
+    }
+    let unstable = core::count_balanceable_games(&config, tier)?;
+    if unstable == 0 {
+        say_capped(tier)?;
+        runlog::say("Nothing to balance, every game is stable.");
+        return Ok(());
+    }
+    let expired = max_duration.map(|limit| start_timer(limit, running.clone()));
+    let out_of_time = || -> Result<bool, AppError> {
+        match (max_duration, &expired) {
+            (Some(limit), Some(expired)) if expired.load(Ordering::SeqCst) => {
+                let left = core::count_balanceable_games(&config, tier)?;
+                runlog::say(&format!(
+                    "Stopped after {}, {} games balanced, {} remaining.",
+                    limit,
+                    unstable.saturating_sub(left),
+                    left
+                ));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    };
+    runlog::say("Start balancing.");
+    if !watch.watch {
+        core::stabilize(config.clone(), sample, tier, running, reporter.as_mut())?;
+        if !out_of_time()? {
+            runlog::say("Finished balancing.");
+        }
+        return Ok(());
+    }
+    for round in 1..=watch.max_rounds {
+        // a failed round is not the end, the next one picks up the rest
+        if let Err(e) = core::stabilize(
+            config.clone(),
+            sample,
+            tier,
+            running.clone(),
+            reporter.as_mut(),
+        ) {
+            runlog::complain(&format!("Round {} failed: {}", round, e));
+        }
+        // games left at the page cap can't be balanced by another round
+        let left = core::count_balanceable_games(&config, tier)?;
+        runlog::say(&format!(
+            "Round {} finished, {} games left unstable.",
+            round, left
+        ));
+        if left == 0 {
+            say_capped(tier)?;
+            runlog::say("Finished balancing.");
+            return Ok(());
+        }
+        if round == watch.max_rounds || !pause(watch.pause, &running) {
+            break;
+        }
+    }
+    // the timer stops the round or the pause after it
+    if out_of_time()? {
+        return Ok(());
+    }
+    Err(AppError::Interrupted(String::from(
+        "Game list is not stable yet, run balance again.",
+    )))
+}
+
+/// Tells about unstable games that wait for a higher max_pages_per_game.
+fn say_capped(tier: Option<u32>) -> Result<(), AppError> {
+    let capped = core::count_unstable_games(tier)?;
+    if capped > 0 {
+        runlog::say(&format!(
+            "{} games stay unstable at max_pages_per_game.",
+            capped
+        ));
+    }
+    Ok(())
+}
+
+fn print_profile() {
+    println!("Phase\tSeconds\tCalls\tAvg ms");
+    for phase in profile::PHASES.iter() {
+        let (time, calls) = profile::total(*phase);
+        let avg = if calls > 0 {
+            time.as_secs_f64() * 1000.0 / calls as f64
+        } else {
+            0.0
+        };
+        println!(
+            "{}\t{:.1}\t{}\t{:.1}",
+            phase.name(),
+            time.as_secs_f64(),
+            calls,
+            avg
+        );
+    }
+}
+
+/// Lowers `running` once `limit` passes, the way a signal does.
+/// The returned flag tells that it happened.
+fn start_timer(limit: TimeLimit, running: Arc<AtomicBool>) -> Arc<AtomicBool> {
+    let expired = Arc::new(AtomicBool::new(false));
+    let flag = expired.clone();
+    thread::spawn(move || {
+        thread::sleep(limit.0);
+        flag.store(true, Ordering::SeqCst);
+        running.store(false, Ordering::SeqCst);
+    });
+    expired
+}
+
+/// Waits `secs` unless interrupted, returns false once stopped by a signal.
+fn pause(secs: u64, running: &AtomicBool) -> bool {
+    for _ in 0..secs {
+        if !running.load(Ordering::SeqCst) {
+            return false;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+    running.load(Ordering::SeqCst)
+}
+
+fn set_user_trust(name: String, trusted: bool) -> Result<(), AppError> {
     let config = core::config()?;
-    println!("Start balancing.");
-    // Prettify output a bit
-    let mut stdout = StandardStream::stdout(ColorChoice::Always);
-    let mut seen_users: u32 = 0;
-    let mut requests: u32 = 0;
-    let mut balanced_games: u32 = 0;
-    let mut num_errs: u32 = 0;
-    core::stabilize(config, running, |m| match m {
-        Message::NoteUserProgress(_) => {
-            seen_users += 1;
-            if seen_users % 50 == 0 {
-                stdout
-                    .set_color(ColorSpec::new().set_fg(Some(Color::Green)))
-                    .unwrap();
-                writeln!(&mut stdout, "Found another 50.").unwrap();
-            };
+    let games = core::set_user_trust(&config, &name, trusted)?;
+    println!(
+        "{} is {}. {} games were recounted.",
+        name,
+        if trusted { "trusted" } else { "not trusted" },
+        games
+    );
+    Ok(())
+}
+
+fn set_watched(id: u32, watched: bool) -> Result<(), AppError> {
+    if !core::set_watched(id, watched)? {
+        return Err(not_listed(id));
+    }
+    if watched {
+        println!("Game {} will be balanced on every run.", id);
+    } else {
+        println!("Game {} is no longer watched.", id);
+    }
+    Ok(())
+}
+
+fn not_listed(id: u32) -> AppError {
+    AppError::Config(format!("Game {} is not in the list.", id))
+}
+
+fn game_notes(id: u32, note: Option<String>) -> Result<(), AppError> {
+    if let Some(note) = note {
+        if !core::set_game_notes(id, &note)? {
+            return Err(not_listed(id));
         }
-        Message::DieResult(game) => {
-            balanced_games += 1;
-            stdout
-                .set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))
-                .unwrap();
-            writeln!(&mut stdout, "{} is balanced.", game.name).unwrap();
-        }
-        Message::NoteErr(error) => {
-            num_errs += 1;
-            stdout
-                .set_color(ColorSpec::new().set_fg(Some(Color::Red)))
-                .unwrap();
-            writeln!(&mut stdout, "{:?}", error).unwrap();
-        }
-        Message::NoteGameProgress(game) => {
-            requests += 1;
-            stdout
-                .set_color(ColorSpec::new().set_fg(Some(Color::Green)))
-                .unwrap();
-            writeln!(&mut stdout, "About to ask BGG about {}", game.name).unwrap();
-        }
-        _ => {}
-    })?;
+    }
+    let (source, notes) = match core::game_notes(id)? {
+        Some(found) => found,
+        None => return Err(not_listed(id)),
+    };
+    println!("Game {} came from {}.", id, source);
+    if let Some(notes) = notes {
+        println!("Notes: {}", notes);
+    }
+    Ok(())
+}
+
+fn snapshot(name: Option<String>, list: bool) -> Result<(), AppError> {
+    if !list {
+        let id = core::take_snapshot(name.as_deref())?;
+        println!("Saved snapshot {}.", id);
+        return Ok(());
+    }
+    let snapshots = core::list_snapshots()?;
+    if snapshots.is_empty() {
+        println!("No snapshots yet.");
+    }
+    for s in snapshots {
+        println!(
+            "{}\t{}\t{} games\t{}",
+            s.id,
+            s.taken,
+            s.games,
+            s.name.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+fn history(limit: u32, users: bool) -> Result<(), AppError> {
+    let runs = core::history(limit)?;
+    if runs.is_empty() {
+        println!("No balance runs yet.");
+    } else if users {
+        // reviews don't look for new users
+        let runs: Vec<_> = runs.into_iter().filter(|r| r.kind == "balance").collect();
+        let new_users: Vec<u32> = runs.iter().map(|r| r.new_users).collect();
+        println!("New users per run: {}", report::sparkline(&new_users));
+        for run in runs {
+            println!(
+                "{}\t{} new\t{} cached",
+                run.started, run.new_users, run.cached_users
+            );
+        }
+    } else {
+        println!("Id\tKind\tStarted\tFinished\tBalanced\tErrors\tRequests\tInterrupted\tRemaining");
+        for run in runs {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                run.id,
+                run.kind,
+                run.started,
+                run.finished,
+                run.balanced,
+                run.errors,
+                run.requests,
+                run.interrupted,
+                run.remaining
+            );
+        }
+    }
+    Ok(())
+}
+
+fn last_run_summary() -> Result<(), AppError> {
+    let run = match core::history(1)?.pop() {
+        Some(run) => run,
+        None => {
+            println!("No balance runs yet.");
+            return Ok(());
+        }
+    };
+    println!("Run {} ({})", run.id, run.kind);
+    println!("Started:\t{}", run.started);
+    println!("Finished:\t{}", run.finished);
+    if run.kind == "review" {
+        println!("Reviewed:\t{}", run.reviewed);
+        println!("Trust changed:\t{}", run.flipped);
+    } else {
+        println!("Balanced:\t{}", run.balanced);
+    }
+    println!("Errors:\t{}", run.errors);
+    if run.interrupted > 0 {
+        println!("Interrupted:\tyes, {} games", run.interrupted);
+    } else {
+        println!("Interrupted:\tno");
+    }
+    println!("Remaining:\t{}", run.remaining);
+    Ok(())
+}
+
+fn status() -> Result<(), AppError> {
+    let config = core::config()?;
+    let status = core::status(&config)?;
+    let totals = &status.totals;
     println!(
-        "Seen {} users, {} balanced games, {} erorrs, {} game requests.",
-        seen_users, balanced_games, num_errs, requests
+        "Games:\t{} stable, {} unstable",
+        totals.games_stable, totals.games_unstable
     );
-    println!("Finished balancing.");
+    println!(
+        "Users:\t{} trusted, {} untrusted",
+        totals.users_trusted, totals.users_untrusted
+    );
+    for (title, stored, fields) in [
+        ("Pulled with", &status.pulled, &core::PULL_FIELDS[..]),
+        ("Balanced with", &status.balanced, &core::BALANCE_FIELDS[..]),
+    ] {
+        println!("{}:\t{}", title, settings(stored.as_ref(), fields));
+    }
+    for drift in &status.drift {
+        runlog::complain(&format!("WARNING: {}.", drift));
+    }
+    if !status.drift.is_empty() {
+        runlog::complain(
+            "WARNING: results don't match the current config, pull or review and balance again.",
+        );
+    }
     Ok(())
 }
 
-fn review_users() -> Result<(), Error> {
-    // TODO: make unstable again. trusted after 180 untrusted 90
-    // any update on user in that mode
-    // makes gametable unbalanced
+/// `field value` pairs of a stored config, as far as it has them.
+fn settings(stored: Option<&serde_json::Value>, fields: &[&str]) -> String {
+    match stored {
+        Some(stored) => fields
+            .iter()
+            .filter_map(|&f| {
+                stored
+                    .get(f)
+                    .map(|v| format!("{} {}", f, core::show_value(v)))
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+        None => String::from("unknown"),
+    }
+}
+
+fn info(json: bool) -> Result<(), AppError> {
+    let info = core::info()?;
+    if json {
+        let json = serde_json::to_string_pretty(&info)
+            .map_err(|e| AppError::Parse(format!("Can't write info: {}", e)))?;
+        println!("{}", json);
+        return Ok(());
+    }
+    let totals = &info.totals;
+    println!("Version:\t{}", info.version);
+    println!(
+        "Schema:\t{} (this build writes {})",
+        info.schema_version,
+        db::SCHEMA_VERSION
+    );
+    println!("Db:\t{}, {} bytes", info.db_path.display(), info.db_size);
+    println!(
+        "Games:\t{} stable, {} unstable",
+        totals.games_stable, totals.games_unstable
+    );
+    println!(
+        "Users:\t{} trusted, {} untrusted",
+        totals.users_trusted, totals.users_untrusted
+    );
+    println!("Ratings:\t{}", totals.ratings);
+    println!(
+        "Pulled with:\t{}",
+        settings(info.pulled.as_ref(), &core::PULL_FIELDS)
+    );
+    println!(
+        "Last balance:\t{}",
+        info.last_balance.as_deref().unwrap_or("never")
+    );
+    Ok(())
+}
+
+fn check(repair: bool) -> Result<(), AppError> {
+    let (findings, repaired) = core::check(repair)?;
+    if repaired > 0 {
+        println!("Repaired {} problems.", repaired);
+    }
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    db::consistency::verdict(&findings)?;
+    println!("No problems found.");
     Ok(())
 }
+
+/// Moment users last checked before are older than `age`.
+fn stale_cutoff(age: Duration) -> Result<DateTime<Local>, AppError> {
+    chrono::Duration::from_std(age)
+        .ok()
+        .and_then(|age| Local::now().checked_sub_signed(age))
+        .ok_or_else(|| AppError::Config(String::from("--stale goes back too far")))
+}
+
+fn list_users(
+    filter: &db::UserFilter,
+    format: &str,
+    style: &report::Style,
+) -> Result<(), AppError> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    if format == "ndjson" {
+        core::for_each_user(filter, |user| Ok(report::write_user_json(&mut out, &user)?))?;
+    } else {
+        report::write_user_header(&mut out, style)?;
+        core::for_each_user(filter, |user| {
+            Ok(report::write_user(&mut out, &user, style)?)
+        })?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn prune_users() -> Result<(), AppError> {
+    let removed = core::prune_users()?;
+    println!("Removed {} users.", removed);
+    Ok(())
+}
+
+fn fetch_users(force: bool, mut reporter: Box<dyn Reporter>) -> Result<(), AppError> {
+    let config = core::config()?;
+    validate(&config, force)?;
+    if config.offline_dir.is_none() {
+        bgg::ensure_online()?;
+    }
+    let running = Arc::new(AtomicBool::new(true));
+    let _signals = signals::set_handler(running.clone())?;
+    let result = core::fetch_users(config, running, reporter.as_mut());
+    if let Some(line) = signals::stopped(&result) {
+        runlog::say(&line);
+    }
+    result.map(|_| ())
+}
+
+fn review_users() -> Result<(), AppError> {
+    let config = core::config()?;
+    let (users, games) = core::review_users(&config)?;
+    runlog::say(&format!(
+        "Trust changed for {} users, {} games were recounted.",
+        users, games
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDb;
+    use bgg_swing2::Game;
+    use std::fs;
+
+    /// Report of `args` written to a file named `name`, what it holds.
+    fn reported(name: &str, args: &[&str]) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        let mut argv = vec!["report", "--output", path.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        make_report(ReportArgs::from_iter(argv)).unwrap();
+        fs::read_to_string(&path).expect("no report file")
+    }
+
+    #[test]
+    fn empty_report_file_still_has_its_header() {
+        let _db = TestDb::new();
+        let header = "Id\tName\tRating";
+        // streamed and collected, no games at all and none stable
+        for args in [&[][..], &["--group-by", "decade"][..]] {
+            let tsv = reported("top.tsv", args);
+            assert!(tsv.starts_with(header), "{:?}", tsv);
+            assert_eq!(tsv.lines().count(), 1, "{:?}", tsv);
+        }
+        db::add_games(
+            vec![Game::from_search(1, String::from("Game 1"), 1000, 7.0, 7.5)],
+            100,
+            "search",
+        )
+        .unwrap();
+        for args in [&[][..], &["--group-by", "decade"][..]] {
+            assert_eq!(reported("top.tsv", args).lines().count(), 1);
+        }
+        assert_eq!(reported("top.ndjson", &[]), "");
+        assert_eq!(reported("top.json", &[]), "[]\n");
+        assert_eq!(reported("top.md", &[]).lines().count(), 2);
+        assert_eq!(
+            reported("top.tsv", &["--include-unstable"]).lines().count(),
+            2
+        );
+    }
+
+    #[test]
+    fn output_extension_picks_the_format() {
+        let _db = TestDb::new();
+        db::add_games(
+            vec![Game::from_search(
+                1,
+                String::from("Rock, Paper"),
+                1000,
+                7.0,
+                7.5,
+            )],
+            100,
+            "search",
+        )
+        .unwrap();
+        let unstable = ["--include-unstable"];
+        let csv = reported("top.csv", &unstable);
+        assert!(csv.starts_with("Id,Name,Rating,"), "{:?}", csv);
+        assert!(csv.contains("\n1,\"Rock, Paper\","), "{:?}", csv);
+        let json: serde_json::Value =
+            serde_json::from_str(&reported("top.JSON", &unstable)).unwrap();
+        assert_eq!(json[0]["name"], "Rock, Paper");
+        assert!(reported("top.md", &unstable).starts_with("| Id | Name |"));
+        assert!(reported("top", &unstable).starts_with("Id\tName\t"));
+        // --format wins over the extension, and is the only way to
+        // write a file of any other kind
+        assert!(reported("top.xlsx", &["--format", "tsv"]).starts_with("Id\tName\t"));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("top.xlsx");
+        let args = ReportArgs::from_iter(vec!["report", "--output", path.to_str().unwrap()]);
+        match make_report(args) {
+            Err(AppError::Config(message)) => assert!(message.contains(".xlsx"), "{}", message),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn compare_reads_snapshots_and_reports_only() {
+        let _db = TestDb::new();
+        let e = compare(&Listed::from("other.db"), None, "text", 2).unwrap_err();
+        assert!(e.to_string().contains("compare-db"), "{}", e);
+        let e = compare(
+            &Listed::from("1"),
+            Some(&Listed::from("top.txt")),
+            "text",
+            2,
+        )
+        .unwrap_err();
+        assert!(e.to_string().starts_with("top.txt is neither"), "{}", e);
+    }
+}
@@ -0,0 +1,212 @@
+//! Gauges of the last balance run in Prometheus text format,
+//! meant for node_exporter's textfile collector.
+use crate::core::{PageCap, ReviewSummary, StabilizeSummary};
+use crate::db;
+use crate::error::AppError;
+use crate::progress::Reporter;
+use bgg_swing2::{Game, User};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Renders the gauges, `totals` are counted in db after the run.
+pub fn render(summary: &StabilizeSummary, totals: &db::Totals) -> String {
+    let gauges = [
+        (
+            "bgg_games_stable",
+            "Games with a stable rating.",
+            f64::from(totals.games_stable),
+        ),
+        (
+            "bgg_games_unstable",
+            "Games still to be balanced.",
+            f64::from(totals.games_unstable),
+        ),
+        (
+            "bgg_users_trusted",
+            "Users whose ratings count.",
+            f64::from(totals.users_trusted),
+        ),
+        (
+            "bgg_users_untrusted",
+            "Users whose ratings are ignored.",
+            f64::from(totals.users_untrusted),
+        ),
+        (
+            "bgg_run_requests_total",
+            "Game pages requested by the last run.",
+            f64::from(summary.requests),
+        ),
+        (
+            "bgg_run_errors_total",
+            "Errors met by the last run.",
+            f64::from(summary.errors),
+        ),
+        (
+            "bgg_run_duration_seconds",
+            "Duration of the last run.",
+            summary.duration,
+        ),
+    ];
+    let mut text = String::new();
+    for (name, help, value) in gauges.iter() {
+        writeln!(text, "# HELP {} {}", name, help).unwrap();
+        writeln!(text, "# TYPE {} gauge", name).unwrap();
+        writeln!(text, "{} {}", name, value).unwrap();
+    }
+    text
+}
+
+/// Writes next to `path` first, so the collector never reads half a file.
+pub fn write(path: &Path, text: &str) -> Result<(), AppError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let failed =
+        |e: io::Error| io::Error::new(e.kind(), format!("Can't write {}: {}", path.display(), e));
+    fs::write(&tmp, text).map_err(failed)?;
+    fs::rename(&tmp, path).map_err(failed)?;
+    Ok(())
+}
+
+/// Passes everything on and writes the metrics file when a run finishes.
+pub struct MetricsReporter {
+    inner: Box<dyn Reporter>,
+    path: PathBuf,
+}
+
+impl MetricsReporter {
+    pub fn new(inner: Box<dyn Reporter>, path: PathBuf) -> MetricsReporter {
+        MetricsReporter { inner, path }
+    }
+
+    fn export(&self, summary: &StabilizeSummary) -> Result<(), AppError> {
+        let totals = db::DbConn::new_read_only()?.get_totals()?;
+        write(&self.path, &render(summary, &totals))
+    }
+}
+
+impl Reporter for MetricsReporter {
+    fn on_pull_page(&mut self, page: usize) {
+        self.inner.on_pull_page(page)
+    }
+    fn on_pull_warning(&mut self, page: usize, warning: &str) {
+        self.inner.on_pull_warning(page, warning)
+    }
+    fn on_user_seen(&mut self, user: &User) {
+        self.inner.on_user_seen(user)
+    }
+    fn on_game_page(&mut self, worker: usize, game: &Game) {
+        self.inner.on_game_page(worker, game)
+    }
+    fn on_worker_error(&mut self, worker: usize) {
+        self.inner.on_worker_error(worker)
+    }
+    fn on_worker_done(&mut self, worker: usize) {
+        self.inner.on_worker_done(worker)
+    }
+    fn on_game_balanced(&mut self, game: &Game) {
+        self.inner.on_game_balanced(game)
+    }
+    fn on_game_changed(&mut self, game: &Game, previous: f64) {
+        self.inner.on_game_changed(game, previous)
+    }
+    fn on_error(&mut self, error: &AppError) {
+        self.inner.on_error(error)
+    }
+    fn on_game_regulated(&mut self, game: &Game) {
+        self.inner.on_game_regulated(game)
+    }
+    fn on_game_capped(&mut self, game: &Game, policy: PageCap) {
+        self.inner.on_game_capped(game, policy)
+    }
+    fn on_divergence(&mut self, game: &Game, delta: f64) {
+        self.inner.on_divergence(game, delta)
+    }
+    fn on_rate_limited(&mut self, delay: Duration) {
+        self.inner.on_rate_limited(delay)
+    }
+    fn on_heartbeat(&mut self) {
+        self.inner.on_heartbeat()
+    }
+    fn on_user_reviewed(&mut self, user: &User, flipped: bool, games: usize) {
+        self.inner.on_user_reviewed(user, flipped, games)
+    }
+    fn on_user_skipped(&mut self, user: &User) {
+        self.inner.on_user_skipped(user)
+    }
+    fn on_review_finished(&mut self, summary: &ReviewSummary) {
+        self.inner.on_review_finished(summary)
+    }
+    fn on_finished(&mut self, summary: &StabilizeSummary) {
+        self.inner.on_finished(summary);
+        // metrics must not fail the run they describe
+        if let Err(e) = self.export(summary) {
+            self.inner.on_error(&e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NullReporter;
+    use crate::testing::TestDb;
+
+    #[test]
+    fn gauges_in_text_format() {
+        let summary = StabilizeSummary {
+            requests: 120,
+            errors: 3,
+            duration: 61.5,
+            ..StabilizeSummary::default()
+        };
+        let totals = db::Totals {
+            games_stable: 95,
+            games_unstable: 5,
+            users_trusted: 1000,
+            users_untrusted: 40,
+            ratings: 20000,
+        };
+        assert_eq!(
+            render(&summary, &totals),
+            "# HELP bgg_games_stable Games with a stable rating.\n\
+             # TYPE bgg_games_stable gauge\n\
+             bgg_games_stable 95\n\
+             # HELP bgg_games_unstable Games still to be balanced.\n\
+             # TYPE bgg_games_unstable gauge\n\
+             bgg_games_unstable 5\n\
+             # HELP bgg_users_trusted Users whose ratings count.\n\
+             # TYPE bgg_users_trusted gauge\n\
+             bgg_users_trusted 1000\n\
+             # HELP bgg_users_untrusted Users whose ratings are ignored.\n\
+             # TYPE bgg_users_untrusted gauge\n\
+             bgg_users_untrusted 40\n\
+             # HELP bgg_run_requests_total Game pages requested by the last run.\n\
+             # TYPE bgg_run_requests_total gauge\n\
+             bgg_run_requests_total 120\n\
+             # HELP bgg_run_errors_total Errors met by the last run.\n\
+             # TYPE bgg_run_errors_total gauge\n\
+             bgg_run_errors_total 3\n\
+             # HELP bgg_run_duration_seconds Duration of the last run.\n\
+             # TYPE bgg_run_duration_seconds gauge\n\
+             bgg_run_duration_seconds 61.5\n"
+        );
+    }
+
+    #[test]
+    fn finished_run_leaves_only_the_metrics_file() {
+        let _db = TestDb::new();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bgg.prom");
+        let mut reporter = MetricsReporter::new(Box::new(NullReporter), path.clone());
+        reporter.on_finished(&StabilizeSummary::default());
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("\nbgg_games_stable 0\n"), "{}", text);
+        assert!(text.ends_with("\nbgg_run_duration_seconds 0\n"), "{}", text);
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+}
@@ -0,0 +1,267 @@
+//! Local stand-in for BGG, serving pages built from plain data.
+//! Used by the tests and by the bench.
+use crate::error::AppError;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Status line and body of an answer.
+pub type Answer = (&'static str, String);
+
+pub struct Mock {
+    pub base_url: String,
+    requests: Arc<AtomicU32>,
+    #[cfg_attr(not(test), allow(dead_code))] // only tests look
+    paths: Arc<Mutex<Vec<String>>>,
+    #[cfg_attr(not(test), allow(dead_code))]
+    agents: Arc<Mutex<Vec<String>>>,
+}
+
+impl Mock {
+    /// Requests answered so far.
+    pub fn requests(&self) -> u32 {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// Paths asked for so far that start with `prefix`.
+    #[cfg(test)]
+    pub fn asked(&self, prefix: &str) -> Vec<String> {
+        let paths = self.paths.lock().unwrap();
+        paths
+            .iter()
+            .filter(|p| p.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// User-Agent of every request so far, empty if it had none.
+    #[cfg(test)]
+    pub fn agents(&self) -> Vec<String> {
+        self.agents.lock().unwrap().clone()
+    }
+}
+
+/// Starts the mock in background, `route` answers the path of every
+/// request. Requests that don't identify themselves get 403.
+pub fn serve<F>(latency: Duration, route: F) -> Result<Mock, AppError>
+where
+    F: Fn(&str) -> Answer + Send + Sync + 'static,
+{
+    start(latency, route, false)
+}
+
+/// Same as `serve`, bodies go out gzipped the way BGG sends them.
+#[cfg(test)]
+pub fn serve_gzipped<F>(latency: Duration, route: F) -> Result<Mock, AppError>
+where
+    F: Fn(&str) -> Answer + Send + Sync + 'static,
+{
+    start(latency, route, true)
+}
+
+fn start<F>(latency: Duration, route: F, gzip: bool) -> Result<Mock, AppError>
+where
+    F: Fn(&str) -> Answer + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    let requests = Arc::new(AtomicU32::new(0));
+    let paths = Arc::new(Mutex::new(Vec::new()));
+    let agents = Arc::new(Mutex::new(Vec::new()));
+    let counter = requests.clone();
+    let log = Log {
+        paths: paths.clone(),
+        agents: agents.clone(),
+    };
+    let route = Arc::new(route);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let counter = counter.clone();
+            let log = log.clone();
+            let route = route.clone();
+            thread::spawn(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(latency);
+                // a broken connection only costs the client a retry
+                let _ = answer(stream, &log, &*route, gzip);
+            });
+        }
+    });
+    Ok(Mock {
+        base_url,
+        requests,
+        paths,
+        agents,
+    })
+}
+
+/// What requests asked for and who sent them.
+#[derive(Clone)]
+struct Log {
+    paths: Arc<Mutex<Vec<String>>>,
+    agents: Arc<Mutex<Vec<String>>>,
+}
+
+fn answer(
+    mut stream: TcpStream,
+    log: &Log,
+    route: &dyn Fn(&str) -> Answer,
+    gzip: bool,
+) -> Result<(), AppError> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let path = head.split_whitespace().nth(1).unwrap_or("/");
+    log.paths.lock().unwrap().push(String::from(path));
+    let agent = head
+        .lines()
+        .find_map(|l| {
+            l.split_once(':')
+                .filter(|(k, _)| k.eq_ignore_ascii_case("user-agent"))
+        })
+        .map_or("", |(_, v)| v.trim());
+    log.agents.lock().unwrap().push(String::from(agent));
+    let (status, body) = if identified(&head) {
+        route(path)
+    } else {
+        // BGG asks to know who is calling
+        ("403 Forbidden", String::new())
+    };
+    let encoding = if gzip {
+        "Content-Encoding: gzip\r\n"
+    } else {
+        ""
+    };
+    let body = encoded(body, gzip)?;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        encoding,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+/// Body as it goes on the wire.
+#[cfg(test)]
+fn encoded(body: String, gzip: bool) -> Result<Vec<u8>, AppError> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    if !gzip {
+        return Ok(body.into_bytes());
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes())?;
+    Ok(encoder.finish()?)
+}
+
+/// Body as it goes on the wire, only tests ask for gzip.
+#[cfg(not(test))]
+fn encoded(body: String, _gzip: bool) -> Result<Vec<u8>, AppError> {
+    Ok(body.into_bytes())
+}
+
+/// Client sends its name and asks for compressed answers.
+fn identified(head: &str) -> bool {
+    let head = head.to_lowercase();
+    head.contains("\r\nuser-agent: bgg_swing2/") && head.contains("\r\naccept-encoding: gzip")
+}
+
+pub fn ok(body: String) -> Answer {
+    ("200 OK", body)
+}
+
+pub fn not_found() -> Answer {
+    ("404 Not Found", String::new())
+}
+
+/// Number after `name` in the query of `path`, 1 if it is missing.
+pub fn param(path: &str, name: &str) -> u32 {
+    path.split(['?', '&'])
+        .find(|p| p.starts_with(name))
+        .and_then(|p| p[name.len()..].parse().ok())
+        .unwrap_or(1)
+}
+
+/// Row of the advanced search as BGG renders it.
+pub fn search_row(id: u32, name: &str, geek_rating: f64, avg_rating: f64, votes: u32) -> String {
+    format!(
+        "<tr><td></td><td></td><td><a href=\"/boardgame/{}/game-{}\">{}</a></td>\
+         <td>{}</td><td>{}</td><td>{}</td></tr>",
+        id, id, name, geek_rating, avg_rating, votes
+    )
+}
+
+/// Search page with `rows` under a header counting `total` games.
+/// A non-empty page gets a promo row too, pull must skip it.
+pub fn search_page(rows: &[String], total: u32) -> String {
+    let mut body = format!(
+        "<html><body><p>1 – {0} of {0}</p><table class=\"collection_table\"><tr><th></th></tr>",
+        total
+    );
+    for row in rows {
+        body.push_str(row);
+    }
+    if !rows.is_empty() {
+        // promo items come without ratings
+        body.push_str(
+            "<tr><td></td><td></td><td><a href=\"/boardgame/99999/promo\">Promo</a></td>\
+             <td>N/A</td><td>N/A</td><td>0</td></tr>",
+        );
+    }
+    body.push_str("</table></body></html>");
+    body
+}
+
+/// Page of user ratings, `total` is what BGG counts on all pages.
+pub fn comments_page(ratings: &[(String, f64)], total: Option<u32>) -> String {
+    let mut body = match total {
+        Some(t) => format!("<items><item><comments totalitems=\"{}\">", t),
+        None => String::from("<items><item><comments>"),
+    };
+    for (user, rating) in ratings {
+        body.push_str(&format!(
+            "<comment username=\"{}\" rating=\"{}\"/>",
+            user, rating
+        ));
+    }
+    body.push_str("</comments></item></items>");
+    body
+}
+
+/// Average sits where bgg::get_user_average_rating looks for it.
+pub fn profile_page(average: f64) -> String {
+    let mut body = String::from("<html><body>");
+    body.push_str(&"<div class=\"profile_block\"></div>".repeat(3));
+    body.push_str("<div class=\"profile_block\">");
+    body.push_str(&"<table></table>".repeat(5));
+    body.push_str(&format!(
+        "<table><tr><td></td></tr><tr><td></td></tr><tr><td>Average</td><td>{}</td></tr></table>",
+        average
+    ));
+    body.push_str("</div></body></html>");
+    body
+}
+
+/// User data of the json api with the average bgg::get_user_json_average
+/// reads.
+#[cfg(test)]
+pub fn user_data(average: f64) -> String {
+    format!(
+        "{{\"username\": \"x\", \"stats\": {{\"average_rating\": {}}}}}",
+        average
+    )
+}
@@ -0,0 +1,64 @@
+//! Wall time spent in each phase of balancing, summed over all threads.
+//! Collected only after `enable`, `balance --profile` does that.
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NANOS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static CALLS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Wait,  // rate limit and backoff delays
+    Fetch, // http request and response body
+    Parse, // html and xml into users, games, ratings
+    Db,    // sqlite reads and writes
+}
+
+pub const PHASES: [Phase; 4] = [Phase::Wait, Phase::Fetch, Phase::Parse, Phase::Db];
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Wait => "wait",
+            Phase::Fetch => "fetch",
+            Phase::Parse => "parse",
+            Phase::Db => "db",
+        }
+    }
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Runs `f`, adding its time to `phase` if profiling is on.
+pub fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let clock = Instant::now();
+    let result = f();
+    let nanos = clock.elapsed().as_nanos() as u64;
+    NANOS[phase as usize].fetch_add(nanos, Ordering::Relaxed);
+    CALLS[phase as usize].fetch_add(1, Ordering::Relaxed);
+    result
+}
+
+/// Total time and number of calls of a phase so far.
+pub fn total(phase: Phase) -> (Duration, u64) {
+    (
+        Duration::from_nanos(NANOS[phase as usize].load(Ordering::SeqCst)),
+        CALLS[phase as usize].load(Ordering::SeqCst),
+    )
+}
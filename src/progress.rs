@@ -0,0 +1,514 @@
+use crate::core::{PageCap, ReviewSummary, StabilizeSummary};
+use crate::error::AppError;
+use crate::runlog::{self, Tee};
+use bgg_swing2::{Game, User};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+/// Receives progress of the long running commands.
+/// Every hook does nothing unless overridden.
+pub trait Reporter {
+    fn on_pull_page(&mut self, _page: usize) {}
+    fn on_pull_warning(&mut self, _page: usize, _warning: &str) {}
+    fn on_user_seen(&mut self, _user: &User) {}
+    fn on_game_page(&mut self, _worker: usize, _game: &Game) {}
+    /// Error on the game the worker is busy with, it is still alive.
+    fn on_worker_error(&mut self, _worker: usize) {}
+    /// The worker took its last game, it is gone.
+    fn on_worker_done(&mut self, _worker: usize) {}
+    fn on_game_balanced(&mut self, _game: &Game) {}
+    /// Balanced game moved by more than the change threshold since the last pull.
+    fn on_game_changed(&mut self, game: &Game, _previous: f64) {
+        self.on_game_balanced(game);
+    }
+    fn on_error(&mut self, _error: &AppError) {}
+    fn on_game_regulated(&mut self, _game: &Game) {}
+    /// Game reached max_pages_per_game, `policy` tells what became of it.
+    fn on_game_capped(&mut self, _game: &Game, _policy: PageCap) {}
+    fn on_divergence(&mut self, _game: &Game, _delta: f64) {}
+    fn on_rate_limited(&mut self, _delay: Duration) {}
+    fn on_heartbeat(&mut self) {}
+    fn on_finished(&mut self, _summary: &StabilizeSummary) {}
+    /// User stats fetched again by review, `games` were recounted.
+    fn on_user_reviewed(&mut self, _user: &User, _flipped: bool, _games: usize) {}
+    fn on_user_skipped(&mut self, _user: &User) {}
+    fn on_review_finished(&mut self, _summary: &ReviewSummary) {}
+}
+
+/// Swallows everything.
+pub struct NullReporter;
+
+impl Reporter for NullReporter {}
+
+/// Colored human readable output, to stdout unless given a stream.
+pub struct ConsoleReporter<W = StandardStream> {
+    stdout: Tee<W>,
+    seen_users: u32,
+    reviewed_users: u32,
+    workers: Option<WorkerBlock>,
+}
+
+/// Live lines at the bottom of the terminal, one per worker.
+struct WorkerBlock {
+    stuck_after: Duration, // silent workers are red after that
+    rows: BTreeMap<usize, WorkerRow>,
+    drawn: usize, // lines on screen now
+}
+
+struct WorkerRow {
+    game_id: u32,
+    name: String,
+    page: u32,
+    heard: Instant,
+}
+
+impl ConsoleReporter {
+    pub fn new() -> ConsoleReporter {
+        ConsoleReporter::to(StandardStream::stdout(ColorChoice::Always))
+    }
+
+    /// Shows what every worker is busy with when stdout is a terminal.
+    pub fn with_workers(stuck_after: Duration) -> ConsoleReporter {
+        let mut reporter = ConsoleReporter::new();
+        if std::io::stdout().is_terminal() {
+            reporter.workers = Some(WorkerBlock {
+                stuck_after,
+                rows: BTreeMap::new(),
+                drawn: 0,
+            });
+        }
+        reporter
+    }
+}
+
+impl<W: WriteColor> ConsoleReporter<W> {
+    pub fn to(stream: W) -> ConsoleReporter<W> {
+        ConsoleReporter {
+            stdout: Tee::new(stream),
+            seen_users: 0,
+            reviewed_users: 0,
+            workers: None,
+        }
+    }
+
+    fn say(&mut self, color: Color, text: &str) {
+        self.clear_workers();
+        self.stdout
+            .set_color(ColorSpec::new().set_fg(Some(color)))
+            .unwrap();
+        writeln!(&mut self.stdout, "{}", text).unwrap();
+        self.draw_workers();
+    }
+
+    /// Moves the cursor up to the block and erases it.
+    fn clear_workers(&mut self) {
+        if let Some(block) = self.workers.as_mut() {
+            if block.drawn > 0 {
+                write!(self.stdout.inner_mut(), "\x1b[{}A\x1b[J", block.drawn).unwrap();
+                block.drawn = 0;
+            }
+        }
+    }
+
+    fn draw_workers(&mut self) {
+        self.clear_workers();
+        let block = match self.workers.as_mut() {
+            Some(block) => block,
+            None => return,
+        };
+        // redrawn in place, not part of the log
+        let stdout = self.stdout.inner_mut();
+        for (id, row) in &block.rows {
+            let silent = row.heard.elapsed();
+            let color = if silent > block.stuck_after {
+                Color::Red
+            } else {
+                Color::White
+            };
+            stdout
+                .set_color(ColorSpec::new().set_fg(Some(color)))
+                .unwrap();
+            writeln!(
+                stdout,
+                "#{} {} p.{} {}s ago",
+                id + 1,
+                row.name,
+                row.page,
+                silent.as_secs()
+            )
+            .unwrap();
+        }
+        stdout.reset().unwrap();
+        block.drawn = block.rows.len();
+    }
+
+    /// The game is done, its worker moves on or quits.
+    fn release_worker(&mut self, game: &Game) {
+        if let Some(block) = self.workers.as_mut() {
+            block.rows.retain(|_, row| row.game_id != game.id);
+        }
+    }
+}
+
+impl<W: WriteColor> Reporter for ConsoleReporter<W> {
+    fn on_pull_page(&mut self, page: usize) {
+        writeln!(&mut self.stdout, "Downloaded page: {}", page).unwrap();
+    }
+    fn on_pull_warning(&mut self, page: usize, warning: &str) {
+        self.say(Color::Yellow, &format!("Page {}: {}", page, warning));
+        self.stdout.reset().unwrap();
+    }
+    fn on_user_seen(&mut self, _user: &User) {
+        self.seen_users += 1;
+        if self.seen_users.is_multiple_of(50) {
+            self.say(Color::Green, "Found another 50.");
+        }
+    }
+    fn on_game_page(&mut self, worker: usize, game: &Game) {
+        let block = match self.workers.as_mut() {
+            Some(block) => block,
+            None => {
+                self.say(
+                    Color::Green,
+                    &format!("About to ask BGG about {}", game.name),
+                );
+                return;
+            }
+        };
+        block.rows.insert(
+            worker,
+            WorkerRow {
+                game_id: game.id,
+                name: game.name.clone(),
+                page: game.page,
+                heard: Instant::now(),
+            },
+        );
+        self.draw_workers();
+    }
+    fn on_worker_error(&mut self, worker: usize) {
+        if let Some(row) = self
+            .workers
+            .as_mut()
+            .and_then(|block| block.rows.get_mut(&worker))
+        {
+            row.heard = Instant::now();
+        }
+    }
+    fn on_worker_done(&mut self, worker: usize) {
+        if let Some(block) = self.workers.as_mut() {
+            block.rows.remove(&worker);
+        }
+        self.draw_workers();
+    }
+    fn on_game_balanced(&mut self, game: &Game) {
+        self.release_worker(game);
+        self.say(Color::Yellow, &format!("{} is balanced.", game.name));
+    }
+    fn on_game_changed(&mut self, game: &Game, previous: f64) {
+        self.release_worker(game);
+        let color = if game.rating > previous {
+            Color::Green
+        } else {
+            Color::Red
+        };
+        self.say(color, &change_line(game, previous));
+    }
+    fn on_error(&mut self, error: &AppError) {
+        self.say(Color::Red, &error.describe());
+    }
+    fn on_game_regulated(&mut self, game: &Game) {
+        self.release_worker(game);
+        self.say(
+            Color::Red,
+            &format!("Too many errors on {}, put aside.", game.name),
+        );
+    }
+    fn on_game_capped(&mut self, game: &Game, policy: PageCap) {
+        let text = match policy {
+            PageCap::Finish => format!(
+                "{} reached the page limit, rated from {} pages.",
+                game.name,
+                game.page - 1
+            ),
+            PageCap::Leave => format!("{} reached the page limit, left unstable.", game.name),
+        };
+        self.say(Color::Yellow, &text);
+    }
+    fn on_divergence(&mut self, game: &Game, delta: f64) {
+        self.say(
+            Color::Red,
+            &format!(
+                "{} is {:+.2} away from BGG average, check it by hand.",
+                game.name, delta
+            ),
+        );
+    }
+    fn on_heartbeat(&mut self) {
+        self.draw_workers();
+    }
+    fn on_finished(&mut self, summary: &StabilizeSummary) {
+        self.clear_workers();
+        if let Some(block) = self.workers.as_mut() {
+            block.rows.clear();
+        }
+        self.stdout.reset().unwrap();
+        writeln!(
+            &mut self.stdout,
+            "Seen {} users ({} known before), {} balanced games, {} errors, {} game requests.",
+            summary.users, summary.cached_users, summary.balanced, summary.errors, summary.requests
+        )
+        .unwrap();
+        if !summary.error_kinds.is_empty() {
+            let kinds: Vec<String> = summary
+                .error_kinds
+                .iter()
+                .map(|(kind, n)| format!("{} {}", n, kind))
+                .collect();
+            writeln!(&mut self.stdout, "Errors: {}.", kinds.join(", ")).unwrap();
+        }
+        if !summary.error_games.is_empty() {
+            let games: Vec<String> = summary
+                .error_games
+                .iter()
+                .map(|(name, n)| format!("{} ({})", name, n))
+                .collect();
+            writeln!(&mut self.stdout, "Most errors: {}.", games.join(", ")).unwrap();
+        }
+        if !summary.error_users.is_empty() {
+            let users: Vec<String> = summary
+                .error_users
+                .iter()
+                .map(|(user, n)| format!("{} ({})", user, n))
+                .collect();
+            writeln!(
+                &mut self.stdout,
+                "Users with most errors: {}.",
+                users.join(", ")
+            )
+            .unwrap();
+        }
+        writeln!(
+            &mut self.stdout,
+            "Average rate {:.1} requests per minute.",
+            summary.rate
+        )
+        .unwrap();
+        if summary.retried > 0 {
+            writeln!(
+                &mut self.stdout,
+                "{} games were retried after too many errors, {} of them failed again.",
+                summary.retried, summary.failed
+            )
+            .unwrap();
+        }
+        if summary.capped > 0 {
+            writeln!(
+                &mut self.stdout,
+                "{} games reached max_pages_per_game.",
+                summary.capped
+            )
+            .unwrap();
+        }
+        if summary.remaining > 0 {
+            writeln!(
+                &mut self.stdout,
+                "{} games were left for the next run.",
+                summary.remaining
+            )
+            .unwrap();
+        }
+    }
+    fn on_user_reviewed(&mut self, user: &User, flipped: bool, games: usize) {
+        self.reviewed_users += 1;
+        if flipped {
+            self.say(
+                Color::Yellow,
+                &format!("Trust of {} changed, {} games were recounted.", user, games),
+            );
+        } else if self.reviewed_users.is_multiple_of(50) {
+            self.say(Color::Green, "Reviewed another 50.");
+        }
+    }
+    fn on_user_skipped(&mut self, user: &User) {
+        self.say(
+            Color::Red,
+            &format!("Failed to review {}, left for the next review.", user),
+        );
+    }
+    fn on_review_finished(&mut self, summary: &ReviewSummary) {
+        self.stdout.reset().unwrap();
+        writeln!(
+            &mut self.stdout,
+            "Reviewed {} users, trust changed for {}, {} games recounted, {} errors, {} requests.",
+            summary.reviewed, summary.flipped, summary.games, summary.errors, summary.requests
+        )
+        .unwrap();
+        if summary.skipped > 0 {
+            writeln!(
+                &mut self.stdout,
+                "{} users failed every try and were skipped.",
+                summary.skipped
+            )
+            .unwrap();
+        }
+        if summary.remaining > 0 {
+            writeln!(
+                &mut self.stdout,
+                "{} users were left for the next review.",
+                summary.remaining
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// "Name 8.61 → 8.47 (-0.14)" for a game whose rating moved.
+fn change_line(game: &Game, previous: f64) -> String {
+    format!(
+        "{} {:.2} → {:.2} ({:+.2})",
+        game.name,
+        previous,
+        game.rating,
+        game.rating - previous
+    )
+}
+
+/// One json object per line, meant for other programs.
+pub struct JsonReporter;
+
+impl JsonReporter {
+    fn emit(&self, value: serde_json::Value) {
+        runlog::say(&value.to_string());
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn on_pull_page(&mut self, page: usize) {
+        self.emit(json!({"event": "pull_page", "page": page}));
+    }
+    fn on_pull_warning(&mut self, page: usize, warning: &str) {
+        self.emit(json!({"event": "pull_warning", "page": page, "warning": warning}));
+    }
+    fn on_user_seen(&mut self, user: &User) {
+        self.emit(json!({"event": "user_seen", "user": user}));
+    }
+    fn on_game_page(&mut self, worker: usize, game: &Game) {
+        self.emit(
+            json!({"event": "game_page", "worker": worker, "id": game.id, "page": game.page}),
+        );
+    }
+    fn on_game_balanced(&mut self, game: &Game) {
+        self.emit(json!({
+            "event": "game_balanced",
+            "id": game.id,
+            "name": game.name,
+            "rating": game.rating,
+            "votes": game.votes
+        }));
+    }
+    fn on_game_changed(&mut self, game: &Game, previous: f64) {
+        self.emit(json!({
+            "event": "game_changed",
+            "id": game.id,
+            "name": game.name,
+            "rating": game.rating,
+            "previous_rating": previous,
+            "votes": game.votes
+        }));
+    }
+    fn on_error(&mut self, error: &AppError) {
+        self.emit(json!({"event": "error", "message": error.to_string()}));
+    }
+    fn on_game_regulated(&mut self, game: &Game) {
+        self.emit(json!({"event": "game_regulated", "id": game.id, "page": game.page}));
+    }
+    fn on_game_capped(&mut self, game: &Game, policy: PageCap) {
+        self.emit(json!({
+            "event": "game_capped",
+            "id": game.id,
+            "page": game.page,
+            "policy": policy
+        }));
+    }
+    fn on_divergence(&mut self, game: &Game, delta: f64) {
+        self.emit(json!({
+            "event": "divergence",
+            "id": game.id,
+            "name": game.name,
+            "rating": game.rating,
+            "bgg_avg_rating": game.bgg_avg_rating,
+            "delta": delta
+        }));
+    }
+    fn on_rate_limited(&mut self, delay: Duration) {
+        self.emit(json!({"event": "rate_limited", "delay_ms": delay.as_millis() as u64}));
+    }
+    fn on_heartbeat(&mut self) {
+        self.emit(json!({"event": "heartbeat"}));
+    }
+    fn on_finished(&mut self, summary: &StabilizeSummary) {
+        self.emit(json!({"event": "finished", "summary": summary}));
+    }
+    fn on_user_reviewed(&mut self, user: &User, flipped: bool, games: usize) {
+        self.emit(json!({
+            "event": "user_reviewed",
+            "user": user,
+            "flipped": flipped,
+            "games": games
+        }));
+    }
+    fn on_user_skipped(&mut self, user: &User) {
+        self.emit(json!({"event": "user_skipped", "user": user}));
+    }
+    fn on_review_finished(&mut self, summary: &ReviewSummary) {
+        self.emit(json!({"event": "review_finished", "summary": summary}));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rated(rating: f64) -> Game {
+        Game {
+            rating,
+            ..Game::from_search(13, String::from("Catan"), 1000, 7.0, 7.1)
+        }
+    }
+
+    #[test]
+    fn summary_groups_errors_by_kind_game_and_user() {
+        let mut reporter = ConsoleReporter::to(termcolor::Buffer::no_color());
+        let summary = StabilizeSummary {
+            errors: 3,
+            error_kinds: BTreeMap::from([("network", 2), ("not-found", 1)]),
+            error_games: vec![(String::from("Catan"), 3)],
+            error_users: vec![(String::from("carol"), 2), (String::from("bob"), 1)],
+            ..StabilizeSummary::default()
+        };
+        reporter.on_finished(&summary);
+        let out = String::from_utf8(reporter.stdout.inner_mut().as_slice().to_vec()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].contains(", 3 errors,"), "{}", out);
+        assert_eq!(lines[1], "Errors: 2 network, 1 not-found.");
+        assert_eq!(lines[2], "Most errors: Catan (3).");
+        assert_eq!(lines[3], "Users with most errors: carol (2), bob (1).");
+
+        // a clean run has none of them
+        let mut reporter = ConsoleReporter::to(termcolor::Buffer::no_color());
+        reporter.on_finished(&StabilizeSummary::default());
+        let out = String::from_utf8(reporter.stdout.inner_mut().as_slice().to_vec()).unwrap();
+        assert!(!out.contains("errors:"), "{}", out);
+        assert!(!out.contains("Errors:"), "{}", out);
+    }
+
+    #[test]
+    fn change_line_shows_both_ratings_and_the_sign() {
+        assert_eq!(change_line(&rated(8.47), 8.61), "Catan 8.61 → 8.47 (-0.14)");
+        assert_eq!(change_line(&rated(7.25), 7.0), "Catan 7.00 → 7.25 (+0.25)");
+    }
+}
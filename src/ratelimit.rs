@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often a waiting thread looks at the stop flag
+const POLL: Duration = Duration::from_millis(100);
+
+/// Request budget shared by every thread. Requests are spaced evenly,
+/// one per `interval`, no bursts.
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>, // earliest moment of the next request
+    granted: AtomicU32,
+}
+
+impl RateLimiter {
+    pub fn per_minute(requests: u32) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs(60) / requests.max(1),
+            next: Mutex::new(Instant::now()),
+            granted: AtomicU32::new(0),
+        }
+    }
+
+    /// Blocks until the next request is allowed. Returns false
+    /// without waiting any longer once `running` is cleared.
+    pub fn acquire(&self, running: &AtomicBool) -> bool {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        loop {
+            if !running.load(Ordering::SeqCst) {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= slot {
+                break;
+            }
+            thread::sleep((slot - now).min(POLL));
+        }
+        self.granted.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    /// Number of requests let through so far.
+    pub fn granted(&self) -> u32 {
+        self.granted.load(Ordering::SeqCst)
+    }
+}
+
+/// Sleeps for `delay`, looking at `running` meanwhile. Returns false
+/// as soon as it is cleared.
+pub fn pause(delay: Duration, running: &AtomicBool) -> bool {
+    let until = Instant::now() + delay;
+    loop {
+        if !running.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep((until - now).min(POLL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn requests_are_spaced_by_the_interval() {
+        let limiter = RateLimiter::per_minute(600); // one per 100 ms
+        let running = AtomicBool::new(true);
+        let start = Instant::now();
+        for _ in 0..4 {
+            assert!(limiter.acquire(&running));
+        }
+        // the first one goes at once
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(limiter.granted(), 4);
+    }
+
+    #[test]
+    fn stop_ends_the_wait() {
+        let limiter = RateLimiter::per_minute(1);
+        let running = Arc::new(AtomicBool::new(true));
+        assert!(limiter.acquire(&running));
+        let flag = running.clone();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            flag.store(false, Ordering::SeqCst);
+        });
+        // the next slot is a minute away
+        let start = Instant::now();
+        assert!(!limiter.acquire(&running));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        stopper.join().unwrap();
+        assert_eq!(limiter.granted(), 1);
+    }
+
+    #[test]
+    fn stopped_before_the_wait_asks_nothing() {
+        let limiter = RateLimiter::per_minute(u32::MAX);
+        assert!(!limiter.acquire(&AtomicBool::new(false)));
+        assert_eq!(limiter.granted(), 0);
+    }
+}
@@ -0,0 +1,1452 @@
+use crate::db::{Order, UserRecord};
+use crate::diff::DbDiff;
+use crate::error::AppError;
+use bgg_swing2::Game;
+use serde_json::json;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+// Header and cell of every column come from the same entry,
+// so they can't drift apart.
+type Cell = fn(&Game, &Style, &Prior) -> String;
+
+const COLUMNS: [(&str, Cell); 11] = [
+    ("Id", |g, _, _| g.id.to_string()),
+    ("Name", |g, _, _| g.name.clone()),
+    ("Rating", |g, s, _| s.number(g.rating)),
+    ("Votes", |g, _, _| g.votes.to_string()),
+    ("Geek Rating", |g, s, _| s.number(g.bgg_geek_rating)),
+    ("Avg BGG Rating", |g, s, _| s.number(g.bgg_avg_rating)),
+    ("BGG Votes", |g, _, _| g.bgg_num_votes.to_string()),
+    ("Weight", |g, s, _| {
+        g.weight.map_or(String::new(), |w| s.number(w))
+    }),
+    ("Stable", |g, _, _| yes_no(g.stable)),
+    ("Sampled", |g, _, _| yes_no(g.sampled)),
+    ("Flagged", |g, _, _| yes_no(g.flagged)),
+];
+const EXTENDED_COLUMNS: [(&str, Cell); 6] = [
+    ("CI Low", |g, s, _| s.number(confidence_interval(g).0)),
+    ("CI High", |g, s, _| s.number(confidence_interval(g).1)),
+    ("Bayes Rating", |g, s, p| s.number(bayesian(g, p))),
+    ("Polarizing", |g, s, _| {
+        g.polarizing.map_or(String::new(), |p| s.number(p))
+    }),
+    ("Hype", |g, s, _| {
+        hype(g).map_or(String::new(), |h| s.number(h))
+    }),
+    ("Tier", |g, _, _| {
+        g.tier.map_or(String::new(), |t| t.to_string())
+    }),
+];
+// Basic columns don't use the prior.
+const NO_PRIOR: Prior = Prior {
+    votes: 0.0,
+    mean: 0.0,
+};
+
+// Used for games whose ratings were not stored, typical for BGG.
+const ASSUMED_SD: f64 = 1.5;
+const Z_95: f64 = 1.96;
+
+/// 95% confidence interval of the rating: normal approximation,
+/// mean ± 1.96 · sd / √n, clamped to the 1-10 scale. Less than
+/// two ratings tell nothing, the whole scale is returned.
+pub fn confidence_interval(game: &Game) -> (f64, f64) {
+    if game.votes < 2 {
+        return (1.0, 10.0);
+    }
+    let sd = game.rating_sd.unwrap_or(ASSUMED_SD);
+    let margin = Z_95 * sd / f64::from(game.votes).sqrt();
+    (
+        (game.rating - margin).max(1.0),
+        (game.rating + margin).min(10.0),
+    )
+}
+
+/// Dummy votes that pull every game's rating towards the mean,
+/// the way BGG computes geek rating.
+pub struct Prior {
+    pub votes: f64,
+    pub mean: f64,
+}
+
+/// (prior votes · prior mean + votes · rating) / (prior votes + votes)
+pub fn bayesian(game: &Game, prior: &Prior) -> f64 {
+    let votes = f64::from(game.votes);
+    let total = prior.votes + votes;
+    if total == 0.0 {
+        return prior.mean;
+    }
+    (prior.votes * prior.mean + votes * game.rating) / total
+}
+
+/// Value of a db::DERIVED_FIELDS sort key.
+fn derived(key: &str, game: &Game, prior: &Prior) -> f64 {
+    match key {
+        "bayes" => bayesian(game, prior),
+        _ => confidence_interval(game).0,
+    }
+}
+
+/// Sorts by every key of `order` in turn, the derived ones too,
+/// the way the query sorts by the others: missing values go first
+/// in ascending order.
+pub fn sort(games: &mut [Game], order: &Order, prior: &Prior) {
+    games.sort_by(|a, b| {
+        let ordering = order
+            .keys
+            .iter()
+            .map(|key| compare_by(key, a, b, prior))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or(Ordering::Equal);
+        if order.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+}
+
+/// Ascending order of two games by one key of db::SORT_FIELDS or
+/// db::DERIVED_FIELDS.
+fn compare_by(key: &str, a: &Game, b: &Game, prior: &Prior) -> Ordering {
+    let number = |f: fn(&Game) -> f64| f(a).total_cmp(&f(b));
+    // None < Some like null in sqlite
+    let optional = |f: fn(&Game) -> Option<f64>| match (f(a), f(b)) {
+        (Some(x), Some(y)) => x.total_cmp(&y),
+        (x, y) => x.is_some().cmp(&y.is_some()),
+    };
+    match key {
+        "rating" => number(|g| g.rating),
+        "votes" => a.votes.cmp(&b.votes),
+        "geek-rating" => number(|g| g.bgg_geek_rating),
+        "bgg-rating" => number(|g| g.bgg_avg_rating),
+        "bgg-votes" => a.bgg_num_votes.cmp(&b.bgg_num_votes),
+        "weight" => optional(|g| g.weight),
+        "name" => a.name.cmp(&b.name),
+        "id" => a.id.cmp(&b.id),
+        "polarizing" => optional(|g| g.polarizing),
+        key => derived(key, a, prior).total_cmp(&derived(key, b, prior)),
+    }
+}
+
+/// How numbers and columns are rendered.
+#[derive(Clone, Copy)]
+pub struct Style {
+    precision: usize,
+    decimal_comma: bool,
+    delimiter: char,
+}
+
+impl Style {
+    pub fn new(precision: usize, decimal_comma: bool, delimiter: char) -> Result<Style, AppError> {
+        if decimal_comma && delimiter == ',' {
+            return Err(AppError::Config(String::from(
+                "Comma can't be both decimal separator and delimiter, use --delimiter ';'",
+            )));
+        }
+        Ok(Style {
+            precision,
+            decimal_comma,
+            delimiter,
+        })
+    }
+
+    /// Cell text that can't break the row, quoted the csv way if it
+    /// has the delimiter, a quote or a line break inside, e.g. a game
+    /// name with a comma.
+    fn field(&self, text: &str) -> String {
+        quoted(text, self.delimiter)
+    }
+
+    /// The only place where floating point columns are rendered.
+    fn number(&self, value: f64) -> String {
+        let text = format!("{:.*}", self.precision, value);
+        if self.decimal_comma {
+            text.replace('.', ",")
+        } else {
+            text
+        }
+    }
+}
+
+/// How much untrusted users raise the rating: average of every rating
+/// minus the trusted one. None unless the game was balanced with
+/// keep_all_ratings.
+pub fn hype(game: &Game) -> Option<f64> {
+    game.all_rating
+        .filter(|_| game.all_votes.unwrap_or(0) > 0)
+        .map(|all| all - game.rating)
+}
+
+/// Writes delimited report, every rating column is rounded
+/// to the same precision. Given the `extended` prior, confidence
+/// interval, bayesian rating, polarizing, hype and tier columns are added.
+/// The group, if any, goes to the last column.
+pub fn write_text(
+    out: &mut impl Write,
+    games: &[Game],
+    style: &Style,
+    extended: Option<&Prior>,
+    group: Option<Group>,
+) -> io::Result<()> {
+    write_rows(out, games, style, extended, group)
+}
+
+/// Tab separated report, same columns as write_text whatever
+/// --delimiter is.
+pub fn write_tsv(
+    out: &mut impl Write,
+    games: &[Game],
+    style: &Style,
+    extended: Option<&Prior>,
+    group: Option<Group>,
+) -> io::Result<()> {
+    let style = Style {
+        delimiter: '\t',
+        ..*style
+    };
+    write_rows(out, games, &style, extended, group)
+}
+
+/// Comma separated report, same columns as write_text. With decimal
+/// comma fields are separated by semicolons, as spreadsheets expect.
+pub fn write_csv(
+    out: &mut impl Write,
+    games: &[Game],
+    style: &Style,
+    extended: Option<&Prior>,
+    group: Option<Group>,
+) -> io::Result<()> {
+    let style = Style {
+        delimiter: if style.decimal_comma { ';' } else { ',' },
+        ..*style
+    };
+    write_rows(out, games, &style, extended, group)
+}
+
+/// Markdown table with the columns of write_text, pipes in names
+/// are escaped.
+pub fn write_markdown(
+    out: &mut impl Write,
+    games: &[Game],
+    style: &Style,
+    extended: Option<&Prior>,
+    group: Option<Group>,
+) -> io::Result<()> {
+    let columns = columns(extended);
+    let prior = extended.unwrap_or(&NO_PRIOR);
+    let mut header: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+    if group.is_some() {
+        header.push("Group");
+    }
+    writeln!(out, "| {} |", header.join(" | "))?;
+    writeln!(out, "|{}", "---|".repeat(header.len()))?;
+    for game in games {
+        let mut row: Vec<String> = columns
+            .iter()
+            .map(|(_, cell)| cell(game, style, prior).replace('|', "\\|"))
+            .collect();
+        if let Some(group) = group {
+            row.push(group(game).replace('|', "\\|"));
+        }
+        writeln!(out, "| {} |", row.join(" | "))?;
+    }
+    Ok(())
+}
+
+fn write_rows(
+    out: &mut impl Write,
+    games: &[Game],
+    style: &Style,
+    extended: Option<&Prior>,
+    group: Option<Group>,
+) -> io::Result<()> {
+    let delimiter = style.delimiter.to_string();
+    let columns = columns(extended);
+    let mut header: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+    if group.is_some() {
+        header.push("Group");
+    }
+    writeln!(out, "{}", header.join(&delimiter))?;
+    for game in games {
+        let mut row = cells(&columns, game, style, extended);
+        if let Some(group) = group {
+            row.push(style.field(&group(game)));
+        }
+        writeln!(out, "{}", row.join(&delimiter))?;
+    }
+    Ok(())
+}
+
+fn columns(extended: Option<&Prior>) -> Vec<(&'static str, Cell)> {
+    let mut columns = COLUMNS.to_vec();
+    if extended.is_some() {
+        columns.extend_from_slice(&EXTENDED_COLUMNS);
+    }
+    columns
+}
+
+fn cells(
+    columns: &[(&str, Cell)],
+    game: &Game,
+    style: &Style,
+    extended: Option<&Prior>,
+) -> Vec<String> {
+    let prior = extended.unwrap_or(&NO_PRIOR);
+    columns
+        .iter()
+        .map(|(_, cell)| style.field(&cell(game, style, prior)))
+        .collect()
+}
+
+/// Formats written a game at a time as the db reads them, with the
+/// same bytes as the writers that take the whole list.
+pub enum Stream<'a> {
+    Tsv(Option<&'a Prior>),
+    Geeklist(&'a str), // template
+    Ndjson(Option<&'a Prior>),
+}
+
+/// Header of a streamed report, rows follow from write_streamed.
+pub fn write_stream_header(out: &mut impl Write, stream: &Stream) -> io::Result<()> {
+    match stream {
+        Stream::Tsv(extended) => {
+            let header: Vec<&str> = columns(*extended).iter().map(|(name, _)| *name).collect();
+            writeln!(out, "{}", header.join("\t"))
+        }
+        Stream::Geeklist(_) => writeln!(out, "objectid,objectname,comment"),
+        Stream::Ndjson(_) => Ok(()),
+    }
+}
+
+/// Row of a streamed report, written as soon as the game is read.
+pub fn write_streamed(
+    out: &mut impl Write,
+    stream: &Stream,
+    game: &Game,
+    style: &Style,
+) -> io::Result<()> {
+    match stream {
+        Stream::Tsv(extended) => {
+            let style = Style {
+                delimiter: '\t',
+                ..*style
+            };
+            let row = cells(&columns(*extended), game, &style, *extended);
+            writeln!(out, "{}", row.join("\t"))
+        }
+        Stream::Geeklist(template) => writeln!(out, "{}", geeklist_row(game, template, style)),
+        Stream::Ndjson(extended) => writeln!(out, "{}", game_json(game, *extended)),
+    }
+}
+
+/// Game as a json object, numbers unrounded. Given the `extended`
+/// prior, the columns of the extended report are added.
+fn game_json(game: &Game, extended: Option<&Prior>) -> serde_json::Value {
+    let mut value = json!({
+        "id": game.id,
+        "name": game.name,
+        "rating": game.rating,
+        "votes": game.votes,
+        "bgg_geek_rating": game.bgg_geek_rating,
+        "bgg_avg_rating": game.bgg_avg_rating,
+        "bgg_num_votes": game.bgg_num_votes,
+        "weight": game.weight,
+        "year": game.year,
+        "stable": game.stable,
+        "sampled": game.sampled,
+        "flagged": game.flagged,
+    });
+    if let Some(prior) = extended {
+        let (low, high) = confidence_interval(game);
+        value["ci_low"] = json!(low);
+        value["ci_high"] = json!(high);
+        value["bayes_rating"] = json!(bayesian(game, prior));
+        value["polarizing"] = json!(game.polarizing);
+        value["hype"] = json!(hype(game));
+        value["tier"] = json!(game.tier);
+    }
+    value
+}
+
+/// One json object per game and line. The group, if any, is
+/// a `group` field.
+pub fn write_ndjson(
+    out: &mut impl Write,
+    games: &[Game],
+    extended: Option<&Prior>,
+    group: Option<Group>,
+) -> io::Result<()> {
+    for game in games {
+        let mut value = game_json(game, extended);
+        if let Some(group) = group {
+            value["group"] = json!(group(game));
+        }
+        writeln!(out, "{}", value)?;
+    }
+    Ok(())
+}
+
+/// The games as one json array, fields as in write_ndjson.
+pub fn write_json(
+    out: &mut impl Write,
+    games: &[Game],
+    extended: Option<&Prior>,
+    group: Option<Group>,
+) -> io::Result<()> {
+    let values: Vec<serde_json::Value> = games
+        .iter()
+        .map(|game| {
+            let mut value = game_json(game, extended);
+            if let Some(group) = group {
+                value["group"] = json!(group(game));
+            }
+            value
+        })
+        .collect();
+    writeln!(out, "{}", serde_json::Value::Array(values))
+}
+
+const USER_COLUMNS: [&str; 6] = ["Name", "Trust", "Pinned", "Average", "Ratings", "Updated"];
+
+/// Header of the users listing, rows follow from write_user.
+pub fn write_user_header(out: &mut impl Write, style: &Style) -> io::Result<()> {
+    writeln!(out, "{}", USER_COLUMNS.join(&style.delimiter.to_string()))
+}
+
+/// Row of the users listing, written as soon as the user is read.
+pub fn write_user(out: &mut impl Write, user: &UserRecord, style: &Style) -> io::Result<()> {
+    let row = [
+        style.field(&user.name),
+        String::from(if user.trusted { "trusted" } else { "untrusted" }),
+        yes_no(user.pinned),
+        user.average.map_or(String::new(), |a| style.number(a)),
+        user.ratings.to_string(),
+        style.field(user.updated.as_deref().unwrap_or("")),
+    ];
+    writeln!(out, "{}", row.join(&style.delimiter.to_string()))
+}
+
+/// User of the listing as one json object and line, the average
+/// is not rounded.
+pub fn write_user_json(out: &mut impl Write, user: &UserRecord) -> io::Result<()> {
+    let value = json!({
+        "name": user.name,
+        "trusted": user.trusted,
+        "pinned": user.pinned,
+        "average": user.average,
+        "ratings": user.ratings,
+        "updated": user.updated,
+    });
+    writeln!(out, "{}", value)
+}
+
+/// Label of the group a game is reported in.
+pub type Group = fn(&Game) -> String;
+
+/// Groups for --group-by.
+pub const GROUPS: [(&str, Group); 1] = [("decade", decade)];
+
+pub fn group_by(key: &str) -> Option<Group> {
+    GROUPS.iter().find(|(k, _)| *k == key).map(|(_, g)| *g)
+}
+
+/// Decade the game was published in, e.g. "1990s".
+fn decade(game: &Game) -> String {
+    game.year.map_or(String::from("Unknown year"), |y| {
+        format!("{}s", y / 10 * 10)
+    })
+}
+
+/// The first `top` games, if given, then grouped. Groups are formed
+/// from what is left, a group of games past the top is not there.
+pub fn arrange(mut games: Vec<Game>, top: Option<usize>, group: Option<Group>) -> Vec<Game> {
+    if let Some(top) = top {
+        games.truncate(top);
+    }
+    match group {
+        Some(group) => grouped(games, group),
+        None => games,
+    }
+}
+
+/// Items of a group next to each other. Groups come in the order
+/// of their first items and items keep their order inside a group,
+/// so the sort still decides what is on top.
+fn grouped<T>(items: Vec<T>, label: impl Fn(&T) -> String) -> Vec<T> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut items: Vec<(usize, T)> = items
+        .into_iter()
+        .map(|item| {
+            let label = label(&item);
+            let at = match labels.iter().position(|l| *l == label) {
+                Some(at) => at,
+                None => {
+                    labels.push(label);
+                    labels.len() - 1
+                }
+            };
+            (at, item)
+        })
+        .collect();
+    items.sort_by_key(|(at, _)| *at);
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Name with * for unstable, ~ for sampled and ! for flagged games.
+fn mark(game: &Game) -> String {
+    let mut name = game.name.clone();
+    if !game.stable {
+        name.push('*');
+    }
+    if game.sampled {
+        name.push('~');
+    }
+    if game.flagged {
+        name.push('!');
+    }
+    name
+}
+
+fn yes_no(value: bool) -> String {
+    String::from(if value { "yes" } else { "no" })
+}
+
+/// Writes `n` games that climbed the most against BGG geek rating order,
+/// then `n` that fell the most. Games are expected sorted by rating.
+pub fn write_movers(
+    out: &mut impl Write,
+    games: &[Game],
+    n: usize,
+    style: &Style,
+) -> io::Result<()> {
+    // BGG ranks by geek rating, the list position is our rank
+    let mut by_geek: Vec<&Game> = games.iter().collect();
+    by_geek.sort_by(|a, b| b.bgg_geek_rating.total_cmp(&a.bgg_geek_rating));
+    let bgg_rank: HashMap<u32, usize> = by_geek
+        .iter()
+        .enumerate()
+        .map(|(i, game)| (game.id, i))
+        .collect();
+    let mut movers: Vec<(i64, usize, usize, &Game)> = games
+        .iter()
+        .enumerate()
+        .map(|(new, game)| {
+            let old = bgg_rank.get(&game.id).cloned().unwrap_or(new);
+            (old as i64 - new as i64, old + 1, new + 1, game)
+        })
+        .collect();
+    movers.sort_by_key(|&(shift, ..)| std::cmp::Reverse(shift));
+
+    let delimiter = style.delimiter.to_string();
+    let header = ["Id", "Name", "BGG Rank", "Rank", "Shift", "Swing"].join(&delimiter);
+    let up = movers.iter().take(n).filter(|m| m.0 > 0);
+    let down = movers.iter().rev().take(n).filter(|m| m.0 < 0);
+    for (title, part) in [
+        ("Moved up", up.collect::<Vec<_>>()),
+        ("Moved down", down.collect()),
+    ]
+    .iter()
+    {
+        writeln!(out, "{}:", title)?;
+        writeln!(out, "{}", header)?;
+        for (shift, old, new, game) in part {
+            let row = [
+                game.id.to_string(),
+                style.field(&game.name),
+                old.to_string(),
+                new.to_string(),
+                format!("{:+}", shift),
+                style.number(game.rating - game.bgg_avg_rating),
+            ];
+            writeln!(out, "{}", row.join(&delimiter))?;
+        }
+    }
+    Ok(())
+}
+
+/// Place and rating of a game in one of the compared lists.
+#[derive(Clone, Copy)]
+pub struct Standing {
+    pub rank: usize,
+    pub rating: f64,
+}
+
+/// Game as it was in the old list and is in the new one,
+/// None on the side it is missing from.
+pub struct Change {
+    pub id: u32,
+    pub name: String,
+    pub old: Option<Standing>,
+    pub new: Option<Standing>,
+}
+
+/// Games that entered, left or moved between two lists sorted best
+/// first. Games with the same rank and rating in both are left out.
+pub fn compare(old: &[Game], new: &[Game]) -> Vec<Change> {
+    let old_standings = standings(old);
+    let mut changes: Vec<Change> = new
+        .iter()
+        .enumerate()
+        .map(|(i, game)| Change {
+            id: game.id,
+            name: game.name.clone(),
+            old: old_standings.get(&game.id).cloned(),
+            new: Some(Standing {
+                rank: i + 1,
+                rating: game.rating,
+            }),
+        })
+        .collect();
+    let new_ids: HashSet<u32> = new.iter().map(|g| g.id).collect();
+    changes.extend(
+        old.iter()
+            .filter(|game| !new_ids.contains(&game.id))
+            .map(|game| Change {
+                id: game.id,
+                name: game.name.clone(),
+                old: old_standings.get(&game.id).cloned(),
+                new: None,
+            }),
+    );
+    changes.retain(|c| match (c.old, c.new) {
+        (Some(old), Some(new)) => old.rank != new.rank || old.rating != new.rating,
+        _ => true,
+    });
+    changes
+}
+
+/// Rank and rating of every game of a list sorted best first.
+fn standings(games: &[Game]) -> HashMap<u32, Standing> {
+    games
+        .iter()
+        .enumerate()
+        .map(|(i, game)| {
+            let standing = Standing {
+                rank: i + 1,
+                rating: game.rating,
+            };
+            (game.id, standing)
+        })
+        .collect()
+}
+
+/// Writes games that entered, left and moved, each part under its
+/// `title:` line. Moved games go by the size of the rank shift.
+pub fn write_comparison(out: &mut impl Write, changes: &[Change], style: &Style) -> io::Result<()> {
+    let delimiter = style.delimiter.to_string();
+    writeln!(out, "Entered:")?;
+    writeln!(out, "{}", ["Id", "Name", "Rank", "Rating"].join(&delimiter))?;
+    for change in changes {
+        if let (None, Some(new)) = (change.old, change.new) {
+            let row = [
+                change.id.to_string(),
+                style.field(&change.name),
+                new.rank.to_string(),
+                style.number(new.rating),
+            ];
+            writeln!(out, "{}", row.join(&delimiter))?;
+        }
+    }
+    writeln!(out, "Left:")?;
+    writeln!(
+        out,
+        "{}",
+        ["Id", "Name", "Old Rank", "Old Rating"].join(&delimiter)
+    )?;
+    for change in changes {
+        if let (Some(old), None) = (change.old, change.new) {
+            let row = [
+                change.id.to_string(),
+                style.field(&change.name),
+                old.rank.to_string(),
+                style.number(old.rating),
+            ];
+            writeln!(out, "{}", row.join(&delimiter))?;
+        }
+    }
+    let mut moved: Vec<(&Change, Standing, Standing)> = changes
+        .iter()
+        .filter_map(|c| match (c.old, c.new) {
+            (Some(old), Some(new)) => Some((c, old, new)),
+            _ => None,
+        })
+        .collect();
+    moved.sort_by_key(|(_, old, new)| std::cmp::Reverse((old.rank as i64 - new.rank as i64).abs()));
+    writeln!(out, "Moved:")?;
+    let header = [
+        "Id",
+        "Name",
+        "Old Rank",
+        "Rank",
+        "Shift",
+        "Old Rating",
+        "Rating",
+        "Change",
+    ];
+    writeln!(out, "{}", header.join(&delimiter))?;
+    for (change, old, new) in moved {
+        let row = [
+            change.id.to_string(),
+            style.field(&change.name),
+            old.rank.to_string(),
+            new.rank.to_string(),
+            format!("{:+}", old.rank as i64 - new.rank as i64),
+            style.number(old.rating),
+            style.number(new.rating),
+            style.number(new.rating - old.rating),
+        ];
+        writeln!(out, "{}", row.join(&delimiter))?;
+    }
+    Ok(())
+}
+
+/// Same changes as write_comparison, one json object per game.
+pub fn comparison_json(changes: &[Change]) -> serde_json::Value {
+    let side = |s: Option<Standing>| s.map(|s| json!({"rank": s.rank, "rating": s.rating}));
+    let games: Vec<serde_json::Value> = changes
+        .iter()
+        .map(|c| {
+            json!({
+                "id": c.id,
+                "name": c.name,
+                "old": side(c.old),
+                "new": side(c.new),
+            })
+        })
+        .collect();
+    json!({ "games": games })
+}
+
+/// Writes shared games that differ, games found in one db only, each
+/// part under its `title:` line, and the summary of shared ratings.
+pub fn write_db_diff(
+    out: &mut impl Write,
+    diff: &DbDiff,
+    style: &Style,
+    names: (&str, &str),
+    threshold: f64,
+) -> io::Result<()> {
+    let delimiter = style.delimiter.to_string();
+    writeln!(out, "Differ by more than {}:", style.number(threshold))?;
+    writeln!(
+        out,
+        "{}",
+        ["Id", "Name", names.0, names.1, "Difference"].join(&delimiter)
+    )?;
+    for shared in &diff.differing {
+        let row = [
+            shared.id.to_string(),
+            style.field(&shared.name),
+            style.number(shared.rating),
+            style.number(shared.other_rating),
+            style.number(shared.difference()),
+        ];
+        writeln!(out, "{}", row.join(&delimiter))?;
+    }
+    for (name, games) in [(names.0, &diff.only_this), (names.1, &diff.only_other)] {
+        writeln!(out, "Only in {}:", name)?;
+        writeln!(out, "{}", ["Id", "Name", "Rating"].join(&delimiter))?;
+        for game in games {
+            let row = [
+                game.id.to_string(),
+                style.field(&game.name),
+                style.number(game.rating),
+            ];
+            writeln!(out, "{}", row.join(&delimiter))?;
+        }
+    }
+    let stat = |value: Option<f64>| value.map_or_else(|| String::from("n/a"), |v| style.number(v));
+    writeln!(out, "Shared games: {}", diff.shared)?;
+    writeln!(
+        out,
+        "Mean absolute difference: {}",
+        stat(diff.mean_abs_difference)
+    )?;
+    writeln!(out, "Rank correlation: {}", stat(diff.rank_correlation))?;
+    Ok(())
+}
+
+/// Same games as write_db_diff, one row each with an empty rating
+/// on the side the game is missing from. Summary is left out.
+pub fn write_db_diff_csv(out: &mut impl Write, diff: &DbDiff) -> io::Result<()> {
+    writeln!(out, "id,name,rating,other_rating,difference")?;
+    for shared in &diff.differing {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            shared.id,
+            csv_field(&shared.name),
+            shared.rating,
+            shared.other_rating,
+            shared.difference()
+        )?;
+    }
+    for game in &diff.only_this {
+        writeln!(
+            out,
+            "{},{},{},,",
+            game.id,
+            csv_field(&game.name),
+            game.rating
+        )?;
+    }
+    for game in &diff.only_other {
+        writeln!(
+            out,
+            "{},{},,{},",
+            game.id,
+            csv_field(&game.name),
+            game.rating
+        )?;
+    }
+    Ok(())
+}
+
+/// Same content as write_db_diff for other programs.
+pub fn db_diff_json(diff: &DbDiff, threshold: f64) -> serde_json::Value {
+    let games = |games: &[Game]| -> Vec<serde_json::Value> {
+        games
+            .iter()
+            .map(|g| json!({"id": g.id, "name": g.name, "rating": g.rating}))
+            .collect()
+    };
+    let differing: Vec<serde_json::Value> = diff
+        .differing
+        .iter()
+        .map(|s| {
+            json!({
+                "id": s.id,
+                "name": s.name,
+                "rating": s.rating,
+                "other_rating": s.other_rating,
+            })
+        })
+        .collect();
+    json!({
+        "threshold": threshold,
+        "differing": differing,
+        "only_this": games(&diff.only_this),
+        "only_other": games(&diff.only_other),
+        "shared": diff.shared,
+        "mean_abs_difference": diff.mean_abs_difference,
+        "rank_correlation": diff.rank_correlation,
+    })
+}
+
+/// Writes `rank. Name (rating vs bgg average)` per game, names are
+/// cut to keep every line within `width` characters. Games come
+/// sorted, not grouped: a `label:` line starts every group and ranks
+/// stay those of the sort.
+pub fn write_compact(
+    out: &mut impl Write,
+    games: &[Game],
+    style: &Style,
+    width: usize,
+    group: Option<Group>,
+) -> io::Result<()> {
+    let ranked: Vec<(usize, &Game)> = games.iter().enumerate().map(|(i, g)| (i + 1, g)).collect();
+    let ranked = match group {
+        Some(group) => grouped(ranked, |(_, game)| group(game)),
+        None => ranked,
+    };
+    let mut last = None;
+    for (rank, game) in ranked {
+        if let Some(label) = group.map(|group| group(game)) {
+            if last.as_ref() != Some(&label) {
+                writeln!(out, "{}:", label)?;
+                last = Some(label);
+            }
+        }
+        let prefix = format!("{}. ", rank);
+        let suffix = format!(
+            " ({} vs {})",
+            style.number(game.rating),
+            style.number(game.bgg_avg_rating)
+        );
+        let room = width.saturating_sub(prefix.chars().count() + suffix.chars().count());
+        writeln!(out, "{}{}{}", prefix, truncate(&mark(game), room), suffix)?;
+    }
+    Ok(())
+}
+
+/// Cuts `text` to `max` characters, the last one becomes an ellipsis.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return String::from(text);
+    }
+    let mut cut: String = text.chars().take(max.saturating_sub(1)).collect();
+    if max > 0 {
+        cut.push('…');
+    }
+    cut
+}
+
+/// Writes csv that BGG accepts for bulk creation of a geeklist.
+/// Template placeholders: {rating}, {votes}, {swing}, {bgg_rating}.
+/// The group, if any, is an extra last column.
+pub fn write_geeklist(
+    out: &mut impl Write,
+    games: &[Game],
+    template: &str,
+    style: &Style,
+    group: Option<Group>,
+) -> io::Result<()> {
+    match group {
+        Some(_) => writeln!(out, "objectid,objectname,comment,group")?,
+        None => writeln!(out, "objectid,objectname,comment")?,
+    }
+    for game in games {
+        write!(out, "{}", geeklist_row(game, template, style))?;
+        match group {
+            Some(group) => writeln!(out, ",{}", csv_field(&group(game)))?,
+            None => writeln!(out)?,
+        }
+    }
+    Ok(())
+}
+
+/// `objectid,objectname,comment` of the game, without line end.
+fn geeklist_row(game: &Game, template: &str, style: &Style) -> String {
+    let swing = game.rating - game.bgg_avg_rating;
+    let sign = if swing < 0.0 { "-" } else { "+" };
+    let comment = template
+        .replace("{rating}", &style.number(game.rating))
+        .replace("{votes}", &game.votes.to_string())
+        .replace("{swing}", &format!("{}{}", sign, style.number(swing.abs())))
+        .replace("{bgg_rating}", &style.number(game.bgg_avg_rating));
+    format!(
+        "{},{},{}",
+        game.id,
+        csv_field(&game.name),
+        csv_field(&comment)
+    )
+}
+
+/// Quotes the field if it has a comma, quote or line break inside.
+fn csv_field(text: &str) -> String {
+    quoted(text, ',')
+}
+
+/// Field in quotes, with quotes inside doubled, if it has `delimiter`,
+/// a quote or a line break inside.
+fn quoted(text: &str, delimiter: char) -> String {
+    if text.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        String::from(text)
+    }
+}
+
+/// Writes a bar chart of games per rating bucket `width` wide,
+/// our rating on the left, BGG average on the right. Bars of
+/// both sides share the scale.
+pub fn write_histogram(out: &mut impl Write, games: &[Game], width: f64) -> io::Result<()> {
+    const BAR: usize = 30;
+    let bucket = |rating: f64| (rating / width).floor() as i64;
+    let ratings = games.iter().flat_map(|g| [g.rating, g.bgg_avg_rating]);
+    let first = ratings.clone().map(bucket).min().unwrap_or(0);
+    let last = ratings.map(bucket).max().unwrap_or(0);
+    let mut ours = vec![0; (last - first + 1) as usize];
+    let mut bgg = ours.clone();
+    for game in games {
+        ours[(bucket(game.rating) - first) as usize] += 1;
+        bgg[(bucket(game.bgg_avg_rating) - first) as usize] += 1;
+    }
+    let max = ours.iter().chain(&bgg).cloned().max().unwrap_or(0).max(1);
+    let bar = |n: usize| match "█".repeat(n * BAR / max) {
+        bar if bar.is_empty() => n.to_string(),
+        bar => format!("{} {}", bar, n),
+    };
+    writeln!(out, "{:<12}{:<w$}BGG", "Rating", "Ours", w = BAR + 6)?;
+    for (i, (o, b)) in ours.iter().zip(&bgg).enumerate() {
+        let from = (first + i as i64) as f64 * width;
+        let range = format!("{:.2}-{:.2}", from, from + width);
+        writeln!(out, "{:<12}{:<w$}{}", range, bar(*o), bar(*b), w = BAR + 6)?;
+    }
+    Ok(())
+}
+
+/// One bar per value, scaled to the largest one.
+pub fn sparkline(values: &[u32]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().cloned().max().unwrap_or(0);
+    values
+        .iter()
+        .map(|&v| {
+            if max == 0 {
+                BARS[0]
+            } else {
+                BARS[(v as usize * (BARS.len() - 1)) / max as usize]
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(id: u32, name: &str, rating: f64) -> Game {
+        Game {
+            rating,
+            votes: 10,
+            stable: true,
+            ..Game::from_search(id, String::from(name), 1000, 7.0, 7.25)
+        }
+    }
+
+    fn text(f: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> String {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn numbers_round_like_format() {
+        let point = Style::new(2, false, '\t').unwrap();
+        let comma = Style::new(2, true, ';').unwrap();
+        for value in [7.0, 7.005, 2.675, 9.999, 0.125, -0.5] {
+            let expected = format!("{:.2}", value);
+            assert_eq!(point.number(value), expected);
+            assert_eq!(comma.number(value), expected.replace('.', ","));
+        }
+        assert_eq!(Style::new(0, true, ';').unwrap().number(7.6), "8");
+        assert!(Style::new(2, true, ',').is_err());
+    }
+
+    #[test]
+    fn fields_with_delimiter_are_quoted() {
+        let style = Style::new(2, true, ';').unwrap();
+        assert_eq!(style.field("Plain"), "Plain");
+        assert_eq!(style.field("A; B"), "\"A; B\"");
+        assert_eq!(style.field(r#"Say "hi""#), r#""Say ""hi""""#);
+        assert_eq!(style.field("Two\nlines"), "\"Two\nlines\"");
+        // commas are data when they are not the delimiter
+        assert_eq!(style.field("1,5"), "1,5");
+        assert_eq!(csv_field("Rock, Paper"), "\"Rock, Paper\"");
+    }
+
+    #[test]
+    fn european_csv_keeps_columns() {
+        let style = Style::new(2, true, ';').unwrap();
+        let games = [game(1, "Tigris; Euphrates", 8.125), game(2, "Go", 7.5)];
+        let out = text(|out| write_text(out, &games, &style, None, None));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Id;Name;Rating;Votes;Geek Rating;Avg BGG Rating;BGG Votes;Weight;Stable;Sampled;Flagged"
+        );
+        assert_eq!(
+            lines[1],
+            "1;\"Tigris; Euphrates\";8,12;10;7,00;7,25;1000;;yes;no;no"
+        );
+        assert_eq!(lines[2], "2;Go;7,50;10;7,00;7,25;1000;;yes;no;no");
+    }
+
+    #[test]
+    fn geeklist_comment_from_template() {
+        let style = Style::new(2, false, '\t').unwrap();
+        let games = [game(13, "CATAN", 6.5), game(822, "Carcassonne", 7.75)];
+        let template = "Rated {rating} by {votes} users ({swing} vs BGG {bgg_rating})";
+        let out = text(|out| write_geeklist(out, &games, template, &style, None));
+        assert_eq!(
+            out,
+            "objectid,objectname,comment\n\
+             13,CATAN,Rated 6.50 by 10 users (-0.75 vs BGG 7.25)\n\
+             822,Carcassonne,Rated 7.75 by 10 users (+0.50 vs BGG 7.25)\n"
+        );
+    }
+
+    #[test]
+    fn marks_are_columns_of_their_own() {
+        let style = Style::new(1, false, ',').unwrap();
+        let games = [
+            game(1, "Done", 8.0),
+            Game {
+                stable: false,
+                ..game(2, "Going", 7.0)
+            },
+            Game {
+                sampled: true,
+                flagged: true,
+                ..game(3, "Odd~!", 6.0)
+            },
+        ];
+        let out = text(|out| write_text(out, &games, &style, None, None));
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(
+            lines[0].ends_with(",Weight,Stable,Sampled,Flagged"),
+            "{}",
+            lines[0]
+        );
+        assert_eq!(lines[1], "1,Done,8.0,10,7.0,7.2,1000,,yes,no,no");
+        assert_eq!(lines[2], "2,Going,7.0,10,7.0,7.2,1000,,no,no,no");
+        // the name is the name, whatever it holds
+        assert_eq!(lines[3], "3,Odd~!,6.0,10,7.0,7.2,1000,,yes,yes,yes");
+        let out = text(|out| write_geeklist(out, &games, "{rating}", &style, None));
+        assert_eq!(out.lines().nth(2).unwrap(), "2,Going,7.0");
+        let out = text(|out| write_tsv(out, &games, &style, None, None));
+        assert!(out.ends_with("\tyes\tyes\tyes\n"), "{}", out);
+        // the terminal has no columns to spare
+        let out = text(|out| write_compact(out, &games, &style, 80, None));
+        assert_eq!(
+            out,
+            "1. Done (8.0 vs 7.2)\n2. Going* (7.0 vs 7.2)\n3. Odd~!~! (6.0 vs 7.2)\n"
+        );
+    }
+
+    #[test]
+    fn csv_json_and_markdown_reports() {
+        let games = [game(1, "Rock, Paper | Scissors", 8.125), game(2, "Go", 7.5)];
+        // csv is text with commas whatever --delimiter is
+        let style = Style::new(2, false, '\t').unwrap();
+        let out = text(|out| write_csv(out, &games, &style, None, None));
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("Id,Name,Rating,"), "{}", lines[0]);
+        assert!(
+            lines[1].starts_with("1,\"Rock, Paper | Scissors\",8.12,"),
+            "{}",
+            lines[1]
+        );
+        // and semicolons once the comma is decimal
+        let european = Style::new(2, true, ';').unwrap();
+        let out = text(|out| write_csv(out, &games, &european, None, None));
+        assert!(
+            out.lines().nth(2).unwrap().starts_with("2;Go;7,50;"),
+            "{}",
+            out
+        );
+
+        let out = text(|out| write_markdown(out, &games, &style, None, group_by("decade")));
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(
+            lines[0].starts_with("| Id | Name | Rating |"),
+            "{}",
+            lines[0]
+        );
+        assert!(lines[0].ends_with(" | Group |"), "{}", lines[0]);
+        assert_eq!(lines[1], "|---|".to_string() + &"---|".repeat(11));
+        assert!(
+            lines[2].starts_with("| 1 | Rock, Paper \\| Scissors | 8.12 |"),
+            "{}",
+            lines[2]
+        );
+        assert!(lines[3].ends_with(" | Unknown year |"), "{}", lines[3]);
+
+        let out = text(|out| write_json(out, &games, None, group_by("decade")));
+        assert!(out.ends_with("]\n"), "{}", out);
+        let list: serde_json::Value = serde_json::from_str(&out).unwrap();
+        let list = list.as_array().unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0]["name"], "Rock, Paper | Scissors");
+        assert_eq!(list[1]["id"], 2);
+        assert_eq!(list[1]["group"], "Unknown year");
+    }
+
+    #[test]
+    fn users_are_listed_in_rows_or_json() {
+        let user = UserRecord {
+            name: String::from("Jane, Doe"),
+            trusted: true,
+            average: Some(7.125),
+            ratings: 3,
+            updated: None,
+            pinned: true,
+        };
+        let style = Style::new(1, false, ',').unwrap();
+        let out = text(|out| {
+            write_user_header(out, &style)?;
+            write_user(out, &user, &style)
+        });
+        assert_eq!(
+            out,
+            "Name,Trust,Pinned,Average,Ratings,Updated\n\"Jane, Doe\",trusted,yes,7.1,3,\n"
+        );
+        let out = text(|out| write_user_json(out, &user));
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(
+            value,
+            json!({"name": "Jane, Doe", "trusted": true, "pinned": true,
+                "average": 7.125, "ratings": 3, "updated": null})
+        );
+    }
+
+    #[test]
+    fn geeklist_quotes_names_and_comments() {
+        let style = Style::new(1, true, ';').unwrap();
+        let games = [game(1, "Twilight Struggle: \"Deluxe\", 2nd", 8.0)];
+        let out = text(|out| write_geeklist(out, &games, "{rating}, honestly", &style, None));
+        assert_eq!(
+            out.lines().nth(1).unwrap(),
+            r#"1,"Twilight Struggle: ""Deluxe"", 2nd","8,0, honestly""#
+        );
+    }
+
+    fn geek(id: u32, rating: f64, geek_rating: f64) -> Game {
+        Game {
+            bgg_geek_rating: geek_rating,
+            ..game(id, &format!("Game {}", id), rating)
+        }
+    }
+
+    #[test]
+    fn movers_rank_against_geek_rating() {
+        // our order 1, 2, 3, BGG has 3, 1, 2
+        let games = [geek(1, 8.0, 7.0), geek(2, 7.5, 6.0), geek(3, 7.0, 8.0)];
+        let style = Style::new(2, false, '\t').unwrap();
+        let out = text(|out| write_movers(out, &games, 1, &style));
+        assert_eq!(
+            out,
+            "Moved up:\nId\tName\tBGG Rank\tRank\tShift\tSwing\n\
+             1\tGame 1\t2\t1\t+1\t0.75\n\
+             Moved down:\nId\tName\tBGG Rank\tRank\tShift\tSwing\n\
+             3\tGame 3\t1\t3\t-2\t-0.25\n"
+        );
+    }
+
+    #[test]
+    fn compare_finds_entered_left_and_moved() {
+        let old = [game(1, "A", 8.0), game(2, "B", 7.0), game(3, "C", 6.0)];
+        let new = [
+            game(2, "B", 7.5),
+            game(1, "A", 7.2),
+            game(4, "D", 6.5),
+            game(3, "C", 6.0),
+        ];
+        let changes = compare(&old, &new);
+        let ranks: Vec<_> = changes
+            .iter()
+            .map(|c| (c.id, c.old.map(|s| s.rank), c.new.map(|s| s.rank)))
+            .collect();
+        // C lost a place, B and A swapped, D entered
+        assert_eq!(
+            ranks,
+            [
+                (2, Some(2), Some(1)),
+                (1, Some(1), Some(2)),
+                (4, None, Some(3)),
+                (3, Some(3), Some(4))
+            ]
+        );
+        let left = compare(&new, &old);
+        assert!(left
+            .iter()
+            .any(|c| c.id == 4 && c.old.is_some() && c.new.is_none()));
+        assert!(compare(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn extended_output_shows_the_tier() {
+        let prior = Prior {
+            votes: 10.0,
+            mean: 7.0,
+        };
+        let games = [
+            Game {
+                tier: Some(5000),
+                ..game(1, "A", 8.0)
+            },
+            game(2, "B", 7.0),
+        ];
+        let style = Style::new(2, false, ',').unwrap();
+        let out = text(|out| write_text(out, &games, &style, Some(&prior), None));
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].ends_with(",Hype,Tier"), "{}", lines[0]);
+        assert!(lines[1].ends_with(",5000"), "{}", lines[1]);
+        assert!(lines[2].ends_with(','), "{}", lines[2]);
+        assert_eq!(game_json(&games[0], Some(&prior))["tier"], 5000);
+        assert!(game_json(&games[1], Some(&prior))["tier"].is_null());
+        assert!(game_json(&games[0], None).get("tier").is_none());
+    }
+
+    fn ids(games: &[Game]) -> Vec<u32> {
+        games.iter().map(|g| g.id).collect()
+    }
+
+    fn order(keys: &[&str], descending: bool) -> Order {
+        Order {
+            keys: keys.iter().map(|k| String::from(*k)).collect(),
+            descending,
+        }
+    }
+
+    const PRIOR: Prior = Prior {
+        votes: 100.0,
+        mean: 5.5,
+    };
+
+    #[test]
+    fn derived_key_keeps_its_place_in_the_order() {
+        // 1 and 2 share votes, 2 is rated from fewer ratings
+        // with the same spread, so its interval starts lower
+        let games = vec![
+            Game {
+                votes: 50,
+                rating_sd: Some(1.0),
+                ..game(1, "A", 7.0)
+            },
+            Game {
+                votes: 50,
+                rating_sd: Some(2.0),
+                ..game(2, "B", 7.0)
+            },
+            Game {
+                votes: 900,
+                rating_sd: Some(1.0),
+                ..game(3, "C", 6.0)
+            },
+        ];
+        let mut sorted = games.clone();
+        sort(&mut sorted, &order(&["votes", "ci-low"], true), &PRIOR);
+        assert_eq!(ids(&sorted), [3, 1, 2]);
+        sort(&mut sorted, &order(&["ci-low", "votes"], true), &PRIOR);
+        assert_eq!(ids(&sorted), [1, 2, 3]);
+        sort(&mut sorted, &order(&["votes", "ci-low"], false), &PRIOR);
+        assert_eq!(ids(&sorted), [2, 1, 3]);
+    }
+
+    #[test]
+    fn missing_values_sort_like_null() {
+        let mut games = vec![
+            Game {
+                weight: Some(2.0),
+                ..game(1, "A", 7.0)
+            },
+            game(2, "B", 7.0),
+        ];
+        sort(&mut games, &order(&["weight", "ci-low"], false), &PRIOR);
+        assert_eq!(ids(&games), [2, 1]);
+        sort(&mut games, &order(&["weight", "ci-low"], true), &PRIOR);
+        assert_eq!(ids(&games), [1, 2]);
+    }
+
+    #[test]
+    fn bayes_goes_from_the_prior_to_the_mean() {
+        let unrated = Game {
+            votes: 0,
+            ..game(1, "A", 0.0)
+        };
+        assert_eq!(bayesian(&unrated, &PRIOR), 5.5);
+        let none = Prior {
+            votes: 0.0,
+            mean: 5.5,
+        };
+        assert_eq!(bayesian(&unrated, &none), 5.5);
+        let huge = Game {
+            votes: 10_000_000,
+            ..game(2, "B", 8.0)
+        };
+        assert!((bayesian(&huge, &PRIOR) - 8.0).abs() < 1e-4);
+        // 100 dummy votes of 5.5 and 100 real ones of 8
+        let even = Game {
+            votes: 100,
+            ..game(3, "C", 8.0)
+        };
+        assert!((bayesian(&even, &PRIOR) - 6.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bayes_key_keeps_its_place_in_the_order() {
+        // few high votes lose to many good ones under the prior
+        let games = vec![
+            Game {
+                votes: 5,
+                ..game(1, "A", 9.0)
+            },
+            Game {
+                votes: 5000,
+                ..game(2, "B", 7.5)
+            },
+            Game {
+                votes: 5000,
+                ..game(3, "C", 7.0)
+            },
+        ];
+        let mut sorted = games.clone();
+        sort(&mut sorted, &order(&["bayes"], true), &PRIOR);
+        assert_eq!(ids(&sorted), [2, 3, 1]);
+        sort(&mut sorted, &order(&["name", "bayes"], true), &PRIOR);
+        assert_eq!(ids(&sorted), [3, 2, 1]);
+        sort(&mut sorted, &order(&["votes", "bayes"], true), &PRIOR);
+        assert_eq!(ids(&sorted), [2, 3, 1]);
+        sort(&mut sorted, &order(&["rating", "bayes"], true), &PRIOR);
+        assert_eq!(ids(&sorted), [1, 2, 3]);
+    }
+
+    fn published(id: u32, name: &str, rating: f64, year: u32) -> Game {
+        Game {
+            year: Some(year),
+            ..game(id, name, rating)
+        }
+    }
+
+    #[test]
+    fn top_larger_than_the_list_keeps_it_all() {
+        let games = vec![game(1, "A", 8.0), game(2, "B", 7.0)];
+        assert_eq!(ids(&arrange(games.clone(), Some(5), None)), [1, 2]);
+        assert_eq!(ids(&arrange(games.clone(), Some(1), None)), [1]);
+        assert!(arrange(games.clone(), Some(0), None).is_empty());
+        assert_eq!(ids(&arrange(games, None, None)), [1, 2]);
+    }
+
+    #[test]
+    fn groups_are_formed_from_the_top_games() {
+        let decade = group_by("decade");
+        let games = vec![
+            published(1, "A", 9.0, 1995),
+            published(2, "B", 8.0, 2004),
+            published(3, "C", 7.0, 1991),
+            published(4, "D", 6.0, 2011),
+        ];
+        // D is past the top, so is its decade
+        assert_eq!(ids(&arrange(games.clone(), Some(3), decade)), [1, 3, 2]);
+        assert_eq!(ids(&arrange(games.clone(), Some(9), decade)), [1, 3, 2, 4]);
+        assert_eq!(ids(&arrange(games, Some(1), decade)), [1]);
+    }
+
+    #[test]
+    fn delimited_formats_carry_the_group_column() {
+        let style = Style::new(1, false, ',').unwrap();
+        let decade = group_by("decade");
+        let games = arrange(
+            vec![
+                published(1, "A", 9.0, 1995),
+                published(2, "B", 8.0, 2004),
+                game(3, "C", 7.0),
+            ],
+            None,
+            decade,
+        );
+        for out in [
+            text(|out| write_text(out, &games, &style, None, decade)),
+            text(|out| write_tsv(out, &games, &style, None, decade)),
+        ] {
+            let lines: Vec<&str> = out.lines().collect();
+            assert_eq!(lines.len(), 4, "{}", out);
+            assert!(lines[0].ends_with("Flagged,Group") || lines[0].ends_with("Flagged\tGroup"));
+            assert!(lines[1].ends_with("1990s"), "{}", lines[1]);
+            assert!(lines[2].ends_with("2000s"), "{}", lines[2]);
+            assert!(lines[3].ends_with("Unknown year"), "{}", lines[3]);
+            assert!(!out.contains(":\n"), "{}", out);
+        }
+    }
+
+    #[test]
+    fn compact_ranks_stay_those_of_the_sort() {
+        let style = Style::new(1, false, ',').unwrap();
+        let games = vec![
+            published(1, "A", 9.0, 1995),
+            published(2, "B", 8.0, 2004),
+            published(3, "C", 7.0, 1991),
+        ];
+        let out = text(|out| write_compact(out, &games, &style, 80, group_by("decade")));
+        assert_eq!(
+            out,
+            "1990s:\n1. A (9.0 vs 7.2)\n3. C (7.0 vs 7.2)\n2000s:\n2. B (8.0 vs 7.2)\n"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use crate::error::AppError;
+use chrono::Local;
+#[cfg(test)]
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use termcolor::{ColorSpec, WriteColor};
+
+// File of --append-log, nothing is recorded without it.
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    // lines recorded by this thread while catch runs
+    static CAUGHT: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` and returns with its result the lines it recorded on this
+/// thread, whether a log is open or not.
+#[cfg(test)]
+pub fn catch<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    CAUGHT.with(|c| *c.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let lines = CAUGHT.with(|c| c.borrow_mut().take()).unwrap_or_default();
+    (result, lines)
+}
+
+/// Appends every line said from now on to `path`, it is created
+/// if missing.
+pub fn open(path: &Path) -> Result<(), AppError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    if LOG.set(Mutex::new(file)).is_err() {
+        let e = String::from("Log file is already open.");
+        return Err(AppError::Config(e));
+    }
+    Ok(())
+}
+
+/// Writes every line of `text` to the log with a timestamp. Write
+/// errors are ignored, a full disk must not stop a run that keeps
+/// its progress in the db anyway.
+pub fn record(text: &str) {
+    #[cfg(test)]
+    CAUGHT.with(|c| {
+        if let Some(lines) = c.borrow_mut().as_mut() {
+            lines.extend(text.lines().map(String::from));
+        }
+    });
+    let log = match LOG.get() {
+        Some(log) => log,
+        None => return,
+    };
+    let mut file = log.lock().unwrap_or_else(|e| e.into_inner());
+    let now = Local::now().format("%Y-%m-%d %H:%M:%S");
+    for line in text.lines() {
+        let _ = writeln!(file, "{} {}", now, line);
+    }
+}
+
+/// println! that is recorded too.
+pub fn say(text: &str) {
+    println!("{}", text);
+    record(text);
+}
+
+/// eprintln! that is recorded too.
+pub fn complain(text: &str) {
+    eprintln!("{}", text);
+    record(text);
+}
+
+/// Passes everything on to `inner` and records every finished line.
+/// Colors only go to `inner`, the log stays plain text.
+pub struct Tee<W> {
+    inner: W,
+    line: Vec<u8>,
+}
+
+impl<W> Tee<W> {
+    pub fn new(inner: W) -> Tee<W> {
+        Tee {
+            inner,
+            line: Vec::new(),
+        }
+    }
+
+    /// Writes here are not recorded, e.g. lines redrawn in place.
+    pub fn inner_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        for &b in &buf[..n] {
+            if b == b'\n' {
+                record(&String::from_utf8_lossy(&self.line));
+                self.line.clear();
+            } else {
+                self.line.push(b);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: WriteColor> WriteColor for Tee<W> {
+    fn supports_color(&self) -> bool {
+        self.inner.supports_color()
+    }
+
+    fn set_color(&mut self, spec: &ColorSpec) -> io::Result<()> {
+        self.inner.set_color(spec)
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.inner.reset()
+    }
+}
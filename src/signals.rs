@@ -0,0 +1,134 @@
+//! Graceful stop of pull and balance. SIGINT (ctrl+c), SIGTERM and SIGHUP lower
+//! the running flag, so does closing the console on Windows.
+use crate::error::AppError;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+
+/// Signal that asked to stop, 0 until one comes.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(unix)]
+const SIGNALS: [(i32, &str); 3] = [
+    (signal_hook::consts::SIGINT, "SIGINT"),
+    (signal_hook::consts::SIGTERM, "SIGTERM"),
+    (signal_hook::consts::SIGHUP, "SIGHUP"),
+];
+
+/// Watches for signals until dropped, the thread that waits for them
+/// is stopped and joined then.
+pub struct Handler {
+    #[cfg(unix)]
+    handle: signal_hook::iterator::Handle,
+    #[cfg(unix)]
+    watcher: Option<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(unix)]
+pub fn set_handler(running: Arc<AtomicBool>) -> Result<Handler, AppError> {
+    use signal_hook::iterator::Signals;
+    use std::thread;
+
+    let ids: Vec<i32> = SIGNALS.iter().map(|(signal, _)| *signal).collect();
+    let mut signals = Signals::new(&ids)?;
+    let handle = signals.handle();
+    let watcher = thread::Builder::new()
+        .name(String::from("signals"))
+        .spawn(move || {
+            // ends when the handle is closed
+            if let Some(signal) = signals.forever().next() {
+                SIGNAL.store(signal, Ordering::SeqCst);
+                running.store(false, Ordering::SeqCst);
+            }
+        })?;
+    Ok(Handler {
+        handle,
+        watcher: Some(watcher),
+    })
+}
+
+#[cfg(unix)]
+impl Drop for Handler {
+    fn drop(&mut self) {
+        self.handle.close();
+        if let Some(watcher) = self.watcher.take() {
+            // the watcher only stores two atomics, it has nothing to panic on
+            let _ = watcher.join();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn set_handler(running: Arc<AtomicBool>) -> Result<Handler, AppError> {
+    ctrlc::set_handler(move || {
+        SIGNAL.store(1, Ordering::SeqCst);
+        running.store(false, Ordering::SeqCst);
+    })
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    Ok(Handler {})
+}
+
+/// Name of the signal that stopped the process, None if none came.
+pub fn received() -> Option<&'static str> {
+    let signal = SIGNAL.load(Ordering::SeqCst);
+    if signal == 0 {
+        return None;
+    }
+    #[cfg(unix)]
+    {
+        SIGNALS
+            .iter()
+            .find(|(s, _)| *s == signal)
+            .map(|(_, name)| *name)
+    }
+    #[cfg(not(unix))]
+    {
+        Some("ctrl+c or console close")
+    }
+}
+
+/// Line to print once a command that `received` a signal is over.
+/// Progress is only saved when the run stopped the way it should.
+pub fn stopped<T>(result: &Result<T, AppError>) -> Option<String> {
+    let signal = received()?;
+    match result {
+        Ok(_) | Err(AppError::Interrupted(_)) => {
+            Some(format!("Stopped by {}, progress is saved.", signal))
+        }
+        Err(_) => None,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use signal_hook::low_level::raise;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // SIGNAL is one for the process, everything runs in one test
+    #[test]
+    fn signal_lowers_the_flag_and_the_watcher_is_joined() {
+        let idle = set_handler(Arc::new(AtomicBool::new(true))).unwrap();
+        drop(idle);
+        assert_eq!(received(), None);
+        assert!(stopped(&Ok(())).is_none());
+
+        let running = Arc::new(AtomicBool::new(true));
+        let handler = set_handler(running.clone()).unwrap();
+        raise(signal_hook::consts::SIGHUP).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while running.load(Ordering::SeqCst) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(handler);
+        assert!(!running.load(Ordering::SeqCst));
+        assert_eq!(received(), Some("SIGHUP"));
+
+        let saved = "Stopped by SIGHUP, progress is saved.";
+        assert_eq!(stopped(&Ok(())).as_deref(), Some(saved));
+        let interrupted: Result<(), AppError> = Err(AppError::Interrupted(String::new()));
+        assert_eq!(stopped(&interrupted).as_deref(), Some(saved));
+        let failed: Result<(), AppError> = Err(AppError::Config(String::from("bad")));
+        assert_eq!(stopped(&failed), None);
+    }
+}
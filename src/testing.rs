@@ -0,0 +1,112 @@
+//! Helpers shared by the tests of all modules.
+use crate::core::{self, Config, StabilizeSummary};
+use crate::db;
+use crate::progress::Reporter;
+use bgg_swing2::{Game, User};
+use chrono::{DateTime, Local};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use tempfile::TempDir;
+
+// all connections go to one db path, tests that use it take turns
+static TURN: Mutex<()> = Mutex::new(());
+
+/// Empty db in a scratch directory, every connection opens it
+/// until this is dropped.
+pub struct TestDb {
+    _dir: TempDir,
+    _turn: MutexGuard<'static, ()>,
+}
+
+impl TestDb {
+    pub fn new() -> TestDb {
+        // a failed test must not fail the ones after it
+        let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let path: &'static Path = Box::leak(dir.path().join("top.db").into_boxed_path());
+        *db::TEST_PATH.lock().unwrap() = Some(path);
+        db::initialize().unwrap();
+        TestDb {
+            _dir: dir,
+            _turn: turn,
+        }
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        *db::TEST_PATH.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Clock of core `days` ahead of the real one until this is dropped,
+/// users cached now are as old then.
+pub struct Clock;
+
+impl Clock {
+    pub fn ahead(days: i64) -> Clock {
+        Clock::at(Local::now() + chrono::Duration::days(days))
+    }
+
+    /// Clock of core stopped at `now`.
+    pub fn at(now: DateTime<Local>) -> Clock {
+        *core::TEST_NOW.lock().unwrap() = Some(now);
+        Clock
+    }
+}
+
+impl Drop for Clock {
+    fn drop(&mut self) {
+        *core::TEST_NOW.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+/// Config of a quick run against the mock at `base_url`.
+pub fn config(base_url: &str) -> Config {
+    let mut config = Config::new(100, 2, 0, 2);
+    config.base_url = String::from(base_url);
+    // the mock has no politeness limits
+    config.max_requests_per_minute = u32::MAX;
+    config
+}
+
+/// Keeps the summary of a run and what became of its games.
+#[derive(Default)]
+pub struct Recorder {
+    pub summary: Option<StabilizeSummary>,
+    /// ids of games reported as plain balanced ones
+    pub balanced: Vec<u32>,
+    /// ids of games reported as moved, with their previous rating
+    pub changed: Vec<(u32, f64)>,
+    /// warnings of a pull
+    pub warnings: Vec<String>,
+    /// workers that told of a page, and those that are gone
+    pub busy: Vec<usize>,
+    pub done: Vec<usize>,
+    /// users a review gave up on
+    pub skipped: Vec<String>,
+}
+
+impl Reporter for Recorder {
+    fn on_game_page(&mut self, worker: usize, _game: &Game) {
+        self.busy.push(worker);
+    }
+    fn on_worker_done(&mut self, worker: usize) {
+        self.done.push(worker);
+    }
+    fn on_game_balanced(&mut self, game: &Game) {
+        self.balanced.push(game.id);
+    }
+    fn on_game_changed(&mut self, game: &Game, previous: f64) {
+        self.changed.push((game.id, previous));
+    }
+    fn on_user_skipped(&mut self, user: &User) {
+        self.skipped.push(user.clone());
+    }
+    fn on_pull_warning(&mut self, _page: usize, warning: &str) {
+        self.warnings.push(String::from(warning));
+    }
+    fn on_finished(&mut self, summary: &StabilizeSummary) {
+        self.summary = Some(summary.clone());
+    }
+}